@@ -0,0 +1,379 @@
+//! Objects in S3 and Google Cloud Storage buckets, addressed as `s3://bucket/key` and
+//! `gs://bucket/key`.
+//!
+//! They are fetched over https, with requests signed with the credentials the AWS and Google
+//! Cloud tools would use. Nix can fetch `s3://` urls itself, with the same credentials, but
+//! can't fetch `gs://` ones, so generated expressions fetch those from their public url.
+
+use anyhow::Context;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// An object in a bucket, and how to fetch it.
+pub struct Object {
+    /// Where the object is fetched from over http.
+    pub url: url::Url,
+    signer: Signer,
+}
+
+enum Signer {
+    /// Signature version 4, for S3 and anything compatible with it.
+    Aws {
+        credentials: Option<AwsCredentials>,
+        region: String,
+    },
+    /// An OAuth access token for Google Cloud Storage.
+    Google(Option<String>),
+}
+
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl Object {
+    /// The object `url` is of, if it is an `s3://` or `gs://` url.
+    pub fn of(url: &url::Url) -> anyhow::Result<Option<Object>> {
+        match url.scheme() {
+            "s3" => s3(url).map(Some),
+            "gs" => gs(url).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Sign `req`, which has to be made to the object's url. Without credentials it is sent
+    /// unsigned, which only works for public objects.
+    pub fn sign(&self, req: ureq::Request) -> ureq::Request {
+        match &self.signer {
+            Signer::Aws {
+                credentials: Some(credentials),
+                region,
+            } => sign_v4(req, credentials, region, chrono::Utc::now()),
+            Signer::Google(Some(token)) => req.set("Authorization", &format!("Bearer {token}")),
+            _ => req,
+        }
+    }
+}
+
+/// The public url of a `gs://` object, which nix fetches it from.
+pub fn public_url(url: &url::Url) -> Option<url::Url> {
+    if url.scheme() != "gs" {
+        return None;
+    }
+    format!(
+        "https://storage.googleapis.com/{}{}",
+        url.host_str()?,
+        url.path()
+    )
+    .parse()
+    .ok()
+}
+
+/// The bucket and key in `url`, checking it has both.
+fn bucket_and_key(url: &url::Url) -> anyhow::Result<(&str, &str)> {
+    let bucket = url
+        .host_str()
+        .filter(|bucket| !bucket.is_empty())
+        .with_context(|| format!("{url} has no bucket"))?;
+    if url.path().trim_start_matches('/').is_empty() {
+        anyhow::bail!("{} has no object key", url);
+    }
+    Ok((bucket, url.path()))
+}
+
+/// Percent-encode everything in `s` but unreserved characters, and slashes if `keep_slashes` is
+/// set, the way signature version 4 wants it.
+fn encode(s: &str, keep_slashes: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            out.push(b as char);
+        } else if b == b'/' && keep_slashes {
+            out.push('/');
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+/// Undo the percent-encoding of `s`.
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(b) if bytes[i] == b'%' => {
+                out.push(b);
+                i += 3;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// A `gs://` object, fetched from `$STORAGE_EMULATOR_HOST` if set, like the Google Cloud client
+/// libraries do, or else from Cloud Storage's XML API.
+fn gs(url: &url::Url) -> anyhow::Result<Object> {
+    let (bucket, key) = bucket_and_key(url)?;
+    let emulator = std::env::var("STORAGE_EMULATOR_HOST").ok();
+    let endpoint = match &emulator {
+        Some(host) if host.contains("://") => host.trim_end_matches('/').to_owned(),
+        Some(host) => format!("http://{host}"),
+        None => "https://storage.googleapis.com".to_owned(),
+    };
+    let object_url = format!("{endpoint}/{bucket}{}", encode(&decode(key), true));
+    let token = match std::env::var("GOOGLE_OAUTH_ACCESS_TOKEN") {
+        Ok(token) => Some(token),
+        Err(_) if emulator.is_none() => gcloud_token(),
+        Err(_) => None,
+    };
+    Ok(Object {
+        url: object_url
+            .parse()
+            .with_context(|| format!("invalid url {object_url}"))?,
+        signer: Signer::Google(token),
+    })
+}
+
+/// The access token of the account gcloud is logged in with, if it is installed and logged in.
+fn gcloud_token() -> Option<String> {
+    let output = std::process::Command::new("gcloud")
+        .args(["auth", "print-access-token"])
+        .stderr(std::process::Stdio::null())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            let token = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+            Some(token).filter(|token| !token.is_empty())
+        }
+        Ok(_) => {
+            log::debug!("not logged in with gcloud, fetching without credentials");
+            None
+        }
+        Err(e) => {
+            log::debug!("failed to run gcloud: {}", e);
+            None
+        }
+    }
+}
+
+/// The sections of an AWS config or credentials file, by name.
+fn parse_ini(text: &str) -> HashMap<String, HashMap<String, String>> {
+    let mut sections: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut current = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = Some(name.trim().to_owned());
+            sections.entry(name.trim().to_owned()).or_default();
+        } else if let (Some(section), Some((key, value))) = (&current, line.split_once('=')) {
+            sections
+                .get_mut(section)
+                .unwrap()
+                .insert(key.trim().to_owned(), value.trim().to_owned());
+        }
+    }
+    sections
+}
+
+/// The section of `profile` in the AWS file in `$var`, or `~/.aws/<default>`. In the config
+/// file, profiles other than the default one are called `profile <name>`.
+fn aws_profile(var: &str, default: &str, profile: &str) -> HashMap<String, String> {
+    let path = std::env::var_os(var).map(PathBuf::from).or_else(|| {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".aws").join(default))
+    });
+    let Some(text) = path.and_then(|path| std::fs::read_to_string(path).ok()) else {
+        return HashMap::new();
+    };
+    let mut sections = parse_ini(&text);
+    let section = match default {
+        "config" if profile != "default" => format!("profile {profile}"),
+        _ => profile.to_owned(),
+    };
+    sections.remove(&section).unwrap_or_default()
+}
+
+/// An `s3://` object. The `region`, `endpoint`, `scheme` and `profile` query parameters nix
+/// takes in these urls are understood too.
+///
+/// Credentials come from `$AWS_ACCESS_KEY_ID` and `$AWS_SECRET_ACCESS_KEY`, or else the
+/// profile in `$AWS_PROFILE`, or the default one, of the shared credentials and config files.
+/// Objects are fetched from the bucket's virtual host, unless an endpoint is given in the url,
+/// `$AWS_ENDPOINT_URL_S3` or `$AWS_ENDPOINT_URL`, which the bucket is then a path under.
+fn s3(url: &url::Url) -> anyhow::Result<Object> {
+    let (bucket, key) = bucket_and_key(url)?;
+    let query: HashMap<_, _> = url.query_pairs().collect();
+    let env = |var: &str| std::env::var(var).ok().filter(|value| !value.is_empty());
+    let profile = query
+        .get("profile")
+        .map(|profile| profile.to_string())
+        .or_else(|| env("AWS_PROFILE"))
+        .unwrap_or("default".to_owned());
+    // The credentials file takes precedence over the config file.
+    let mut files = aws_profile("AWS_CONFIG_FILE", "config", &profile);
+    files.extend(aws_profile(
+        "AWS_SHARED_CREDENTIALS_FILE",
+        "credentials",
+        &profile,
+    ));
+    let credentials = match (env("AWS_ACCESS_KEY_ID"), env("AWS_SECRET_ACCESS_KEY")) {
+        (Some(access_key_id), Some(secret_access_key)) => Some(AwsCredentials {
+            access_key_id,
+            secret_access_key,
+            session_token: env("AWS_SESSION_TOKEN"),
+        }),
+        _ => match (
+            files.remove("aws_access_key_id"),
+            files.remove("aws_secret_access_key"),
+        ) {
+            (Some(access_key_id), Some(secret_access_key)) => Some(AwsCredentials {
+                access_key_id,
+                secret_access_key,
+                session_token: files.remove("aws_session_token"),
+            }),
+            _ => None,
+        },
+    };
+    if credentials.is_none() {
+        log::debug!("no AWS credentials, fetching {} without them", url);
+    }
+    let region = query
+        .get("region")
+        .map(|region| region.to_string())
+        .or_else(|| env("AWS_REGION"))
+        .or_else(|| env("AWS_DEFAULT_REGION"))
+        .or_else(|| files.remove("region"))
+        .unwrap_or("us-east-1".to_owned());
+    let key = encode(&decode(key), true);
+    let endpoint = query
+        .get("endpoint")
+        .map(|endpoint| endpoint.to_string())
+        .or_else(|| env("AWS_ENDPOINT_URL_S3"))
+        .or_else(|| env("AWS_ENDPOINT_URL"));
+    let object_url = match endpoint {
+        Some(endpoint) if endpoint.contains("://") => {
+            format!("{}/{bucket}{key}", endpoint.trim_end_matches('/'))
+        }
+        Some(endpoint) => {
+            let scheme = query.get("scheme").map_or("https", |scheme| scheme);
+            format!(
+                "{scheme}://{}/{bucket}{key}",
+                endpoint.trim_end_matches('/')
+            )
+        }
+        None => format!("https://{bucket}.s3.{region}.amazonaws.com{key}"),
+    };
+    Ok(Object {
+        url: object_url
+            .parse()
+            .with_context(|| format!("invalid url {object_url}"))?,
+        signer: Signer::Aws {
+            credentials,
+            region,
+        },
+    })
+}
+
+/// HMAC-SHA256 of `data` with `key`.
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        let key = crate::store::sha256(key);
+        block[..key.len()].copy_from_slice(&key);
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(move |b| b ^ byte);
+    let inner: Vec<u8> = pad(0x36).chain(data.iter().copied()).collect();
+    let outer: Vec<u8> = pad(0x5c).chain(crate::store::sha256(&inner)).collect();
+    crate::store::sha256(&outer)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Sign `req` with AWS signature version 4, made at `now`. The payload isn't signed, there is
+/// none to sign for the requests made here.
+fn sign_v4(
+    req: ureq::Request,
+    credentials: &AwsCredentials,
+    region: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> ureq::Request {
+    let Ok(url) = url::Url::parse(req.url()) else {
+        return req;
+    };
+    let date = now.format("%Y%m%d").to_string();
+    let time = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let host = match url.port() {
+        Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+        None => url.host_str().unwrap_or_default().to_owned(),
+    };
+    let mut query: Vec<_> = url
+        .query_pairs()
+        .map(|(key, value)| (encode(&key, false), encode(&value, false)))
+        .collect();
+    query.sort();
+    let query: Vec<_> = query
+        .into_iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect();
+    let mut headers = vec![
+        ("host", host),
+        ("x-amz-content-sha256", "UNSIGNED-PAYLOAD".to_owned()),
+        ("x-amz-date", time.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{value}\n"))
+        .collect();
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{canonical_headers}\n{signed_headers}\nUNSIGNED-PAYLOAD",
+        req.method(),
+        encode(&decode(url.path()), true),
+        query.join("&"),
+    );
+    let scope = format!("{date}/{region}/s3/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{time}\n{scope}\n{}",
+        hex(&crate::store::sha256(canonical_request.as_bytes()))
+    );
+    let key = [date.as_str(), region, "s3", "aws4_request"].iter().fold(
+        format!("AWS4{}", credentials.secret_access_key).into_bytes(),
+        |key, part| hmac(&key, part.as_bytes()),
+    );
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+        credentials.access_key_id
+    );
+    // Host is set by ureq itself, from the url.
+    headers
+        .into_iter()
+        .skip(1)
+        .fold(req, |req, (name, value)| req.set(name, &value))
+        .set("Authorization", &authorization)
+}
@@ -0,0 +1,61 @@
+//! When each source was last checked upstream successfully, whether it changed or not.
+//!
+//! This is kept in the state directory rather than in the sources file, so checking sources that
+//! didn't change leaves the sources file as it was.
+
+use anyhow::Context;
+use chrono::{DateTime, FixedOffset};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The times sources were last checked, by name.
+#[derive(Default, Debug)]
+pub struct LastChecked {
+    path: PathBuf,
+    times: BTreeMap<String, DateTime<FixedOffset>>,
+}
+
+impl LastChecked {
+    /// Read the times the sources in `sources_file` were checked. Sources that were never
+    /// checked, or before this was recorded, don't have one.
+    pub fn load(sources_file: &Path) -> anyhow::Result<Self> {
+        let path = crate::state_dir(sources_file).join("last-checked.json");
+        let times = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)
+                .with_context(|| format!("failed to read {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read {}", path.display()));
+            }
+        };
+        Ok(Self { path, times })
+    }
+
+    /// When the source `name` was last checked.
+    pub fn get(&self, name: &str) -> Option<DateTime<FixedOffset>> {
+        self.times.get(name).copied()
+    }
+
+    /// Record that the source `name` was checked at `time`.
+    pub fn set(&mut self, name: &str, time: DateTime<FixedOffset>) {
+        self.times.insert(name.to_owned(), time);
+    }
+
+    /// Move the time of the source `old` to `new`, which it was renamed to.
+    pub fn rename(&mut self, old: &str, new: &str) {
+        if let Some(time) = self.times.remove(old) {
+            self.times.insert(new.to_owned(), time);
+        }
+    }
+
+    /// Write the times back, forgetting those of sources not in `sources` anymore.
+    pub fn save(&mut self, sources: &crate::Sources) -> anyhow::Result<()> {
+        self.times
+            .retain(|name, _| sources.sources.contains_key(name));
+        let dir = self.path.parent().unwrap();
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.times)?)
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+}
@@ -0,0 +1,307 @@
+//! Shell completion scripts, generated from the definition of the command line.
+//!
+//! Besides subcommands and flags, the scripts complete the names of the sources in the sources
+//! file, by running `ls --names`, for subcommands taking the name of an existing source.
+
+use argh_shared::{CommandInfoWithArgs, FlagInfo, FlagInfoKind, Optionality};
+use std::fmt::Write;
+
+/// A shell to generate a completion script for.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl std::str::FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(format!("unknown shell {s}, expected bash, zsh or fish")),
+        }
+    }
+}
+
+/// Generate the completion script of `program`, whose command line is described by `info`, for
+/// `shell`.
+pub fn script(shell: Shell, program: &str, info: &CommandInfoWithArgs) -> String {
+    match shell {
+        Shell::Bash => bash(program, info),
+        Shell::Zsh => zsh(program, info),
+        Shell::Fish => fish(program, info),
+    }
+}
+
+/// Whether the first positional argument of `command` is the name of an existing source.
+fn takes_source_name(name: &str, command: &CommandInfoWithArgs) -> bool {
+    // `add` takes the name of a new source.
+    name != "add"
+        && command
+            .positionals
+            .first()
+            .is_some_and(|p| matches!(p.name, "name" | "names" | "old"))
+}
+
+fn flags<'a>(info: &'a CommandInfoWithArgs) -> impl Iterator<Item = &'a FlagInfo<'a>> {
+    info.flags.iter().filter(|flag| !flag.hidden)
+}
+
+/// The spellings of a flag, the long one first.
+fn spellings(flag: &FlagInfo) -> Vec<String> {
+    std::iter::once(flag.long.to_owned())
+        .chain(flag.short.map(|short| format!("-{short}")))
+        .collect()
+}
+
+/// The first sentence of a description, short enough for a completion menu.
+fn summary(description: &str) -> String {
+    // Braces are doubled in descriptions, which are format strings.
+    let description = description
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace("{{", "{")
+        .replace("}}", "}");
+    match description.find(". ") {
+        Some(end) => description[..end].to_owned(),
+        None => description.trim_end_matches('.').to_owned(),
+    }
+}
+
+fn function_name(program: &str) -> String {
+    format!(
+        "_{}",
+        program.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+    )
+}
+
+fn bash(program: &str, info: &CommandInfoWithArgs) -> String {
+    let function = function_name(program);
+    // Flags taking a value, whose value is completed as a file.
+    let options = |info: &CommandInfoWithArgs| {
+        flags(info)
+            .filter(|flag| matches!(flag.kind, FlagInfoKind::Option { .. }))
+            .flat_map(spellings)
+            .collect::<Vec<_>>()
+    };
+    let words = |info: &CommandInfoWithArgs| flags(info).flat_map(spellings).collect::<Vec<_>>();
+    let complete_values = |out: &mut String, options: &[String]| {
+        if !options.is_empty() {
+            writeln!(
+                out,
+                "        case $prev in {}) COMPREPLY=($(compgen -f -- \"$cur\")); return ;; esac",
+                options.join("|")
+            )
+            .unwrap();
+        }
+    };
+
+    let mut out = String::new();
+    let global_options = options(info);
+    writeln!(out, "# bash completion for {program}").unwrap();
+    writeln!(out, "{function}() {{").unwrap();
+    writeln!(
+        out,
+        "    local cur=${{COMP_WORDS[COMP_CWORD]}} prev=${{COMP_WORDS[COMP_CWORD-1]}}"
+    )
+    .unwrap();
+    writeln!(out, "    local command= words= names= i").unwrap();
+    writeln!(out, "    for ((i = 1; i < COMP_CWORD; i++)); do").unwrap();
+    writeln!(out, "        case ${{COMP_WORDS[i]}} in").unwrap();
+    if !global_options.is_empty() {
+        writeln!(out, "        {}) ((i++)) ;;", global_options.join("|")).unwrap();
+    }
+    writeln!(out, "        -*) ;;").unwrap();
+    writeln!(out, "        *) command=${{COMP_WORDS[i]}}; break ;;").unwrap();
+    writeln!(out, "        esac").unwrap();
+    writeln!(out, "    done").unwrap();
+    writeln!(out, "    case $command in").unwrap();
+    writeln!(out, "    '')").unwrap();
+    complete_values(&mut out, &global_options);
+    let mut global_words = words(info);
+    global_words.extend(info.commands.iter().map(|sub| sub.name.to_owned()));
+    writeln!(out, "        words=\"{}\" ;;", global_words.join(" ")).unwrap();
+    for sub in &info.commands {
+        writeln!(out, "    {})", sub.name).unwrap();
+        complete_values(&mut out, &options(&sub.command));
+        write!(out, "        words=\"{}\"", words(&sub.command).join(" ")).unwrap();
+        if takes_source_name(sub.name, &sub.command) {
+            write!(out, " names=1").unwrap();
+        }
+        writeln!(out, " ;;").unwrap();
+    }
+    writeln!(out, "    esac").unwrap();
+    writeln!(out, "    if [[ -n $names && $cur != -* ]]; then").unwrap();
+    writeln!(
+        out,
+        "        words=\"$words $({program} ls --names 2>/dev/null)\""
+    )
+    .unwrap();
+    writeln!(out, "    fi").unwrap();
+    writeln!(out, "    COMPREPLY=($(compgen -W \"$words\" -- \"$cur\"))").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out, "complete -F {function} {program}").unwrap();
+    out
+}
+
+/// Quote `s` for zsh, as a single word.
+fn zsh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// An `_arguments` spec for a flag.
+fn zsh_flag(flag: &FlagInfo) -> String {
+    let description = summary(flag.description)
+        .replace('[', "\\[")
+        .replace(']', "\\]");
+    let mut spec = String::new();
+    let names = spellings(flag);
+    if flag.optionality == Optionality::Repeating {
+        spec.push_str("'*'");
+    } else if names.len() > 1 {
+        spec.push_str(&zsh_quote(&format!("({})", names.join(" "))));
+    }
+    if names.len() > 1 {
+        write!(spec, "{{{}}}", names.join(",")).unwrap();
+    } else {
+        spec.push_str(&names[0]);
+    }
+    let mut rest = format!("[{description}]");
+    if let FlagInfoKind::Option { arg_name } = flag.kind {
+        write!(rest, ":{arg_name}:_files").unwrap();
+    }
+    spec.push_str(&zsh_quote(&rest));
+    spec
+}
+
+fn zsh(program: &str, info: &CommandInfoWithArgs) -> String {
+    let function = function_name(program);
+    let mut out = String::new();
+    writeln!(out, "#compdef {program}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "{function}_names() {{").unwrap();
+    writeln!(out, "    local -a names").unwrap();
+    writeln!(
+        out,
+        "    names=(${{(f)\"$({program} ls --names 2>/dev/null)\"}})"
+    )
+    .unwrap();
+    writeln!(out, "    compadd -a names").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "{function}() {{").unwrap();
+    writeln!(out, "    local curcontext=$curcontext state line").unwrap();
+    writeln!(out, "    local -a commands").unwrap();
+    writeln!(out, "    commands=(").unwrap();
+    for sub in &info.commands {
+        let description = summary(sub.command.description).replace(':', "\\:");
+        writeln!(
+            out,
+            "        {}",
+            zsh_quote(&format!("{}:{}", sub.name, description))
+        )
+        .unwrap();
+    }
+    writeln!(out, "    )").unwrap();
+    writeln!(out, "    _arguments -C \\").unwrap();
+    for flag in flags(info) {
+        writeln!(out, "        {} \\", zsh_flag(flag)).unwrap();
+    }
+    writeln!(out, "        '1:command:->command' \\").unwrap();
+    writeln!(out, "        '*::argument:->argument'").unwrap();
+    writeln!(out, "    case $state in").unwrap();
+    writeln!(out, "    command) _describe command commands ;;").unwrap();
+    writeln!(out, "    argument)").unwrap();
+    writeln!(out, "        case $words[1] in").unwrap();
+    for sub in &info.commands {
+        let mut specs: Vec<_> = flags(&sub.command).map(zsh_flag).collect();
+        let positionals = sub.command.positionals.iter().filter(|p| !p.hidden);
+        if takes_source_name(sub.name, &sub.command) {
+            let first = &sub.command.positionals[0];
+            if matches!(
+                first.optionality,
+                Optionality::Repeating | Optionality::Greedy
+            ) {
+                specs.push(format!("'*:source:{function}_names'"));
+            } else {
+                specs.push(format!("'1:source:{function}_names'"));
+                if positionals.count() > 1 {
+                    specs.push("'*:argument:_files'".to_owned());
+                }
+            }
+        } else if positionals.count() > 0 {
+            specs.push("'*:argument:_files'".to_owned());
+        }
+        writeln!(out, "        {})", sub.name).unwrap();
+        writeln!(out, "            _arguments \\").unwrap();
+        for (i, spec) in specs.iter().enumerate() {
+            let end = if i + 1 < specs.len() { " \\" } else { " ;;" };
+            writeln!(out, "                {spec}{end}").unwrap();
+        }
+        if specs.is_empty() {
+            writeln!(out, "                ;;").unwrap();
+        }
+    }
+    writeln!(out, "        esac ;;").unwrap();
+    writeln!(out, "    esac").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "{function} \"$@\"").unwrap();
+    out
+}
+
+/// Quote `s` for fish, as a single word.
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish_flag(out: &mut String, program: &str, condition: &str, flag: &FlagInfo) {
+    write!(out, "complete -c {program} -n {condition}").unwrap();
+    if let Some(short) = flag.short {
+        write!(out, " -s {short}").unwrap();
+    }
+    write!(out, " -l {}", flag.long.trim_start_matches("--")).unwrap();
+    if let FlagInfoKind::Option { .. } = flag.kind {
+        write!(out, " -r -F").unwrap();
+    }
+    writeln!(out, " -d {}", fish_quote(&summary(flag.description))).unwrap();
+}
+
+fn fish(program: &str, info: &CommandInfoWithArgs) -> String {
+    let mut out = String::new();
+    writeln!(out, "complete -c {program} -f").unwrap();
+    for flag in flags(info) {
+        fish_flag(&mut out, program, "__fish_use_subcommand", flag);
+    }
+    for sub in &info.commands {
+        writeln!(
+            out,
+            "complete -c {program} -n __fish_use_subcommand -a {} -d {}",
+            sub.name,
+            fish_quote(&summary(sub.command.description))
+        )
+        .unwrap();
+    }
+    for sub in &info.commands {
+        let condition = fish_quote(&format!("__fish_seen_subcommand_from {}", sub.name));
+        for flag in flags(&sub.command) {
+            fish_flag(&mut out, program, &condition, flag);
+        }
+        if takes_source_name(sub.name, &sub.command) {
+            writeln!(
+                out,
+                "complete -c {program} -n {condition} -a {}",
+                fish_quote(&format!("({program} ls --names 2>/dev/null)"))
+            )
+            .unwrap();
+        } else if !sub.command.positionals.is_empty() {
+            writeln!(out, "complete -c {program} -n {condition} -F").unwrap();
+        }
+    }
+    out
+}
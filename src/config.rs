@@ -0,0 +1,110 @@
+//! The user's configuration file, holding what doesn't belong in a sources file, like
+//! credentials, and defaults for command line options. A project can have its own, named
+//! `.nix-source.toml`, whose settings take precedence.
+
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+/// Path of the config file, `nix-source/config.toml` in `$XDG_CONFIG_HOME`, or `~/.config` if
+/// that isn't set.
+pub fn path() -> Option<std::path::PathBuf> {
+    let dir = match (
+        std::env::var_os("XDG_CONFIG_HOME"),
+        std::env::var_os("HOME"),
+    ) {
+        (Some(dir), _) => std::path::PathBuf::from(dir),
+        (None, Some(home)) => std::path::Path::new(&home).join(".config"),
+        (None, None) => return None,
+    };
+    Some(dir.join("nix-source").join("config.toml"))
+}
+
+/// Path of the project's config file, the closest `.nix-source.toml` in the current directory
+/// or its parents.
+pub fn local_path() -> Option<PathBuf> {
+    let dir = std::env::current_dir().ok()?;
+    dir.ancestors()
+        .map(|dir| dir.join(".nix-source.toml"))
+        .find(|path| path.is_file())
+}
+
+/// Read the config file at `path`, which is empty if it doesn't exist.
+fn read(path: &Path) -> anyhow::Result<toml::Table> {
+    let config = match std::fs::read_to_string(path) {
+        Ok(config) => config,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    toml::from_str(&config).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+/// Read the config file, with the settings of the project's on top.
+pub fn load() -> anyhow::Result<toml::Table> {
+    let mut config = match path() {
+        Some(path) => read(&path)?,
+        None => Default::default(),
+    };
+    if let Some(path) = local_path() {
+        let mut local = read(&path)?;
+        // The project's sources file is relative to the project, not to where we are in it.
+        if let Some(toml::Value::String(sources)) = local.get_mut("sources") {
+            if let Some(dir) = path.parent() {
+                *sources = dir.join(&*sources).to_string_lossy().into_owned();
+            }
+        }
+        config.extend(local);
+    }
+    Ok(config)
+}
+
+/// Defaults for command line options, under the same names as the options, e.g.
+/// `hash-type = "sha512"`. The options and environment variables take precedence.
+#[derive(serde::Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Defaults {
+    pub sources: Option<PathBuf>,
+    #[serde(default, deserialize_with = "parse")]
+    pub prefetcher: Option<crate::prefetch::Prefetcher>,
+    #[serde(default, deserialize_with = "parse")]
+    pub hash_type: Option<crate::HashType>,
+    pub proxy: Option<String>,
+    pub timeout: Option<u64>,
+    pub prefetch_timeout: Option<u64>,
+    pub retries: Option<usize>,
+    pub max_wait: Option<u64>,
+    /// Number of sources to work on concurrently.
+    pub jobs: Option<usize>,
+    /// Shell commands to run before and after each source is updated.
+    pub pre_update: Option<String>,
+    pub post_update: Option<String>,
+    /// Whether to keep garbage collector roots for the sources whenever the sources file is
+    /// written.
+    #[serde(default)]
+    pub gc_roots: bool,
+    /// Binary caches to look sources up in before downloading them.
+    pub substituters: Option<Vec<url::Url>>,
+    /// Gateways to fetch IPFS sources through, in order.
+    pub ipfs_gateways: Option<Vec<url::Url>>,
+}
+
+impl Defaults {
+    /// Read the defaults in the config files.
+    pub fn load() -> anyhow::Result<Self> {
+        toml::Value::Table(load()?)
+            .try_into()
+            .context("invalid defaults in the config file")
+    }
+}
+
+/// Deserialize a value from its string form, like it is given on the command line.
+fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    value
+        .map(|value| value.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
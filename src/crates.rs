@@ -0,0 +1,48 @@
+//! Following the latest release of a crate on crates.io.
+
+use anyhow::Context;
+
+#[derive(serde::Deserialize)]
+struct CrateResponse {
+    #[serde(rename = "crate")]
+    krate: Crate,
+}
+
+#[derive(serde::Deserialize)]
+struct Crate {
+    /// Not set if every version is a prerelease.
+    max_stable_version: Option<String>,
+    max_version: String,
+}
+
+/// Find the latest stable version of `name`, or the latest prerelease if there is none, and
+/// return it and the url of its `.crate`.
+pub fn latest(client: &crate::http::Client, name: &str) -> anyhow::Result<(String, url::Url)> {
+    // Overridable to use a mirror.
+    let api =
+        std::env::var("NIX_SOURCE_CRATES_API_URL").unwrap_or("https://crates.io/api/v1".to_owned());
+    let downloads = std::env::var("NIX_SOURCE_CRATES_DOWNLOAD_URL")
+        .unwrap_or("https://static.crates.io/crates".to_owned());
+    let url: url::Url = format!("{}/crates/{}", api.trim_end_matches('/'), name).parse()?;
+    // crates.io rejects requests without a user agent identifying the client.
+    let user_agent = concat!("nix-source/", env!("CARGO_PKG_VERSION"));
+    let res = match client.call("GET", &url, |req| req.set("User-Agent", user_agent)) {
+        Ok((res, _)) => res,
+        Err(e) => match e.downcast_ref() {
+            Some(ureq::Error::Status(404, _)) => anyhow::bail!("no crate {} on crates.io", name),
+            _ => return Err(e),
+        },
+    };
+    let res: CrateResponse =
+        serde_json::from_reader(res.into_reader()).context("failed to parse the crate")?;
+    let version = res
+        .krate
+        .max_stable_version
+        .unwrap_or(res.krate.max_version);
+    let url = format!(
+        "{}/{name}/{name}-{version}.crate",
+        downloads.trim_end_matches('/')
+    )
+    .parse()?;
+    Ok((version, url))
+}
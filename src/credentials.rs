@@ -0,0 +1,157 @@
+//! Credentials for servers that need them, from `~/.netrc` and the config file. They are never
+//! stored in the sources file.
+
+use anyhow::Context;
+use base64::Engine;
+use std::collections::HashMap;
+
+/// How to authenticate to a host.
+#[derive(Clone, Debug)]
+pub enum Credential {
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+impl Credential {
+    /// Value of the `Authorization` header for this credential.
+    pub fn authorization(&self) -> String {
+        match self {
+            Credential::Basic { username, password } => {
+                format!(
+                    "Basic {}",
+                    base64::engine::general_purpose::STANDARD
+                        .encode(format!("{username}:{password}"))
+                )
+            }
+            Credential::Bearer(token) => format!("Bearer {token}"),
+        }
+    }
+}
+
+/// Credentials by host name.
+#[derive(Default, Clone, Debug)]
+pub struct Credentials {
+    hosts: HashMap<String, Credential>,
+    /// From the `default` entry of `.netrc`, used for hosts without their own.
+    default: Option<Credential>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HostConfig {
+    username: Option<String>,
+    password: Option<String>,
+    token: Option<String>,
+}
+
+impl Credentials {
+    /// Read the credentials in `$NETRC`, or `~/.netrc`, and the `hosts` table of the config
+    /// file, which takes precedence.
+    ///
+    /// In the config file, each host has either a `username` and `password`, or a `token` sent
+    /// as a bearer token.
+    pub fn load() -> anyhow::Result<Self> {
+        let netrc = std::env::var_os("NETRC")
+            .map(std::path::PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".netrc"))
+            });
+        let mut credentials = match netrc {
+            Some(path) => match std::fs::read_to_string(&path) {
+                Ok(netrc) => parse_netrc(&netrc)
+                    .with_context(|| format!("failed to parse {}", path.display()))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to read {}", path.display()))
+                }
+            },
+            None => Default::default(),
+        };
+        let Some(hosts) = crate::config::load()?.remove("hosts") else {
+            return Ok(credentials);
+        };
+        let hosts: HashMap<String, HostConfig> = hosts
+            .try_into()
+            .context("invalid hosts in the config file")?;
+        for (host, config) in hosts {
+            let credential = match config {
+                HostConfig {
+                    username: Some(username),
+                    password: Some(password),
+                    token: None,
+                } => Credential::Basic { username, password },
+                HostConfig {
+                    username: None,
+                    password: None,
+                    token: Some(token),
+                } => Credential::Bearer(token),
+                _ => anyhow::bail!(
+                    "{} in the config file needs either a username and a password, or a token",
+                    host
+                ),
+            };
+            credentials.hosts.insert(host, credential);
+        }
+        Ok(credentials)
+    }
+
+    /// The credential to send to `url`'s host, if there is one.
+    pub fn get(&self, url: &url::Url) -> Option<&Credential> {
+        url.host_str()
+            .and_then(|host| self.hosts.get(host))
+            .or(self.default.as_ref())
+    }
+}
+
+/// Parse the `machine` and `default` entries of a `.netrc` file.
+fn parse_netrc(netrc: &str) -> anyhow::Result<Credentials> {
+    let mut credentials = Credentials::default();
+    let mut tokens = netrc.split_whitespace();
+    // The entry being read: its host, or None for `default`, login and password.
+    let mut entry: Option<(Option<String>, Option<String>, Option<String>)> = None;
+    let mut finish = |entry: Option<(Option<String>, _, _)>| {
+        if let Some((host, Some(username), Some(password))) = entry {
+            let credential = Credential::Basic { username, password };
+            match host {
+                // Like curl, the first entry for a host wins.
+                Some(host) => {
+                    credentials.hosts.entry(host).or_insert(credential);
+                }
+                None => credentials.default = Some(credential),
+            }
+        }
+    };
+    while let Some(token) = tokens.next() {
+        let mut value = || {
+            tokens
+                .next()
+                .with_context(|| format!("{} without a value", token))
+        };
+        match token {
+            "machine" => {
+                let host = value()?.to_owned();
+                finish(entry.replace((Some(host), None, None)));
+            }
+            "default" => finish(entry.replace((None, None, None))),
+            "login" => {
+                let login = value()?.to_owned();
+                if let Some(entry) = &mut entry {
+                    entry.1 = Some(login);
+                }
+            }
+            "password" => {
+                let password = value()?.to_owned();
+                if let Some(entry) = &mut entry {
+                    entry.2 = Some(password);
+                }
+            }
+            "account" => {
+                value()?;
+            }
+            "macdef" => anyhow::bail!("macdef is not supported"),
+            _ => anyhow::bail!("unexpected {}", token),
+        }
+    }
+    finish(entry);
+    Ok(credentials)
+}
@@ -0,0 +1,265 @@
+//! Importing the locked inputs of a `flake.lock`, and exporting sources as flake inputs.
+
+use crate::{github, Source, SourceType, Sources};
+use anyhow::Context;
+use std::collections::BTreeMap;
+
+#[derive(serde::Deserialize)]
+struct Lock {
+    nodes: BTreeMap<String, Node>,
+    root: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Node {
+    locked: Option<serde_json::Value>,
+    original: Option<Original>,
+}
+
+/// The input as written in `flake.nix`, only needed for the branch it follows.
+#[derive(serde::Deserialize)]
+struct Original {
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Locked {
+    Github {
+        owner: String,
+        repo: String,
+        rev: String,
+        #[serde(rename = "narHash")]
+        nar_hash: String,
+    },
+    Gitlab {
+        owner: String,
+        repo: String,
+        rev: String,
+        host: Option<String>,
+        #[serde(rename = "narHash")]
+        nar_hash: String,
+    },
+    Tarball {
+        url: String,
+        #[serde(rename = "narHash")]
+        nar_hash: String,
+    },
+    Git {
+        url: String,
+        rev: String,
+        #[serde(rename = "ref")]
+        git_ref: Option<String>,
+        #[serde(rename = "narHash")]
+        nar_hash: String,
+        #[serde(default)]
+        submodules: bool,
+    },
+}
+
+fn convert(node: Node) -> anyhow::Result<Source> {
+    let locked = node.locked.context("not locked")?;
+    let ty = locked["type"].as_str().unwrap_or_default().to_owned();
+    let locked: Locked =
+        serde_json::from_value(locked).map_err(|_| anyhow::anyhow!("unsupported type {}", ty))?;
+    // narHash is the hash of the unpacked input, which is what is stored for tarballs and
+    // git repositories too.
+    Ok(match locked {
+        Locked::Github {
+            owner,
+            repo,
+            rev,
+            nar_hash,
+        } => {
+            let url = format!(
+                "https://github.com/{}/{}/archive/{}.tar.gz",
+                owner, repo, rev
+            );
+            let git_ref = node.original.and_then(|original| original.git_ref);
+            Source {
+                hash: Some(nar_hash.parse()?),
+                ty: Some(SourceType::Tarball),
+                github_branch: git_ref.map(|branch| github::GithubBranch {
+                    owner,
+                    repo,
+                    branch,
+                }),
+                rev: Some(rev),
+                ..Source::new(url.parse()?)
+            }
+        }
+        Locked::Gitlab {
+            owner,
+            repo,
+            rev,
+            host,
+            nar_hash,
+        } => {
+            let url = format!(
+                "https://{}/{}/{}/-/archive/{rev}/{}-{rev}.tar.gz",
+                host.as_deref().unwrap_or("gitlab.com"),
+                owner,
+                repo,
+                repo
+            );
+            Source {
+                hash: Some(nar_hash.parse()?),
+                ty: Some(SourceType::Tarball),
+                ..Source::new(url.parse()?)
+            }
+        }
+        Locked::Tarball { url, nar_hash } => Source {
+            hash: Some(nar_hash.parse()?),
+            ty: Some(SourceType::Tarball),
+            ..Source::new(url.parse()?)
+        },
+        Locked::Git {
+            url,
+            rev,
+            git_ref,
+            nar_hash,
+            submodules,
+        } => Source {
+            hash: Some(nar_hash.parse()?),
+            ty: Some(SourceType::Git),
+            git_ref: git_ref.or(Some(rev.clone())),
+            rev: Some(rev),
+            submodules,
+            ..Source::new(url.parse()?)
+        },
+    })
+}
+
+/// Read the flake lock file at `path`, converting each locked input that can be. Inputs that
+/// can't are returned with the reason why.
+pub fn import(path: &std::path::Path) -> anyhow::Result<Vec<(String, anyhow::Result<Source>)>> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let lock: Lock = serde_json::from_reader(std::io::BufReader::new(file))?;
+    Ok(lock
+        .nodes
+        .into_iter()
+        .filter(|(name, _)| *name != lock.root)
+        .map(|(name, node)| (name, convert(node)))
+        .collect())
+}
+
+/// The flake reference, and the original and locked nodes of `source` as a flake input.
+fn input(source: &Source) -> anyhow::Result<(String, serde_json::Value, serde_json::Value)> {
+    let hash = source.hash.as_ref().context("no hash")?;
+    if hash.pick_algorithm() != ssri::Algorithm::Sha256 {
+        anyhow::bail!("flake.lock only takes sha256 hashes");
+    }
+    let nar_hash = hash.to_string();
+    if source.subdir.is_some() {
+        anyhow::bail!("flake inputs can't be a subdirectory of what is fetched");
+    }
+    if source.ty == Some(SourceType::Oci) {
+        anyhow::bail!("flake inputs can't be container images");
+    }
+    if source.ty == Some(SourceType::Git) {
+        let rev = source.rev.as_ref().context("no revision")?;
+        let mut flake_ref = format!("git+{}", source.url);
+        let mut original = serde_json::json!({ "type": "git", "url": source.url });
+        let mut locked = serde_json::json!({
+            "type": "git",
+            "url": source.url,
+            "rev": rev,
+            "narHash": nar_hash,
+        });
+        if source.deep_clone {
+            anyhow::bail!("flake inputs can't keep the .git directory of a clone");
+        }
+        let mut query = Vec::new();
+        if let Some(git_ref) = source.git_ref.as_ref().filter(|r| *r != rev) {
+            query.push(format!("ref={git_ref}"));
+            original["ref"] = git_ref.as_str().into();
+            locked["ref"] = git_ref.as_str().into();
+        }
+        if source.submodules {
+            query.push("submodules=1".to_owned());
+            original["submodules"] = true.into();
+            locked["submodules"] = true.into();
+        }
+        if !query.is_empty() {
+            flake_ref = format!("{flake_ref}?{}", query.join("&"));
+        }
+        return Ok((flake_ref, original, locked));
+    }
+    if let (Some(branch), Some(rev)) = (&source.github_branch, &source.rev) {
+        let flake_ref = format!("github:{}/{}/{}", branch.owner, branch.repo, branch.branch);
+        let original = serde_json::json!({
+            "type": "github",
+            "owner": branch.owner,
+            "repo": branch.repo,
+            "ref": branch.branch,
+        });
+        let locked = serde_json::json!({
+            "type": "github",
+            "owner": branch.owner,
+            "repo": branch.repo,
+            "rev": rev,
+            "narHash": nar_hash,
+        });
+        return Ok((flake_ref, original, locked));
+    }
+    // narHash is over the unpacked tarball, flat hashes of files can't be converted to it.
+    let unpacked = source
+        .unpack
+        .unwrap_or(source.ty.is_some_and(SourceType::unpacks));
+    if !unpacked || source.decompress {
+        anyhow::bail!("only tarballs and git repositories can be flake inputs");
+    }
+    if matches!(source.url.scheme(), "s3" | "gs") {
+        anyhow::bail!("flake inputs can't be fetched from buckets");
+    }
+    if source.url.scheme() == "ipfs" {
+        anyhow::bail!("flake inputs can't be fetched from IPFS");
+    }
+    let url = source.resolved_url.as_ref().unwrap_or(&source.url);
+    let original = serde_json::json!({ "type": "tarball", "url": source.url });
+    let locked = serde_json::json!({ "type": "tarball", "url": url, "narHash": nar_hash });
+    Ok((format!("tarball+{}", source.url), original, locked))
+}
+
+/// Generate the `inputs` of a flake.nix for every source that can be one, and add them to
+/// `lock`, the contents of an existing flake.lock. Sources that can't be inputs are returned
+/// with the reason why.
+pub fn export(
+    sources: &Sources,
+    lock: Option<serde_json::Value>,
+) -> (String, serde_json::Value, Vec<(String, anyhow::Error)>) {
+    let mut lock = lock.unwrap_or_else(|| {
+        serde_json::json!({
+            "nodes": { "root": { "inputs": {} } },
+            "root": "root",
+            "version": 7,
+        })
+    });
+    let root = lock["root"].as_str().unwrap_or("root").to_owned();
+    let mut inputs = String::from("inputs = {\n");
+    let mut skipped = Vec::new();
+    for (name, source) in &sources.sources {
+        let (flake_ref, original, locked) = match input(source) {
+            Ok(input) => input,
+            Err(e) => {
+                skipped.push((name.clone(), e));
+                continue;
+            }
+        };
+        inputs.push_str(&format!(
+            "  {} = {{ url = {}; flake = false; }};\n",
+            crate::nix::attr_name(name),
+            crate::nix::string(&flake_ref)
+        ));
+        lock["nodes"][name] = serde_json::json!({
+            "flake": false,
+            "locked": locked,
+            "original": original,
+        });
+        lock["nodes"][&root]["inputs"][name] = name.as_str().into();
+    }
+    inputs.push_str("};\n");
+    (inputs, lock, skipped)
+}
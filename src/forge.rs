@@ -0,0 +1,174 @@
+//! Following the releases of a repository on a forge, through the tarballs the forge generates
+//! of its tags.
+
+use crate::{gitea, github, gitlab, sourcehut};
+use anyhow::Context;
+
+/// A repository on a forge.
+pub trait Forge {
+    /// Url of the page of the repository.
+    fn url(&self) -> anyhow::Result<url::Url>;
+    /// Find the tag of the latest release, or just the latest tag on forges without releases.
+    fn latest_release(&self, client: &crate::http::Client) -> anyhow::Result<String>;
+    /// Url of the tarball of the repository at `tag`.
+    fn archive_url(&self, tag: &str) -> anyhow::Result<url::Url>;
+    /// Find the commit at the tip of `branch`, and return it and the url of its tarball.
+    fn branch_head(
+        &self,
+        _client: &crate::http::Client,
+        _branch: &str,
+    ) -> anyhow::Result<(String, url::Url)> {
+        anyhow::bail!("following a branch is only supported on GitHub and GitLab")
+    }
+    /// The notes of the release of `tag`, if the forge has releases and there are any.
+    fn release_notes(
+        &self,
+        _client: &crate::http::Client,
+        _tag: &str,
+    ) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// A token authenticating requests to a forge, only sent to the forge's own host so it doesn't
+/// leak to where downloads redirect to.
+pub struct Auth {
+    pub host: String,
+    pub header: &'static str,
+    pub value: String,
+}
+
+impl Auth {
+    /// Authenticate `req` if it goes to the forge.
+    pub fn apply(&self, req: ureq::Request) -> ureq::Request {
+        let host = url::Url::parse(req.url())
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_owned));
+        if host.as_deref() == Some(&self.host) {
+            req.set(self.header, &self.value)
+        } else {
+            req
+        }
+    }
+}
+
+/// The token in the environment variable `var`, e.g. `GITHUB_TOKEN`, or else the one in the
+/// config file under the same name in lower case with dashes, e.g. `github-token`.
+pub fn token(var: &str) -> anyhow::Result<Option<String>> {
+    if let Ok(token) = std::env::var(var) {
+        return Ok(Some(token));
+    }
+    let key = var.to_lowercase().replace('_', "-");
+    match crate::config::load()?.remove(&key) {
+        None => Ok(None),
+        Some(toml::Value::String(token)) => Ok(Some(token)),
+        Some(_) => anyhow::bail!("{} in the config file is not a string", key),
+    }
+}
+
+/// The forges a source can follow.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ForgeKind {
+    Github,
+    Gitlab,
+    /// Gitea and Forgejo, Codeberg by default.
+    Gitea,
+    Sourcehut,
+}
+
+impl std::str::FromStr for ForgeKind {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "github" => Ok(ForgeKind::Github),
+            "gitlab" => Ok(ForgeKind::Gitlab),
+            "gitea" | "forgejo" | "codeberg" => Ok(ForgeKind::Gitea),
+            "sourcehut" | "srht" => Ok(ForgeKind::Sourcehut),
+            _ => Err(anyhow::anyhow!(
+                "invalid forge, expected github, gitlab, gitea, codeberg or sourcehut"
+            )),
+        }
+    }
+}
+
+/// A repository on one of the supported forges, as stored in the sources file.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(tag = "forge", rename_all = "lowercase")]
+pub enum Repository {
+    Github(github::GithubRepository),
+    Gitlab(gitlab::GitlabProject),
+    Gitea(gitea::GiteaRepository),
+    Sourcehut(sourcehut::SourcehutRepository),
+}
+
+impl Repository {
+    /// Parse `path`, the path of a repository on a forge of type `kind`. `instance` is the url
+    /// of a self-hosted forge.
+    pub fn new(kind: ForgeKind, path: &str, instance: Option<url::Url>) -> anyhow::Result<Self> {
+        Ok(match kind {
+            ForgeKind::Github => {
+                if instance.is_some() {
+                    // GitHub Enterprise is configured like in GitHub Actions instead.
+                    anyhow::bail!("--instance can't be used with GitHub, set GITHUB_API_URL");
+                }
+                let (owner, repo) = path
+                    .split_once('/')
+                    .with_context(|| format!("invalid repository {}, expected OWNER/REPO", path))?;
+                Repository::Github(github::GithubRepository {
+                    owner: owner.to_owned(),
+                    repo: repo.to_owned(),
+                })
+            }
+            ForgeKind::Gitlab => {
+                let project = path.trim_matches('/');
+                if !project.contains('/') {
+                    anyhow::bail!("invalid project {}, expected GROUP/PROJECT", path);
+                }
+                Repository::Gitlab(gitlab::GitlabProject {
+                    instance: instance.unwrap_or("https://gitlab.com".parse()?),
+                    project: project.to_owned(),
+                })
+            }
+            ForgeKind::Gitea => {
+                let (owner, repo) = path
+                    .split_once('/')
+                    .with_context(|| format!("invalid repository {}, expected OWNER/REPO", path))?;
+                Repository::Gitea(gitea::GiteaRepository {
+                    instance: instance.unwrap_or("https://codeberg.org".parse()?),
+                    owner: owner.to_owned(),
+                    repo: repo.to_owned(),
+                })
+            }
+            ForgeKind::Sourcehut => {
+                // Users are written ~user on sourcehut, but the ~ is optional here.
+                let (owner, repo) = path
+                    .trim_start_matches('~')
+                    .split_once('/')
+                    .with_context(|| format!("invalid repository {}, expected ~USER/REPO", path))?;
+                Repository::Sourcehut(sourcehut::SourcehutRepository {
+                    instance: instance.unwrap_or("https://git.sr.ht".parse()?),
+                    owner: owner.to_owned(),
+                    repo: repo.to_owned(),
+                })
+            }
+        })
+    }
+
+    /// The token to authenticate downloads from the forge with, if one is configured.
+    pub fn auth(&self) -> anyhow::Result<Option<Auth>> {
+        match self {
+            Repository::Github(_) => github::auth(),
+            Repository::Gitlab(project) => project.auth(),
+            Repository::Gitea(_) | Repository::Sourcehut(_) => Ok(None),
+        }
+    }
+
+    pub fn forge(&self) -> &dyn Forge {
+        match self {
+            Repository::Github(repo) => repo,
+            Repository::Gitlab(project) => project,
+            Repository::Gitea(repo) => repo,
+            Repository::Sourcehut(repo) => repo,
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Git repository sources, prefetched with `nix-prefetch-git`.
+
+use crate::{HashType, Source};
+use anyhow::Context;
+
+#[derive(serde::Deserialize)]
+struct PrefetchOutput {
+    rev: String,
+    /// SRI hash, only printed by newer versions of `nix-prefetch-git`.
+    hash: Option<String>,
+    sha256: String,
+    /// Store path of the checkout.
+    path: Option<std::path::PathBuf>,
+}
+
+/// Resolve `git_ref`, or the default branch, of the repository at `url` to a revision.
+pub fn resolve(
+    url: &url::Url,
+    git_ref: Option<&str>,
+    timeout: std::time::Duration,
+) -> anyhow::Result<String> {
+    let git_ref = git_ref.unwrap_or("HEAD");
+    // A revision can't be looked up with ls-remote, and doesn't need to be.
+    if git_ref.len() == 40 && git_ref.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(git_ref.to_owned());
+    }
+    let mut command = std::process::Command::new("git");
+    command.args(["ls-remote", url.as_str(), git_ref]);
+    let output = crate::prefetch::output_with_timeout(&mut command, Some(timeout))
+        .context("failed to run git ls-remote")?;
+    if !output.status.success() {
+        anyhow::bail!("git ls-remote failed for {}: {}", url, output.status);
+    }
+    let output = String::from_utf8(output.stdout)?;
+    let rev = output
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().next())
+        .with_context(|| format!("{} has no ref {}", url, git_ref))?;
+    Ok(rev.to_owned())
+}
+
+/// Resolve the ref `source` tracks to a revision, and hash its checkout with `hash_type`.
+/// Unless `force` is set, the checkout is only hashed again if the revision changed.
+pub fn refresh(
+    source: &Source,
+    force: bool,
+    hash_type: HashType,
+    client: &crate::http::Client,
+) -> anyhow::Result<Source> {
+    if !force && source.hash.is_some() {
+        if let Some(rev) = &source.rev {
+            if resolve(&source.url, source.git_ref.as_deref(), client.timeout())? == *rev {
+                log::debug!("\tnot modified");
+                return Ok(source.clone());
+            }
+        }
+    }
+    let mut command = std::process::Command::new("nix-prefetch-git");
+    command.args(["--quiet", "--url", source.url.as_str()]);
+    if let Some(git_ref) = &source.git_ref {
+        command.args(["--rev", git_ref]);
+    }
+    if source.submodules {
+        command.arg("--fetch-submodules");
+    }
+    if let Some(subdir) = &source.subdir {
+        command.args(["--sparse-checkout", subdir]);
+    }
+    if source.deep_clone {
+        // fetchgit keeps .git of deep clones, which is part of what is hashed.
+        command.args(["--deepClone", "--leave-dotGit"]);
+    }
+    let output = crate::prefetch::output_with_timeout(&mut command, client.prefetch_timeout())
+        .context("failed to run nix-prefetch-git")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "nix-prefetch-git failed for {}: {}",
+            source.url,
+            output.status
+        );
+    }
+    let output: PrefetchOutput = serde_json::from_slice(&output.stdout)?;
+    let store_path = output.path.clone();
+    let hash = match (hash_type, output.hash) {
+        (HashType::Sha256, Some(hash)) => hash.parse()?,
+        (HashType::Sha256, None) => crate::to_sri(output.sha256.as_ref(), HashType::Sha256)?,
+        // nix-prefetch-git only hashes with sha256, hash the checkout it left in the store
+        // again.
+        (hash_type, _) => {
+            let path = output
+                .path
+                .context("nix-prefetch-git didn't print the path of the checkout")?;
+            hash_path(&path, hash_type)?
+        }
+    };
+    log::debug!("\t{} {}", output.rev, hash);
+    Ok(Source {
+        hash: Some(hash),
+        rev: Some(output.rev),
+        store_path,
+        ..source.clone()
+    })
+}
+
+/// Hash the store path `path` with `hash_type`, as nix hashes unpacked sources.
+fn hash_path(path: &std::path::Path, hash_type: HashType) -> anyhow::Result<ssri::Integrity> {
+    let output = std::process::Command::new("nix")
+        .args(["hash", "path", "--type", &hash_type.to_string()])
+        .arg(path)
+        .output()
+        .context("failed to run nix hash path")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "nix hash path failed for {}: {}",
+            path.display(),
+            output.status
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().parse()?)
+}
@@ -0,0 +1,86 @@
+//! Tags of a repository on Gitea or Forgejo, like Codeberg.
+
+use anyhow::Context;
+
+/// A repository on a Gitea or Forgejo instance.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct GiteaRepository {
+    pub instance: url::Url,
+    pub owner: String,
+    pub repo: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Tag {
+    name: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Release {
+    body: String,
+}
+
+impl GiteaRepository {
+    fn base(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.instance.as_str().trim_end_matches('/'),
+            self.owner,
+            self.repo
+        )
+    }
+}
+
+impl crate::forge::Forge for GiteaRepository {
+    fn url(&self) -> anyhow::Result<url::Url> {
+        Ok(self.base().parse()?)
+    }
+
+    fn latest_release(&self, client: &crate::http::Client) -> anyhow::Result<String> {
+        // Tags are listed newest first.
+        let url: url::Url = format!(
+            "{}/api/v1/repos/{}/{}/tags?limit=1",
+            self.instance.as_str().trim_end_matches('/'),
+            self.owner,
+            self.repo
+        )
+        .parse()?;
+        let tags: Vec<Tag> = serde_json::from_reader(client.get(&url)?.into_reader())
+            .context("failed to parse the tags")?;
+        let tag = tags
+            .into_iter()
+            .next()
+            .with_context(|| format!("{}/{} has no tags", self.owner, self.repo))?;
+        Ok(tag.name)
+    }
+
+    fn archive_url(&self, tag: &str) -> anyhow::Result<url::Url> {
+        Ok(format!("{}/archive/{}.tar.gz", self.base(), tag).parse()?)
+    }
+
+    fn release_notes(
+        &self,
+        client: &crate::http::Client,
+        tag: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let url: url::Url = format!(
+            "{}/api/v1/repos/{}/{}/releases/tags/{}",
+            self.instance.as_str().trim_end_matches('/'),
+            self.owner,
+            self.repo,
+            tag
+        )
+        .parse()?;
+        let res = match client.get(&url) {
+            Ok(res) => res,
+            // Tags don't have to be released.
+            Err(e) if matches!(e.downcast_ref(), Some(ureq::Error::Status(404, _))) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        let release: Release =
+            serde_json::from_reader(res.into_reader()).context("failed to parse the release")?;
+        Ok(Some(release.body).filter(|body| !body.trim().is_empty()))
+    }
+}
@@ -0,0 +1,276 @@
+//! Tracking the latest release, or a branch, of a GitHub repository.
+
+use anyhow::Context;
+
+/// A GitHub repository, and a pattern matching the name of the release asset to fetch.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct GithubRelease {
+    pub owner: String,
+    pub repo: String,
+    /// Regex matching the name of the asset.
+    pub asset_pattern: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(serde::Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: url::Url,
+}
+
+/// A GitHub repository, followed through the tarballs GitHub generates of its release tags.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct GithubRepository {
+    pub owner: String,
+    pub repo: String,
+}
+
+/// A branch of a GitHub repository, followed through the tarballs GitHub generates of its
+/// commits.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct GithubBranch {
+    pub owner: String,
+    pub repo: String,
+    pub branch: String,
+}
+
+/// GET `path` from the GitHub API, authenticating with `GITHUB_TOKEN` if it is set.
+fn api_get(
+    client: &crate::http::Client,
+    path: &str,
+    accept: &str,
+) -> anyhow::Result<ureq::Response> {
+    // Overridable like in GitHub Actions, for GitHub Enterprise.
+    let api = std::env::var("GITHUB_API_URL").unwrap_or("https://api.github.com".to_owned());
+    let url: url::Url = format!("{}{}", api.trim_end_matches('/'), path).parse()?;
+    let token = crate::forge::token("GITHUB_TOKEN")?;
+    let result = client.call("GET", &url, |req| {
+        let req = req.set("Accept", accept);
+        match &token {
+            Some(token) => req.set("Authorization", &format!("Bearer {token}")),
+            None => req,
+        }
+    });
+    match result {
+        Ok((res, _)) => Ok(res),
+        Err(e) => match e.downcast_ref() {
+            Some(ureq::Error::Status(403 | 429, res))
+                if res.header("X-RateLimit-Remaining") == Some("0") =>
+            {
+                anyhow::bail!(
+                    "GitHub API rate limit exceeded{}",
+                    if token.is_none() {
+                        ", set GITHUB_TOKEN to raise it"
+                    } else {
+                        ""
+                    }
+                )
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref(), Some(ureq::Error::Status(404, _)))
+}
+
+/// Base url of the GitHub web interface, which serves the tarballs.
+fn server_url() -> String {
+    // Also overridable like in GitHub Actions.
+    let server = std::env::var("GITHUB_SERVER_URL").unwrap_or("https://github.com".to_owned());
+    server.trim_end_matches('/').to_owned()
+}
+
+/// The token to authenticate downloads from the GitHub web interface with, so tarballs of
+/// private repositories can be fetched.
+pub fn auth() -> anyhow::Result<Option<crate::forge::Auth>> {
+    let Some(token) = crate::forge::token("GITHUB_TOKEN")? else {
+        return Ok(None);
+    };
+    let server: url::Url = server_url().parse()?;
+    Ok(Some(crate::forge::Auth {
+        host: server.host_str().unwrap_or_default().to_owned(),
+        header: "Authorization",
+        value: format!("Bearer {token}"),
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct ReleaseNotes {
+    body: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct Comparison {
+    commits: Vec<ComparedCommit>,
+}
+
+#[derive(serde::Deserialize)]
+struct ComparedCommit {
+    sha: String,
+    commit: CommitMessage,
+}
+
+#[derive(serde::Deserialize)]
+struct CommitMessage {
+    message: String,
+}
+
+/// The notes of the release of `tag` of `owner/repo`, if it has any.
+pub fn release_notes(
+    client: &crate::http::Client,
+    owner: &str,
+    repo: &str,
+    tag: &str,
+) -> anyhow::Result<Option<String>> {
+    let path = format!("/repos/{}/{}/releases/tags/{}", owner, repo, tag);
+    let res = match api_get(client, &path, "application/vnd.github+json") {
+        Ok(res) => res,
+        // Only tagged, not released.
+        Err(e) if is_not_found(&e) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let notes: ReleaseNotes =
+        serde_json::from_reader(res.into_reader()).context("failed to parse the GitHub release")?;
+    Ok(notes.body.filter(|body| !body.trim().is_empty()))
+}
+
+/// Find the latest release of `owner/repo`, which GitHub defines as the most recent one that
+/// isn't a prerelease or a draft.
+fn latest_release(
+    client: &crate::http::Client,
+    owner: &str,
+    repo: &str,
+) -> anyhow::Result<Release> {
+    let path = format!("/repos/{}/{}/releases/latest", owner, repo);
+    let res = match api_get(client, &path, "application/vnd.github+json") {
+        Ok(res) => res,
+        Err(e) if is_not_found(&e) => anyhow::bail!("{}/{} has no releases", owner, repo),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_reader(res.into_reader()).context("failed to parse the GitHub release")
+}
+
+impl GithubRelease {
+    /// Find the latest release, and return its tag and the url of the matching asset.
+    pub fn latest(&self, client: &crate::http::Client) -> anyhow::Result<(String, url::Url)> {
+        let pattern = regex::Regex::new(&self.asset_pattern)?;
+        let release = latest_release(client, &self.owner, &self.repo)?;
+        let asset = release
+            .assets
+            .into_iter()
+            .find(|asset| pattern.is_match(&asset.name))
+            .with_context(|| {
+                format!(
+                    "no asset of {}/{} {} matches {}",
+                    self.owner, self.repo, release.tag_name, self.asset_pattern
+                )
+            })?;
+        Ok((release.tag_name, asset.browser_download_url))
+    }
+}
+
+impl GithubBranch {
+    /// Find the commit at the tip of the branch, and return it and the url of its tarball.
+    pub fn head(&self, client: &crate::http::Client) -> anyhow::Result<(String, url::Url)> {
+        let path = format!(
+            "/repos/{}/{}/commits/{}",
+            self.owner, self.repo, self.branch
+        );
+        // With this media type, the response is just the hash of the commit.
+        let res = match api_get(client, &path, "application/vnd.github.sha") {
+            Ok(res) => res,
+            Err(e) if is_not_found(&e) => {
+                anyhow::bail!("{}/{} has no branch {}", self.owner, self.repo, self.branch)
+            }
+            Err(e) => return Err(e),
+        };
+        let rev = res.into_string()?.trim().to_owned();
+        if rev.len() != 40 || !rev.bytes().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("GitHub returned {:?} instead of a commit hash", rev);
+        }
+        let url = format!(
+            "{}/{}/{}/archive/{}.tar.gz",
+            server_url(),
+            self.owner,
+            self.repo,
+            rev
+        )
+        .parse()?;
+        Ok((rev, url))
+    }
+
+    /// The commits on the branch after `old` up to `new`, one per line with the first line of
+    /// their message.
+    pub fn log(
+        &self,
+        client: &crate::http::Client,
+        old: &str,
+        new: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let path = format!(
+            "/repos/{}/{}/compare/{}...{}",
+            self.owner, self.repo, old, new
+        );
+        let res = api_get(client, &path, "application/vnd.github+json")?;
+        let comparison: Comparison = serde_json::from_reader(res.into_reader())
+            .context("failed to parse the GitHub comparison")?;
+        let lines: Vec<_> = comparison
+            .commits
+            .iter()
+            .map(|c| {
+                let subject = c.commit.message.lines().next().unwrap_or_default();
+                format!("{} {}", &c.sha[..c.sha.len().min(12)], subject)
+            })
+            .collect();
+        Ok((!lines.is_empty()).then(|| lines.join("\n")))
+    }
+}
+
+impl crate::forge::Forge for GithubRepository {
+    fn url(&self) -> anyhow::Result<url::Url> {
+        Ok(format!("{}/{}/{}", server_url(), self.owner, self.repo).parse()?)
+    }
+
+    fn latest_release(&self, client: &crate::http::Client) -> anyhow::Result<String> {
+        Ok(latest_release(client, &self.owner, &self.repo)?.tag_name)
+    }
+
+    fn archive_url(&self, tag: &str) -> anyhow::Result<url::Url> {
+        Ok(format!(
+            "{}/{}/{}/archive/refs/tags/{}.tar.gz",
+            server_url(),
+            self.owner,
+            self.repo,
+            tag
+        )
+        .parse()?)
+    }
+
+    fn branch_head(
+        &self,
+        client: &crate::http::Client,
+        branch: &str,
+    ) -> anyhow::Result<(String, url::Url)> {
+        GithubBranch {
+            owner: self.owner.clone(),
+            repo: self.repo.clone(),
+            branch: branch.to_owned(),
+        }
+        .head(client)
+    }
+
+    fn release_notes(
+        &self,
+        client: &crate::http::Client,
+        tag: &str,
+    ) -> anyhow::Result<Option<String>> {
+        release_notes(client, &self.owner, &self.repo, tag)
+    }
+}
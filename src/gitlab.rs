@@ -0,0 +1,132 @@
+//! Releases and branches of a project on GitLab, either gitlab.com or a self-hosted instance.
+
+use anyhow::Context;
+
+/// A project on a GitLab instance.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct GitlabProject {
+    pub instance: url::Url,
+    /// Full path of the project, including its groups, e.g. `group/subgroup/project`.
+    pub project: String,
+}
+
+#[derive(serde::Deserialize)]
+struct Release {
+    tag_name: String,
+    description: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct Branch {
+    commit: Commit,
+}
+
+#[derive(serde::Deserialize)]
+struct Commit {
+    id: String,
+}
+
+impl GitlabProject {
+    fn base(&self) -> &str {
+        self.instance.as_str().trim_end_matches('/')
+    }
+
+    /// The token to authenticate requests to the instance with, from `GITLAB_TOKEN`.
+    pub fn auth(&self) -> anyhow::Result<Option<crate::forge::Auth>> {
+        Ok(
+            crate::forge::token("GITLAB_TOKEN")?.map(|token| crate::forge::Auth {
+                host: self.instance.host_str().unwrap_or_default().to_owned(),
+                header: "PRIVATE-TOKEN",
+                value: token,
+            }),
+        )
+    }
+
+    /// GET `path` under the API of the project, authenticated if a token is configured.
+    fn api_get(&self, client: &crate::http::Client, path: &str) -> anyhow::Result<ureq::Response> {
+        let url: url::Url = format!(
+            "{}/api/v4/projects/{}/{}",
+            self.base(),
+            self.project.replace('/', "%2F"),
+            path
+        )
+        .parse()?;
+        let auth = self.auth()?;
+        let result = client.call("GET", &url, |req| match &auth {
+            Some(auth) => auth.apply(req),
+            None => req,
+        });
+        match result {
+            Ok((res, _)) => Ok(res),
+            Err(e) => match e.downcast_ref() {
+                Some(ureq::Error::Status(429, _)) => anyhow::bail!(
+                    "GitLab API rate limit exceeded{}",
+                    if auth.is_none() {
+                        ", set GITLAB_TOKEN to raise it"
+                    } else {
+                        ""
+                    }
+                ),
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+impl crate::forge::Forge for GitlabProject {
+    fn url(&self) -> anyhow::Result<url::Url> {
+        Ok(format!("{}/{}", self.base(), self.project).parse()?)
+    }
+
+    fn latest_release(&self, client: &crate::http::Client) -> anyhow::Result<String> {
+        // Releases are sorted by release date, newest first.
+        let res = self.api_get(client, "releases?per_page=1")?;
+        let releases: Vec<Release> = serde_json::from_reader(res.into_reader())
+            .context("failed to parse the GitLab releases")?;
+        let release = releases
+            .into_iter()
+            .next()
+            .with_context(|| format!("{} has no releases", self.project))?;
+        Ok(release.tag_name)
+    }
+
+    fn archive_url(&self, tag: &str) -> anyhow::Result<url::Url> {
+        let name = self.project.rsplit('/').next().unwrap_or(&self.project);
+        Ok(format!(
+            "{}/{}/-/archive/{tag}/{name}-{tag}.tar.gz",
+            self.base(),
+            self.project
+        )
+        .parse()?)
+    }
+
+    fn branch_head(
+        &self,
+        client: &crate::http::Client,
+        branch: &str,
+    ) -> anyhow::Result<(String, url::Url)> {
+        let path = format!("repository/branches/{}", branch.replace('/', "%2F"));
+        let res = match self.api_get(client, &path) {
+            Ok(res) => res,
+            Err(e) if matches!(e.downcast_ref(), Some(ureq::Error::Status(404, _))) => {
+                anyhow::bail!("{} has no branch {}", self.project, branch)
+            }
+            Err(e) => return Err(e),
+        };
+        let branch: Branch = serde_json::from_reader(res.into_reader())
+            .context("failed to parse the GitLab branch")?;
+        let url = self.archive_url(&branch.commit.id)?;
+        Ok((branch.commit.id, url))
+    }
+
+    fn release_notes(
+        &self,
+        client: &crate::http::Client,
+        tag: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let res = self.api_get(client, &format!("releases/{}", tag.replace('/', "%2F")))?;
+        let release: Release = serde_json::from_reader(res.into_reader())
+            .context("failed to parse the GitLab release")?;
+        Ok(release.description.filter(|notes| !notes.trim().is_empty()))
+    }
+}
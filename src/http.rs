@@ -0,0 +1,511 @@
+//! HTTP client shared by everything that talks to the network.
+
+use anyhow::Context;
+
+pub struct Client {
+    /// Agent used for requests that don't go through a proxy. Agents never follow redirects
+    /// themselves, see [`Client::call`].
+    agent: ureq::Agent,
+    /// Proxies for http and https urls, if any, and the agents going through them.
+    http_proxy: Option<(ureq::Proxy, ureq::Agent)>,
+    https_proxy: Option<(ureq::Proxy, ureq::Agent)>,
+    /// Hosts that are connected to directly, even with a proxy.
+    no_proxy: Vec<String>,
+    /// Maximum number of redirects followed for a single request.
+    max_redirects: usize,
+    /// Number of times a request is retried after a transient failure.
+    retries: usize,
+    /// How long to wait for a connection or for data.
+    timeout: std::time::Duration,
+    /// How long the tools run to prefetch sources may take, if there is a limit at all.
+    prefetch_timeout: Option<std::time::Duration>,
+    /// Sent to the hosts they are for, unless a request is already authenticated.
+    credentials: crate::credentials::Credentials,
+    /// TLS configuration with extra certificate authorities or a client certificate, instead of
+    /// ureq's default one.
+    tls: Option<std::sync::Arc<rustls::ClientConfig>>,
+    /// How much longer requests may wait, in total, for servers that rate limited them.
+    wait_budget: std::sync::Mutex<std::time::Duration>,
+    /// Refuse to send any request.
+    offline: bool,
+}
+
+/// A file downloaded into a temporary directory, removed when dropped.
+pub struct Download {
+    _dir: tempfile::TempDir,
+    pub path: std::path::PathBuf,
+}
+
+impl Client {
+    pub fn new(max_redirects: usize, retries: usize, timeout: std::time::Duration) -> Self {
+        Self {
+            agent: agent_builder(timeout, None).build(),
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: Vec::new(),
+            max_redirects,
+            retries,
+            timeout,
+            prefetch_timeout: None,
+            credentials: Default::default(),
+            tls: None,
+            wait_budget: Default::default(),
+            offline: false,
+        }
+    }
+
+    /// Wait up to `max_wait` in total for servers that say when rate limited requests can be
+    /// sent again. Without it, those requests fail right away.
+    pub fn with_max_wait(self, max_wait: std::time::Duration) -> Self {
+        Self {
+            wait_budget: max_wait.into(),
+            ..self
+        }
+    }
+
+    /// Authenticate requests with `credentials`.
+    pub fn with_credentials(self, credentials: crate::credentials::Credentials) -> Self {
+        Self {
+            credentials,
+            ..self
+        }
+    }
+
+    /// Kill the tools run to prefetch sources if they take longer than `prefetch_timeout`.
+    /// Without it, they are waited for however long downloading and hashing takes.
+    pub fn with_prefetch_timeout(self, prefetch_timeout: Option<std::time::Duration>) -> Self {
+        Self {
+            prefetch_timeout,
+            ..self
+        }
+    }
+
+    /// Refuse to send any request if `offline` is set, and tell the prefetchers not to run.
+    pub fn with_offline(self, offline: bool) -> Self {
+        Self { offline, ..self }
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Send requests through `proxy`. Without one, the proxies in `http_proxy`, `https_proxy`
+    /// or `all_proxy` are used, like curl does. Either way, hosts in `no_proxy` are connected to
+    /// directly.
+    pub fn with_proxy(self, proxy: Option<&str>) -> anyhow::Result<Self> {
+        let var = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| std::env::var(name).ok().filter(|v| !v.is_empty()))
+        };
+        let all = var(&["ALL_PROXY", "all_proxy"]);
+        let (http, https) = match proxy {
+            Some(proxy) => (Some(proxy.to_owned()), Some(proxy.to_owned())),
+            // Only the lower case http_proxy, as HTTP_PROXY can be set by CGI.
+            None => (
+                var(&["http_proxy"]).or(all.clone()),
+                var(&["HTTPS_PROXY", "https_proxy"]).or(all),
+            ),
+        };
+        let agent = |proxy: Option<String>| -> anyhow::Result<_> {
+            proxy
+                .map(|proxy| {
+                    let proxy = ureq::Proxy::new(&proxy)
+                        .with_context(|| format!("invalid proxy {}", proxy))?;
+                    let agent = agent_builder(self.timeout, self.tls.clone())
+                        .proxy(proxy.clone())
+                        .build();
+                    anyhow::Ok((proxy, agent))
+                })
+                .transpose()
+        };
+        let no_proxy = var(&["NO_PROXY", "no_proxy"])
+            .unwrap_or_default()
+            .split(',')
+            .map(|host| host.trim().trim_start_matches('.').to_owned())
+            .filter(|host| !host.is_empty())
+            .collect();
+        Ok(Self {
+            http_proxy: agent(http)?,
+            https_proxy: agent(https)?,
+            no_proxy,
+            ..self
+        })
+    }
+
+    /// Use `tls` for https connections, see [`crate::tls::config`].
+    pub fn with_tls(self, tls: Option<std::sync::Arc<rustls::ClientConfig>>) -> Self {
+        let agent = |proxy: Option<(ureq::Proxy, _)>| {
+            proxy.map(|(proxy, _)| {
+                let agent = agent_builder(self.timeout, tls.clone())
+                    .proxy(proxy.clone())
+                    .build();
+                (proxy, agent)
+            })
+        };
+        Self {
+            agent: agent_builder(self.timeout, tls.clone()).build(),
+            http_proxy: agent(self.http_proxy),
+            https_proxy: agent(self.https_proxy),
+            tls,
+            ..self
+        }
+    }
+
+    /// Whether https connections use a TLS configuration of our own, which external tools
+    /// don't know about.
+    pub fn has_custom_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// The agent to send requests to `url` with, through a proxy or not.
+    fn agent(&self, url: &url::Url) -> &ureq::Agent {
+        let host = url.host_str().unwrap_or_default();
+        let direct = self.no_proxy.iter().any(|pattern| {
+            pattern == "*"
+                || host == pattern
+                || host
+                    .strip_suffix(pattern.as_str())
+                    .is_some_and(|rest| rest.ends_with('.'))
+        });
+        let proxy = match url.scheme() {
+            "http" => self.http_proxy.as_ref().map(|(_, agent)| agent),
+            "https" => self.https_proxy.as_ref().map(|(_, agent)| agent),
+            _ => None,
+        };
+        match proxy {
+            Some(proxy) if !direct => proxy,
+            _ => &self.agent,
+        }
+    }
+
+    /// Whether requests to `url` are authenticated with the configured credentials.
+    pub fn has_credentials(&self, url: &url::Url) -> bool {
+        self.credentials.get(url).is_some()
+    }
+
+    pub fn timeout(&self) -> std::time::Duration {
+        self.timeout
+    }
+
+    pub fn prefetch_timeout(&self) -> Option<std::time::Duration> {
+        self.prefetch_timeout
+    }
+
+    /// Run `attempt` until it succeeds, retrying with exponential backoff as long as it fails in
+    /// a way `transient` says might not happen again.
+    pub fn retry<T, E: std::fmt::Display>(
+        &self,
+        mut attempt: impl FnMut() -> Result<T, E>,
+        transient: impl Fn(&E) -> bool,
+    ) -> Result<T, E> {
+        let mut delay = std::time::Duration::from_millis(500);
+        for _ in 0..self.retries {
+            let err = match attempt() {
+                Err(err) if transient(&err) => err,
+                res => return res,
+            };
+            log::warn!("{:#}, retrying in {:?}", err, delay);
+            std::thread::sleep(delay);
+            delay *= 2;
+        }
+        attempt()
+    }
+
+    /// Send `request`, retrying on connection errors and 5xx responses, and waiting out rate
+    /// limits within the budget. Other errors, 4xx in particular, are deterministic and returned
+    /// immediately.
+    fn send(&self, request: impl Fn() -> ureq::Request) -> anyhow::Result<ureq::Response> {
+        loop {
+            let err = match self.retry(
+                || request().call().map_err(Box::new),
+                |err| is_transient(err),
+            ) {
+                Ok(res) => return Ok(res),
+                Err(err) => *err,
+            };
+            let Some(wait) = rate_limit_wait(&err) else {
+                return Err(err.into());
+            };
+            let mut budget = self.wait_budget.lock().unwrap();
+            if wait > *budget {
+                log::warn!(
+                    "{}, rate limited for {:?}, longer than the {:?} left to wait",
+                    err,
+                    wait,
+                    *budget
+                );
+                return Err(err.into());
+            }
+            *budget -= wait;
+            drop(budget);
+            log::warn!("{}, rate limited, waiting {:?}", err, wait);
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Send a request to `url`, following redirects by hand so the chain can be recorded.
+    ///
+    /// `prepare` is applied to the request sent to every hop.
+    pub fn call(
+        &self,
+        method: &str,
+        url: &url::Url,
+        prepare: impl Fn(ureq::Request) -> ureq::Request,
+    ) -> anyhow::Result<(ureq::Response, Vec<url::Url>)> {
+        if self.offline {
+            anyhow::bail!("can't fetch {} offline", url);
+        }
+        let mut current = url.clone();
+        let mut chain = Vec::new();
+        loop {
+            let credential = self.credentials.get(&current);
+            let res = self.send(|| {
+                let req = prepare(self.agent(&current).request_url(method, &current));
+                match credential {
+                    Some(credential) if req.header("Authorization").is_none() => {
+                        req.set("Authorization", &credential.authorization())
+                    }
+                    _ => req,
+                }
+            })?;
+            if !matches!(res.status(), 301 | 302 | 303 | 307 | 308) {
+                return Ok((res, chain));
+            }
+            if chain.len() >= self.max_redirects {
+                anyhow::bail!("too many redirects fetching {}", url);
+            }
+            let location = res
+                .header("Location")
+                .with_context(|| format!("redirect from {current} without a Location"))?;
+            current = current.join(location)?;
+            if current == *url || chain.contains(&current) {
+                anyhow::bail!("redirect loop fetching {} at {}", url, current);
+            }
+            chain.push(current.clone());
+        }
+    }
+
+    /// GET `url`, following redirects.
+    pub fn get(&self, url: &url::Url) -> anyhow::Result<ureq::Response> {
+        Ok(self.call("GET", url, |req| req)?.0)
+    }
+
+    /// Download `url` into a temporary file called `filename`.
+    ///
+    /// The download is kept in the cache directory while it is in progress, and resumed with a
+    /// range request if the connection breaks, in this run or a later one, as long as the server
+    /// gave a validator telling whether it is still the same file.
+    pub fn download(
+        &self,
+        url: &url::Url,
+        filename: &str,
+        prepare: impl Fn(ureq::Request) -> ureq::Request,
+    ) -> anyhow::Result<Download> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(filename);
+        let Some(partials) = partial_dir() else {
+            let (res, _) = self.call("GET", url, prepare)?;
+            std::io::copy(&mut res.into_reader(), &mut std::fs::File::create(&path)?)
+                .with_context(|| format!("failed to download {url}"))?;
+            return Ok(Download { _dir: dir, path });
+        };
+        std::fs::create_dir_all(&partials)
+            .with_context(|| format!("failed to create {}", partials.display()))?;
+        prune_partials(&partials);
+        let key: String = crate::store::sha256(url.as_str().as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let part = partials.join(format!("{key}.part"));
+        let state = partials.join(format!("{key}.json"));
+        let mut resumes = 0;
+        loop {
+            match self.download_part(url, &part, &state, &prepare) {
+                Ok(()) => break,
+                Err(e) if resumes < self.retries && is_interrupted(&e) && state.exists() => {
+                    log::warn!("{:#}, resuming", e);
+                    resumes += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        // The cache and temporary directories can be on different file systems.
+        if std::fs::rename(&part, &path).is_err() {
+            std::fs::copy(&part, &path)?;
+            std::fs::remove_file(&part)?;
+        }
+        let _ = std::fs::remove_file(&state);
+        Ok(Download { _dir: dir, path })
+    }
+
+    /// Download `url` into `part`, resuming what is in it from an earlier attempt if `state`
+    /// says it is of the same url, and the server says it didn't change since.
+    fn download_part(
+        &self,
+        url: &url::Url,
+        part: &std::path::Path,
+        state: &std::path::Path,
+        prepare: impl Fn(ureq::Request) -> ureq::Request,
+    ) -> anyhow::Result<()> {
+        let partial = std::fs::read(state)
+            .ok()
+            .and_then(|state| serde_json::from_slice::<Partial>(&state).ok())
+            .filter(|partial| partial.url == *url);
+        let offset = std::fs::metadata(part).map_or(0, |metadata| metadata.len());
+        let resume = partial.filter(|_| offset > 0);
+        let res = self.call("GET", url, |req| {
+            let req = prepare(req);
+            match &resume {
+                // Ranges are of the encoded body, which ureq would decode.
+                Some(partial) => req
+                    .set("Range", &format!("bytes={offset}-"))
+                    .set("If-Range", &partial.validator)
+                    .set("Accept-Encoding", "identity"),
+                None => req,
+            }
+        });
+        let res = match res {
+            // What is left is already complete, or not there anymore.
+            Err(e) if matches!(e.downcast_ref(), Some(ureq::Error::Status(416, _))) => {
+                log::debug!("can't resume the download of {}, restarting", url);
+                std::fs::remove_file(state)?;
+                return self.download_part(url, part, state, prepare);
+            }
+            res => res?.0,
+        };
+        let resumed = resume.is_some() && res.status() == 206;
+        if resumed && content_range_start(&res) != Some(offset) {
+            anyhow::bail!("{} sent a different range than asked for", url);
+        }
+        // If-Range doesn't take weak ETags.
+        let validator = res
+            .header("ETag")
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(res.header("Last-Modified"))
+            .filter(|_| res.header("Content-Encoding").is_none());
+        match validator {
+            Some(validator) => {
+                let partial = Partial {
+                    url: url.clone(),
+                    validator: validator.to_owned(),
+                };
+                std::fs::write(state, serde_json::to_vec(&partial)?)?;
+            }
+            None => match std::fs::remove_file(state) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            },
+        }
+        let mut file = if resumed {
+            log::info!("resuming the download of {} from {} bytes", url, offset);
+            std::fs::OpenOptions::new().append(true).open(part)?
+        } else {
+            std::fs::File::create(part)?
+        };
+        std::io::copy(&mut res.into_reader(), &mut file)
+            .with_context(|| format!("failed to download {url}"))?;
+        Ok(())
+    }
+}
+
+/// A download in progress, kept next to it.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Partial {
+    url: url::Url,
+    /// The ETag, or else the Last-Modified date, of what is being downloaded.
+    validator: String,
+}
+
+/// The directory downloads in progress are kept in, `nix-source/downloads` in
+/// `$XDG_CACHE_HOME`, or `~/.cache` if that isn't set.
+fn partial_dir() -> Option<std::path::PathBuf> {
+    let dir = match (std::env::var_os("XDG_CACHE_HOME"), std::env::var_os("HOME")) {
+        (Some(dir), _) => std::path::PathBuf::from(dir),
+        (None, Some(home)) => std::path::Path::new(&home).join(".cache"),
+        (None, None) => return None,
+    };
+    Some(dir.join("nix-source").join("downloads"))
+}
+
+/// Remove downloads in `dir` that were left unfinished for more than a week.
+fn prune_partials(dir: &std::path::Path) {
+    const MAX_AGE: std::time::Duration = std::time::Duration::from_secs(7 * 24 * 60 * 60);
+    for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+        let old = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > MAX_AGE));
+        if old {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Where the range in the `Content-Range` of `res` starts.
+fn content_range_start(res: &ureq::Response) -> Option<u64> {
+    let range = res.header("Content-Range")?.strip_prefix("bytes ")?;
+    range.split_once('-')?.0.trim().parse().ok()
+}
+
+/// Whether `err` is of a connection that broke, or went quiet, in the middle of a response.
+pub fn is_interrupted(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        err.downcast_ref::<std::io::Error>().is_some_and(|err| {
+            matches!(
+                err.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::UnexpectedEof
+            )
+        })
+    })
+}
+
+fn agent_builder(
+    timeout: std::time::Duration,
+    tls: Option<std::sync::Arc<rustls::ClientConfig>>,
+) -> ureq::AgentBuilder {
+    let builder = ureq::AgentBuilder::new()
+        .redirects(0)
+        .timeout_connect(timeout)
+        .timeout_read(timeout);
+    match tls {
+        Some(tls) => builder.tls_config(tls),
+        None => builder,
+    }
+}
+
+/// How long to wait before sending a request again, if `err` says it was rate limited and when
+/// that is over, in `Retry-After` or GitHub's `X-RateLimit-Reset`.
+fn rate_limit_wait(err: &ureq::Error) -> Option<std::time::Duration> {
+    let ureq::Error::Status(429 | 403, res) = err else {
+        return None;
+    };
+    let until = if let Some(retry_after) = res.header("Retry-After") {
+        // Either a number of seconds or an HTTP date.
+        match retry_after.trim().parse() {
+            Ok(seconds) => return Some(std::time::Duration::from_secs(seconds)),
+            Err(_) => chrono::DateTime::parse_from_rfc2822(retry_after)
+                .ok()?
+                .with_timezone(&chrono::Utc),
+        }
+    } else if res.header("X-RateLimit-Remaining") == Some("0") {
+        let reset = res.header("X-RateLimit-Reset")?.trim().parse().ok()?;
+        chrono::DateTime::from_timestamp(reset, 0)?
+    } else {
+        return None;
+    };
+    Some((until - chrono::Utc::now()).to_std().unwrap_or_default())
+}
+
+fn is_transient(err: &ureq::Error) -> bool {
+    match err {
+        ureq::Error::Status(status, _) => *status >= 500,
+        ureq::Error::Transport(transport) => matches!(
+            transport.kind(),
+            ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io
+        ),
+    }
+}
@@ -0,0 +1,154 @@
+//! Sources on IPFS, addressed as `ipfs://CID` or `ipfs://CID/path`, fetched through HTTP
+//! gateways.
+//!
+//! Gateways are tried in order until one serves the content. CIDs of raw blocks hashed with
+//! sha256 are the sha256 of the content itself, so what a gateway serves for one is checked
+//! against it, and a gateway serving something else is skipped. Other CIDs are of a DAG of
+//! blocks, which gateways don't send.
+
+use crate::{HashType, RefreshOptions, Source};
+use anyhow::Context;
+
+/// The gateways used unless the config file sets `ipfs-gateways`.
+pub const DEFAULT_GATEWAYS: &[&str] = &["https://ipfs.io", "https://dweb.link"];
+
+/// The CID in `url`.
+fn cid(url: &url::Url) -> anyhow::Result<&str> {
+    url.host_str()
+        .filter(|cid| !cid.is_empty())
+        .with_context(|| format!("{url} has no CID"))
+}
+
+/// Where `gateway` serves what the `ipfs://` url `url` is of.
+pub fn gateway_url(gateway: &url::Url, url: &url::Url) -> anyhow::Result<url::Url> {
+    let url = format!(
+        "{}/ipfs/{}{}",
+        gateway.as_str().trim_end_matches('/'),
+        cid(url)?,
+        url.path()
+    );
+    url.parse()
+        .with_context(|| format!("invalid gateway url {url}"))
+}
+
+/// Decode `s` from lower case base32 without padding, as multibase's `b` prefix means.
+fn base32(s: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+/// The sha256 of the content `cid` is of, if it is a CIDv1 of a raw block hashed with sha256.
+fn raw_sha256(cid: &str) -> Option<Vec<u8>> {
+    let bytes = base32(cid.strip_prefix('b')?)?;
+    // Version 1, the raw codec, and a sha2-256 multihash of 32 bytes, all one byte varints.
+    match bytes.as_slice() {
+        [0x01, 0x55, 0x12, 0x20, digest @ ..] if digest.len() == 32 => Some(digest.to_vec()),
+        _ => None,
+    }
+}
+
+/// Fetch and hash `source` through the first of the gateways in `options` serving it. What is
+/// on IPFS never changes, so a source with a hash isn't fetched again unless forced.
+pub fn refresh(
+    client: &crate::http::Client,
+    source: &Source,
+    options: RefreshOptions,
+) -> anyhow::Result<Source> {
+    let hash_type = options.hash_type_for(source);
+    if !options.force
+        && source
+            .hash
+            .as_ref()
+            .is_some_and(|hash| HashType::of(hash) == Some(hash_type))
+    {
+        log::debug!("\tcontent on IPFS doesn't change");
+        return Ok(source.clone());
+    }
+    if options.gateways.is_empty() {
+        anyhow::bail!("no IPFS gateways to fetch {} through", source.url);
+    }
+    let cid = cid(&source.url)?;
+    let expected = raw_sha256(cid).filter(|_| source.url.path().trim_matches('/').is_empty());
+    let mut last_error = None;
+    for gateway in options.gateways {
+        let fetched = crate::refresh_url(
+            client,
+            &Source {
+                url: gateway_url(gateway, &source.url)?,
+                etag: None,
+                last_modified: None,
+                resolved_url: None,
+                ..source.clone()
+            },
+            RefreshOptions {
+                force: true,
+                hash_type: Some(hash_type),
+                ..options
+            },
+        )
+        .and_then(|fetched| {
+            let Some(expected) = &expected else {
+                log::debug!("\t{} is not of raw content, it can't be checked", cid);
+                return Ok(fetched);
+            };
+            let flat = !fetched.decompress
+                && !fetched
+                    .unpack
+                    .unwrap_or(fetched.ty.is_some_and(crate::SourceType::unpacks));
+            let sha256 = fetched
+                .hash
+                .as_ref()
+                .filter(|hash| flat && HashType::of(hash) == Some(HashType::Sha256));
+            match sha256 {
+                Some(hash) => {
+                    let (_, hex) = hash.to_hex();
+                    let expected: String = expected.iter().map(|b| format!("{b:02x}")).collect();
+                    if hex != expected {
+                        anyhow::bail!("{} served content that isn't {}", gateway, cid);
+                    }
+                    log::debug!("\tverified against {}", cid);
+                }
+                None => log::debug!("\t{} isn't hashed as is, it can't be checked", cid),
+            }
+            Ok(fetched)
+        });
+        match fetched {
+            Ok(fetched) => {
+                log::debug!("\tfetched through {}", gateway);
+                return Ok(Source {
+                    url: source.url.clone(),
+                    // Any gateway serves the same content.
+                    etag: None,
+                    last_modified: None,
+                    resolved_url: None,
+                    redirects: source.redirects.clone(),
+                    ..fetched
+                });
+            }
+            Err(e) => {
+                log::warn!(
+                    "failed to fetch {} through {}: {:#}",
+                    source.url,
+                    gateway,
+                    e
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error
+        .unwrap()
+        .context(format!("no gateway could fetch {}", source.url)))
+}
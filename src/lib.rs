@@ -0,0 +1,1873 @@
+//! Keeping track of sources fetched from the internet, and the hashes nix needs to fetch them.
+//!
+//! A [`Sources`] file maps names to [`Source`]s. It is read with [`Sources::load`] and written
+//! with [`Sources::save`], sources are added and removed with [`Sources::add`] and
+//! [`Sources::remove`], and [`refresh_source`] fetches a source again, returning it with its new
+//! hash and validators.
+
+use anyhow::Context;
+use chrono::{DateTime, FixedOffset, Utc};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+pub mod bucket;
+pub mod checked;
+pub mod completions;
+pub mod config;
+pub mod crates;
+pub mod credentials;
+pub mod flake;
+pub mod forge;
+pub mod git;
+pub mod gitea;
+pub mod github;
+pub mod gitlab;
+pub mod http;
+pub mod ipfs;
+pub mod manpages;
+mod nar;
+pub mod niv;
+pub mod nix;
+pub mod nixpkgs;
+pub mod npins;
+pub mod oci;
+pub mod prefetch;
+pub mod progress;
+pub mod pypi;
+pub mod sbom;
+pub mod sourcehut;
+pub mod store;
+pub mod tls;
+pub mod upgrade;
+pub mod version;
+
+/// How a source is fetched and hashed.
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceType {
+    Tarball,
+    File,
+    Git,
+    /// The latest source distribution of a package on PyPI, fetched as a file.
+    Pypi,
+    /// The latest `.crate` of a crate on crates.io, fetched as a file.
+    Crate,
+    /// The nixexprs tarball of the current release of a Nix channel.
+    Channel,
+    /// A local file or directory, hashed the way nix hashes paths it adds to the store.
+    Path,
+    /// A container image in a registry, pinned to the digest of its tag for
+    /// `dockerTools.pullImage`.
+    Oci,
+    /// An asset of the latest release of a GitHub repository, fetched as a file.
+    GithubRelease,
+}
+
+impl SourceType {
+    /// Whether sources of this type are unpacked before hashing, unless `unpack` says otherwise.
+    pub fn unpacks(self) -> bool {
+        matches!(self, SourceType::Tarball | SourceType::Channel)
+    }
+}
+
+impl std::fmt::Display for SourceType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SourceType::Tarball => "tarball",
+            SourceType::File => "file",
+            SourceType::Git => "git",
+            SourceType::Pypi => "pypi",
+            SourceType::Crate => "crate",
+            SourceType::Channel => "channel",
+            SourceType::Path => "path",
+            SourceType::Oci => "oci",
+            SourceType::GithubRelease => "github-release",
+        })
+    }
+}
+
+impl std::str::FromStr for SourceType {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tarball" => Ok(SourceType::Tarball),
+            "file" => Ok(SourceType::File),
+            "git" => Ok(SourceType::Git),
+            "pypi" => Ok(SourceType::Pypi),
+            "crate" => Ok(SourceType::Crate),
+            "channel" => Ok(SourceType::Channel),
+            "path" => Ok(SourceType::Path),
+            "oci" => Ok(SourceType::Oci),
+            "github-release" => Ok(SourceType::GithubRelease),
+            _ => Err(anyhow::anyhow!("invalid source type")),
+        }
+    }
+}
+
+/// Compression format of a source whose hash is computed over the decompressed stream.
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    pub fn detect(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+/// Hash algorithm used for the stored hashes.
+#[derive(PartialEq, Debug, Eq, Clone, Copy)]
+pub enum HashType {
+    Sha256,
+    Sha512,
+}
+
+impl HashType {
+    /// The algorithm `hash` was computed with, if it is one we support.
+    pub fn of(hash: &ssri::Integrity) -> Option<Self> {
+        match hash.pick_algorithm() {
+            ssri::Algorithm::Sha256 => Some(HashType::Sha256),
+            ssri::Algorithm::Sha512 => Some(HashType::Sha512),
+            _ => None,
+        }
+    }
+
+    pub fn algorithm(self) -> ssri::Algorithm {
+        match self {
+            HashType::Sha256 => ssri::Algorithm::Sha256,
+            HashType::Sha512 => ssri::Algorithm::Sha512,
+        }
+    }
+}
+
+impl std::fmt::Display for HashType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            HashType::Sha256 => "sha256",
+            HashType::Sha512 => "sha512",
+        })
+    }
+}
+
+impl std::str::FromStr for HashType {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(HashType::Sha256),
+            "sha512" => Ok(HashType::Sha512),
+            _ => Err(anyhow::anyhow!("invalid hash type")),
+        }
+    }
+}
+
+/// A source in the sources file, and what is needed to fetch it again.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Source {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub hash: Option<ssri::Integrity>,
+    pub url: url::Url,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub last_modified: Option<DateTime<FixedOffset>>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub etag: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none", default)]
+    pub ty: Option<SourceType>,
+    /// Hash the decompressed, but not unpacked, stream instead of the file as downloaded.
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub decompress: bool,
+    /// Compression format detected when `decompress` is set.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub compression: Option<Compression>,
+    /// Redirects followed during the last fetch, in order.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub redirects: Vec<url::Url>,
+    /// Where `url` redirected to when the source was last fetched, if anywhere. This is what
+    /// the hash was computed from.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub resolved_url: Option<url::Url>,
+    /// Detached signature the downloaded artifact must be verified against.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub signature: Option<Signature>,
+    /// Checksum file published with the source, like `SHA256SUMS`, whose entry for the
+    /// downloaded artifact it must match.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub checksums: Option<url::Url>,
+    /// Whether to unpack the source before hashing, overriding what `ty` implies.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub unpack: Option<bool>,
+    /// Whether the single top-level directory of an unpacked tarball is stripped, so it is the
+    /// root of the source, as it is unless this is false.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub strip_root: Option<bool>,
+    /// Path of the only directory of the source that is fetched and hashed. An unpacked tarball
+    /// is reduced to it, as the root of the source, while git sources are checked out sparsely,
+    /// with the files on the way to it.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub subdir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub version: Option<String>,
+    /// Where to look for newer versions of this source.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub version_index: Option<version::VersionIndex>,
+    /// Url with a `{version}` placeholder, the url is derived from it and `version` when the
+    /// source is refreshed.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub url_template: Option<String>,
+    /// GitHub repository whose latest release this source follows.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub github: Option<github::GithubRelease>,
+    /// GitHub branch whose tip this source follows, `rev` is the commit it was last at.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub github_branch: Option<github::GithubBranch>,
+    /// Repository on a forge whose latest release this source follows.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub repository: Option<forge::Repository>,
+    /// Branch of `repository` whose tip this source follows instead of its releases, `rev` is
+    /// the commit it was last at.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub branch: Option<String>,
+    /// Name of the package in the registry of its type, whose latest version this source
+    /// follows.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub package: Option<String>,
+    /// Other urls serving the same content, tried in order if the url can't be fetched.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub mirrors: Vec<url::Url>,
+    /// The mirror the source was last fetched from, because its url couldn't be. Nix is still
+    /// given the url.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub fetched_from: Option<url::Url>,
+    /// Branch, tag or revision a git source tracks.
+    #[serde(rename = "ref", skip_serializing_if = "Option::is_none", default)]
+    pub git_ref: Option<String>,
+    /// Revision a git source was last resolved to.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub rev: Option<String>,
+    /// Check out the submodules of a git source too, like `fetchSubmodules` of `fetchgit`.
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub submodules: bool,
+    /// Clone the whole history of a git source and keep its `.git` directory, like `deepClone`
+    /// of `fetchgit`, instead of only checking out the revision.
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub deep_clone: bool,
+    /// Name of the source in the nix store, instead of one derived from its file name.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub store_name: Option<String>,
+    /// Path the source was last fetched to in the nix store.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub store_path: Option<std::path::PathBuf>,
+    /// Size in bytes, as reported by the server.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub size: Option<u64>,
+    /// Extra headers sent when fetching the source. `${VAR}` in a value is replaced with the
+    /// environment variable `VAR` at request time, so secrets don't have to be stored here.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub headers: BTreeMap<String, String>,
+    /// Pinned sources are left alone when updating all sources, but still updated when named,
+    /// or when all sources are updated with pinned ones included.
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub pinned: bool,
+    /// Frozen sources are left alone by update even when named, or with pinned ones included,
+    /// unless it is forced.
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub frozen: bool,
+    /// Shell command run before the source is updated. The update is skipped if it fails.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub pre_update: Option<String>,
+    /// Shell command run after the source changed, with the sources file already updated. The
+    /// source is put back as it was if it fails.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub post_update: Option<String>,
+    /// Nix installable built after the source changed, with the sources file already updated,
+    /// to check what uses the source still builds. The source is put back as it was if the
+    /// build fails.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub test_build: Option<String>,
+    /// What the source is, for whoever reads the sources file. Kept as is when updating.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub description: Option<String>,
+    /// Home page of the project the source comes from.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub homepage: Option<url::Url>,
+    /// License of the source, preferably as an SPDX license expression.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub license: Option<String>,
+    /// Group the source belongs to, so it can be worked on with the others in it, like with
+    /// `update --group`.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub group: Option<String>,
+    /// Attribute path of the package in nixpkgs this source is a newer or older version of,
+    /// to compare them. The name of the source by default.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub nixpkgs: Option<String>,
+    /// How the source was pinned before it was updated, the latest first.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub history: Vec<Pin>,
+}
+
+/// How a source was pinned before, to roll it back to.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Pin {
+    pub url: url::Url,
+    pub hash: ssri::Integrity,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub rev: Option<String>,
+    /// When the source was updated away from this pin.
+    pub until: DateTime<FixedOffset>,
+}
+
+/// Number of previous pins kept in the history of each source.
+pub const HISTORY_LEN: usize = 10;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Signature {
+    /// Url of the detached `.asc`/`.sig` signature.
+    pub url: url::Url,
+    /// ID or fingerprint of the gpg key the signature must be made with, or with `signify`,
+    /// the public key, the second line of its `.pub` file.
+    pub key: String,
+    /// The signature is made with signify instead of gpg.
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub signify: bool,
+}
+
+impl Source {
+    pub fn new(url: url::Url) -> Self {
+        Self {
+            hash: None,
+            url,
+            last_modified: None,
+            etag: None,
+            ty: None,
+            decompress: false,
+            compression: None,
+            redirects: Vec::new(),
+            resolved_url: None,
+            signature: None,
+            checksums: None,
+            unpack: None,
+            strip_root: None,
+            subdir: None,
+            version: None,
+            version_index: None,
+            url_template: None,
+            github: None,
+            github_branch: None,
+            repository: None,
+            branch: None,
+            package: None,
+            mirrors: Vec::new(),
+            fetched_from: None,
+            git_ref: None,
+            rev: None,
+            submodules: false,
+            deep_clone: false,
+            store_name: None,
+            store_path: None,
+            size: None,
+            headers: BTreeMap::new(),
+            pinned: false,
+            frozen: false,
+            pre_update: None,
+            post_update: None,
+            test_build: None,
+            description: None,
+            homepage: None,
+            license: None,
+            group: None,
+            nixpkgs: None,
+            history: Vec::new(),
+        }
+    }
+
+    /// Add how `old` was pinned to the history of this source, if its hash changed since.
+    pub fn remember(&mut self, old: &Source) {
+        let Some(hash) = &old.hash else {
+            return;
+        };
+        if self.hash.as_ref() == Some(hash) {
+            return;
+        }
+        self.history.insert(
+            0,
+            Pin {
+                url: old.url.clone(),
+                hash: hash.clone(),
+                version: old.version.clone(),
+                rev: old.rev.clone(),
+                until: Utc::now().fixed_offset(),
+            },
+        );
+        self.history.truncate(HISTORY_LEN);
+    }
+
+    /// Go back to the `n`th pin in the history, 1 being the latest. That pin is taken out of
+    /// the history, and the current one put in it, so rolling back again undoes it.
+    pub fn rollback(&mut self, n: usize) -> anyhow::Result<()> {
+        if n == 0 || n > self.history.len() {
+            anyhow::bail!("there are {} previous pins, not {}", self.history.len(), n);
+        }
+        let pin = self.history.remove(n - 1);
+        let old = self.clone();
+        self.url = pin.url;
+        self.hash = Some(pin.hash);
+        self.version = pin.version;
+        self.rev = pin.rev;
+        // What was learned fetching the current pin doesn't hold for the old one.
+        self.etag = None;
+        self.last_modified = None;
+        self.resolved_url = None;
+        self.fetched_from = None;
+        self.redirects = Vec::new();
+        self.size = None;
+        self.store_path = None;
+        self.remember(&old);
+        Ok(())
+    }
+
+    /// Whether the url is worked out from a release, branch, package or version the source
+    /// follows, rather than given.
+    pub fn follows_upstream(&self) -> bool {
+        self.github.is_some()
+            || self.github_branch.is_some()
+            || self.repository.is_some()
+            || self.package.is_some()
+            || self.version_index.is_some()
+    }
+
+    /// Replace the url with where it last redirected to, so the source is pinned to the file
+    /// that was hashed rather than something like `/download/latest`. Returns whether it
+    /// redirected anywhere.
+    pub fn follow_redirects(&mut self) -> bool {
+        let Some(url) = self.resolved_url.take() else {
+            return false;
+        };
+        self.url = url;
+        // They led from the old url.
+        self.redirects = Vec::new();
+        true
+    }
+
+    /// The token to authenticate with when fetching this source, if it follows a repository on a
+    /// forge that one is configured for.
+    pub fn forge_auth(&self) -> anyhow::Result<Option<forge::Auth>> {
+        if self.github.is_some() || self.github_branch.is_some() {
+            return github::auth();
+        }
+        match &self.repository {
+            Some(repository) => repository.auth(),
+            None => Ok(None),
+        }
+    }
+
+    /// The extra headers to send when fetching this source, with environment variables
+    /// substituted.
+    pub fn request_headers(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let var = regex::Regex::new(r"\$\{([^}]*)\}").unwrap();
+        self.headers
+            .iter()
+            .map(|(name, value)| {
+                let mut missing = None;
+                let value = var.replace_all(value, |c: &regex::Captures| {
+                    std::env::var(&c[1]).unwrap_or_else(|_| {
+                        missing = Some(c[1].to_owned());
+                        String::new()
+                    })
+                });
+                if let Some(missing) = missing {
+                    anyhow::bail!(
+                        "environment variable {} for header {} is not set",
+                        missing,
+                        name
+                    );
+                }
+                Ok((name.clone(), value.into_owned()))
+            })
+            .collect()
+    }
+}
+
+/// What refreshing a source is busy with.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stage {
+    /// Asking the server whether it changed, or what the latest release is.
+    Checking,
+    Downloading,
+    Hashing,
+}
+
+/// Options that control how a source is refreshed.
+#[derive(Default, Clone, Copy)]
+pub struct RefreshOptions<'a> {
+    /// Record the redirect chain followed when fetching the source.
+    pub record_redirects: bool,
+    /// Ignore the stored validators, so the source is always fetched and hashed again.
+    pub force: bool,
+    /// Hash algorithm to use, instead of the one of the stored hash, or sha256 if there isn't
+    /// one. Git sources are always hashed with sha256.
+    pub hash_type: Option<HashType>,
+    pub prefetcher: prefetch::Prefetcher,
+    /// Fetch and hash the source even if the server says it hasn't changed, to catch servers
+    /// that wrongly say so.
+    pub revalidate: bool,
+    /// Told what refreshing the source is busy with as it goes.
+    pub progress: Option<&'a (dyn Fn(Stage) + Sync)>,
+    /// Binary caches to look a source up in before downloading it again. If one has the store
+    /// path its hash is for, the source is taken to be unchanged, and isn't downloaded.
+    pub substituters: &'a [url::Url],
+    /// Gateways to fetch `ipfs://` sources through, in order.
+    pub gateways: &'a [url::Url],
+}
+
+impl RefreshOptions<'_> {
+    /// The hash algorithm to hash `source` with.
+    pub fn hash_type_for(&self, source: &Source) -> HashType {
+        self.hash_type
+            .or_else(|| source.hash.as_ref().and_then(HashType::of))
+            .unwrap_or(HashType::Sha256)
+    }
+
+    fn report(&self, stage: Stage) {
+        if let Some(progress) = self.progress {
+            progress(stage);
+        }
+    }
+}
+
+/// Version of the layout of sources files this version of nix-source writes. Older files are
+/// upgraded with [`upgrade::upgrade`], newer ones aren't touched.
+pub const VERSION: u64 = 2;
+
+/// The contents of a sources file.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+pub struct Sources {
+    /// Version of the layout of the file, missing in files from before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    /// Ordered by name, so the sources file is written in a stable order.
+    #[serde(default)]
+    pub sources: BTreeMap<String, Source>,
+    /// Other sources files whose sources are worked on with these, relative to this one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<std::path::PathBuf>,
+    /// Other top-level keys, kept as they are.
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+    /// The files included by this one, and by those, whose sources were merged into `sources`.
+    #[serde(skip)]
+    included: Vec<Included>,
+}
+
+/// A sources file included by another, as it was read.
+struct Included {
+    path: std::path::PathBuf,
+    format: Format,
+    /// The file without its sources, which are merged into those of the file including it.
+    file: Sources,
+    /// The names of the sources that came from it, which are written back to it.
+    names: std::collections::BTreeSet<String>,
+}
+
+/// Format of the sources file, chosen by its extension unless told otherwise.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Format {
+    Json,
+    Toml,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            _ => Err(anyhow::anyhow!("invalid sources file format")),
+        }
+    }
+}
+
+impl Format {
+    /// The format of the sources file at `path`, TOML for `.toml` files and JSON otherwise.
+    pub fn of(path: &std::path::Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext == "toml" => Format::Toml,
+            _ => Format::Json,
+        }
+    }
+
+    /// Parse the contents of a sources file, as [`Sources`] or as a plain value.
+    pub fn parse<T: serde::de::DeserializeOwned>(self, text: &str) -> anyhow::Result<T> {
+        Ok(match self {
+            Format::Json => serde_json::from_str(text)?,
+            Format::Toml => toml::from_str(text)?,
+        })
+    }
+
+    /// Serialize `sources` as the contents of a sources file. JSON is pretty-printed unless
+    /// `compact` is set.
+    pub fn serialize(self, sources: &Sources, compact: bool) -> anyhow::Result<String> {
+        Ok(match self {
+            Format::Json if compact => serde_json::to_string(sources)?,
+            Format::Json => serde_json::to_string_pretty(sources)?,
+            Format::Toml => toml::to_string(sources)?,
+        })
+    }
+}
+
+/// What to do when a source being added already exists.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum OnCollision {
+    Error,
+    /// Leave the existing source alone, and don't add the new one.
+    Skip,
+    /// Append `-2`, `-3`, etc. to the name until it is unique.
+    Suffix,
+}
+
+impl std::str::FromStr for OnCollision {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(OnCollision::Error),
+            "skip" => Ok(OnCollision::Skip),
+            "suffix" => Ok(OnCollision::Suffix),
+            _ => Err(anyhow::anyhow!("invalid collision mode")),
+        }
+    }
+}
+
+impl Sources {
+    /// The sources file of the project `dir` is in: the closest `sources.json` or
+    /// `nix/sources.json`, or their TOML equivalents, in `dir` or its parents.
+    pub fn find(dir: &std::path::Path) -> Option<std::path::PathBuf> {
+        const NAMES: [&str; 4] = [
+            "sources.json",
+            "sources.toml",
+            "nix/sources.json",
+            "nix/sources.toml",
+        ];
+        dir.ancestors()
+            .flat_map(|dir| NAMES.map(|name| dir.join(name)))
+            .find(|path| path.is_file())
+    }
+
+    /// An empty sources file, of the current [`VERSION`].
+    pub fn new() -> Self {
+        Self {
+            version: Some(VERSION),
+            ..Default::default()
+        }
+    }
+
+    /// Read the sources file at `path`, in `format`. Files newer than [`VERSION`] are refused,
+    /// as they could mean something else.
+    ///
+    /// The sources of the files it includes, and those they include, are merged into it, each
+    /// file read in the format its extension says. Names must be unique across all of them.
+    pub fn load(path: &std::path::Path, format: Format) -> anyhow::Result<Self> {
+        Self::load_including(path, format, &mut Vec::new())
+    }
+
+    /// Like [`Sources::load`], with `stack` the files including this one, to catch cycles.
+    fn load_including(
+        path: &std::path::Path,
+        format: Format,
+        stack: &mut Vec<std::path::PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let mut sources: Self = std::fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| format.parse(&text))
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        if let Some(version) = sources.version.filter(|&version| version > VERSION) {
+            anyhow::bail!(
+                "{} is version {}, newer than the version {} this nix-source knows",
+                path.display(),
+                version,
+                VERSION
+            );
+        }
+        let canonical = path.canonicalize().unwrap_or(path.to_owned());
+        if stack.contains(&canonical) {
+            anyhow::bail!("{} includes itself", path.display());
+        }
+        stack.push(canonical);
+        let dir = path.parent().unwrap_or(std::path::Path::new(""));
+        for include in sources.include.clone() {
+            let include = dir.join(include);
+            let format = Format::of(&include);
+            let mut file = Self::load_including(&include, format, stack)?;
+            let mut names = std::collections::BTreeSet::new();
+            for (name, source) in std::mem::take(&mut file.sources) {
+                if sources.sources.contains_key(&name) {
+                    anyhow::bail!("source {} is defined again in {}", name, include.display());
+                }
+                sources.sources.insert(name.clone(), source);
+                names.insert(name);
+            }
+            let nested = std::mem::take(&mut file.included);
+            sources.included.push(Included {
+                path: include,
+                format,
+                file,
+                names,
+            });
+            sources.included.extend(nested);
+        }
+        stack.pop();
+        Ok(sources)
+    }
+
+    /// The files these sources were read from, the one at `path` in `format` last, each with
+    /// only the sources it holds. Sources that came from an included file belong to it, and
+    /// the others, like new sources, to the file at `path`.
+    pub fn files(
+        &self,
+        path: &std::path::Path,
+        format: Format,
+    ) -> Vec<(std::path::PathBuf, Format, Sources)> {
+        let own = |file: &Sources, keep: &dyn Fn(&str) -> bool| Sources {
+            version: file.version,
+            sources: self
+                .sources
+                .iter()
+                .filter(|(name, _)| keep(name))
+                .map(|(name, source)| (name.clone(), source.clone()))
+                .collect(),
+            include: file.include.clone(),
+            other: file.other.clone(),
+            included: Vec::new(),
+        };
+        let mut files: Vec<_> = self
+            .included
+            .iter()
+            .map(|included| {
+                (
+                    included.path.clone(),
+                    included.format,
+                    own(&included.file, &|name| included.names.contains(name)),
+                )
+            })
+            .collect();
+        let root = own(self, &|name| {
+            !self.included.iter().any(|i| i.names.contains(name))
+        });
+        files.push((path.to_owned(), format, root));
+        files
+    }
+
+    /// Replace the sources file at `path`, in `format`, and the files it includes, as split by
+    /// [`Sources::files`]. JSON is pretty-printed unless `compact` is set.
+    ///
+    /// The new contents are written to a temporary file which is then renamed over each file,
+    /// so none is ever left partially written.
+    pub fn save(
+        &self,
+        path: &std::path::Path,
+        format: Format,
+        compact: bool,
+    ) -> anyhow::Result<()> {
+        for (path, format, file) in self.files(path, format) {
+            file.write(&path, format, compact)?;
+        }
+        Ok(())
+    }
+
+    /// Write only this file to `path`, not those it includes.
+    fn write(&self, path: &std::path::Path, format: Format, compact: bool) -> anyhow::Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(format.serialize(self, compact)?.as_bytes())?;
+        let permissions = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.permissions(),
+            Err(_) => std::os::unix::fs::PermissionsExt::from_mode(0o644),
+        };
+        file.as_file().set_permissions(permissions)?;
+        file.as_file().sync_all()?;
+        file.persist(path)?;
+        Ok(())
+    }
+
+    /// Add `source` as `name`, which must not exist yet.
+    pub fn add(&mut self, name: String, source: Source) -> anyhow::Result<()> {
+        if self.sources.contains_key(&name) {
+            anyhow::bail!("source {} already exists", name);
+        }
+        self.sources.insert(name, source);
+        Ok(())
+    }
+
+    /// Remove the source `name`, and return it.
+    pub fn remove(&mut self, name: &str) -> anyhow::Result<Source> {
+        self.sources
+            .remove(name)
+            .with_context(|| format!("source {} does not exist", name))
+    }
+
+    /// Rename the source `old` to `new`, which must not exist yet. It stays in the file it came
+    /// from.
+    pub fn rename(&mut self, old: &str, new: String) -> anyhow::Result<()> {
+        if self.sources.contains_key(&new) {
+            anyhow::bail!("source {} already exists", new);
+        }
+        let source = self.remove(old)?;
+        for included in &mut self.included {
+            if included.names.remove(old) {
+                included.names.insert(new.clone());
+            }
+        }
+        self.sources.insert(new, source);
+        Ok(())
+    }
+
+    /// Refresh the source `name` in place with [`refresh_source`].
+    pub fn refresh(
+        &mut self,
+        client: &http::Client,
+        name: &str,
+        options: RefreshOptions,
+    ) -> anyhow::Result<()> {
+        let source = self
+            .sources
+            .get_mut(name)
+            .with_context(|| format!("source {} does not exist", name))?;
+        *source = refresh_source(client, source, options)?;
+        Ok(())
+    }
+
+    /// Pick the name a new source called `name` should be inserted under, or `None` if it
+    /// shouldn't be added.
+    pub fn resolve_collision(
+        &self,
+        name: &str,
+        mode: OnCollision,
+    ) -> anyhow::Result<Option<String>> {
+        if !self.sources.contains_key(name) {
+            return Ok(Some(name.to_owned()));
+        }
+        match mode {
+            OnCollision::Error => anyhow::bail!("source {} already exists", name),
+            OnCollision::Skip => {
+                log::warn!("skipping {}: source already exists", name);
+                Ok(None)
+            }
+            OnCollision::Suffix => {
+                let new_name = (2..)
+                    .map(|i| format!("{name}-{i}"))
+                    .find(|n| !self.sources.contains_key(n))
+                    .unwrap();
+                log::info!("{} already exists, renaming to {}", name, new_name);
+                Ok(Some(new_name))
+            }
+        }
+    }
+}
+
+/// The directory next to `sources_file` that nix-source keeps what it knows about the sources
+/// in, besides the sources file itself.
+pub fn state_dir(sources_file: &std::path::Path) -> std::path::PathBuf {
+    sources_file
+        .parent()
+        .unwrap_or(std::path::Path::new(""))
+        .join(".nix-source")
+}
+
+/// Turn `name` into a valid store path name, replacing disallowed characters with `_`. Names
+/// with nothing meaningful left, like `..` or `@@@`, become `source`.
+pub fn sanitize_file_name(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        let c = match c {
+            '0'..='9' | 'a'..='z' | 'A'..='Z' | '+' | '-' | '.' | '_' | '?' | '=' => c,
+            _ => '_',
+        };
+        if !(c == '_' && out.ends_with('_')) {
+            out.push(c);
+        }
+    }
+    // Store path names can't start with a dot.
+    let out = out.trim_start_matches(['.', '_']);
+    if !out.contains(|c: char| c.is_ascii_alphanumeric()) {
+        return "source".to_string();
+    }
+    out.to_string()
+}
+
+/// Convert a `hash_type` hash in any format nix understands, e.g. base32 as printed by
+/// `nix-prefetch-url`, to SRI. The type has to be given, since base16 and base32 hashes don't say
+/// which they are.
+pub fn to_sri(hash: &std::ffi::OsStr, hash_type: HashType) -> anyhow::Result<ssri::Integrity> {
+    let output = std::process::Command::new("nix")
+        .args(["hash", "to-sri", "--type", &hash_type.to_string()])
+        .arg(hash)
+        .output()
+        .context("failed to run nix hash to-sri")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "nix hash to-sri failed for {}: {}",
+            hash.to_string_lossy(),
+            output.status
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().parse()?)
+}
+
+/// Decompress `reader` and hash the resulting bytes.
+fn hash_decompressed(
+    reader: impl std::io::Read,
+    hash_type: HashType,
+) -> anyhow::Result<(ssri::Integrity, Compression)> {
+    let mut reader = std::io::BufReader::new(reader);
+    let compression = Compression::detect(reader.fill_buf()?)
+        .context("source is not gzip, xz or zstd compressed")?;
+    let mut hasher = ssri::IntegrityOpts::new().algorithm(hash_type.algorithm());
+    match compression {
+        Compression::Gzip => {
+            std::io::copy(
+                &mut flate2::bufread::MultiGzDecoder::new(reader),
+                &mut hasher,
+            )?;
+        }
+        Compression::Xz => {
+            lzma_rs::xz_decompress(&mut reader, &mut hasher)?;
+        }
+        Compression::Zstd => {
+            std::io::copy(
+                &mut ruzstd::decoding::StreamingDecoder::new(reader)?,
+                &mut hasher,
+            )?;
+        }
+    }
+    Ok((hasher.result(), compression))
+}
+
+/// Check `file` against the detached signature described by `signature` using gpg, or signify.
+fn verify_signature(
+    client: &http::Client,
+    file: &std::path::Path,
+    signature: &Signature,
+) -> anyhow::Result<()> {
+    let sig = client.download(&signature.url, "signature", |req| req)?;
+    if signature.signify {
+        // signify wants the public key in a file, comment included.
+        let mut key = tempfile::NamedTempFile::new()?;
+        writeln!(key, "untrusted comment: signify public key")?;
+        writeln!(key, "{}", signature.key)?;
+        key.flush()?;
+        let status = std::process::Command::new("signify")
+            .args(["-V", "-q", "-p"])
+            .arg(key.path())
+            .arg("-x")
+            .arg(&sig.path)
+            .arg("-m")
+            .arg(file)
+            .status()
+            .context("failed to run signify")?;
+        if !status.success() {
+            anyhow::bail!(
+                "signature {} is not a valid signature by {}",
+                signature.url,
+                signature.key
+            );
+        }
+        return Ok(());
+    }
+    let output = std::process::Command::new("gpg")
+        .args(["--batch", "--status-fd", "1", "--verify"])
+        .arg(&sig.path)
+        .arg(file)
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .context("failed to run gpg")?;
+    let key = signature
+        .key
+        .trim_start_matches("0x")
+        .replace(' ', "")
+        .to_uppercase();
+    // VALIDSIG <fingerprint> ... <primary key fingerprint>
+    let valid = String::from_utf8_lossy(&output.stdout).lines().any(|line| {
+        line.strip_prefix("[GNUPG:] VALIDSIG ").is_some_and(|rest| {
+            let fields: Vec<_> = rest.split(' ').collect();
+            fields.first().is_some_and(|f| f.ends_with(&key))
+                || fields.last().is_some_and(|f| f.ends_with(&key))
+        })
+    });
+    if !output.status.success() || !valid {
+        anyhow::bail!(
+            "signature {} is not a valid signature by {}",
+            signature.url,
+            signature.key
+        );
+    }
+    Ok(())
+}
+
+/// Check `file`, named `filename`, against its entry in the checksum file at `checksums`.
+///
+/// Both the `HASH  NAME` lines of `sha256sum` and the `SHA256 (NAME) = HASH` lines of BSD tools
+/// are understood. The algorithm is told by the length of the hash, sha256 or sha512.
+fn verify_checksum(
+    client: &http::Client,
+    file: &std::path::Path,
+    filename: Option<&str>,
+    checksums: &url::Url,
+) -> anyhow::Result<()> {
+    let filename = filename.context("the source has no file name to look up checksums with")?;
+    let list = client.download(checksums, "checksums", |req| req)?;
+    let list = std::fs::read_to_string(&list.path)
+        .with_context(|| format!("{} is not a checksum file", checksums))?;
+    let expected = list
+        .lines()
+        .find_map(|line| {
+            let line = line.trim();
+            if let Some((name, hash)) = line
+                .split_once(" (")
+                .and_then(|(_, rest)| rest.rsplit_once(") = "))
+            {
+                return (name == filename).then_some(hash);
+            }
+            let (hash, name) = line.split_once(char::is_whitespace)?;
+            // sha256sum marks files hashed in binary mode with a `*`.
+            let name = name.trim_start().trim_start_matches('*');
+            (name == filename).then_some(hash)
+        })
+        .with_context(|| format!("{} has no entry for {}", checksums, filename))?
+        .to_lowercase();
+    let hash_type = match expected.len() {
+        64 => HashType::Sha256,
+        128 => HashType::Sha512,
+        _ => anyhow::bail!("invalid hash for {} in {}", filename, checksums),
+    };
+    let mut hasher = ssri::IntegrityOpts::new().algorithm(hash_type.algorithm());
+    std::io::copy(&mut std::fs::File::open(file)?, &mut hasher)?;
+    let (_, actual) = hasher.result().to_hex();
+    if actual != expected {
+        anyhow::bail!(
+            "{} has {} hash {}, but {} says {}",
+            filename,
+            hash_type,
+            actual,
+            checksums,
+            expected
+        );
+    }
+    Ok(())
+}
+
+/// Guess whether a file is an archive from its Content-Type.
+fn is_archive_content_type(content_type: &str) -> bool {
+    matches!(
+        content_type,
+        "application/zip"
+            | "application/gzip"
+            | "application/x-gzip"
+            | "application/x-tar"
+            | "application/x-gtar"
+            | "application/x-compressed-tar"
+            | "application/x-bzip2"
+            | "application/x-xz"
+            | "application/zstd"
+    )
+}
+
+/// Name of `source` in the nix store, given the name of the file it is served as.
+pub fn store_name(source: &Source, filename: Option<&str>) -> String {
+    source.store_name.clone().unwrap_or_else(|| {
+        filename
+            .map(sanitize_file_name)
+            .unwrap_or("source".to_owned())
+    })
+}
+
+/// Where the fetchers of nix and nixpkgs put `source` hashed as `hash` in the store, given its
+/// type and the name of the file it is served as. This is the path binary caches have it at.
+pub fn fetched_store_path(
+    source: &Source,
+    hash: &ssri::Integrity,
+    ty: SourceType,
+    filename: Option<&str>,
+) -> std::path::PathBuf {
+    let recursive = matches!(ty, SourceType::Git | SourceType::Path)
+        || (!source.decompress && source.unpack.unwrap_or(ty.unpacks()));
+    // Only files and paths are named after their file name by default.
+    let name = match &source.store_name {
+        Some(name) => name.clone(),
+        None if recursive && ty != SourceType::Path => "source".to_owned(),
+        None => store_name(source, filename),
+    };
+    store::path(&name, hash, recursive)
+}
+
+/// The store paths `source` could be at with its current hash: the one it was last fetched
+/// to, and the one the fetchers of nix and nixpkgs put it at.
+pub fn store_paths(source: &Source) -> Vec<std::path::PathBuf> {
+    let Some(hash) = &source.hash else {
+        return Vec::new();
+    };
+    // Where pullImage puts an image depends on more than its hash.
+    if source.ty == Some(SourceType::Oci) {
+        return Vec::new();
+    }
+    let url = source.resolved_url.as_ref().unwrap_or(&source.url);
+    let filename = url.path_segments().and_then(|mut s| s.next_back());
+    let ty = source.ty.unwrap_or_else(|| detect_type(filename, None));
+    let fetched = fetched_store_path(source, hash, ty, filename);
+    let mut paths: Vec<_> = source.store_path.iter().cloned().collect();
+    if !paths.contains(&fetched) {
+        paths.push(fetched);
+    }
+    paths
+}
+
+/// Parse `url`, which can also be the path to a local file.
+pub fn parse_source_url(url: &str) -> anyhow::Result<url::Url> {
+    match url.parse() {
+        Err(url::ParseError::RelativeUrlWithoutBase) => {
+            let path = std::fs::canonicalize(url)
+                .with_context(|| format!("{} is neither a url nor an existing file", url))?;
+            url::Url::from_file_path(&path)
+                .map_err(|_| anyhow::anyhow!("invalid path {}", path.display()))
+        }
+        url => Ok(url?),
+    }
+}
+
+/// Substitute `version` for the `{version}` placeholder in `template`.
+pub fn expand_url_template(template: &str, version: &str) -> anyhow::Result<url::Url> {
+    if !template.contains("{version}") {
+        anyhow::bail!("url template {} has no {{version}} placeholder", template);
+    }
+    Ok(template.replace("{version}", version).parse()?)
+}
+
+/// The commit at the tip of the branch `source` follows and the url of its tarball, if it
+/// follows a branch.
+pub fn branch_head(
+    client: &http::Client,
+    source: &Source,
+) -> anyhow::Result<Option<(String, url::Url)>> {
+    Ok(
+        match (&source.github_branch, &source.repository, &source.branch) {
+            (Some(branch), _, _) => Some(branch.head(client)?),
+            (None, Some(repository), Some(branch)) => {
+                Some(repository.forge().branch_head(client, branch)?)
+            }
+            _ => None,
+        },
+    )
+}
+
+/// The version and url of the latest release of `source`, if it follows the releases of a
+/// repository or package.
+pub fn latest_release(
+    client: &http::Client,
+    source: &Source,
+) -> anyhow::Result<Option<(String, url::Url)>> {
+    Ok(
+        match (&source.github, &source.repository, &source.package) {
+            (Some(github), _, _) => Some(github.latest(client)?),
+            (None, Some(repository), _) => {
+                let forge = repository.forge();
+                let tag = forge.latest_release(client)?;
+                let url = forge.archive_url(&tag)?;
+                Some((tag, url))
+            }
+            (None, None, Some(package)) => Some(match source.ty {
+                Some(SourceType::Pypi) => pypi::latest(client, package)?,
+                Some(SourceType::Crate) => crates::latest(client, package)?,
+                _ => anyhow::bail!("only pypi and crate sources can follow a package"),
+            }),
+            (None, None, None) if source.ty == Some(SourceType::GithubRelease) => {
+                anyhow::bail!("github-release sources need a repository and an asset pattern")
+            }
+            (None, None, None) => None,
+        },
+    )
+}
+
+/// What changed going from `old` to `new`, as the forge tells: the notes of the release the
+/// source moved to, or the commits its branch moved by.
+pub fn release_notes(
+    client: &http::Client,
+    old: &Source,
+    new: &Source,
+) -> anyhow::Result<Option<String>> {
+    if let (Some(branch), Some(old), Some(new)) = (&new.github_branch, &old.rev, &new.rev) {
+        if old == new {
+            return Ok(None);
+        }
+        return branch.log(client, old, new);
+    }
+    let Some(tag) = new
+        .version
+        .as_ref()
+        .filter(|&tag| old.version.as_ref() != Some(tag))
+    else {
+        return Ok(None);
+    };
+    match (&new.github, &new.repository) {
+        (Some(github), _) => github::release_notes(client, &github.owner, &github.repo, tag),
+        (None, Some(repository)) => repository.forge().release_notes(client, tag),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Fetch `source` again, following its release, branch or package if it has one, and return it
+/// with its new url, hash and validators. It isn't hashed again if the server says it hasn't
+/// changed, unless `options` say otherwise.
+pub fn refresh_source(
+    client: &http::Client,
+    source: &Source,
+    options: RefreshOptions,
+) -> anyhow::Result<Source> {
+    options.report(Stage::Checking);
+    if let Some((rev, url)) = branch_head(client, source)? {
+        if !options.force && source.hash.is_some() && source.rev.as_ref() == Some(&rev) {
+            log::debug!("\tnot modified");
+            return Ok(source.clone());
+        }
+        log::debug!(
+            "\tnew commit {} -> {}",
+            source.rev.as_deref().unwrap_or("unknown"),
+            rev
+        );
+        let source = Source {
+            url,
+            rev: Some(rev),
+            hash: None,
+            etag: None,
+            last_modified: None,
+            ..source.clone()
+        };
+        return refresh_url(client, &source, options);
+    }
+    let released;
+    let source = if let Some((tag, url)) = latest_release(client, source)? {
+        if url == source.url {
+            source
+        } else {
+            log::debug!(
+                "\tnew release {} -> {}",
+                source.version.as_deref().unwrap_or("unknown"),
+                tag
+            );
+            released = Source {
+                url,
+                version: Some(tag),
+                // The old validators and hash are for a different file.
+                hash: None,
+                etag: None,
+                last_modified: None,
+                ..source.clone()
+            };
+            &released
+        }
+    } else {
+        source
+    };
+    let templated;
+    let source = if let (Some(template), Some(version)) = (&source.url_template, &source.version) {
+        templated = Source {
+            url: expand_url_template(template, version)?,
+            ..source.clone()
+        };
+        &templated
+    } else {
+        source
+    };
+    let mut result = refresh_url(client, source, options).map(|fetched| Source {
+        fetched_from: None,
+        ..fetched
+    });
+    for mirror in &source.mirrors {
+        let Err(e) = &result else { break };
+        log::warn!("failed to fetch {}: {:#}, trying {}", source.url, e, mirror);
+        // The validators are for the primary url, and the mirror must serve what it did.
+        let fetched = refresh_url(
+            client,
+            &Source {
+                url: mirror.clone(),
+                etag: None,
+                last_modified: None,
+                ..source.clone()
+            },
+            RefreshOptions {
+                force: true,
+                hash_type: Some(options.hash_type_for(source)),
+                ..options
+            },
+        );
+        result = fetched.and_then(|fetched| {
+            if source.hash.is_some() && fetched.hash != source.hash {
+                anyhow::bail!(
+                    "{} does not serve the same content as {}",
+                    mirror,
+                    source.url
+                );
+            }
+            log::debug!("\tfetched from {}", mirror);
+            Ok(Source {
+                hash: fetched.hash,
+                ty: fetched.ty,
+                compression: fetched.compression,
+                size: fetched.size,
+                rev: fetched.rev,
+                fetched_from: Some(mirror.clone()),
+                ..source.clone()
+            })
+        });
+    }
+    if source.ty == Some(SourceType::Channel) {
+        result = result.map(|mut source| {
+            // Channels redirect to e.g. .../nixos-24.05.1234.abcdef/nixexprs.tar.xz, the
+            // release is named by the parent directory.
+            source.version = source
+                .resolved_url
+                .as_ref()
+                .and_then(|url| url.path_segments()?.rev().nth(1).map(str::to_owned))
+                .or(source.version);
+            source
+        });
+    }
+    result
+}
+
+/// Guess the type of a source from its file name, or its content type if the name has no
+/// extension.
+fn detect_type(filename: Option<&str>, content_type: Option<&str>) -> SourceType {
+    let archive_content_type = content_type.is_some_and(is_archive_content_type);
+    if let Some(filename) = filename {
+        let filename = std::path::Path::new(filename);
+        let ext = filename.extension().unwrap_or_default();
+        let stem = std::path::Path::new(filename.file_stem().unwrap_or_default());
+        let ext2 = stem.extension().unwrap_or_default();
+        if ext == "zip" || ext == "tgz" || ext2 == "tar" || (ext.is_empty() && archive_content_type)
+        {
+            SourceType::Tarball
+        } else {
+            SourceType::File
+        }
+    } else if archive_content_type {
+        SourceType::Tarball
+    } else {
+        SourceType::File
+    }
+}
+
+/// Refresh a source in a local file, hashing it without any network access.
+fn refresh_local(
+    client: &http::Client,
+    source: &Source,
+    options: RefreshOptions,
+) -> anyhow::Result<Source> {
+    let path = source
+        .url
+        .to_file_path()
+        .map_err(|_| anyhow::anyhow!("{} is not a local path", source.url))?;
+    let metadata =
+        std::fs::metadata(&path).with_context(|| format!("{} does not exist", path.display()))?;
+    let last_modified = metadata
+        .modified()
+        .ok()
+        .map(|time| DateTime::<Utc>::from(time).fixed_offset());
+    let filename = path.file_name().map(|s| s.to_string_lossy().into_owned());
+    let ty = match source.ty {
+        Some(ty) => ty,
+        None if metadata.is_dir() => SourceType::Path,
+        None => detect_type(filename.as_deref(), None),
+    };
+    if metadata.is_dir() && ty != SourceType::Path {
+        anyhow::bail!(
+            "{} is a directory, which only path sources can be",
+            path.display()
+        );
+    }
+    let store_name = store_name(source, filename.as_deref());
+    let hash_type = options.hash_type_for(source);
+    if ty == SourceType::Path {
+        options.report(Stage::Hashing);
+        let hash = nar::hash_path(&path, hash_type)?;
+        log::debug!("\t{}", hash);
+        // The modification time of a directory doesn't change with what is deep in it.
+        let (last_modified, size) = match metadata.is_dir() {
+            true => (None, None),
+            false => (last_modified, Some(metadata.len())),
+        };
+        return Ok(Source {
+            store_path: Some(store::path(&store_name, &hash, true)),
+            hash: Some(hash),
+            last_modified,
+            etag: None,
+            ty: Some(ty),
+            size,
+            ..source.clone()
+        });
+    }
+    if let Some(signature) = &source.signature {
+        verify_signature(client, &path, signature)?;
+        log::debug!("\tsignature verified");
+    }
+    if let Some(checksums) = &source.checksums {
+        verify_checksum(client, &path, filename.as_deref(), checksums)?;
+        log::debug!("\tchecksum verified");
+    }
+    options.report(Stage::Hashing);
+    let (hash, compression) = if source.decompress {
+        let (hash, compression) = hash_decompressed(std::fs::File::open(&path)?, hash_type)?;
+        (hash, Some(compression))
+    } else {
+        let unpack = source.unpack.unwrap_or(ty.unpacks());
+        if source.subdir.is_some() && !unpack {
+            anyhow::bail!("only unpacked and git sources can be reduced to a subdirectory");
+        }
+        let hash = if unpack && (source.strip_root == Some(false) || source.subdir.is_some()) {
+            prefetch::hash_unpacked(
+                client,
+                &source.url,
+                hash_type,
+                source.strip_root != Some(false),
+                source.subdir.as_deref(),
+            )?
+        } else {
+            options
+                .prefetcher
+                .prefetch(client, &source.url, &store_name, unpack, hash_type)?
+        };
+        (hash, None)
+    };
+    log::debug!("\t{}", hash);
+    let unpacked = !source.decompress && source.unpack.unwrap_or(ty.unpacks());
+    Ok(Source {
+        store_path: Some(store::path(&store_name, &hash, unpacked)),
+        hash: Some(hash),
+        last_modified,
+        etag: None,
+        ty: Some(ty),
+        compression,
+        size: Some(metadata.len()),
+        ..source.clone()
+    })
+}
+
+/// Headers asking the server to only send `source` if it changed since it was last fetched.
+fn conditional_headers(source: &Source) -> Vec<(&'static str, String)> {
+    let mut headers = Vec::new();
+    if let Some(etag) = &source.etag {
+        headers.push(("If-None-Match", etag.clone()));
+    }
+    if let Some(last_modified) = &source.last_modified {
+        // HTTP dates are always in GMT.
+        let time = last_modified
+            .with_timezone(&Utc)
+            .format("%a, %d %b %Y %H:%M:%S GMT");
+        headers.push(("If-Modified-Since", time.to_string()));
+    }
+    headers
+}
+
+/// Send a HEAD request for `url`, falling back to GET for servers that reject HEAD.
+fn head(
+    client: &http::Client,
+    url: &url::Url,
+    prepare: impl Fn(ureq::Request) -> ureq::Request,
+) -> anyhow::Result<(ureq::Response, Vec<url::Url>)> {
+    match client.call("HEAD", url, &prepare) {
+        // Some servers reject HEAD but serve GET just fine, answering it with one of these. Other
+        // errors, 404 in particular, would be the same for GET. Only the headers of the GET
+        // response are used, the body is dropped unread.
+        Err(e)
+            if matches!(
+                e.downcast_ref(),
+                Some(ureq::Error::Status(403 | 405 | 501, _))
+            ) =>
+        {
+            log::debug!("HEAD rejected, retrying with GET");
+            client.call("GET", url, prepare)
+        }
+        res => res,
+    }
+}
+
+/// How requests for a source are made: to the url of its bucket object if it is one, with its
+/// custom headers, the token of its forge and a signature.
+struct SourceRequest {
+    url: url::Url,
+    headers: Vec<(String, String)>,
+    auth: Option<forge::Auth>,
+    object: Option<bucket::Object>,
+}
+
+impl SourceRequest {
+    fn of(source: &Source) -> anyhow::Result<Self> {
+        let object = bucket::Object::of(&source.url)?;
+        Ok(Self {
+            url: object
+                .as_ref()
+                .map_or(&source.url, |object| &object.url)
+                .clone(),
+            headers: source.request_headers()?,
+            auth: source.forge_auth()?,
+            object,
+        })
+    }
+
+    /// Add what the source is requested with to `req`, a request for `self.url` or a hop of
+    /// its redirects.
+    fn prepare(&self, req: ureq::Request) -> ureq::Request {
+        let req = match &self.auth {
+            Some(auth) => auth.apply(req),
+            None => req,
+        };
+        // The headers and signature are for where the source is, not wherever it redirects to,
+        // which they could leak credentials to.
+        let origin = url::Url::parse(req.url()).map(|hop| hop.origin());
+        if origin.ok() != Some(self.url.origin()) {
+            return req;
+        }
+        let req = self
+            .headers
+            .iter()
+            .fold(req, |req, (name, value)| req.set(name, value));
+        match &self.object {
+            Some(object) => object.sign(req),
+            None => req,
+        }
+    }
+}
+
+/// Refresh `source` from its url only.
+fn refresh_url(
+    client: &http::Client,
+    source: &Source,
+    options: RefreshOptions,
+) -> anyhow::Result<Source> {
+    if source.ty == Some(SourceType::Git) {
+        return git::refresh(source, options.force, options.hash_type_for(source), client);
+    }
+    if source.ty == Some(SourceType::Oci) {
+        return oci::refresh(client, source, options.force, options.hash_type_for(source));
+    }
+    if source.url.scheme() == "file" {
+        return refresh_local(client, source, options);
+    }
+    if source.url.scheme() == "ipfs" {
+        return ipfs::refresh(client, source, options);
+    }
+    if source.ty == Some(SourceType::Path) {
+        anyhow::bail!(
+            "{} is not a local path, which path sources must be",
+            source.url
+        );
+    }
+    let request = SourceRequest::of(source)?;
+    let url = &request.url;
+    let with_headers = |req| request.prepare(req);
+    let conditional = if source.hash.is_none() || options.force {
+        Vec::new()
+    } else {
+        conditional_headers(source)
+    };
+    let prepare = |req: ureq::Request| {
+        conditional
+            .iter()
+            .fold(with_headers(req), |req, (name, value)| req.set(name, value))
+    };
+    let (res, redirects) = head(client, url, prepare)?;
+    let size = res
+        .header("Content-Length")
+        .and_then(|s| s.parse::<u64>().ok());
+    if res.status() == 304 {
+        log::debug!("\tnot modified");
+        if let (Some(old), Some(new)) = (source.size, size) {
+            if old != new {
+                log::warn!(
+                    "{} reported not modified, but its size changed from {} to {}",
+                    source.url,
+                    old,
+                    new
+                );
+            }
+        }
+        if options.revalidate {
+            log::debug!("\trevalidating");
+            let fresh = refresh_url(
+                client,
+                source,
+                RefreshOptions {
+                    force: true,
+                    revalidate: false,
+                    hash_type: Some(options.hash_type_for(source)),
+                    ..options
+                },
+            )?;
+            if fresh.hash != source.hash {
+                log::warn!(
+                    "{} reported not modified, but its content changed",
+                    source.url
+                );
+            }
+            return Ok(fresh);
+        }
+        return Ok(source.clone());
+    }
+    // Weak etags are kept too, If-None-Match uses weak comparison.
+    let etag = res.header("ETag").map(str::to_owned);
+    let last_modified = res
+        .header("Last-Modified")
+        .and_then(|s| DateTime::parse_from_rfc2822(s).ok());
+    let filename = res
+        .header("Content-Disposition")
+        .and_then(|s| {
+            mailparse::parse_content_disposition(s)
+                .params
+                .get("filename")
+                .map(|s| s.to_string())
+        })
+        .or_else(|| {
+            source
+                .url
+                .path_segments()
+                .into_iter()
+                .flatten()
+                .last()
+                .map(|s| s.to_string())
+        });
+    log::debug!("last modified: {last_modified:?}, etag: {etag:?}, file name: {filename:?}");
+    let resolved_url = redirects.last().cloned();
+    let fetch_url = resolved_url.as_ref().unwrap_or(url);
+    // A chain recorded by an earlier fetch would not be the one just followed.
+    let redirects = if options.record_redirects {
+        redirects
+    } else {
+        Vec::new()
+    };
+    let ty = source
+        .ty
+        .unwrap_or_else(|| detect_type(filename.as_deref(), Some(res.content_type())));
+    let store_name = store_name(source, filename.as_deref());
+    let hash_type = options.hash_type_for(source);
+    let unpack = source.unpack.unwrap_or(ty.unpacks());
+    // Only when the server couldn't tell whether it changed, else it did.
+    if let Some(hash) = source.hash.as_ref().filter(|hash| {
+        !options.substituters.is_empty()
+            && conditional.is_empty()
+            && HashType::of(hash) == Some(hash_type)
+    }) {
+        let path = fetched_store_path(source, hash, ty, filename.as_deref());
+        if let Some(cache) = store::find_substitute(client, options.substituters, &path) {
+            log::debug!("\t{} is in {}, not downloading", path.display(), cache);
+            return Ok(Source {
+                last_modified,
+                etag,
+                ty: Some(ty),
+                redirects,
+                resolved_url,
+                size,
+                ..source.clone()
+            });
+        }
+    }
+    // Download the artifact ourselves if it has to be verified, or the prefetcher can't fetch it
+    // because it needs custom headers, a token, signed requests, credentials or certificate
+    // authorities of our own. What is hashed is then exactly what was downloaded.
+    let downloaded = if source.signature.is_some()
+        || source.checksums.is_some()
+        || !request.headers.is_empty()
+        || request.auth.is_some()
+        || request.object.is_some()
+        || client.has_credentials(fetch_url)
+        || client.has_custom_tls()
+    {
+        options.report(Stage::Downloading);
+        let file = client.download(fetch_url, &store_name, with_headers)?;
+        if let Some(signature) = &source.signature {
+            verify_signature(client, &file.path, signature)?;
+            log::debug!("\tsignature verified");
+        }
+        if let Some(checksums) = &source.checksums {
+            verify_checksum(client, &file.path, filename.as_deref(), checksums)?;
+            log::debug!("\tchecksum verified");
+        }
+        Some(file)
+    } else {
+        None
+    };
+    // The prefetcher downloads the source as it hashes it, unless it was downloaded already.
+    options.report(match downloaded {
+        Some(_) => Stage::Hashing,
+        None => Stage::Downloading,
+    });
+    if source.decompress {
+        let (hash, compression) = if let Some(file) = &downloaded {
+            hash_decompressed(std::fs::File::open(&file.path)?, hash_type)?
+        } else {
+            hash_decompressed(client.get(fetch_url)?.into_reader(), hash_type)?
+        };
+        log::debug!("\t{}", hash);
+        return Ok(Source {
+            store_path: Some(store::path(&store_name, &hash, false)),
+            hash: Some(hash),
+            last_modified,
+            etag,
+            ty: Some(ty),
+            compression: Some(compression),
+            redirects,
+            resolved_url,
+            size,
+            ..source.clone()
+        });
+    }
+    let url = match &downloaded {
+        Some(file) => url::Url::from_file_path(&file.path)
+            .map_err(|_| anyhow::anyhow!("invalid download path"))?,
+        None => fetch_url.clone(),
+    };
+    if source.subdir.is_some() && !unpack {
+        anyhow::bail!("only unpacked and git sources can be reduced to a subdirectory");
+    }
+    let hash = if unpack && (source.strip_root == Some(false) || source.subdir.is_some()) {
+        prefetch::hash_unpacked(
+            client,
+            &url,
+            hash_type,
+            source.strip_root != Some(false),
+            source.subdir.as_deref(),
+        )?
+    } else {
+        options
+            .prefetcher
+            .prefetch(client, &url, &store_name, unpack, hash_type)?
+    };
+    log::debug!("\t{}", hash);
+    Ok(Source {
+        store_path: Some(store::path(&store_name, &hash, unpack)),
+        hash: Some(hash),
+        last_modified,
+        etag,
+        ty: Some(ty),
+        redirects,
+        resolved_url,
+        size,
+        ..source.clone()
+    })
+}
+
+/// Whether a source looks out of date, as far as can be told without fetching it.
+pub enum Freshness {
+    UpToDate,
+    Outdated(String),
+    Unknown(String),
+}
+
+/// Check whether `source` changed upstream with as little work as possible: a conditional
+/// request, a forge API call or a `git ls-remote`, but never fetching or hashing it.
+pub fn check_freshness(client: &http::Client, source: &Source) -> anyhow::Result<Freshness> {
+    if source.hash.is_none() {
+        return Ok(Freshness::Outdated("never fetched".to_owned()));
+    }
+    if let Some((rev, _)) = branch_head(client, source)? {
+        return Ok(if source.rev.as_ref() == Some(&rev) {
+            Freshness::UpToDate
+        } else {
+            Freshness::Outdated(format!("new commit {}", rev))
+        });
+    }
+    let latest = match &source.version_index {
+        Some(index) => Some(index.latest(client)?),
+        None => latest_release(client, source)?,
+    };
+    if let Some((version, url)) = latest {
+        return Ok(if url == source.url {
+            Freshness::UpToDate
+        } else {
+            Freshness::Outdated(format!("new version {}", version))
+        });
+    }
+    if source.ty == Some(SourceType::Git) {
+        let rev = git::resolve(&source.url, source.git_ref.as_deref(), client.timeout())?;
+        return Ok(if source.rev.as_ref() == Some(&rev) {
+            Freshness::UpToDate
+        } else {
+            Freshness::Outdated(format!("new revision {}", rev))
+        });
+    }
+    if source.ty == Some(SourceType::Oci) {
+        let digest = oci::resolve(client, &oci::Image::from_url(&source.url)?)?;
+        return Ok(if source.rev.as_ref() == Some(&digest) {
+            Freshness::UpToDate
+        } else {
+            Freshness::Outdated(format!("new digest {}", digest))
+        });
+    }
+    // What a CID is of never changes.
+    if source.url.scheme() == "ipfs" {
+        return Ok(Freshness::UpToDate);
+    }
+    if source.url.scheme() == "file" {
+        let path = source
+            .url
+            .to_file_path()
+            .map_err(|_| anyhow::anyhow!("{} is not a local path", source.url))?;
+        let metadata = std::fs::metadata(&path)
+            .with_context(|| format!("{} does not exist", path.display()))?;
+        if metadata.is_dir() {
+            return Ok(Freshness::Unknown(
+                "a directory, only hashing it can tell".to_owned(),
+            ));
+        }
+        let last_modified = metadata
+            .modified()
+            .ok()
+            .map(|time| DateTime::<Utc>::from(time).fixed_offset());
+        return Ok(
+            if last_modified == source.last_modified && Some(metadata.len()) == source.size {
+                Freshness::UpToDate
+            } else {
+                Freshness::Outdated("modified".to_owned())
+            },
+        );
+    }
+    if source.etag.is_none() && source.last_modified.is_none() {
+        return Ok(Freshness::Unknown(
+            "no validators, only fetching it can tell".to_owned(),
+        ));
+    }
+    let request = SourceRequest::of(source)?;
+    let conditional = conditional_headers(source);
+    let (res, _) = head(client, &request.url, |req| {
+        conditional
+            .iter()
+            .fold(request.prepare(req), |req, (name, value)| {
+                req.set(name, value)
+            })
+    })?;
+    // Not every server answers conditional requests, so compare the validators too.
+    let etag = res.header("ETag");
+    let last_modified = res
+        .header("Last-Modified")
+        .and_then(|s| DateTime::parse_from_rfc2822(s).ok());
+    let unchanged = res.status() == 304
+        || (source.etag.is_some() && etag == source.etag.as_deref())
+        || (source.etag.is_none() && last_modified == source.last_modified);
+    Ok(if unchanged {
+        Freshness::UpToDate
+    } else {
+        Freshness::Outdated("changed upstream".to_owned())
+    })
+}
@@ -1,338 +1,3310 @@
 use anyhow::Context;
-use argh::FromArgs;
-use chrono::{DateTime, FixedOffset};
+use argh::{ArgsInfo, FromArgs};
 use std::collections::HashMap;
-use std::io::{Seek, Write};
-use std::os::unix::ffi::OsStrExt;
+use std::io::{IsTerminal, Write};
 
-#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug, Eq, Clone, Copy)]
-#[serde(rename_all = "kebab-case")]
-enum SourceType {
-    Tarball,
-    File,
+use nix_source::*;
+
+/// State shared by all commands, derived from the global options.
+struct Global {
+    /// Path to the sources file.
+    sources: std::path::PathBuf,
+    /// Refuse to modify the sources file.
+    read_only: bool,
+    /// Write the sources file on a single line instead of pretty-printing it.
+    compact: bool,
+    /// Format of the sources file.
+    format: Format,
+    /// Hash algorithm to use when adding or updating sources.
+    hash_type: Option<HashType>,
+    /// Tool to prefetch sources with, the builtin one if not given.
+    prefetcher: Option<prefetch::Prefetcher>,
+    client: http::Client,
+    /// Don't lock the sources file.
+    no_lock: bool,
+    /// Fail instead of waiting if the sources file is locked.
+    no_wait: bool,
+    /// Forbid network access.
+    offline: bool,
+    /// Fail instead of modifying the sources file.
+    frozen: bool,
+    /// Replace a sources file that can't be parsed, after backing it up, instead of failing.
+    force: bool,
+    /// How commands print their results.
+    output: Output,
+    /// Highlight changes in what is printed on stdout.
+    color: bool,
+    /// Number of sources to work on concurrently, from the config file.
+    jobs: Option<usize>,
+    /// Hooks to run when updating without --pre-update or --post-update, from the config file.
+    pre_update: Option<String>,
+    post_update: Option<String>,
+    /// Keep garbage collector roots for the store paths of the sources whenever the sources file
+    /// is written, from the config file.
+    gc_roots: bool,
+    /// Binary caches to look sources up in with --substitute, from the config file.
+    substituters: Vec<url::Url>,
+    /// Gateways to fetch IPFS sources through, from the config file.
+    ipfs_gateways: Vec<url::Url>,
+    /// The lock file, once the lock is taken.
+    lock: std::sync::Mutex<Option<std::fs::File>>,
+}
+
+impl Global {
+    /// Fail if the sources file must not be modified.
+    fn ensure_writable(&self) -> anyhow::Result<()> {
+        if self.read_only {
+            anyhow::bail!("{} is read-only", self.sources.display());
+        }
+        Ok(())
+    }
+
+    /// Number of sources to work on concurrently, `jobs` if given on the command line.
+    fn jobs(&self, jobs: Option<usize>) -> usize {
+        jobs.or(self.jobs).unwrap_or(4)
+    }
+
+    /// Fail if network access is forbidden, before the command gets anywhere.
+    fn ensure_online(&self) -> anyhow::Result<()> {
+        if self.offline {
+            anyhow::bail!("this command needs network access, which --offline forbids");
+        }
+        Ok(())
+    }
+
+    /// Lock the sources file, exclusively if `exclusive` is set, blocking until the lock is
+    /// free. The lock is taken on a separate file, as the sources file itself is replaced when
+    /// written, and held until the process exits.
+    ///
+    /// Only exclusive locks create the lock file. Without one, no command is writing the
+    /// sources file, so readers go ahead without a lock, and don't need to be able to write
+    /// to its directory.
+    fn lock(&self, exclusive: bool) -> anyhow::Result<()> {
+        if self.no_lock {
+            return Ok(());
+        }
+        let mut lock = self.lock.lock().unwrap();
+        if lock.is_some() {
+            return Ok(());
+        }
+        let mut path = self.sources.clone().into_os_string();
+        path.push(".lock");
+        let path = std::path::PathBuf::from(path);
+        let file = if exclusive {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+        } else {
+            match std::fs::File::open(&path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                file => file,
+            }
+        }
+        .with_context(|| format!("failed to open lock file {}", path.display()))?;
+        let locked = if exclusive {
+            file.try_lock()
+        } else {
+            file.try_lock_shared()
+        };
+        match locked {
+            Ok(()) => (),
+            Err(std::fs::TryLockError::WouldBlock) if self.no_wait => {
+                anyhow::bail!("{} is locked by another process", self.sources.display());
+            }
+            Err(std::fs::TryLockError::WouldBlock) => {
+                log::info!("Waiting for the lock on {}", path.display());
+                if exclusive {
+                    file.lock()?
+                } else {
+                    file.lock_shared()?
+                }
+            }
+            Err(std::fs::TryLockError::Error(e)) => return Err(e.into()),
+        }
+        *lock = Some(file);
+        Ok(())
+    }
+
+    /// Read the sources file. If `write` is set, the caller intends to write it back.
+    fn load(&self, write: bool) -> anyhow::Result<Sources> {
+        if write {
+            self.ensure_writable()?;
+        }
+        self.lock(write)?;
+        let err = match Sources::load(&self.sources, self.format) {
+            Ok(sources) => return Ok(sources),
+            Err(err) => err,
+        };
+        // Only the file itself is replaced, not the files it includes, so it must be what
+        // doesn't parse.
+        let unparseable = std::fs::read_to_string(&self.sources)
+            .is_ok_and(|text| self.format.parse::<Sources>(&text).is_err());
+        if !write || !unparseable {
+            return Err(err);
+        }
+        if !self.force {
+            return Err(err.context(format!(
+                "{} can't be parsed, pass --force to back it up and start over",
+                self.sources.display()
+            )));
+        }
+        let backup = (1..)
+            .map(|i| match i {
+                1 => format!("{}.bak", self.sources.display()),
+                i => format!("{}.bak.{i}", self.sources.display()),
+            })
+            .map(std::path::PathBuf::from)
+            .find(|path| !path.exists())
+            .unwrap();
+        std::fs::copy(&self.sources, &backup)
+            .with_context(|| format!("failed to back up {}", self.sources.display()))?;
+        log::warn!(
+            "{:#}, starting over with it backed up to {}",
+            err,
+            backup.display()
+        );
+        Ok(Sources::new())
+    }
+
+    /// Like [`Global::load`], but a missing sources file is an empty set of sources.
+    fn load_or_default(&self, write: bool) -> anyhow::Result<Sources> {
+        if write {
+            self.ensure_writable()?;
+        }
+        // Lock first, so a file created concurrently isn't missed.
+        self.lock(write)?;
+        if !self.sources.exists() {
+            return Ok(Sources::new());
+        }
+        self.load(write)
+    }
+
+    /// Replace the sources file with `sources`.
+    fn write(&self, sources: &Sources) -> anyhow::Result<()> {
+        if self.frozen {
+            // Compared as values, so a file formatted differently doesn't count as modified.
+            let current = Sources::load(&self.sources, self.format)
+                .ok()
+                .map(serde_json::to_value)
+                .transpose()?;
+            if current != Some(serde_json::to_value(sources)?) {
+                anyhow::bail!(
+                    "{} would be modified, which --frozen forbids",
+                    self.sources.display()
+                );
+            }
+            return Ok(());
+        }
+        sources.save(&self.sources, self.format, self.compact)?;
+        if self.gc_roots {
+            store::sync_roots(&store::roots_dir(&self.sources), sources)?;
+        }
+        Ok(())
+    }
+}
+
+/// How commands print their results.
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Output {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for Output {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            _ => Err(anyhow::anyhow!(
+                "invalid output format, expected text or json"
+            )),
+        }
+    }
+}
+
+impl Global {
+    /// Whether results should be printed as JSON, because of `--output json` or the `--json`
+    /// switch of the command, `json`.
+    fn json(&self, json: bool) -> bool {
+        json || self.output == Output::Json
+    }
+
+    /// Only leave warnings and errors on stderr when printing JSON or showing progress, unless
+    /// more was explicitly asked for.
+    fn quiet(&self) {
+        if log::max_level() == log::LevelFilter::Info {
+            log::set_max_level(log::LevelFilter::Warn);
+        }
+    }
+}
+
+/// Print `value` as JSON on stdout.
+fn print_json(value: &impl serde::Serialize) -> anyhow::Result<()> {
+    serde_json::to_writer_pretty(std::io::stdout(), value)?;
+    println!();
+    Ok(())
+}
+
+trait Command {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()>;
+}
+
+/// add a source to the sources file
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "add")]
+struct AddCommand {
+    /// name of the source
+    #[argh(positional)]
+    name: String,
+    /// url of the source, which can be an s3:// or gs:// object or an ipfs:// CID, or path to a
+    /// local file, or a url template with --version. Not needed with --github
+    #[argh(positional)]
+    url: Option<String>,
+    /// follow the latest release, or a branch with --branch, of this GitHub repository, given
+    /// as OWNER/REPO
+    #[argh(option)]
+    github: Option<String>,
+    /// regex matching the name of the release asset to fetch, for --github
+    #[argh(option)]
+    asset: Option<String>,
+    /// follow the tip of this branch of the --github repository, or of the --forge one on
+    /// GitHub or GitLab, instead of its releases
+    #[argh(option)]
+    branch: Option<String>,
+    /// follow the releases of a repository on this forge, one of github, gitlab, gitea,
+    /// codeberg or sourcehut, through the tarballs of their tags. The url is then the path of
+    /// the repository, like OWNER/REPO
+    #[argh(option)]
+    forge: Option<forge::ForgeKind>,
+    /// url of the self-hosted instance the --forge repository is on
+    #[argh(option)]
+    instance: Option<url::Url>,
+    /// version of the source, the url is then a template in which {{version}} is replaced with it
+    #[argh(option)]
+    version: Option<String>,
+    /// url of a mirror to fall back to if the source can't be fetched from its url, can be
+    /// repeated
+    #[argh(option)]
+    mirror: Vec<url::Url>,
+    /// what the source is, for whoever reads the sources file
+    #[argh(option)]
+    description: Option<String>,
+    /// home page of the project the source comes from
+    #[argh(option)]
+    homepage: Option<url::Url>,
+    /// license of the source, preferably as an SPDX license expression
+    #[argh(option)]
+    license: Option<String>,
+    /// group to put the source in, to update or list it with the others in it
+    #[argh(option)]
+    group: Option<String>,
+    /// attribute path of the package in nixpkgs the source is a version of, for
+    /// compare-nixpkgs, the name of the source by default
+    #[argh(option)]
+    nixpkgs: Option<String>,
+    /// hash algorithm for the source, sha256 or sha512, instead of the one given by the global
+    /// --hash-type. The source keeps it when updated
+    #[argh(option)]
+    hash_type: Option<HashType>,
+    /// type of the source, either tarball, file, git, path for a local file or directory
+    /// hashed as nix adds it to the store, pypi or crate to follow the latest release of the
+    /// package named by the url on PyPI or crates.io, channel to follow the Nix channel named
+    /// by the url, oci to follow the tag of the container image named by the url, like
+    /// nginx:1.25, or github-release to follow the latest release of the GitHub repository
+    /// named by the url, like OWNER/REPO, fetching the asset matching --asset as a file
+    #[argh(option, short = 't', long = "type")]
+    ty: Option<SourceType>,
+    /// branch, tag or revision to track, for git sources
+    #[argh(option)]
+    rev: Option<String>,
+    /// check out the submodules of a git source too, like fetchSubmodules of fetchgit
+    #[argh(switch)]
+    submodules: bool,
+    /// clone the whole history of a git source and keep its .git directory, like deepClone of
+    /// fetchgit, instead of a shallow checkout of the revision
+    #[argh(switch)]
+    deep_clone: bool,
+    /// name of the source in the nix store, derived from its file name by default
+    #[argh(option)]
+    store_name: Option<String>,
+    /// extra header to send when fetching the source, as NAME:VALUE. ${{VAR}} in the value is
+    /// replaced with the environment variable VAR at request time
+    #[argh(option)]
+    header: Vec<String>,
+    /// hash the decompressed stream instead of the file as downloaded
+    #[argh(switch)]
+    decompress: bool,
+    /// what to do if the name is taken: error, skip adding the source, or suffix the name with
+    /// -2, -3 and so on until it is free
+    #[argh(option, default = "OnCollision::Error")]
+    on_collision: OnCollision,
+    /// if the name is taken, refresh the existing source if it has the same url, or replace it
+    /// otherwise
+    #[argh(switch)]
+    update_if_exists: bool,
+    /// record the redirects followed when fetching the source
+    #[argh(switch)]
+    record_redirects: bool,
+    /// url of a detached signature to verify the source against
+    #[argh(option)]
+    verify_sig: Option<url::Url>,
+    /// key the signature must be made with, required with --verify-sig
+    #[argh(option)]
+    key: Option<String>,
+    /// the signature is made with signify, and --key is the public key, instead of gpg
+    #[argh(switch)]
+    signify: bool,
+    /// url of a checksum file like SHA256SUMS with an entry for the source, to check it against
+    #[argh(option)]
+    checksums: Option<url::Url>,
+    /// always unpack the source before hashing, regardless of its type
+    #[argh(switch)]
+    unpack: bool,
+    /// never unpack the source before hashing, regardless of its type
+    #[argh(switch)]
+    no_unpack: bool,
+    /// keep the top-level directory of an unpacked tarball, instead of making its only
+    /// directory the root of the source
+    #[argh(switch)]
+    no_strip_root: bool,
+    /// only fetch and hash this directory of the source, like packages/foo of a monorepo. An
+    /// unpacked tarball is reduced to it, while git sources are checked out sparsely
+    #[argh(option, from_str_fn(parse_subdir))]
+    subdir: Option<String>,
+    /// treat the url as a directory listing, and pick the latest file whose name matches this
+    /// regex, with the version captured by the first group
+    #[argh(option)]
+    version_regex: Option<String>,
+    /// directory listing to find versions in with --version-regex, instead of the url. The url
+    /// is then a template in which {{version}} is replaced with the latest version
+    #[argh(option)]
+    listing: Option<url::Url>,
+    /// shell command to run before the source is updated, which skips the update if it fails
+    #[argh(option)]
+    pre_update: Option<String>,
+    /// shell command to run after the source changed, with the sources file updated. The
+    /// source is put back as it was if it fails
+    #[argh(option)]
+    post_update: Option<String>,
+    /// nix installable to build after the source changed, like .#package, with the sources
+    /// file updated. The source is put back as it was if the build fails
+    #[argh(option)]
+    test_build: Option<String>,
+}
+
+/// Parse the path of a directory in a source, which must stay inside of it.
+fn parse_subdir(s: &str) -> Result<String, String> {
+    let path = s.trim_matches('/');
+    if path.is_empty() || path.split('/').any(|c| matches!(c, "" | "." | "..")) {
+        return Err(format!(
+            "invalid directory {s}, expected a path like packages/foo"
+        ));
+    }
+    Ok(path.to_owned())
+}
+
+/// Pick the tool used to prefetch sources, and make sure it is available.
+fn check_nix_tools(ctx: &Global) -> anyhow::Result<prefetch::Prefetcher> {
+    let prefetcher = ctx.prefetcher.unwrap_or_default();
+    prefetcher.check()?;
+    Ok(prefetcher)
+}
+
+/// Fail if `store_name` isn't a name nix accepts for store paths, as every prefetch would.
+fn check_store_name(store_name: &str) -> anyhow::Result<()> {
+    if sanitize_file_name(store_name) != store_name {
+        anyhow::bail!("{} is not a valid store name", store_name);
+    }
+    Ok(())
+}
+
+impl Command for AddCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        ctx.ensure_online()?;
+        ctx.ensure_writable()?;
+        let prefetcher = check_nix_tools(ctx)?;
+        let signature = match (self.verify_sig, self.key) {
+            (Some(url), Some(key)) => Some(Signature {
+                url,
+                key,
+                signify: self.signify,
+            }),
+            (None, None) if !self.signify => None,
+            _ => anyhow::bail!(
+                "--verify-sig and --key must be used together, and --signify with them"
+            ),
+        };
+        if let Some(store_name) = &self.store_name {
+            check_store_name(store_name)?;
+        }
+        let headers = self
+            .header
+            .iter()
+            .map(|header| {
+                let (name, value) = header
+                    .split_once(':')
+                    .with_context(|| format!("invalid header {}, expected NAME:VALUE", header))?;
+                Ok((name.trim().to_owned(), value.trim().to_owned()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        if self.rev.is_some() && self.ty != Some(SourceType::Git) {
+            anyhow::bail!("--rev can only be used with git sources");
+        }
+        if (self.submodules || self.deep_clone) && self.ty != Some(SourceType::Git) {
+            anyhow::bail!("--submodules and --deep-clone can only be used with git sources");
+        }
+        if self.version.is_some() && self.version_regex.is_some() {
+            anyhow::bail!("--version and --version-regex are mutually exclusive");
+        }
+        if self.listing.is_some() && self.version_regex.is_none() {
+            anyhow::bail!("--listing can only be used with --version-regex");
+        }
+        // The url of a github-release source is the repository, like --github takes.
+        let (github_arg, url_arg) = match self.ty {
+            Some(SourceType::GithubRelease) if self.github.is_some() => {
+                anyhow::bail!("the repository of a github-release source is its url, not --github")
+            }
+            Some(SourceType::GithubRelease) if self.asset.is_none() => {
+                anyhow::bail!("github-release sources need --asset")
+            }
+            Some(SourceType::GithubRelease) => {
+                (Some(self.url.context("the repository is required")?), None)
+            }
+            _ => (self.github, self.url),
+        };
+        let (github_branch_arg, forge_branch) = match self.forge {
+            Some(_) => (None, self.branch),
+            None => (self.branch, None),
+        };
+        let (github, github_branch) = match (github_arg, self.asset, github_branch_arg) {
+            (Some(repo), asset, branch) => {
+                let (owner, repo) = repo
+                    .split_once('/')
+                    .with_context(|| format!("invalid repository {}, expected OWNER/REPO", repo))?;
+                let (owner, repo) = (owner.to_owned(), repo.to_owned());
+                match (asset, branch) {
+                    (Some(asset_pattern), None) => {
+                        regex::Regex::new(&asset_pattern)?;
+                        let release = github::GithubRelease {
+                            owner,
+                            repo,
+                            asset_pattern,
+                        };
+                        (Some(release), None)
+                    }
+                    (None, Some(branch)) => {
+                        let branch = github::GithubBranch {
+                            owner,
+                            repo,
+                            branch,
+                        };
+                        (None, Some(branch))
+                    }
+                    (None, None) => anyhow::bail!("--github needs either --asset or --branch"),
+                    (Some(_), Some(_)) => {
+                        anyhow::bail!("--asset and --branch are mutually exclusive")
+                    }
+                }
+            }
+            (None, None, None) => (None, None),
+            _ => anyhow::bail!("--asset and --branch can only be used with --github"),
+        };
+        let (source_url, repository) = match (self.forge, self.instance) {
+            (Some(_), _) if github.is_some() || github_branch.is_some() => {
+                anyhow::bail!("--forge and --github are mutually exclusive")
+            }
+            (Some(_), _) if self.version.is_some() => {
+                anyhow::bail!("--version can't be used with --forge")
+            }
+            (Some(kind), instance) => {
+                let path = url_arg.context("the repository is required with --forge")?;
+                let repository = forge::Repository::new(kind, &path, instance)?;
+                // Replaced with the url of the tarball of the latest release when the source
+                // is refreshed.
+                (
+                    Some(repository.forge().url()?.to_string()),
+                    Some(repository),
+                )
+            }
+            (None, Some(_)) => anyhow::bail!("--instance can only be used with --forge"),
+            (None, None) => (url_arg, None),
+        };
+        let (source_url, package) = match self.ty {
+            Some(ty @ (SourceType::Pypi | SourceType::Crate)) => {
+                let package = source_url.context("the package name is required")?;
+                // Replaced with the url of the latest release when the source is refreshed.
+                let url = match ty {
+                    SourceType::Pypi => format!("https://pypi.org/project/{}/", package),
+                    _ => format!("https://crates.io/crates/{}", package),
+                };
+                (Some(url), Some(package))
+            }
+            Some(SourceType::Channel) => {
+                let channel = source_url.context("the channel name is required")?;
+                (Some(nixpkgs::channel_url(&channel)), None)
+            }
+            Some(SourceType::Oci) => {
+                let image = source_url.context("the image is required")?;
+                (Some(oci::Image::parse(&image)?.url()?.to_string()), None)
+            }
+            _ => (source_url, None),
+        };
+        let github_repo = github
+            .as_ref()
+            .map(|g| (&g.owner, &g.repo))
+            .or(github_branch.as_ref().map(|b| (&b.owner, &b.repo)));
+        let (url, url_template) = match (source_url, &self.version, github_repo) {
+            (Some(_), _, Some(_)) => anyhow::bail!("a url can't be given with --github"),
+            (_, Some(_), Some(_)) => anyhow::bail!("--version can't be used with --github"),
+            // Replaced with the url of the release asset or tarball when the source is refreshed.
+            (None, _, Some((owner, repo))) => {
+                let url = format!("https://github.com/{}/{}", owner, repo);
+                (url.parse()?, None)
+            }
+            (None, _, None) => anyhow::bail!("a url is required"),
+            (Some(url), Some(version), None) => (expand_url_template(&url, version)?, Some(url)),
+            // Replaced with the template filled with the latest version in the listing below.
+            (Some(url), None, None) if self.listing.is_some() => {
+                (self.listing.clone().unwrap(), Some(url))
+            }
+            (Some(url), None, None) if url.contains("{version}") => anyhow::bail!(
+                "{} is a url template, which needs --version, or --listing to find one",
+                url
+            ),
+            (Some(url), None, None) => (parse_source_url(&url)?, None),
+        };
+        let unpack = match (self.unpack, self.no_unpack) {
+            (true, true) => anyhow::bail!("--unpack and --no-unpack are mutually exclusive"),
+            (true, false) => Some(true),
+            (false, true) => Some(false),
+            (false, false) => None,
+        };
+        let mut sources = ctx.load_or_default(true)?;
+        let options = RefreshOptions {
+            record_redirects: self.record_redirects,
+            hash_type: self.hash_type.or(ctx.hash_type),
+            prefetcher,
+            gateways: &ctx.ipfs_gateways,
+            ..Default::default()
+        };
+        let existing = sources
+            .sources
+            .get(&self.name)
+            .filter(|_| self.update_if_exists)
+            .cloned();
+        let name = if let Some(existing) = existing {
+            let existing_url = existing
+                .version_index
+                .as_ref()
+                .map_or(&existing.url, |index| &index.url);
+            if *existing_url == url {
+                log::info!("Updating {}", self.name);
+                let source = refresh_source(&ctx.client, &existing, options)?;
+                report_pin(ctx, &self.name, &source)?;
+                sources.sources.insert(self.name, source);
+                return ctx.write(&sources);
+            }
+            log::info!("Replacing {}", self.name);
+            self.name
+        } else {
+            let Some(name) = sources.resolve_collision(&self.name, self.on_collision)? else {
+                return Ok(());
+            };
+            log::info!("Adding {}", name);
+            name
+        };
+        let mut source = Source {
+            ty: self.ty,
+            decompress: self.decompress,
+            signature,
+            checksums: self.checksums,
+            unpack,
+            strip_root: self.no_strip_root.then_some(false),
+            subdir: self.subdir,
+            git_ref: self.rev,
+            submodules: self.submodules,
+            deep_clone: self.deep_clone,
+            store_name: self.store_name,
+            pre_update: self.pre_update,
+            post_update: self.post_update,
+            test_build: self.test_build,
+            description: self.description,
+            homepage: self.homepage,
+            license: self.license,
+            group: self.group,
+            nixpkgs: self.nixpkgs,
+            headers,
+            version: self.version,
+            url_template,
+            mirrors: self.mirror,
+            github,
+            github_branch,
+            repository,
+            branch: forge_branch,
+            package,
+            ..Source::new(url)
+        };
+        if let Some(pattern) = self.version_regex {
+            let index = version::VersionIndex {
+                url: source.url.clone(),
+                pattern,
+            };
+            let (version, url) = index.latest(&ctx.client)?;
+            log::debug!("\tfound version {version}");
+            source.url = match &source.url_template {
+                Some(template) => expand_url_template(template, &version)?,
+                None => url,
+            };
+            source.version = Some(version);
+            source.version_index = Some(index);
+        }
+        let source = refresh_source(&ctx.client, &source, options)?;
+        report_pin(ctx, &name, &source)?;
+        sources.sources.insert(name, source);
+        ctx.write(&sources)
+    }
+}
+
+/// Parse an age like `7d`, a number followed by s, m, h, d or w for seconds, minutes, hours,
+/// days or weeks.
+fn parse_age(s: &str) -> Result<chrono::Duration, String> {
+    let error = || format!("invalid age {s}, expected a number followed by s, m, h, d or w");
+    let unit = s.chars().last().ok_or_else(error)?;
+    let n: i64 = s[..s.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| error())?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return Err(error()),
+    };
+    n.checked_mul(seconds)
+        // Durations are kept in milliseconds.
+        .filter(|seconds| (0..=i64::MAX / 1000).contains(seconds))
+        .map(chrono::Duration::seconds)
+        .ok_or_else(error)
+}
+
+/// update sources in the sources file
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "update")]
+struct UpdateCommand {
+    /// names of the sources to update, or shell-style globs matching them, all sources by
+    /// default
+    #[argh(positional)]
+    names: Vec<String>,
+    /// skip the sources whose name matches this glob, can be given multiple times
+    #[argh(option)]
+    except: Vec<String>,
+    /// only update the sources in this group
+    #[argh(option)]
+    group: Option<String>,
+    /// only update the sources that weren't checked within this long, like 30m, 12h, 7d or
+    /// 2w. Sources that weren't checked since this was recorded are always updated
+    #[argh(option, from_str_fn(parse_age))]
+    max_age: Option<chrono::Duration>,
+    /// record the redirects followed when fetching the sources. Without it, those fetched
+    /// again lose the redirects recorded before
+    #[argh(switch)]
+    record_redirects: bool,
+    /// look for newer versions of sources that have a version index
+    #[argh(switch)]
+    check_latest: bool,
+    /// number of sources to update concurrently, 4 by default
+    #[argh(option, short = 'j')]
+    jobs: Option<usize>,
+    /// report which sources changed upstream, and newer versions of those with a version
+    /// index, with the checks outdated makes, exiting with status 2 if any did. Nothing is
+    /// fetched, hashed or written, unless --revalidate is given too
+    #[argh(switch)]
+    dry_run: bool,
+    /// fetch and hash the sources again even if the server says they haven't changed, and
+    /// update frozen sources too
+    #[argh(switch)]
+    force: bool,
+    /// instead of reporting progress, print a JSON object mapping each source to its status,
+    /// one of updated, unchanged or error, or with --dry-run also unknown if only fetching the
+    /// source could tell, with the reason. Same as --output json
+    #[argh(switch)]
+    json: bool,
+    /// still make conditional requests, but when a source is reported as not modified, fetch
+    /// and hash it anyway and warn if it did change. This downloads every source, giving up
+    /// the bandwidth saved by conditional requests. With --dry-run, changes make update fail
+    #[argh(switch)]
+    revalidate: bool,
+    /// update pinned sources too when updating all sources
+    #[argh(switch)]
+    include_pinned: bool,
+    /// show the release notes of sources following a forge that moved to a new release, or
+    /// the commits of those following a branch
+    #[argh(switch)]
+    notes: bool,
+    /// show each change, with its release notes, and ask whether to accept it, skip it, or
+    /// skip it and freeze the source so it stays as it is until updated with --force
+    #[argh(switch, short = 'i')]
+    interactive: bool,
+    /// replace the urls of sources that redirect with where they redirect to, pinning the file
+    /// that was hashed instead of a url like /download/latest. Sources following a release,
+    /// package or url template keep their urls
+    #[argh(switch)]
+    follow: bool,
+    /// don't download sources the server can't tell changed if a binary cache has them with
+    /// their current hash, keeping that hash
+    #[argh(switch)]
+    substitute: bool,
+    /// commit the sources file, and the files it includes, with git if any source changed, with
+    /// a message listing them
+    #[argh(switch)]
+    commit: bool,
+    /// shell command to run before each source is updated, besides its own. The source is
+    /// skipped if it fails
+    #[argh(option)]
+    pre_update: Option<String>,
+    /// shell command to run after each source changed, besides its own, with the sources file
+    /// updated. The source is put back as it was if it fails
+    #[argh(option)]
+    post_update: Option<String>,
+    /// nix installable to build after each source changed, besides its own, like .#package,
+    /// with the sources file updated. The source is put back as it was if the build fails
+    #[argh(option)]
+    test_build: Option<String>,
+}
+
+impl UpdateCommand {
+    /// The sources to update: those named, those matching the globs given, or all of them, but
+    /// the ones matching --except or not in --group. Pinned sources are only updated if named,
+    /// or with --include-pinned, and frozen ones only with --force.
+    fn select(&self, sources: &Sources) -> anyhow::Result<Vec<(String, Source)>> {
+        if let Some(group) = &self.group {
+            if !sources
+                .sources
+                .values()
+                .any(|s| s.group.as_ref() == Some(group))
+            {
+                anyhow::bail!("no source is in group {}", group);
+            }
+        }
+        let except = self
+            .except
+            .iter()
+            .map(|pattern| glob::Pattern::new(pattern))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut selected = std::collections::BTreeMap::new();
+        let mut select_matching = |pattern: &glob::Pattern| {
+            let mut matching = sources
+                .sources
+                .iter()
+                .filter(|(name, _)| pattern.matches(name))
+                .peekable();
+            let matched = matching.peek().is_some();
+            for (name, source) in matching {
+                if source.frozen && !self.force {
+                    log::info!("{}: frozen, skipping", name);
+                } else if source.pinned && !self.include_pinned {
+                    log::info!("{}: pinned, skipping", name);
+                } else {
+                    selected.insert(name, source);
+                }
+            }
+            matched
+        };
+        let mut named = Vec::new();
+        if self.names.is_empty() {
+            select_matching(&glob::Pattern::new("*")?);
+        }
+        for name in &self.names {
+            if let Some(source) = sources.sources.get_key_value(name) {
+                named.push(source);
+            } else if !name.contains(['*', '?', '[']) {
+                anyhow::bail!("source {} does not exist", name);
+            } else if !select_matching(&glob::Pattern::new(name)?) {
+                anyhow::bail!("no source matches {}", name);
+            }
+        }
+        for (name, source) in named {
+            if source.frozen && !self.force {
+                log::warn!("{} is frozen, skipping it unless --force is given", name);
+                continue;
+            }
+            if source.pinned {
+                log::warn!("{} is pinned", name);
+            }
+            selected.insert(name, source);
+        }
+        Ok(selected
+            .into_iter()
+            .filter(|(name, source)| {
+                !except.iter().any(|pattern| pattern.matches(name))
+                    && self
+                        .group
+                        .as_ref()
+                        .is_none_or(|g| source.group.as_ref() == Some(g))
+            })
+            .map(|(name, source)| (name.clone(), source.clone()))
+            .collect())
+    }
+}
+
+/// Run the shell command `hook` for the source `name`, telling it about the source in
+/// NIX_SOURCE_NAME, NIX_SOURCE_OLD_HASH and, after updating, NIX_SOURCE_NEW_HASH.
+fn run_hook(hook: &str, name: &str, old: &Source, new: Option<&Source>) -> anyhow::Result<()> {
+    let hash = |s: &Source| s.hash.as_ref().map(|h| h.to_string()).unwrap_or_default();
+    let mut command = std::process::Command::new("sh");
+    command
+        .args(["-c", hook])
+        .env("NIX_SOURCE_NAME", name)
+        .env("NIX_SOURCE_OLD_HASH", hash(old))
+        .stdin(std::process::Stdio::null());
+    if let Some(new) = new {
+        command.env("NIX_SOURCE_NEW_HASH", hash(new));
+    }
+    let status = command
+        .status()
+        .with_context(|| format!("failed to run {}", hook))?;
+    if !status.success() {
+        anyhow::bail!("{} failed: {}", hook, status);
+    }
+    Ok(())
+}
+
+/// Build the nix installable `installable`, to check it still builds with a changed source.
+/// What nix prints goes to stderr, so it doesn't mix with the output on stdout.
+fn test_build(installable: &str) -> anyhow::Result<()> {
+    log::info!("Building {}", installable);
+    let status = std::process::Command::new("nix")
+        .args(["--extra-experimental-features", "nix-command flakes"])
+        .args(["build", "--no-link", installable])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::io::stderr())
+        .status()
+        .context("failed to run nix build")?;
+    if !status.success() {
+        anyhow::bail!("building {} failed: {}", installable, status);
+    }
+    Ok(())
+}
+
+/// Run `f` on every item on up to `jobs` threads, returning the results in order.
+fn parallel_map<T: Send, R: Send>(jobs: usize, items: Vec<T>, f: impl Fn(T) -> R + Sync) -> Vec<R> {
+    let count = items.len();
+    let items = std::sync::Mutex::new(items.into_iter().enumerate());
+    let results = std::sync::Mutex::new((0..count).map(|_| None).collect::<Vec<_>>());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, count.max(1)) {
+            scope.spawn(|| loop {
+                let Some((i, item)) = items.lock().unwrap().next() else {
+                    break;
+                };
+                let result = f(item);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(Option::unwrap)
+        .collect()
+}
+
+/// What changed besides the hash between `old` and `new`, as the field and its old and new
+/// values.
+fn changes(old: &Source, new: &Source) -> Vec<(&'static str, Option<String>, Option<String>)> {
+    let fields = [
+        ("url", Some(old.url.to_string()), Some(new.url.to_string())),
+        ("version", old.version.clone(), new.version.clone()),
+        ("rev", old.rev.clone(), new.rev.clone()),
+        (
+            "last modified",
+            old.last_modified.map(|t| t.to_rfc2822()),
+            new.last_modified.map(|t| t.to_rfc2822()),
+        ),
+    ];
+    fields
+        .into_iter()
+        .filter(|(_, old, new)| old != new)
+        .collect()
+}
+
+/// Print the hash of `name` going from `old` to `new`, then what else changed, one field per
+/// line. Old values are red and new ones green if `color` is set.
+fn print_changes(name: &str, old: &Source, new: &Source, color: bool) {
+    let or_none = |s: Option<String>| s.unwrap_or("none".to_owned());
+    let paint = |code: &str, s: String| {
+        if color {
+            format!("\x1b[{code}m{s}\x1b[0m")
+        } else {
+            s
+        }
+    };
+    let hash = |s: &Source| s.hash.as_ref().map(|h| h.to_string());
+    println!(
+        "{}: {} -> {}",
+        paint("1", name.to_owned()),
+        paint("31", hash(old).unwrap_or("no hash".to_owned())),
+        paint("32", hash(new).unwrap_or("no hash".to_owned()))
+    );
+    for (field, old, new) in changes(old, new) {
+        println!(
+            "\t{}: {} -> {}",
+            field,
+            paint("31", or_none(old)),
+            paint("32", or_none(new))
+        );
+    }
+}
+
+/// Log what `name` is pinned to now, its revision first if it has one, shown unless --quiet.
+/// With JSON output, print an object mapping `name` to the source instead.
+fn report_pin(ctx: &Global, name: &str, source: &Source) -> anyhow::Result<()> {
+    if ctx.json(false) {
+        return print_json(&serde_json::json!({ name: source }));
+    }
+    let hash = source
+        .hash
+        .as_ref()
+        .map_or("no hash".to_owned(), |h| h.to_string());
+    match &source.rev {
+        Some(rev) => log::info!("{}: {} {}", name, rev, hash),
+        None => log::info!("{}: {}", name, hash),
+    }
+    Ok(())
+}
+
+/// The notes of the release or commits `source` would move to upstream, without fetching it.
+fn upstream_notes(ctx: &Global, source: &Source) -> anyhow::Result<Option<String>> {
+    let upstream = if let Some((rev, url)) = branch_head(&ctx.client, source)? {
+        Source {
+            rev: Some(rev),
+            url,
+            ..source.clone()
+        }
+    } else if let Some((version, url)) = latest_release(&ctx.client, source)? {
+        Source {
+            version: Some(version),
+            url,
+            ..source.clone()
+        }
+    } else {
+        return Ok(None);
+    };
+    release_notes(&ctx.client, source, &upstream)
+}
+
+/// What to do with a change to a source, in `update --interactive`.
+enum Answer {
+    Accept,
+    Skip,
+    /// Skip the change, and freeze the source.
+    Freeze,
+}
+
+/// Ask whether to accept the change to `name` until one of the answers is given. At the end of
+/// the input, the change is skipped.
+fn ask_accept(name: &str) -> anyhow::Result<Answer> {
+    loop {
+        print!("Accept the change to {}? [y]es, [s]kip, [f]reeze: ", name);
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            println!();
+            return Ok(Answer::Skip);
+        }
+        match answer.trim().to_ascii_lowercase().as_str() {
+            "y" | "yes" => return Ok(Answer::Accept),
+            "s" | "skip" => return Ok(Answer::Skip),
+            "f" | "freeze" => return Ok(Answer::Freeze),
+            _ => (),
+        }
+    }
+}
+
+/// The version of `source`, or its revision or hash if it has none.
+fn describe(source: &Source) -> String {
+    if let Some(version) = &source.version {
+        return version.clone();
+    }
+    if let Some(rev) = &source.rev {
+        return rev.chars().take(12).collect();
+    }
+    match &source.hash {
+        Some(hash) => hash.to_string(),
+        None => "no hash".to_owned(),
+    }
+}
+
+/// Commit the sources file at `path` and the files it includes, `files`, and nothing else, with
+/// git. The message lists the sources in `changed` and how they changed.
+fn commit(
+    path: &std::path::Path,
+    files: &[std::path::PathBuf],
+    changed: &[(String, String)],
+) -> anyhow::Result<()> {
+    let subject = match changed {
+        [(name, _)] => format!("Update {}", name),
+        _ => format!("Update {} sources", changed.len()),
+    };
+    let body: Vec<_> = changed
+        .iter()
+        .map(|(name, change)| format!("{}: {}", name, change))
+        .collect();
+    let message = format!("{}\n\n{}\n", subject, body.join("\n"));
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    // git runs next to the sources file, which the included files are relative to.
+    let files: Vec<&std::ffi::OsStr> = files
+        .iter()
+        .map(|file| file.strip_prefix(dir).unwrap_or(file).as_os_str())
+        .collect();
+    let git = |args: &[&std::ffi::OsStr]| -> anyhow::Result<()> {
+        let status = std::process::Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .context("failed to run git")?;
+        if !status.success() {
+            anyhow::bail!("git {} failed: {}", args[0].to_string_lossy(), status);
+        }
+        Ok(())
+    };
+    git(&[&["add".as_ref(), "--".as_ref()], &files[..]].concat())?;
+    git(&[
+        &[
+            "commit".as_ref(),
+            "--message".as_ref(),
+            message.as_ref(),
+            "--".as_ref(),
+        ],
+        &files[..],
+    ]
+    .concat())?;
+    log::info!("Committed {}", path.display());
+    Ok(())
+}
+
+/// Whether updating a source changed what is stored about it.
+fn is_changed(old: &Source, new: &Source) -> bool {
+    old.url != new.url
+        || old.hash != new.hash
+        || old.etag != new.etag
+        || old.last_modified != new.last_modified
+}
+
+impl UpdateCommand {
+    /// Run the post-update hooks and test builds of `name`, which changed to `source`, with the
+    /// change written out. If one fails, the error is returned so the old source is kept.
+    fn post_update(
+        &self,
+        ctx: &Global,
+        sources: &mut Sources,
+        name: &str,
+        source: Source,
+    ) -> anyhow::Result<Source> {
+        let hooks: Vec<_> = [&self.post_update, &source.post_update]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        let builds: Vec<_> = [&self.test_build, &source.test_build]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        if hooks.is_empty() && builds.is_empty() {
+            return Ok(source);
+        }
+        let old = sources
+            .sources
+            .insert(name.to_owned(), source.clone())
+            .unwrap();
+        let result = ctx.write(sources).and_then(|()| {
+            hooks
+                .iter()
+                .try_for_each(|hook| run_hook(hook, name, &old, Some(&source)))
+                .context("post-update hook failed, keeping the old version")?;
+            builds
+                .iter()
+                .try_for_each(|installable| test_build(installable))
+                .context("test build failed, keeping the old version")
+        });
+        // The caller compares against the old source, and stores the new one if it is kept.
+        sources.sources.insert(name.to_owned(), old);
+        result.map(|()| source)
+    }
+
+    /// Report which of `work` changed upstream, for --dry-run, without fetching them.
+    fn check(&self, ctx: &Global, work: Vec<(String, Source)>, json: bool) -> anyhow::Result<()> {
+        if !json && progress::start(work.len()) {
+            ctx.quiet();
+        }
+        let results = parallel_map(ctx.jobs(self.jobs), work, |(name, source)| {
+            progress::set(&name, progress::State::Busy(Stage::Checking));
+            let freshness = check_freshness(&ctx.client, &source)
+                .with_context(|| format!("failed to check {}", name));
+            progress::set(
+                &name,
+                match &freshness {
+                    Ok(Freshness::Outdated(_)) => progress::State::Updated,
+                    Ok(_) => progress::State::Unchanged,
+                    Err(_) => progress::State::Failed,
+                },
+            );
+            let notes = match &freshness {
+                Ok(Freshness::Outdated(_)) if self.notes => upstream_notes(ctx, &source)
+                    .unwrap_or_else(|e| {
+                        log::warn!("failed to fetch the release notes of {}: {:#}", name, e);
+                        None
+                    }),
+                _ => None,
+            };
+            (name, freshness, notes)
+        });
+        progress::finish();
+        let mut outdated = 0;
+        let mut failed = Vec::new();
+        let mut summary = serde_json::Map::new();
+        for (name, result, notes) in results {
+            let (status, reason) = match &result {
+                Ok(Freshness::UpToDate) => ("unchanged", None),
+                Ok(Freshness::Outdated(reason)) => {
+                    outdated += 1;
+                    ("updated", Some(reason.clone()))
+                }
+                Ok(Freshness::Unknown(reason)) => ("unknown", Some(reason.clone())),
+                Err(e) => {
+                    log::error!("{:#}", e);
+                    failed.push(name.clone());
+                    ("error", Some(format!("{:#}", e)))
+                }
+            };
+            if json {
+                let mut status = serde_json::json!({ "status": status, "reason": reason });
+                if let Some(notes) = notes {
+                    status["notes"] = notes.into();
+                }
+                summary.insert(name, status);
+                continue;
+            }
+            match result {
+                Ok(Freshness::UpToDate) => println!("{}: unchanged", name),
+                Ok(Freshness::Outdated(reason)) => println!("{}: would change, {}", name, reason),
+                Ok(Freshness::Unknown(reason)) => println!("{}: unknown, {}", name, reason),
+                Err(_) => (),
+            }
+            for line in notes.iter().flat_map(|notes| notes.lines()) {
+                println!("\t| {}", line);
+            }
+        }
+        if json {
+            print_json(&summary)?;
+        }
+        if !failed.is_empty() {
+            anyhow::bail!(
+                "{} sources could not be checked: {}",
+                failed.len(),
+                failed.join(", ")
+            );
+        }
+        if outdated > 0 {
+            return Err(OutOfDate(format!("{} sources are out of date", outdated)).into());
+        }
+        Ok(())
+    }
+
+    /// Refresh one source, returning what should be stored in its place.
+    fn update_one(
+        &self,
+        ctx: &Global,
+        prefetcher: prefetch::Prefetcher,
+        name: &str,
+        mut source: Source,
+        progress: &(dyn Fn(Stage) + Sync),
+    ) -> anyhow::Result<Source> {
+        log::debug!("Updating {}", name);
+        for hook in [&self.pre_update, &source.pre_update].into_iter().flatten() {
+            run_hook(hook, name, &source, None).context("pre-update hook failed")?;
+        }
+        let old = source.clone();
+        if let Some(index) = source.version_index.as_ref().filter(|_| self.check_latest) {
+            progress(Stage::Checking);
+            let (version, url) = index.latest(&ctx.client)?;
+            let url = match &source.url_template {
+                Some(template) => expand_url_template(template, &version)?,
+                None => url,
+            };
+            if url != source.url {
+                log::debug!(
+                    "\tnew version {} -> {version}",
+                    source.version.as_deref().unwrap_or("unknown")
+                );
+                source.url = url;
+                source.version = Some(version);
+                // The old validators and hash are for a different file.
+                source.hash = None;
+                source.etag = None;
+                source.last_modified = None;
+            }
+        }
+        let new_source = refresh_source(
+            &ctx.client,
+            &source,
+            RefreshOptions {
+                record_redirects: self.record_redirects,
+                force: self.force,
+                hash_type: ctx.hash_type,
+                prefetcher,
+                revalidate: self.revalidate,
+                progress: Some(progress),
+                substituters: if self.substitute {
+                    &ctx.substituters
+                } else {
+                    &[]
+                },
+                gateways: &ctx.ipfs_gateways,
+            },
+        )?;
+        source.url = new_source.url;
+        source.hash = new_source.hash;
+        source.last_modified = new_source.last_modified;
+        source.etag = new_source.etag;
+        source.ty = new_source.ty;
+        source.compression = new_source.compression;
+        source.redirects = new_source.redirects;
+        source.resolved_url = new_source.resolved_url;
+        source.fetched_from = new_source.fetched_from;
+        source.size = new_source.size;
+        source.rev = new_source.rev;
+        source.version = new_source.version;
+        source.store_path = new_source.store_path;
+        if self.follow && !source.follows_upstream() && source.url_template.is_none() {
+            let from = source.url.clone();
+            if source.follow_redirects() {
+                log::info!("{}: {} redirects to {}", name, from, source.url);
+            }
+        }
+        source.remember(&old);
+        Ok(source)
+    }
+}
+
+impl Command for UpdateCommand {
+    fn execute(mut self, ctx: &Global) -> anyhow::Result<()> {
+        ctx.ensure_online()?;
+        self.pre_update = self.pre_update.or_else(|| ctx.pre_update.clone());
+        self.post_update = self.post_update.or_else(|| ctx.post_update.clone());
+        if self.commit && self.dry_run {
+            anyhow::bail!("--commit can't be used with --dry-run");
+        }
+        if self.interactive && self.dry_run {
+            anyhow::bail!("--interactive can't be used with --dry-run");
+        }
+        let mut sources = ctx.load(!self.dry_run)?;
+        let json = ctx.json(self.json);
+        if json && self.interactive {
+            anyhow::bail!("--interactive can't be used with JSON output");
+        }
+        if json {
+            ctx.quiet();
+        }
+        let mut checked = checked::LastChecked::load(&ctx.sources)?;
+        let now = chrono::Utc::now().fixed_offset();
+        let mut work = self.select(&sources)?;
+        if let Some(max_age) = self.max_age {
+            work.retain(|(name, _)| match checked.get(name) {
+                Some(time) if now - time < max_age => {
+                    log::info!("{}: checked at {}, skipping", name, time);
+                    false
+                }
+                _ => true,
+            });
+        }
+        if self.dry_run && !self.revalidate {
+            return self.check(ctx, work, json);
+        }
+        let prefetcher = check_nix_tools(ctx)?;
+        if !json && progress::start(work.len()) {
+            ctx.quiet();
+        }
+        let results = parallel_map(ctx.jobs(self.jobs), work, |(name, source)| {
+            let old = source.clone();
+            let report = |stage| progress::set(&name, progress::State::Busy(stage));
+            let result = self.update_one(ctx, prefetcher, &name, source, &report);
+            let state = match &result {
+                Ok(new) if is_changed(&old, new) => progress::State::Updated,
+                Ok(_) => progress::State::Unchanged,
+                Err(_) => progress::State::Failed,
+            };
+            progress::set(&name, state);
+            let notes = match &result {
+                Ok(new) if (self.notes || self.interactive) && is_changed(&old, new) => {
+                    release_notes(&ctx.client, &old, new).unwrap_or_else(|e| {
+                        log::warn!("failed to fetch the release notes of {}: {:#}", name, e);
+                        None
+                    })
+                }
+                _ => None,
+            };
+            (name, result, notes)
+        });
+        progress::finish();
+        let mut failed = Vec::new();
+        // The sources that changed, and from what version to what, for the commit message.
+        let mut changed = Vec::new();
+        let mut summary = serde_json::Map::new();
+        for (name, result, notes) in results {
+            if result.is_ok() {
+                checked.set(&name, now);
+            }
+            let print_notes = || {
+                for line in notes.iter().flat_map(|notes| notes.lines()) {
+                    println!("\t| {}", line);
+                }
+            };
+            if let (true, Ok(source)) = (self.interactive, &result) {
+                let old = &sources.sources[&name];
+                if is_changed(old, source) {
+                    print_changes(&name, old, source, ctx.color);
+                    print_notes();
+                    match ask_accept(&name)? {
+                        Answer::Accept => (),
+                        Answer::Skip => continue,
+                        Answer::Freeze => {
+                            sources.sources.get_mut(&name).unwrap().frozen = true;
+                            continue;
+                        }
+                    }
+                }
+            }
+            let result = match result {
+                Ok(source) if !self.dry_run && is_changed(&sources.sources[&name], &source) => {
+                    self.post_update(ctx, &mut sources, &name, source)
+                }
+                result => result,
+            };
+            match result {
+                Ok(source) => {
+                    let old = &sources.sources[&name];
+                    let hash = |s: &Source| s.hash.as_ref().map(|h| h.to_string());
+                    if is_changed(old, &source) {
+                        let change = format!("{} -> {}", describe(old), describe(&source));
+                        changed.push((name.clone(), change));
+                        if json {
+                            let changes: serde_json::Map<_, _> = changes(old, &source)
+                                .into_iter()
+                                .map(|(field, old, new)| {
+                                    let change = serde_json::json!({ "old": old, "new": new });
+                                    (field.replace(' ', "_"), change)
+                                })
+                                .collect();
+                            let mut status = serde_json::json!({
+                                "status": "updated",
+                                "old": hash(old),
+                                "new": hash(&source),
+                                "changes": changes,
+                            });
+                            if let Some(notes) = notes {
+                                status["notes"] = notes.into();
+                            }
+                            summary.insert(name.clone(), status);
+                        } else if !self.interactive {
+                            // Already shown when asking.
+                            print_changes(&name, old, &source, ctx.color);
+                            print_notes();
+                        }
+                    } else if json {
+                        let status = serde_json::json!({ "status": "unchanged" });
+                        summary.insert(name.clone(), status);
+                    } else if self.dry_run {
+                        println!("{}: unchanged", name);
+                    }
+                    sources.sources.insert(name, source);
+                }
+                Err(e) => {
+                    log::error!("failed to update {}: {:#}", name, e);
+                    if json {
+                        let status = serde_json::json!({
+                            "status": "error",
+                            "error": format!("{:#}", e),
+                        });
+                        summary.insert(name.clone(), status);
+                    }
+                    failed.push(name);
+                }
+            }
+        }
+        if json {
+            print_json(&summary)?;
+        }
+        if !self.dry_run {
+            // Sources that did update are written out even if others failed.
+            ctx.write(&sources)?;
+            if !ctx.frozen {
+                checked.save(&sources)?;
+            }
+            if self.commit && !changed.is_empty() {
+                let files: Vec<_> = sources
+                    .files(&ctx.sources, ctx.format)
+                    .into_iter()
+                    .map(|(path, _, _)| path)
+                    .collect();
+                commit(&ctx.sources, &files, &changed)?;
+            }
+        }
+        if !failed.is_empty() {
+            anyhow::bail!(
+                "{} sources failed to update: {}",
+                failed.len(),
+                failed.join(", ")
+            );
+        }
+        if self.dry_run && !changed.is_empty() {
+            return Err(OutOfDate(format!("{} sources are out of date", changed.len())).into());
+        }
+        Ok(())
+    }
+}
+
+/// delete a source from the sources file
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "rm")]
+struct DeleteCommand {
+    /// name of the source
+    #[argh(positional)]
+    name: String,
+    /// report what would be removed without modifying the sources file, with JSON output as
+    /// a list of names
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+impl Command for DeleteCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        let mut sources = ctx.load(!self.dry_run)?;
+        sources.remove(&self.name)?;
+        if self.dry_run {
+            if ctx.json(false) {
+                return print_json(&[&self.name]);
+            }
+            println!("Would remove {}", self.name);
+            return Ok(());
+        }
+        ctx.write(&sources)
+    }
+}
+
+/// list the sources not referenced by any of the given files, and remove them with --yes
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "prune")]
+struct PruneCommand {
+    /// files to look for references in. Directories are searched recursively, and glob patterns
+    /// are expanded
+    #[argh(positional)]
+    paths: Vec<String>,
+    /// remove the unreferenced sources instead of only listing them. With JSON output, they
+    /// are listed by name either way
+    #[argh(switch)]
+    yes: bool,
+}
+
+/// Add every file under `path` to `files`.
+fn collect_files(
+    path: &std::path::Path,
+    files: &mut Vec<std::path::PathBuf>,
+) -> anyhow::Result<()> {
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            collect_files(&entry?.path(), files)?;
+        }
+    } else {
+        files.push(path.to_owned());
+    }
+    Ok(())
+}
+
+impl Command for PruneCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        if self.paths.is_empty() {
+            anyhow::bail!("no files to look for references in");
+        }
+        let mut sources = ctx.load(self.yes)?;
+        let mut files = Vec::new();
+        for pattern in &self.paths {
+            let mut matched = false;
+            for path in glob::glob(pattern)? {
+                collect_files(&path?, &mut files)?;
+                matched = true;
+            }
+            if !matched {
+                anyhow::bail!("{} does not match any file", pattern);
+            }
+        }
+        let mut unreferenced: std::collections::BTreeSet<_> =
+            sources.sources.keys().cloned().collect();
+        for file in files {
+            let contents = std::fs::read(&file)
+                .with_context(|| format!("failed to read {}", file.display()))?;
+            let contents = String::from_utf8_lossy(&contents);
+            unreferenced.retain(|name| !contents.contains(name.as_str()));
+        }
+        let json = ctx.json(false);
+        for name in &unreferenced {
+            if self.yes {
+                if !json {
+                    println!("Removing {}", name);
+                }
+                sources.sources.remove(name);
+            } else if !json {
+                println!("{} is not referenced", name);
+            }
+        }
+        if json {
+            print_json(&unreferenced)?;
+        }
+        if self.yes && !unreferenced.is_empty() {
+            ctx.write(&sources)?;
+        }
+        Ok(())
+    }
+}
+
+/// generate a nix expression fetching every source
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "emit-nix")]
+struct EmitNixCommand {
+    /// file to write the expression to, stdout by default
+    #[argh(option, short = 'o')]
+    output: Option<std::path::PathBuf>,
+}
+
+impl Command for EmitNixCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        let sources = ctx.load(false)?;
+        let expr = nix::generate(&sources, &ctx.ipfs_gateways);
+        if let Some(output) = self.output {
+            std::fs::write(output, expr)?;
+        } else {
+            print!("{}", expr);
+        }
+        Ok(())
+    }
+}
+
+/// write a sources.nix next to the sources file, which fetches the sources listed in it when
+/// evaluated
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "generate")]
+struct GenerateCommand {}
+
+impl Command for GenerateCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        let file_name = ctx
+            .sources
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("the sources file has no valid file name")?;
+        let toml = ctx.format == Format::Toml;
+        let path = ctx.sources.with_file_name("sources.nix");
+        std::fs::write(&path, nix::loader(file_name, toml, &ctx.ipfs_gateways))
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        log::info!("Wrote {}", path.display());
+        Ok(())
+    }
+}
+
+/// Format to export sources to.
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum ExportFormat {
+    Flake,
+    /// A CycloneDX software bill of materials.
+    Cyclonedx,
+    /// An SPDX software bill of materials.
+    Spdx,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flake" => Ok(ExportFormat::Flake),
+            "cyclonedx" => Ok(ExportFormat::Cyclonedx),
+            "spdx" => Ok(ExportFormat::Spdx),
+            _ => Err(anyhow::anyhow!("invalid export format")),
+        }
+    }
+}
+
+/// export sources for another tool, printing the inputs block of a flake.nix for the flake
+/// format, or a software bill of materials
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "export")]
+struct ExportCommand {
+    /// format to export to: flake, or cyclonedx or spdx for a software bill of materials in
+    /// JSON
+    #[argh(option)]
+    format: ExportFormat,
+    /// flake.lock to add the locked inputs to, created if it doesn't exist. Only for the flake
+    /// format
+    #[argh(option)]
+    lock: Option<std::path::PathBuf>,
+}
+
+impl Command for ExportCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        if self.lock.is_some() && self.format != ExportFormat::Flake {
+            anyhow::bail!("--lock is only for the flake format");
+        }
+        let sources = ctx.load(false)?;
+        match self.format {
+            ExportFormat::Cyclonedx => print_json(&sbom::cyclonedx(&sources))?,
+            ExportFormat::Spdx => {
+                let name = ctx
+                    .sources
+                    .file_name()
+                    .map_or("sources".into(), |name| name.to_string_lossy());
+                print_json(&sbom::spdx(&sources, &name))?
+            }
+            ExportFormat::Flake => {
+                let existing = match &self.lock {
+                    Some(path) if path.exists() => {
+                        let file = std::fs::File::open(path)
+                            .with_context(|| format!("failed to open {}", path.display()))?;
+                        Some(serde_json::from_reader(std::io::BufReader::new(file))?)
+                    }
+                    _ => None,
+                };
+                let (inputs, lock, skipped) = flake::export(&sources, existing);
+                for (name, e) in skipped {
+                    log::warn!("skipping {}: {:#}", name, e);
+                }
+                print!("{}", inputs);
+                if let Some(path) = self.lock {
+                    let mut contents = serde_json::to_string_pretty(&lock)?;
+                    contents.push('\n');
+                    std::fs::write(path, contents)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// rename a source
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "rename")]
+struct RenameCommand {
+    /// current name of the source
+    #[argh(positional)]
+    old: String,
+    /// new name of the source
+    #[argh(positional)]
+    new: String,
+}
+
+impl Command for RenameCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        let mut sources = ctx.load(true)?;
+        sources.rename(&self.old, self.new.clone())?;
+        ctx.write(&sources)?;
+        if !ctx.frozen {
+            // So --max-age doesn't take it for a source that was never checked.
+            let mut checked = checked::LastChecked::load(&ctx.sources)?;
+            checked.rename(&self.old, &self.new);
+            checked.save(&sources)?;
+        }
+        Ok(())
+    }
+}
+
+/// Format of a sources file to import from.
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum ImportFormat {
+    Niv,
+    Npins,
+    FlakeLock,
+}
+
+impl std::str::FromStr for ImportFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "niv" => Ok(ImportFormat::Niv),
+            "npins" => Ok(ImportFormat::Npins),
+            "flake-lock" => Ok(ImportFormat::FlakeLock),
+            _ => Err(anyhow::anyhow!("invalid import format")),
+        }
+    }
+}
+
+/// add every source listed in a manifest, a JSON array of {{name, url, type}} objects
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "batch-add")]
+struct BatchAddCommand {
+    /// the manifest file
+    #[argh(positional)]
+    manifest: std::path::PathBuf,
+    /// if a name is taken, refresh the existing source if it has the same url, or replace it
+    /// otherwise
+    #[argh(switch)]
+    update_if_exists: bool,
+    /// what to do with sources whose name is taken, unless --update-if-exists: error, which is
+    /// the default, skip adding them, or suffix the name with -2, -3 and so on until it is free
+    #[argh(option, default = "OnCollision::Error")]
+    on_collision: OnCollision,
+    /// number of sources to add concurrently, 4 by default
+    #[argh(option, short = 'j')]
+    jobs: Option<usize>,
+}
+
+#[derive(serde::Deserialize)]
+struct ManifestEntry {
+    name: String,
+    url: String,
+    #[serde(rename = "type", default)]
+    ty: Option<SourceType>,
 }
 
-impl std::str::FromStr for SourceType {
-    type Err = anyhow::Error;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "tarball" => Ok(SourceType::Tarball),
-            "file" => Ok(SourceType::File),
-            _ => Err(anyhow::anyhow!("invalid source type")),
+impl Command for BatchAddCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        ctx.ensure_online()?;
+        ctx.ensure_writable()?;
+        let prefetcher = check_nix_tools(ctx)?;
+        let file = std::fs::File::open(&self.manifest)
+            .with_context(|| format!("failed to open {}", self.manifest.display()))?;
+        let entries: Vec<ManifestEntry> = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("failed to parse {}", self.manifest.display()))?;
+        let mut sources = ctx.load_or_default(true)?;
+        let options = RefreshOptions {
+            hash_type: ctx.hash_type,
+            prefetcher,
+            gateways: &ctx.ipfs_gateways,
+            ..Default::default()
+        };
+        let mut names = std::collections::HashSet::new();
+        // Names given to new sources, which hold the source as listed until it is refreshed, so
+        // later entries aren't given the same name.
+        let mut reserved = std::collections::HashSet::new();
+        let plan = |entry: ManifestEntry| -> anyhow::Result<Option<(String, Source)>> {
+            if self.on_collision == OnCollision::Error && !names.insert(entry.name.clone()) {
+                anyhow::bail!("{} is listed more than once", entry.name);
+            }
+            let url = parse_source_url(&entry.url)?;
+            let new = Source {
+                ty: entry.ty,
+                ..Source::new(url.clone())
+            };
+            match sources.sources.get(&entry.name) {
+                Some(existing) if self.update_if_exists && !reserved.contains(&entry.name) => {
+                    let source = if existing.url == url {
+                        existing.clone()
+                    } else {
+                        new
+                    };
+                    return Ok(Some((entry.name, source)));
+                }
+                _ => (),
+            }
+            let Some(name) = sources.resolve_collision(&entry.name, self.on_collision)? else {
+                return Ok(None);
+            };
+            sources.sources.insert(name.clone(), new.clone());
+            reserved.insert(name.clone());
+            Ok(Some((name, new)))
+        };
+        let work: Vec<_> = entries
+            .into_iter()
+            .map(plan)
+            .filter_map(Result::transpose)
+            .collect();
+        let results = parallel_map(ctx.jobs(self.jobs), work, |entry| -> anyhow::Result<_> {
+            let (name, source) = entry?;
+            log::info!("Adding {}", name);
+            let source = refresh_source(&ctx.client, &source, options)
+                .with_context(|| format!("failed to add {}", name))?;
+            Ok((name, source))
+        });
+        let mut failed = 0;
+        for result in results {
+            match result {
+                Ok((name, source)) => {
+                    reserved.remove(&name);
+                    sources.sources.insert(name, source);
+                }
+                Err(e) => {
+                    log::error!("{:#}", e);
+                    failed += 1;
+                }
+            }
         }
+        for name in reserved {
+            sources.sources.remove(&name);
+        }
+        // The sources that could be added are written out even if others failed.
+        ctx.write(&sources)?;
+        if failed > 0 {
+            anyhow::bail!("{} sources could not be added", failed);
+        }
+        Ok(())
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
-struct Source {
-    #[serde(skip_serializing_if = "Option::is_none", default)]
-    hash: Option<ssri::Integrity>,
-    url: url::Url,
-    #[serde(skip_serializing_if = "Option::is_none", default)]
-    last_modified: Option<DateTime<FixedOffset>>,
-    #[serde(skip_serializing_if = "Option::is_none", default)]
-    etag: Option<String>,
-    #[serde(rename = "type", skip_serializing_if = "Option::is_none", default)]
-    ty: Option<SourceType>,
+/// import sources from another tool's sources file
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "import")]
+struct ImportCommand {
+    /// format of the file, one of niv, npins or flake-lock
+    #[argh(option)]
+    format: ImportFormat,
+    /// the file to import
+    #[argh(positional)]
+    path: std::path::PathBuf,
+    /// what to do with sources whose name is taken: skip them, which is the default, error, or
+    /// suffix the name with -2, -3 and so on until it is free
+    #[argh(option, default = "OnCollision::Skip")]
+    on_collision: OnCollision,
 }
 
-impl Source {
-    fn new(url: url::Url) -> Self {
-        Self {
-            hash: None,
-            url,
-            last_modified: None,
-            etag: None,
-            ty: None,
+impl Command for ImportCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        ctx.ensure_writable()?;
+        check_nix_tools(ctx)?;
+        let imported = match self.format {
+            ImportFormat::Niv => niv::import(&self.path)?,
+            ImportFormat::Npins => npins::import(&self.path)?,
+            ImportFormat::FlakeLock => flake::import(&self.path)?,
+        };
+        let mut sources = ctx.load_or_default(true)?;
+        let mut skipped = 0;
+        for (name, source) in imported {
+            let Some(name) = sources.resolve_collision(&name, self.on_collision)? else {
+                skipped += 1;
+                continue;
+            };
+            match source {
+                Ok(source) => {
+                    if source.hash.is_none() {
+                        log::warn!("{}: imported without a hash, run update to fetch it", name);
+                    } else {
+                        log::info!("{}: imported", name);
+                    }
+                    sources.sources.insert(name, source);
+                }
+                Err(e) => {
+                    log::warn!("skipping {}: {:#}", name, e);
+                    skipped += 1;
+                }
+            }
         }
+        ctx.write(&sources)?;
+        if skipped > 0 {
+            log::warn!("{} sources could not be imported", skipped);
+        }
+        Ok(())
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Default)]
-struct Sources {
-    #[serde(default)]
-    sources: HashMap<String, Source>,
+/// change the version of a source with a url template, and fetch it
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "bump")]
+struct BumpCommand {
+    /// name of the source
+    #[argh(positional)]
+    name: String,
+    /// the new version
+    #[argh(positional)]
+    version: String,
 }
 
-trait Command {
-    fn execute(self, sources: std::path::PathBuf) -> anyhow::Result<()>;
+impl Command for BumpCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        ctx.ensure_online()?;
+        let mut sources = ctx.load(true)?;
+        let prefetcher = check_nix_tools(ctx)?;
+        let source = sources
+            .sources
+            .get_mut(&self.name)
+            .with_context(|| format!("source {} does not exist", self.name))?;
+        if source.url_template.is_none() {
+            anyhow::bail!("source {} has no url template", self.name);
+        }
+        log::info!(
+            "Bumping {} from {} to {}",
+            self.name,
+            source.version.as_deref().unwrap_or("unknown"),
+            self.version
+        );
+        let hash_type = ctx
+            .hash_type
+            .or_else(|| source.hash.as_ref().and_then(HashType::of));
+        let bumped = Source {
+            version: Some(self.version),
+            // The old validators and hash are for a different file.
+            hash: None,
+            etag: None,
+            last_modified: None,
+            ..source.clone()
+        };
+        let old = std::mem::replace(
+            source,
+            refresh_source(
+                &ctx.client,
+                &bumped,
+                RefreshOptions {
+                    hash_type,
+                    prefetcher,
+                    gateways: &ctx.ipfs_gateways,
+                    ..Default::default()
+                },
+            )?,
+        );
+        source.remember(&old);
+        report_pin(ctx, &self.name, source)?;
+        ctx.write(&sources)
+    }
 }
 
-/// add a source to the sources file
-#[derive(FromArgs, PartialEq, Debug)]
-#[argh(subcommand, name = "add")]
-struct AddCommand {
+/// change the url or type of a source, and fetch it again, or only change its description,
+/// homepage or license
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "set")]
+struct SetCommand {
     /// name of the source
     #[argh(positional)]
     name: String,
-    /// url of the source
-    #[argh(positional)]
-    url: url::Url,
-    /// type of the source, either tarball or file
+    /// the new url of the source, or path to a local file
+    #[argh(option)]
+    url: Option<String>,
+    /// the new type of the source, either tarball, file, git or path
     #[argh(option, short = 't', long = "type")]
     ty: Option<SourceType>,
+    /// the new branch, tag or revision to track, for git sources
+    #[argh(option)]
+    rev: Option<String>,
+    /// the new name of the source in the nix store
+    #[argh(option)]
+    store_name: Option<String>,
+    /// the new description of the source, removed if empty
+    #[argh(option)]
+    description: Option<String>,
+    /// the new home page of the source, removed if empty
+    #[argh(option)]
+    homepage: Option<String>,
+    /// the new license of the source, removed if empty
+    #[argh(option)]
+    license: Option<String>,
+    /// the new group of the source, removed from its group if empty
+    #[argh(option)]
+    group: Option<String>,
+    /// the new attribute path of the package in nixpkgs the source is a version of, back to
+    /// the name of the source if empty
+    #[argh(option)]
+    nixpkgs: Option<String>,
 }
 
-fn sanitize_file_name(name: &str) -> String {
-    let mut out = String::new();
-    let mut chars = name.chars();
-    if let Some(c) = chars.next() {
-        if c == '.' {
-            out.push('_')
-        } else {
-            out.push(c)
-        }
-    } else {
-        return "source".to_string();
+/// Replace `field` with `value` if given, or remove it if `value` is empty.
+fn set_metadata(field: &mut Option<String>, value: Option<String>) {
+    if let Some(value) = value {
+        *field = (!value.is_empty()).then_some(value);
     }
-    out.extend(chars.map(|c| match c {
-        '0'..='9' | 'a'..='z' | 'A'..='Z' | '+' | '-' | '.' | '_' | '?' | '=' => c,
-        _ => '_',
-    }));
-    out
 }
 
-fn refresh_source(source: &Source) -> anyhow::Result<Source> {
-    let req = ureq::head(source.url.as_str());
-    let req = if source.hash.is_some() {
-        let req = if let Some(etag) = &source.etag {
-            req.set("If-None-Match", etag)
-        } else {
-            req
+impl Command for SetCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        // Without anything to refetch, like when only the description changes, the source
+        // stays as it was fetched.
+        let refetch = self.url.is_some()
+            || self.ty.is_some()
+            || self.rev.is_some()
+            || self.store_name.is_some()
+            || (self.description.is_none()
+                && self.homepage.is_none()
+                && self.license.is_none()
+                && self.group.is_none()
+                && self.nixpkgs.is_none());
+        if let Some(store_name) = &self.store_name {
+            check_store_name(store_name)?;
+        }
+        if refetch {
+            ctx.ensure_online()?;
+        }
+        let mut sources = ctx.load(true)?;
+        let source = sources
+            .sources
+            .get_mut(&self.name)
+            .with_context(|| format!("source {} does not exist", self.name))?;
+        set_metadata(&mut source.description, self.description);
+        set_metadata(&mut source.license, self.license);
+        set_metadata(&mut source.group, self.group);
+        set_metadata(&mut source.nixpkgs, self.nixpkgs);
+        if let Some(homepage) = self.homepage {
+            source.homepage = match homepage.as_str() {
+                "" => None,
+                url => Some(url.parse().context("invalid homepage")?),
+            };
+        }
+        if !refetch {
+            return ctx.write(&sources);
+        }
+        let prefetcher = check_nix_tools(ctx)?;
+        let mut changed = Source {
+            // The hash may not be right for the new url or type.
+            hash: None,
+            ..source.clone()
         };
-        if let Some(last_modified) = &source.last_modified {
-            let time = last_modified.to_rfc2822();
-            assert!(time.ends_with(" +0000"));
-            let time = &time[..time.len() - 6];
-            let time = format!("{} GMT", time);
-            req.set("If-Modified-Since", &time)
-        } else {
-            req
+        if let Some(url) = &self.url {
+            if source.follows_upstream() {
+                anyhow::bail!(
+                    "{} follows a release or package, its url can't be set",
+                    self.name
+                );
+            }
+            changed.url = parse_source_url(url)?;
+            // An explicit url replaces the template, and everything learned about the old url.
+            changed.url_template = None;
+            changed.etag = None;
+            changed.last_modified = None;
+            changed.resolved_url = None;
+            changed.fetched_from = None;
+            changed.redirects = Vec::new();
+            changed.size = None;
         }
-    } else {
-        req
-    };
-    let res = req.call()?;
-    if res.status() == 304 {
-        println!("\tnot modified");
-        return Ok(source.clone());
-    }
-    let etag = res.header("ETag").and_then(|s| {
-        if s.starts_with("W/") {
-            None
-        } else {
-            Some(s.to_string())
+        if let Some(ty) = self.ty {
+            changed.ty = Some(ty);
         }
-    });
-    let last_modified = res
-        .header("Last-Modified")
-        .and_then(|s| DateTime::parse_from_rfc2822(s).ok());
-    let filename = res
-        .header("Content-Disposition")
-        .and_then(|s| {
-            mailparse::parse_content_disposition(s)
-                .params
-                .get("filename")
-                .map(|s| s.to_string())
-        })
-        .or_else(|| {
-            source
-                .url
-                .path_segments()
-                .into_iter()
-                .flatten()
-                .last()
-                .map(|s| s.to_string())
-        });
-    println!("{last_modified:?} {etag:?} {filename:?}");
-    let ty = if let Some(ty) = source.ty {
-        ty
-    } else if let Some(filename) = &filename {
-        let filename = std::path::Path::new(&filename);
-        let ext = filename.extension().unwrap_or_default();
-        let stem = std::path::Path::new(filename.file_stem().unwrap_or_default());
-        let ext2 = stem.extension().unwrap_or_default();
-        if ext == "zip" || ext == "tgz" || ext2 == "tar" {
-            SourceType::Tarball
-        } else {
-            SourceType::File
+        if changed.ty != Some(SourceType::Git) {
+            if self.rev.is_some() {
+                anyhow::bail!("--rev can only be used with git sources");
+            }
+            changed.git_ref = None;
+            changed.rev = None;
+        } else if self.rev.is_some() {
+            changed.git_ref = self.rev;
+            changed.rev = None;
         }
-    } else {
-        SourceType::File
-    };
-    let mut command = std::process::Command::new("nix-prefetch-url");
+        if self.store_name.is_some() {
+            changed.store_name = self.store_name;
+        }
+        log::info!("Updating {}", self.name);
+        let hash_type = ctx
+            .hash_type
+            .or_else(|| source.hash.as_ref().and_then(HashType::of));
+        let old = std::mem::replace(
+            source,
+            refresh_source(
+                &ctx.client,
+                &changed,
+                RefreshOptions {
+                    hash_type,
+                    prefetcher,
+                    // The stored validators say nothing about the hash under the new settings.
+                    force: true,
+                    gateways: &ctx.ipfs_gateways,
+                    ..Default::default()
+                },
+            )?,
+        );
+        source.remember(&old);
+        report_pin(ctx, &self.name, source)?;
+        ctx.write(&sources)
+    }
+}
+
+/// go back to how a source was pinned before it was last updated, or before the n-th last
+/// update, without fetching it
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "rollback")]
+struct RollbackCommand {
+    /// name of the source
+    #[argh(positional)]
+    name: String,
+    /// how many updates to go back, 1 by default
+    #[argh(positional, default = "1")]
+    n: usize,
+    /// list the previous pins of the source instead
+    #[argh(switch)]
+    list: bool,
+}
+
+impl Command for RollbackCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        let mut sources = ctx.load(!self.list)?;
+        let source = sources
+            .sources
+            .get_mut(&self.name)
+            .with_context(|| format!("source {} does not exist", self.name))?;
+        if self.list {
+            if ctx.json(false) {
+                return print_json(&source.history);
+            }
+            for (i, pin) in source.history.iter().enumerate() {
+                let version = pin.version.as_deref().or(pin.rev.as_deref());
+                println!(
+                    "{}: {} {} {}, until {}",
+                    i + 1,
+                    version.unwrap_or("-"),
+                    pin.hash,
+                    pin.url,
+                    pin.until.to_rfc3339()
+                );
+            }
+            return Ok(());
+        }
+        let from = describe(source);
+        source.rollback(self.n)?;
+        log::info!(
+            "Rolled {} back from {} to {}",
+            self.name,
+            from,
+            describe(source)
+        );
+        ctx.write(&sources)
+    }
+}
+
+/// pin a source, so it is skipped when updating all sources unless --include-pinned is given
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "pin")]
+struct PinCommand {
+    /// name of the source
+    #[argh(positional)]
+    name: String,
+}
+
+/// unpin a source
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "unpin")]
+struct UnpinCommand {
+    /// name of the source
+    #[argh(positional)]
+    name: String,
+}
+
+fn set_pinned(ctx: &Global, name: &str, pinned: bool) -> anyhow::Result<()> {
+    let mut sources = ctx.load(true)?;
+    sources
+        .sources
+        .get_mut(name)
+        .with_context(|| format!("source {} does not exist", name))?
+        .pinned = pinned;
+    ctx.write(&sources)
+}
+
+impl Command for PinCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        set_pinned(ctx, &self.name, true)
+    }
+}
 
-    let store_name = filename
-        .map(|s| sanitize_file_name(&s))
-        .unwrap_or("source".to_owned());
-    command.args(["--name", &store_name]);
-    if ty == SourceType::Tarball {
-        command.arg("--unpack");
+impl Command for UnpinCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        set_pinned(ctx, &self.name, false)
     }
+}
 
-    command.arg(source.url.as_str());
-    command.stderr(std::process::Stdio::inherit());
-    let output = command.output()?;
-    println!("{:?}", output.stdout);
+/// freeze a source, so update leaves it alone even when it is named, unless --force is given
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "freeze")]
+struct FreezeCommand {
+    /// name of the source
+    #[argh(positional)]
+    name: String,
+}
 
-    let hash = if output.stdout.ends_with(b"\n") {
-        &output.stdout[..output.stdout.len() - 1]
-    } else {
-        &output.stdout
-    };
-    let hash = std::ffi::OsStr::from_bytes(hash);
-    let hash = std::process::Command::new("nix")
-        .args(["hash", "to-sri", "--type", "sha256"])
-        .arg(hash)
-        .output()?
-        .stdout;
-    let hash = String::from_utf8(hash)?.trim().to_owned();
-    println!("{:?}", hash);
-    Ok(Source {
-        hash: Some(hash.parse()?),
-        url: source.url.clone(),
-        last_modified,
-        etag,
-        ty: Some(ty),
-    })
+/// unfreeze a source
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "unfreeze")]
+struct UnfreezeCommand {
+    /// name of the source
+    #[argh(positional)]
+    name: String,
 }
 
-impl Command for AddCommand {
-    fn execute(self, sources: std::path::PathBuf) -> anyhow::Result<()> {
-        let (mut file, mut sources): (_, Sources) = if !sources.exists() {
-            let mut file = std::fs::File::create(&sources)?;
-            write!(file, "{{}}")?; // Make sure the file is valid JSON even if we fail.
-            (file, Default::default())
+fn set_frozen(ctx: &Global, name: &str, frozen: bool) -> anyhow::Result<()> {
+    let mut sources = ctx.load(true)?;
+    sources
+        .sources
+        .get_mut(name)
+        .with_context(|| format!("source {} does not exist", name))?
+        .frozen = frozen;
+    ctx.write(&sources)
+}
+
+impl Command for FreezeCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        set_frozen(ctx, &self.name, true)
+    }
+}
+
+impl Command for UnfreezeCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        set_frozen(ctx, &self.name, false)
+    }
+}
+
+/// check which sources changed upstream without fetching them or writing the sources file,
+/// exiting with status 2 if any did
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "outdated")]
+struct OutdatedCommand {
+    /// name of the source
+    #[argh(positional)]
+    name: Option<String>,
+    /// number of sources to check concurrently, 4 by default
+    #[argh(option, short = 'j')]
+    jobs: Option<usize>,
+}
+
+impl Command for OutdatedCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        ctx.ensure_online()?;
+        let sources = ctx.load(false)?;
+        let work: Vec<(String, Source)> = if let Some(name) = &self.name {
+            let source = sources
+                .sources
+                .get(name)
+                .with_context(|| format!("source {} does not exist", name))?;
+            vec![(name.clone(), source.clone())]
         } else {
-            let file = std::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&sources)?;
-            let sources = serde_json::from_reader(&file)?;
-            (file, sources)
+            sources
+                .sources
+                .into_iter()
+                .filter(|(_, source)| !source.pinned && !source.frozen)
+                .collect()
         };
-        if sources.sources.contains_key(&self.name) {
-            anyhow::bail!("source {} already exists", self.name);
-        }
-        println!("Adding {}", self.name);
-        let source = refresh_source(&Source::new(self.url))?;
-        sources.sources.insert(self.name, source);
-        file.seek(std::io::SeekFrom::Start(0))?;
-        file.set_len(0)?;
-        serde_json::to_writer_pretty(file, &sources)?;
+        let json = ctx.json(false);
+        if json {
+            ctx.quiet();
+        }
+        let results = parallel_map(ctx.jobs(self.jobs), work, |(name, source)| {
+            let freshness = check_freshness(&ctx.client, &source)
+                .with_context(|| format!("failed to check {}", name));
+            (name, freshness)
+        });
+        let mut outdated = 0;
+        let mut failed = 0;
+        let mut summary = serde_json::Map::new();
+        for (name, result) in results {
+            let (status, reason) = match &result {
+                Ok(Freshness::UpToDate) => ("up-to-date", None),
+                Ok(Freshness::Outdated(reason)) => {
+                    outdated += 1;
+                    ("outdated", Some(reason.clone()))
+                }
+                Ok(Freshness::Unknown(reason)) => ("unknown", Some(reason.clone())),
+                Err(e) => {
+                    log::error!("{:#}", e);
+                    failed += 1;
+                    ("error", Some(format!("{:#}", e)))
+                }
+            };
+            if json {
+                let status = serde_json::json!({ "status": status, "reason": reason });
+                summary.insert(name, status);
+                continue;
+            }
+            match result {
+                Ok(Freshness::UpToDate) => println!("{}: up to date", name),
+                Ok(Freshness::Outdated(reason)) => println!("{}: outdated, {}", name, reason),
+                Ok(Freshness::Unknown(reason)) => println!("{}: unknown, {}", name, reason),
+                Err(_) => (),
+            }
+        }
+        if json {
+            print_json(&summary)?;
+        }
+        if failed > 0 {
+            anyhow::bail!(
+                "{} sources are out of date, {} could not be checked",
+                outdated,
+                failed
+            );
+        }
+        if outdated > 0 {
+            return Err(OutOfDate(format!("{} sources are out of date", outdated)).into());
+        }
         Ok(())
     }
 }
 
-/// update sources in the sources file
-#[derive(FromArgs, PartialEq, Debug)]
-#[argh(subcommand, name = "update")]
-struct UpdateCommand {
+/// compare the versions of sources with those of the same packages in nixpkgs
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "compare-nixpkgs")]
+struct CompareNixpkgsCommand {
+    /// names of the sources to compare, all sources with a version by default
+    #[argh(positional)]
+    names: Vec<String>,
+    /// the Nix channel whose nixpkgs to compare with
+    #[argh(option, default = "String::from(\"nixos-unstable\")")]
+    channel: String,
+}
+
+impl Command for CompareNixpkgsCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        ctx.ensure_online()?;
+        let sources = ctx.load(false)?;
+        for name in &self.names {
+            if !sources.sources.contains_key(name) {
+                anyhow::bail!("source {} does not exist", name);
+            }
+        }
+        let mut attrs = std::collections::BTreeMap::new();
+        for (name, source) in &sources.sources {
+            if !self.names.is_empty() && !self.names.contains(name) {
+                continue;
+            }
+            if source.version.is_none() {
+                log::info!("{}: no version to compare", name);
+                continue;
+            }
+            let attr = source.nixpkgs.clone().unwrap_or_else(|| name.clone());
+            attrs.insert(name.clone(), attr);
+        }
+        let theirs = nixpkgs::versions(&self.channel, &attrs)?;
+        // With JSON output, an object mapping each source to its version, that of nixpkgs, and
+        // whether it is older, newer, the same, or missing from nixpkgs.
+        let json = ctx.json(false);
+        let mut summary = serde_json::Map::new();
+        for name in attrs.keys() {
+            let ours = sources.sources[name].version.as_deref().unwrap();
+            // Tags of releases are often the version with a v in front.
+            let version = ours
+                .strip_prefix('v')
+                .filter(|v| v.starts_with(|c: char| c.is_ascii_digit()))
+                .unwrap_or(ours);
+            let theirs = theirs.get(name);
+            let status = match theirs.map(|theirs| version::compare(version, theirs)) {
+                None => "missing",
+                Some(std::cmp::Ordering::Less) => "older",
+                Some(std::cmp::Ordering::Equal) => "same",
+                Some(std::cmp::Ordering::Greater) => "newer",
+            };
+            if json {
+                let status = serde_json::json!({
+                    "status": status,
+                    "version": ours,
+                    "nixpkgs": theirs,
+                });
+                summary.insert(name.clone(), status);
+                continue;
+            }
+            match theirs {
+                None => println!("{}: {}, not in nixpkgs", name, ours),
+                Some(_) if status == "same" => {
+                    println!("{}: {}, the same as nixpkgs", name, ours)
+                }
+                Some(theirs) => {
+                    println!("{}: {}, {} than {} in nixpkgs", name, ours, status, theirs)
+                }
+            }
+        }
+        if json {
+            print_json(&summary)?;
+        }
+        Ok(())
+    }
+}
+
+/// check that the stored hashes still match what upstream serves
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "verify")]
+struct VerifyCommand {
     /// name of the source
     #[argh(positional)]
     name: Option<String>,
+    /// number of sources to verify concurrently, 4 by default
+    #[argh(option, short = 'j')]
+    jobs: Option<usize>,
+    /// count sources a binary cache has with their hash as verified, without downloading them
+    #[argh(switch)]
+    substitute: bool,
+    /// only check that the sources are in the local nix store with their hash, without
+    /// network access
+    #[argh(switch)]
+    local: bool,
 }
 
-impl Command for UpdateCommand {
-    fn execute(self, sources: std::path::PathBuf) -> anyhow::Result<()> {
-        let mut file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&sources)?;
-        let mut sources: Sources = serde_json::from_reader(&file)?;
-        let sources_mut: Vec<(&str, &mut Source)> = if let Some(name) = &self.name {
-            vec![(
-                &name,
-                sources
-                    .sources
-                    .get_mut(name)
-                    .with_context(|| format!("source {} does not exist", name))?,
-            )]
+/// Result of verifying a source.
+enum Verification {
+    /// The source has no hash to verify.
+    Skipped,
+    Ok,
+    /// With --local, the source isn't in the store with its hash.
+    Missing,
+    /// The stored hash, and the one of what upstream serves.
+    Mismatch(ssri::Integrity, Option<ssri::Integrity>),
+}
+
+impl Command for VerifyCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        if self.local && self.substitute {
+            anyhow::bail!("--local and --substitute are mutually exclusive");
+        }
+        if !self.local {
+            ctx.ensure_online()?;
+        }
+        let sources = ctx.load(false)?;
+        let prefetcher = if self.local {
+            Default::default()
         } else {
-            sources
+            check_nix_tools(ctx)?
+        };
+        let work: Vec<(String, Source)> = if let Some(name) = &self.name {
+            let source = sources
                 .sources
-                .iter_mut()
-                .map(|(k, v)| (k.as_str(), v))
-                .collect()
+                .get(name)
+                .with_context(|| format!("source {} does not exist", name))?;
+            vec![(name.clone(), source.clone())]
+        } else {
+            sources.sources.into_iter().collect()
         };
-        for (name, source) in sources_mut {
-            println!("Updating {}", name);
-            let new_source = refresh_source(source)?;
-            source.hash = new_source.hash;
-            source.last_modified = new_source.last_modified;
-            source.etag = new_source.etag;
-        }
-        file.seek(std::io::SeekFrom::Start(0))?;
-        file.set_len(0)?;
-        serde_json::to_writer_pretty(file, &sources)?;
+        let options = RefreshOptions {
+            force: true,
+            prefetcher,
+            substituters: if self.substitute {
+                &ctx.substituters
+            } else {
+                &[]
+            },
+            gateways: &ctx.ipfs_gateways,
+            ..Default::default()
+        };
+        let json = ctx.json(false);
+        if json {
+            ctx.quiet();
+        }
+        let verify = |name: &str, source: Source| -> anyhow::Result<Verification> {
+            let Some(hash) = source.hash.clone() else {
+                return Ok(Verification::Skipped);
+            };
+            if self.local {
+                for path in store_paths(&source) {
+                    if store::is_valid(&path)
+                        .with_context(|| format!("failed to verify {}", name))?
+                    {
+                        log::debug!("{}: {} is valid", name, path.display());
+                        return Ok(Verification::Ok);
+                    }
+                }
+                return Ok(Verification::Missing);
+            }
+            // Verify the revision or release that was hashed, not whatever is the latest now.
+            let source = Source {
+                git_ref: source.rev.clone().or(source.git_ref),
+                // Without the release it follows, the asset of a github-release source is a
+                // plain file.
+                ty: match source.ty {
+                    Some(SourceType::GithubRelease) => Some(SourceType::File),
+                    ty => ty,
+                },
+                github: None,
+                github_branch: None,
+                repository: None,
+                package: None,
+                ..source
+            };
+            let new_hash = refresh_source(&ctx.client, &source, options)
+                .with_context(|| format!("failed to verify {}", name))?
+                .hash;
+            if new_hash.as_ref() == Some(&hash) {
+                Ok(Verification::Ok)
+            } else {
+                Ok(Verification::Mismatch(hash, new_hash))
+            }
+        };
+        let results = parallel_map(ctx.jobs(self.jobs), work, |(name, source)| {
+            let result = verify(&name, source);
+            (name, result)
+        });
+        let mut mismatched = 0;
+        let mut failed = 0;
+        let mut summary = serde_json::Map::new();
+        let hash = |h: &Option<ssri::Integrity>| h.as_ref().map(|h| h.to_string());
+        for (name, result) in results {
+            let status = match &result {
+                Ok(Verification::Skipped) => serde_json::json!({ "status": "skipped" }),
+                Ok(Verification::Ok) => serde_json::json!({ "status": "ok" }),
+                Ok(Verification::Missing) => {
+                    failed += 1;
+                    serde_json::json!({ "status": "missing" })
+                }
+                Ok(Verification::Mismatch(stored, upstream)) => {
+                    mismatched += 1;
+                    serde_json::json!({
+                        "status": "mismatch",
+                        "stored": stored.to_string(),
+                        "upstream": hash(upstream),
+                    })
+                }
+                Err(e) => {
+                    log::error!("{:#}", e);
+                    failed += 1;
+                    serde_json::json!({ "status": "error", "error": format!("{:#}", e) })
+                }
+            };
+            if json {
+                summary.insert(name, status);
+                continue;
+            }
+            match result {
+                Ok(Verification::Skipped) => println!("{}: no hash, skipped", name),
+                Ok(Verification::Ok) => println!("{}: ok", name),
+                Ok(Verification::Missing) => println!("{}: not in the store", name),
+                Ok(Verification::Mismatch(stored, upstream)) => println!(
+                    "{}: MISMATCH, stored {}, upstream {}",
+                    name,
+                    stored,
+                    hash(&upstream).as_deref().unwrap_or("no hash")
+                ),
+                Err(_) => (),
+            }
+        }
+        if json {
+            print_json(&summary)?;
+        }
+        if mismatched > 0 || failed > 0 {
+            anyhow::bail!(
+                "{} sources do not match their hash, {} could not be verified",
+                mismatched,
+                failed
+            );
+        }
         Ok(())
     }
 }
 
-/// delete a source from the sources file
+/// list the sources in the sources file
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "ls")]
+struct ListCommand {
+    /// only list sources whose name matches this glob
+    #[argh(positional)]
+    pattern: Option<String>,
+    /// only list sources of this type
+    #[argh(option, short = 't', long = "type")]
+    ty: Option<SourceType>,
+    /// only list sources in this group
+    #[argh(option)]
+    group: Option<String>,
+    /// print the sources as JSON
+    #[argh(switch)]
+    json: bool,
+    /// print only the names of the sources, one per line
+    #[argh(switch)]
+    names: bool,
+    /// also print the description, homepage and license of the sources, with descriptions cut
+    /// short to keep the table narrow
+    #[argh(switch, short = 'l')]
+    long: bool,
+}
+
+/// How many characters of a description `ls --long` shows.
+const DESCRIPTION_WIDTH: usize = 40;
+
+/// `description` cut short to [`DESCRIPTION_WIDTH`] characters, ending with `...` if it was.
+fn truncate(description: &str) -> String {
+    if description.chars().count() <= DESCRIPTION_WIDTH {
+        return description.to_owned();
+    }
+    let mut short: String = description.chars().take(DESCRIPTION_WIDTH - 3).collect();
+    short.push_str("...");
+    short
+}
+
+impl Command for ListCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        // No sources file is just an empty set of sources.
+        if !ctx.sources.exists() {
+            return Ok(());
+        }
+        let pattern = self
+            .pattern
+            .as_deref()
+            .map(glob::Pattern::new)
+            .transpose()?;
+        let sources = ctx.load(false)?;
+        let sources: Vec<_> = sources
+            .sources
+            .into_iter()
+            .filter(|(name, source)| {
+                pattern.as_ref().is_none_or(|p| p.matches(name))
+                    && self.ty.is_none_or(|ty| source.ty == Some(ty))
+                    && self
+                        .group
+                        .as_ref()
+                        .is_none_or(|g| source.group.as_ref() == Some(g))
+            })
+            .collect();
+        if self.names {
+            for (name, _) in &sources {
+                println!("{}", name);
+            }
+            return Ok(());
+        }
+        if ctx.json(self.json) {
+            let entries: Vec<_> = sources
+                .iter()
+                .map(|(name, source)| {
+                    serde_json::json!({
+                        "name": name,
+                        "url": source.url,
+                        "type": source.ty,
+                        "hash": source.hash.is_some(),
+                        "version": source.version,
+                        "description": source.description,
+                        "homepage": source.homepage,
+                        "license": source.license,
+                        "group": source.group,
+                    })
+                })
+                .collect();
+            return print_json(&entries);
+        }
+        let rows: Vec<_> = sources
+            .iter()
+            .map(|(name, source)| {
+                let ty = source.ty.map_or("-".to_owned(), |ty| ty.to_string());
+                let version = source.version.as_deref().unwrap_or("-");
+                let hash = if source.hash.is_some() { "yes" } else { "no" };
+                let mut row = vec![name.clone(), ty, version.to_owned(), hash.to_owned()];
+                if self.long {
+                    let or_none = |field: Option<&str>| field.unwrap_or("-").to_owned();
+                    row.push(or_none(
+                        source.description.as_deref().map(truncate).as_deref(),
+                    ));
+                    row.push(or_none(source.homepage.as_ref().map(|h| h.as_str())));
+                    row.push(or_none(source.license.as_deref()));
+                }
+                // The url is last, as it is the longest.
+                row.push(source.url.to_string());
+                row
+            })
+            .collect();
+        let mut header = vec!["NAME", "TYPE", "VERSION", "HASH"];
+        if self.long {
+            header.extend(["DESCRIPTION", "HOMEPAGE", "LICENSE"]);
+        }
+        header.push("URL");
+        let header: Vec<_> = header.into_iter().map(str::to_owned).collect();
+        let mut widths: Vec<_> = header.iter().map(|h| h.chars().count()).collect();
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        for row in std::iter::once(&header).chain(&rows) {
+            let (last, cells) = row.split_last().unwrap();
+            let mut line = String::new();
+            for (cell, width) in cells.iter().zip(&widths) {
+                line.push_str(&format!("{:<width$}  ", cell));
+            }
+            line.push_str(last);
+            println!("{}", line);
+        }
+        Ok(())
+    }
+}
+
+/// rewrite the sources file in the form it is written in, with sources ordered by name
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "fmt")]
+struct FmtCommand {
+    /// only check that the sources file is formatted, failing if it isn't
+    #[argh(switch)]
+    check: bool,
+}
+
+impl Command for FmtCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        let sources = ctx.load(!self.check)?;
+        // Each file is compared with only its own sources, as included files are written back
+        // on their own.
+        let mut unformatted = Vec::new();
+        for (path, format, file) in sources.files(&ctx.sources, ctx.format) {
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            if text != format.serialize(&file, ctx.compact)? {
+                unformatted.push(path.display().to_string());
+            }
+        }
+        if unformatted.is_empty() {
+            return Ok(());
+        }
+        if self.check {
+            anyhow::bail!("{} is not formatted", unformatted.join(", "));
+        }
+        ctx.write(&sources)
+    }
+}
+
+/// upgrade the sources file to the current layout, converting hashes that aren't SRI
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "upgrade")]
+struct UpgradeCommand {}
+
+impl Command for UpgradeCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        ctx.ensure_writable()?;
+        ctx.lock(true)?;
+        // Read as a plain value, older files might not make sense as sources.
+        let mut value: serde_json::Value = std::fs::read_to_string(&ctx.sources)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| ctx.format.parse(&text))
+            .with_context(|| format!("failed to read {}", ctx.sources.display()))?;
+        let version = upgrade::upgrade(&mut value)
+            .with_context(|| format!("failed to upgrade {}", ctx.sources.display()))?;
+        let sources: Sources = serde_json::from_value(value)
+            .with_context(|| format!("failed to upgrade {}", ctx.sources.display()))?;
+        ctx.write(&sources)?;
+        if version < VERSION {
+            log::info!(
+                "Upgraded {} from version {} to {}",
+                ctx.sources.display(),
+                version,
+                VERSION
+            );
+        }
+        Ok(())
+    }
+}
+
+/// add garbage collector roots in .nix-source/gcroots next to the sources file for the store
+/// paths the sources were fetched to, so they aren't collected between builds
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "gc-roots")]
+struct GcRootsCommand {
+    /// remove the roots instead
+    #[argh(switch)]
+    remove: bool,
+}
+
+impl Command for GcRootsCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        let dir = store::roots_dir(&ctx.sources);
+        if self.remove {
+            return match std::fs::remove_dir_all(&dir) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                result => result.with_context(|| format!("failed to remove {}", dir.display())),
+            };
+        }
+        let sources = ctx.load(false)?;
+        store::sync_roots(&dir, &sources)
+    }
+}
+
+/// print a completion script for bash, zsh or fish
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "completions")]
+struct CompletionsCommand {
+    /// the shell to complete for
+    #[argh(positional)]
+    shell: completions::Shell,
+}
+
+impl Command for CompletionsCommand {
+    fn execute(self, _ctx: &Global) -> anyhow::Result<()> {
+        print!(
+            "{}",
+            completions::script(
+                self.shell,
+                env!("CARGO_BIN_NAME"),
+                &Options::get_args_info()
+            )
+        );
+        Ok(())
+    }
+}
+
+/// write man pages for every command, and a plain text reference, into a directory
 #[derive(FromArgs, PartialEq, Debug)]
-#[argh(subcommand, name = "rm")]
-struct DeleteCommand {
+struct GenerateManpagesCommand {
+    /// directory to write the pages into, created if it doesn't exist
+    #[argh(positional)]
+    dir: std::path::PathBuf,
+}
+
+impl GenerateManpagesCommand {
+    fn execute(self) -> anyhow::Result<()> {
+        let program = env!("CARGO_BIN_NAME");
+        let info = Options::get_args_info();
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        let pages = manpages::pages(program, &info).into_iter().chain([(
+            format!("{program}.txt"),
+            manpages::reference(program, &info),
+        )]);
+        for (name, page) in pages {
+            let path = self.dir.join(name);
+            std::fs::write(&path, page)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// print everything stored about a source
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "show")]
+struct ShowCommand {
     /// name of the source
     #[argh(positional)]
     name: String,
+    /// print the source as JSON
+    #[argh(switch)]
+    json: bool,
 }
 
-impl Command for DeleteCommand {
-    fn execute(self, sources: std::path::PathBuf) -> anyhow::Result<()> {
-        let mut file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&sources)?;
-        let mut sources: Sources = serde_json::from_reader(&file)?;
-        if sources.sources.remove(&self.name).is_none() {
-            anyhow::bail!("source {} does not exist", self.name);
-        }
-        file.seek(std::io::SeekFrom::Start(0))?;
-        file.set_len(0)?;
-        serde_json::to_writer_pretty(file, &sources)?;
+impl Command for ShowCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        let sources = ctx.load(false)?;
+        let source = sources
+            .sources
+            .get(&self.name)
+            .with_context(|| format!("source {} does not exist", self.name))?;
+        if ctx.json(self.json) {
+            return print_json(source);
+        }
+        println!("name: {}", self.name);
+        let serde_json::Value::Object(fields) = serde_json::to_value(source)? else {
+            unreachable!()
+        };
+        for (key, value) in fields {
+            // Spelled as words, like the store name below, which covers the stored one.
+            let key = key.replace('_', " ");
+            match value {
+                _ if key == "store name" => (),
+                serde_json::Value::String(value) => println!("{}: {}", key, value),
+                serde_json::Value::Array(values) if values.iter().all(|v| v.is_string()) => {
+                    let values: Vec<_> = values.iter().filter_map(|v| v.as_str()).collect();
+                    println!("{}: {}", key, values.join(", "));
+                }
+                value => println!("{}: {}", key, value),
+            }
+        }
+        // Without fetching the source, the file name can only come from its url. The server may
+        // still name it differently with Content-Disposition.
+        let url = source.resolved_url.as_ref().unwrap_or(&source.url);
+        let filename = url.path_segments().and_then(|mut s| s.next_back());
+        println!("store name: {}", store_name(source, filename));
         Ok(())
     }
 }
 
-#[derive(FromArgs, PartialEq, Debug)]
+/// print a single field of a source, like url, hash, type or version, failing if it isn't set
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "get")]
+struct GetCommand {
+    /// name of the source
+    #[argh(positional)]
+    name: String,
+    /// name of the field, as in the sources file
+    #[argh(positional)]
+    field: String,
+}
+
+impl Command for GetCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        let sources = ctx.load(false)?;
+        let source = sources
+            .sources
+            .get(&self.name)
+            .with_context(|| format!("source {} does not exist", self.name))?;
+        let serde_json::Value::Object(mut fields) = serde_json::to_value(source)? else {
+            unreachable!()
+        };
+        // Allow store-name for store_name, as options are spelled.
+        let value = fields
+            .remove(&self.field.replace('-', "_"))
+            .with_context(|| format!("{} has no {}", self.name, self.field))?;
+        if ctx.json(false) {
+            return print_json(&value);
+        }
+        match value {
+            serde_json::Value::String(value) => println!("{}", value),
+            value => println!("{}", value),
+        }
+        Ok(())
+    }
+}
+
+/// remove sources with the same url and hash as another source
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(subcommand, name = "dedupe")]
+struct DedupeCommand {
+    /// only list the duplicates, with JSON output as an object mapping each duplicate to the
+    /// source it duplicates
+    #[argh(switch)]
+    dry_run: bool,
+    /// remove the duplicates without asking for confirmation
+    #[argh(switch)]
+    apply: bool,
+}
+
+impl Command for DedupeCommand {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
+        let json = ctx.json(false);
+        if json && !self.dry_run && !self.apply {
+            anyhow::bail!(
+                "removals can't be confirmed with JSON output, pass --dry-run or --apply"
+            );
+        }
+        let mut sources = ctx.load(!self.dry_run)?;
+        let names: Vec<_> = sources.sources.keys().cloned().collect();
+        // Of each set of duplicates, the first name in sorted order is kept.
+        let mut kept = HashMap::new();
+        let mut duplicates = Vec::new();
+        let mut summary = serde_json::Map::new();
+        for name in names {
+            let source = &sources.sources[&name];
+            let Some(hash) = &source.hash else { continue };
+            let key = (source.url.clone(), hash.to_string());
+            if let Some(original) = kept.get(&key) {
+                if json {
+                    summary.insert(name.clone(), serde_json::json!(original));
+                } else {
+                    println!("{} duplicates {}", name, original);
+                }
+                duplicates.push(name);
+            } else {
+                kept.insert(key, name);
+            }
+        }
+        if json {
+            print_json(&summary)?;
+        }
+        if duplicates.is_empty() || self.dry_run {
+            return Ok(());
+        }
+        if !self.apply {
+            print!("Remove {} duplicate sources? [y/N] ", duplicates.len());
+            std::io::stdout().flush()?;
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            if !answer.trim().eq_ignore_ascii_case("y") {
+                return Ok(());
+            }
+        }
+        for name in duplicates {
+            sources.sources.remove(&name);
+        }
+        ctx.write(&sources)
+    }
+}
+
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
 #[argh(subcommand)]
+#[allow(clippy::large_enum_variant)] // Only ever constructed once.
 enum SubCommands {
     Add(AddCommand),
     Update(UpdateCommand),
     Delete(DeleteCommand),
+    Dedupe(DedupeCommand),
+    List(ListCommand),
+    Verify(VerifyCommand),
+    Outdated(OutdatedCommand),
+    CompareNixpkgs(CompareNixpkgsCommand),
+    Rename(RenameCommand),
+    EmitNix(EmitNixCommand),
+    Export(ExportCommand),
+    Generate(GenerateCommand),
+    Pin(PinCommand),
+    Rollback(RollbackCommand),
+    Unpin(UnpinCommand),
+    Freeze(FreezeCommand),
+    Unfreeze(UnfreezeCommand),
+    Bump(BumpCommand),
+    Set(SetCommand),
+    Import(ImportCommand),
+    Show(ShowCommand),
+    Get(GetCommand),
+    Prune(PruneCommand),
+    BatchAdd(BatchAddCommand),
+    Fmt(FmtCommand),
+    Upgrade(UpgradeCommand),
+    GcRoots(GcRootsCommand),
+    Completions(CompletionsCommand),
 }
 
 impl Command for SubCommands {
-    fn execute(self, sources: std::path::PathBuf) -> anyhow::Result<()> {
+    fn execute(self, ctx: &Global) -> anyhow::Result<()> {
         match self {
-            SubCommands::Add(cmd) => cmd.execute(sources),
-            SubCommands::Update(cmd) => cmd.execute(sources),
-            SubCommands::Delete(cmd) => cmd.execute(sources),
+            SubCommands::Add(cmd) => cmd.execute(ctx),
+            SubCommands::Update(cmd) => cmd.execute(ctx),
+            SubCommands::Delete(cmd) => cmd.execute(ctx),
+            SubCommands::Dedupe(cmd) => cmd.execute(ctx),
+            SubCommands::List(cmd) => cmd.execute(ctx),
+            SubCommands::Verify(cmd) => cmd.execute(ctx),
+            SubCommands::Outdated(cmd) => cmd.execute(ctx),
+            SubCommands::CompareNixpkgs(cmd) => cmd.execute(ctx),
+            SubCommands::Rename(cmd) => cmd.execute(ctx),
+            SubCommands::EmitNix(cmd) => cmd.execute(ctx),
+            SubCommands::Export(cmd) => cmd.execute(ctx),
+            SubCommands::Generate(cmd) => cmd.execute(ctx),
+            SubCommands::Pin(cmd) => cmd.execute(ctx),
+            SubCommands::Rollback(cmd) => cmd.execute(ctx),
+            SubCommands::Unpin(cmd) => cmd.execute(ctx),
+            SubCommands::Freeze(cmd) => cmd.execute(ctx),
+            SubCommands::Unfreeze(cmd) => cmd.execute(ctx),
+            SubCommands::Bump(cmd) => cmd.execute(ctx),
+            SubCommands::Set(cmd) => cmd.execute(ctx),
+            SubCommands::Import(cmd) => cmd.execute(ctx),
+            SubCommands::Show(cmd) => cmd.execute(ctx),
+            SubCommands::Get(cmd) => cmd.execute(ctx),
+            SubCommands::Prune(cmd) => cmd.execute(ctx),
+            SubCommands::BatchAdd(cmd) => cmd.execute(ctx),
+            SubCommands::Fmt(cmd) => cmd.execute(ctx),
+            SubCommands::Upgrade(cmd) => cmd.execute(ctx),
+            SubCommands::GcRoots(cmd) => cmd.execute(ctx),
+            SubCommands::Completions(cmd) => cmd.execute(ctx),
         }
     }
 }
 
 /// manipulate the sources.json file
-#[derive(FromArgs, PartialEq, Debug)]
+#[derive(FromArgs, ArgsInfo, PartialEq, Debug)]
+#[argh(
+    note = "Defaults for --sources, --prefetcher, --hash-type, --proxy, --timeout, \
+--prefetch-timeout, --retries and --max-wait, for --jobs, and for --pre-update and \
+--post-update of update can be set in ~/.config/nix-source/config.toml, or in a \
+.nix-source.toml in the project, which takes precedence, under the names of the options, like `timeout = 30`. With `gc-roots = true`, the \
+garbage collector roots of gc-roots are kept up to date whenever the sources file is written, \
+`substituters = [...]` replaces https://cache.nixos.org as the binary caches --substitute \
+looks sources up in, and `ipfs-gateways = [...]` replaces https://ipfs.io and https://dweb.link \
+as the gateways ipfs:// sources are fetched through, in order."
+)]
 struct Options {
-    #[argh(
-        option,
-        short = 's',
-        default = "std::path::PathBuf::from(\"sources.json\")"
-    )]
-    /// the sources.json file
-    sources: std::path::PathBuf,
+    #[argh(option, short = 's')]
+    /// the sources.json file, defaults to $NIX_SOURCE_FILE, then the config file, then the
+    /// closest sources.json or nix/sources.json in the current directory or its parents, or a
+    /// new sources.json if there is none
+    sources: Option<std::path::PathBuf>,
+    /// never modify the sources file, mutating commands fail instead
+    #[argh(switch)]
+    read_only: bool,
+    /// write a JSON sources file on a single line
+    #[argh(switch)]
+    compact: bool,
+    /// format of the sources file, json or toml. Defaults to toml for .toml files, json
+    /// otherwise
+    #[argh(option)]
+    format: Option<Format>,
+    /// don't lock the sources file. Without this, commands wait for each other so that changes
+    /// aren't lost
+    #[argh(switch)]
+    no_lock: bool,
+    /// fail instead of waiting when another command holds the lock on the sources file
+    #[argh(switch)]
+    no_wait: bool,
+    /// forbid network access and running the prefetchers. Commands that need them fail right
+    /// away
+    #[argh(switch)]
+    offline: bool,
+    /// fail if the sources file would be modified, instead of writing it
+    #[argh(switch)]
+    frozen: bool,
+    /// let commands modifying a sources file that can't be parsed start over from no sources,
+    /// with the file backed up next to it as .bak, instead of failing
+    #[argh(switch)]
+    force: bool,
+    /// print results as text, or as json
+    #[argh(option, default = "Output::Text")]
+    output: Output,
+    /// don't color the output, like when NO_COLOR is set or stdout isn't a terminal
+    #[argh(switch)]
+    no_color: bool,
+    /// tool to prefetch sources with: builtin, which downloads and hashes sources without nix,
+    /// or nix or nix-prefetch-url to add them to the store too. auto picks nix if it supports
+    /// `nix store prefetch-file`, then nix-prefetch-url, then builtin. Defaults to builtin
+    #[argh(option)]
+    prefetcher: Option<prefetch::Prefetcher>,
+    /// only print warnings and errors
+    #[argh(switch, short = 'q')]
+    quiet: bool,
+    /// print debugging information
+    #[argh(switch, short = 'v')]
+    verbose: bool,
+    /// hash algorithm for added and updated sources, sha256 or sha512. Defaults to the algorithm
+    /// of the existing hash when updating, sha256 otherwise
+    #[argh(option)]
+    hash_type: Option<HashType>,
+    /// maximum number of redirects to follow for a single request
+    #[argh(option, default = "5")]
+    max_redirects: usize,
+    /// seconds to wait for a server to respond. Defaults to 60
+    #[argh(option)]
+    timeout: Option<u64>,
+    /// seconds a tool prefetching a source may take, downloading included, before it is
+    /// killed. There is no limit by default
+    #[argh(option)]
+    prefetch_timeout: Option<u64>,
+    /// number of times to retry a request after a network error or server error. Defaults to 3
+    #[argh(option)]
+    retries: Option<usize>,
+    /// proxy to send all requests through, like http://proxy:3128, instead of the ones in
+    /// http_proxy, https_proxy and all_proxy. Hosts in no_proxy are still connected to directly
+    #[argh(option)]
+    proxy: Option<String>,
+    /// longest time in seconds to wait, in total, for servers that rate limit requests. Sources
+    /// that would need longer fail, and the others are still updated. Defaults to 60
+    #[argh(option)]
+    max_wait: Option<u64>,
+    /// certificate authorities to trust besides the usual ones, a PEM file or a directory of
+    /// them
+    #[argh(option)]
+    ca_file: Option<std::path::PathBuf>,
+    /// PEM file with a client certificate to authenticate with, requires --client-key
+    #[argh(option)]
+    client_cert: Option<std::path::PathBuf>,
+    /// PEM file with the private key of --client-cert
+    #[argh(option)]
+    client_key: Option<std::path::PathBuf>,
     #[argh(subcommand)]
     subcommand: SubCommands,
 }
 
-fn main() -> anyhow::Result<()> {
-    env_logger::init();
-    which::which("nix-prefetch-url").context("nix-prefetch-url not found")?;
-    which::which("nix").context("nix not found")?;
+/// Log progress as plain lines on stderr. RUST_LOG is honored, but --quiet and --verbose take
+/// precedence over it.
+fn init_logger(quiet: bool, verbose: bool) {
+    let mut builder = env_logger::Builder::new();
+    builder
+        .filter_level(log::LevelFilter::Info)
+        .parse_default_env()
+        .target(env_logger::Target::Pipe(Box::new(progress::Stderr)))
+        .format(|buf, record| match record.level() {
+            log::Level::Info => writeln!(buf, "{}", record.args()),
+            level => writeln!(buf, "{}: {}", level.as_str().to_lowercase(), record.args()),
+        });
+    if verbose {
+        builder.filter_level(log::LevelFilter::Debug);
+    } else if quiet {
+        builder.filter_level(log::LevelFilter::Warn);
+    }
+    builder.init();
+}
+
+/// Sources being out of date, which checks report with their own exit status so it can be told
+/// apart from failures.
+#[derive(Debug)]
+struct OutOfDate(String);
+
+impl std::fmt::Display for OutOfDate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for OutOfDate {}
+
+/// Exits with status 0 if the command succeeded, 2 if sources are out of date and 1 on any
+/// other error.
+fn main() -> std::process::ExitCode {
+    match run() {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            if e.is::<OutOfDate>() {
+                std::process::ExitCode::from(2)
+            } else {
+                std::process::ExitCode::FAILURE
+            }
+        }
+    }
+}
 
+fn run() -> anyhow::Result<()> {
+    // For packagers, not users, so it is left out of the help and completions. argh has no
+    // hidden subcommands, it is picked out before the rest are parsed.
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("generate-manpages") {
+        let rest: Vec<&str> = args[2..].iter().map(String::as_str).collect();
+        let command = match GenerateManpagesCommand::from_args(&[&args[0], &args[1]], &rest) {
+            Ok(command) => command,
+            Err(argh::EarlyExit { output, status }) => {
+                if status.is_ok() {
+                    println!("{output}");
+                    std::process::exit(0);
+                }
+                eprintln!("{output}");
+                std::process::exit(1);
+            }
+        };
+        return command.execute();
+    }
     let opts = argh::from_env::<Options>();
-    opts.subcommand.execute(opts.sources)?;
+    init_logger(opts.quiet, opts.verbose);
+    let defaults = config::Defaults::load()?;
+    let sources = opts
+        .sources
+        .or_else(|| std::env::var_os("NIX_SOURCE_FILE").map(Into::into))
+        .or(defaults.sources)
+        .or_else(|| Sources::find(&std::env::current_dir().ok()?))
+        // Nothing to find before the first source is added.
+        .unwrap_or_else(|| "sources.json".into());
+    let proxy = opts.proxy.or(defaults.proxy);
+    if let Some(proxy) = &proxy {
+        // For the nix tools and git too.
+        std::env::set_var("http_proxy", proxy);
+        std::env::set_var("https_proxy", proxy);
+    }
+    let ctx = Global {
+        format: opts.format.unwrap_or_else(|| Format::of(&sources)),
+        sources,
+        read_only: opts.read_only,
+        compact: opts.compact,
+        hash_type: opts.hash_type.or(defaults.hash_type),
+        prefetcher: opts.prefetcher.or(defaults.prefetcher),
+        client: http::Client::new(
+            opts.max_redirects,
+            opts.retries.or(defaults.retries).unwrap_or(3),
+            std::time::Duration::from_secs(opts.timeout.or(defaults.timeout).unwrap_or(60)),
+        )
+        .with_credentials(credentials::Credentials::load()?)
+        .with_proxy(proxy.as_deref())?
+        .with_max_wait(std::time::Duration::from_secs(
+            opts.max_wait.or(defaults.max_wait).unwrap_or(60),
+        ))
+        .with_prefetch_timeout(
+            opts.prefetch_timeout
+                .or(defaults.prefetch_timeout)
+                .map(std::time::Duration::from_secs),
+        )
+        .with_offline(opts.offline)
+        .with_tls(tls::config(
+            opts.ca_file.as_deref(),
+            opts.client_cert.as_deref(),
+            opts.client_key.as_deref(),
+        )?),
+        no_lock: opts.no_lock,
+        no_wait: opts.no_wait,
+        offline: opts.offline,
+        frozen: opts.frozen,
+        force: opts.force,
+        output: opts.output,
+        color: !opts.no_color
+            && std::env::var_os("NO_COLOR").is_none()
+            && std::io::stdout().is_terminal(),
+        jobs: defaults.jobs,
+        pre_update: defaults.pre_update,
+        gc_roots: defaults.gc_roots,
+        substituters: defaults
+            .substituters
+            .unwrap_or_else(|| vec!["https://cache.nixos.org".parse().unwrap()]),
+        ipfs_gateways: defaults.ipfs_gateways.unwrap_or_else(|| {
+            ipfs::DEFAULT_GATEWAYS
+                .iter()
+                .map(|gateway| gateway.parse().unwrap())
+                .collect()
+        }),
+        post_update: defaults.post_update,
+        lock: Default::default(),
+    };
+    opts.subcommand.execute(&ctx)?;
     Ok(())
 }
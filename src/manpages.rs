@@ -0,0 +1,240 @@
+//! Man pages and a plain text reference, generated from the definition of the command line, so
+//! they document every subcommand and flag of the binary they come with.
+//!
+//! The top-level command gets a page of its own, and every subcommand one named after the
+//! program and the subcommand, like `git` does, e.g. `nix-source-add(1)`.
+
+use argh_shared::{CommandInfoWithArgs, FlagInfo, FlagInfoKind, Optionality, PositionalInfo};
+use std::fmt::Write;
+
+/// A description with its lines joined, and the braces doubled in format strings undone.
+fn text(description: &str) -> String {
+    description
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace("{{", "{")
+        .replace("}}", "}")
+}
+
+/// Escape `s` for roff, where backslashes start escapes, dashes may be turned into hyphens, and
+/// lines starting with a dot or quote are requests.
+fn roff(s: &str) -> String {
+    let s = s.replace('\\', "\\e").replace('-', "\\-");
+    if s.starts_with(['.', '\'']) {
+        format!("\\&{s}")
+    } else {
+        s
+    }
+}
+
+fn flags<'a>(info: &'a CommandInfoWithArgs) -> impl Iterator<Item = &'a FlagInfo<'a>> {
+    info.flags.iter().filter(|flag| !flag.hidden)
+}
+
+fn positionals<'a>(info: &'a CommandInfoWithArgs) -> impl Iterator<Item = &'a PositionalInfo<'a>> {
+    info.positionals.iter().filter(|p| !p.hidden)
+}
+
+/// A positional argument as usage shows it, like `<name>` or `[<names...>]`.
+fn usage(positional: &PositionalInfo) -> String {
+    match positional.optionality {
+        Optionality::Required => format!("<{}>", positional.name),
+        Optionality::Optional => format!("[<{}>]", positional.name),
+        Optionality::Repeating | Optionality::Greedy => format!("[<{}...>]", positional.name),
+    }
+}
+
+/// A flag as it is spelled, with the value it takes.
+fn spelling(flag: &FlagInfo) -> String {
+    let mut out = match flag.short {
+        Some(short) => format!("-{short}, {}", flag.long),
+        None => flag.long.to_owned(),
+    };
+    if let FlagInfoKind::Option { arg_name } = flag.kind {
+        write!(out, " <{arg_name}>").unwrap();
+    }
+    out
+}
+
+/// The pages of `program`, whose command line is described by `info`, by file name.
+pub fn pages(program: &str, info: &CommandInfoWithArgs) -> Vec<(String, String)> {
+    let mut pages = Vec::new();
+    add_pages(&mut pages, &[program], info);
+    pages
+}
+
+fn add_pages(pages: &mut Vec<(String, String)>, path: &[&str], info: &CommandInfoWithArgs) {
+    pages.push((format!("{}.1", path.join("-")), page(path, info)));
+    for sub in &info.commands {
+        let mut path = path.to_vec();
+        path.push(sub.name);
+        add_pages(pages, &path, &sub.command);
+    }
+}
+
+/// The man page of the command at `path`, the program followed by its subcommands.
+fn page(path: &[&str], info: &CommandInfoWithArgs) -> String {
+    let name = path.join("-");
+    let mut out = String::new();
+    writeln!(
+        out,
+        ".TH \"{}\" 1 \"\" \"{} {}\" \"User Commands\"",
+        name.to_uppercase(),
+        path[0],
+        env!("CARGO_PKG_VERSION")
+    )
+    .unwrap();
+    writeln!(out, ".SH NAME").unwrap();
+    writeln!(out, "{} \\- {}", roff(&name), roff(&text(info.description))).unwrap();
+
+    writeln!(out, ".SH SYNOPSIS").unwrap();
+    writeln!(out, ".B {}", roff(&path.join(" "))).unwrap();
+    let mut synopsis = Vec::new();
+    if flags(info).next().is_some() {
+        synopsis.push("[options]".to_owned());
+    }
+    synopsis.extend(positionals(info).map(usage));
+    if !info.commands.is_empty() {
+        synopsis.push("<command> [<args>]".to_owned());
+    }
+    if !synopsis.is_empty() {
+        writeln!(out, "{}", roff(&synopsis.join(" "))).unwrap();
+    }
+
+    writeln!(out, ".SH DESCRIPTION").unwrap();
+    let mut description = text(info.description);
+    if let Some(first) = description.get_mut(..1) {
+        first.make_ascii_uppercase();
+    }
+    writeln!(out, "{}.", roff(description.trim_end_matches('.'))).unwrap();
+
+    if positionals(info).next().is_some() {
+        writeln!(out, ".SH ARGUMENTS").unwrap();
+        for positional in positionals(info) {
+            writeln!(out, ".TP").unwrap();
+            writeln!(out, "\\fI{}\\fR", roff(positional.name)).unwrap();
+            writeln!(out, "{}", roff(&text(positional.description))).unwrap();
+        }
+    }
+    if flags(info).next().is_some() {
+        writeln!(out, ".SH OPTIONS").unwrap();
+        for flag in flags(info) {
+            writeln!(out, ".TP").unwrap();
+            let mut spelling = match flag.short {
+                Some(short) => format!("\\fB\\-{short}\\fR, \\fB{}\\fR", roff(flag.long)),
+                None => format!("\\fB{}\\fR", roff(flag.long)),
+            };
+            if let FlagInfoKind::Option { arg_name } = flag.kind {
+                write!(spelling, " \\fI{}\\fR", roff(arg_name)).unwrap();
+            }
+            writeln!(out, "{spelling}").unwrap();
+            writeln!(out, "{}", roff(&text(flag.description))).unwrap();
+        }
+    }
+    if !info.commands.is_empty() {
+        writeln!(out, ".SH COMMANDS").unwrap();
+        for sub in &info.commands {
+            writeln!(out, ".TP").unwrap();
+            writeln!(out, "\\fB{}\\fR", roff(sub.name)).unwrap();
+            writeln!(out, "{}", roff(&text(sub.command.description))).unwrap();
+            writeln!(out, "See \\fB{}\\-{}\\fR(1).", roff(&name), roff(sub.name)).unwrap();
+        }
+    }
+    if !info.examples.is_empty() {
+        writeln!(out, ".SH EXAMPLES").unwrap();
+        for example in info.examples {
+            writeln!(out, ".nf").unwrap();
+            writeln!(
+                out,
+                "{}",
+                roff(&example.replace("{command_name}", &path.join(" ")))
+            )
+            .unwrap();
+            writeln!(out, ".fi").unwrap();
+        }
+    }
+    if !info.notes.is_empty() {
+        writeln!(out, ".SH NOTES").unwrap();
+        for note in info.notes {
+            writeln!(out, ".PP").unwrap();
+            writeln!(out, "{}", roff(&text(note))).unwrap();
+        }
+    }
+    if path.len() > 1 {
+        writeln!(out, ".SH SEE ALSO").unwrap();
+        let parent = path[..path.len() - 1].join("-");
+        writeln!(out, "\\fB{}\\fR(1)", roff(&parent)).unwrap();
+    }
+    out
+}
+
+/// Wrap `text` to `width` columns, indenting every line by `indent` spaces.
+fn wrap(out: &mut String, text: &str, indent: usize, width: usize) {
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && indent + line.len() + 1 + word.len() > width {
+            writeln!(out, "{:indent$}{line}", "").unwrap();
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        writeln!(out, "{:indent$}{line}", "").unwrap();
+    }
+}
+
+/// A plain text reference of every command of `program`, whose command line is described by
+/// `info`.
+pub fn reference(program: &str, info: &CommandInfoWithArgs) -> String {
+    let mut out = String::new();
+    add_reference(&mut out, &[program], info);
+    out
+}
+
+fn add_reference(out: &mut String, path: &[&str], info: &CommandInfoWithArgs) {
+    if !out.is_empty() {
+        writeln!(out).unwrap();
+    }
+    let mut usage_line = path.join(" ");
+    if flags(info).next().is_some() {
+        usage_line.push_str(" [options]");
+    }
+    for positional in positionals(info) {
+        write!(usage_line, " {}", usage(positional)).unwrap();
+    }
+    if !info.commands.is_empty() {
+        usage_line.push_str(" <command> [<args>]");
+    }
+    writeln!(out, "{usage_line}").unwrap();
+    writeln!(out).unwrap();
+    wrap(out, &text(info.description), 2, 80);
+    if positionals(info).next().is_some() {
+        writeln!(out).unwrap();
+        writeln!(out, "  Arguments:").unwrap();
+        for positional in positionals(info) {
+            writeln!(out, "    {}", positional.name).unwrap();
+            wrap(out, &text(positional.description), 8, 80);
+        }
+    }
+    if flags(info).next().is_some() {
+        writeln!(out).unwrap();
+        writeln!(out, "  Options:").unwrap();
+        for flag in flags(info) {
+            writeln!(out, "    {}", spelling(flag)).unwrap();
+            wrap(out, &text(flag.description), 8, 80);
+        }
+    }
+    for note in info.notes {
+        writeln!(out).unwrap();
+        wrap(out, &text(note), 2, 80);
+    }
+    for sub in &info.commands {
+        let mut path = path.to_vec();
+        path.push(sub.name);
+        add_reference(out, &path, &sub.command);
+    }
+}
@@ -0,0 +1,441 @@
+//! Hashing tarballs the way nix hashes them once unpacked, by serializing their contents to a NAR
+//! without unpacking them to disk, and local paths the way nix hashes them when adding them to
+//! the store.
+
+use crate::{Compression, HashType};
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Read, Seek, Write};
+
+/// A file in the unpacked tarball. The contents of regular files are kept in a spool file.
+#[derive(Clone)]
+enum Node {
+    Regular {
+        executable: bool,
+        offset: u64,
+        len: u64,
+    },
+    Symlink(Vec<u8>),
+    Directory(BTreeMap<Vec<u8>, Node>),
+}
+
+/// An archive [`hash_tarball`] can't unpack, like a zip file or a bzip2 compressed tarball,
+/// which the nix tools can.
+#[derive(Debug)]
+pub struct Unsupported;
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("only tar archives, optionally gzip, xz or zstd compressed, can be unpacked")
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// Hash the NAR of what the tarball read from `reader` unpacks to, like `nix-prefetch-url
+/// --unpack`. The tarball may be gzip, xz or zstd compressed, and must hold a single top-level
+/// file or directory, unless `strip_root` is unset and the directory holding it all is hashed
+/// instead. If `subdir` is given, only what is at that path in it is hashed.
+pub fn hash_tarball(
+    reader: impl Read,
+    hash_type: HashType,
+    strip_root: bool,
+    subdir: Option<&str>,
+) -> anyhow::Result<ssri::Integrity> {
+    let mut reader = std::io::BufReader::new(reader);
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(b"PK\x03\x04") || magic.starts_with(b"BZh") {
+        return Err(Unsupported.into());
+    }
+    let mut spool = tempfile::tempfile()?;
+    let root = match Compression::detect(magic) {
+        Some(Compression::Gzip) => {
+            read_tar(flate2::bufread::MultiGzDecoder::new(reader), &mut spool)?
+        }
+        Some(Compression::Xz) => {
+            // lzma-rs only decompresses into a writer, so the tarball goes through a file too.
+            let mut tar = tempfile::tempfile()?;
+            lzma_rs::xz_decompress(&mut reader, &mut tar)?;
+            tar.rewind()?;
+            read_tar(std::io::BufReader::new(tar), &mut spool)?
+        }
+        Some(Compression::Zstd) => {
+            read_tar(ruzstd::decoding::StreamingDecoder::new(reader)?, &mut spool)?
+        }
+        None => read_tar(reader, &mut spool)?,
+    };
+    let top = if strip_root {
+        let Node::Directory(mut entries) = root else {
+            unreachable!()
+        };
+        // Like nix, the single top level entry is the unpacked source.
+        if entries.len() != 1 {
+            anyhow::bail!(
+                "tarball has {} top-level entries instead of one",
+                entries.len()
+            );
+        }
+        entries.pop_first().unwrap().1
+    } else {
+        root
+    };
+    let top = match subdir {
+        Some(subdir) => lookup(&top, subdir.as_bytes())
+            .with_context(|| format!("tarball has no {}", subdir))?
+            .clone(),
+        None => top,
+    };
+    let mut hasher = ssri::IntegrityOpts::new().algorithm(hash_type.algorithm());
+    write_str(&mut hasher, b"nix-archive-1")?;
+    write_nar(&mut hasher, &top, &mut spool)?;
+    Ok(hasher.result())
+}
+
+/// Hash the NAR of the file or directory at `path`, like `nix hash path`.
+pub fn hash_path(path: &std::path::Path, hash_type: HashType) -> anyhow::Result<ssri::Integrity> {
+    let mut hasher = ssri::IntegrityOpts::new().algorithm(hash_type.algorithm());
+    write_str(&mut hasher, b"nix-archive-1")?;
+    write_path(&mut hasher, path)?;
+    Ok(hasher.result())
+}
+
+fn write_path(out: &mut impl Write, path: &std::path::Path) -> anyhow::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::PermissionsExt;
+    let metadata = std::fs::symlink_metadata(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    write_str(out, b"(")?;
+    write_str(out, b"type")?;
+    if metadata.is_symlink() {
+        write_str(out, b"symlink")?;
+        write_str(out, b"target")?;
+        write_str(out, std::fs::read_link(path)?.as_os_str().as_bytes())?;
+    } else if metadata.is_dir() {
+        write_str(out, b"directory")?;
+        let mut entries = std::fs::read_dir(path)
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        // Ordered by name, bytewise, as nix wants them.
+        entries.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        for name in entries {
+            write_str(out, b"entry")?;
+            write_str(out, b"(")?;
+            write_str(out, b"name")?;
+            write_str(out, name.as_bytes())?;
+            write_str(out, b"node")?;
+            write_path(out, &path.join(name))?;
+            write_str(out, b")")?;
+        }
+    } else if metadata.is_file() {
+        write_str(out, b"regular")?;
+        if metadata.permissions().mode() & 0o100 != 0 {
+            write_str(out, b"executable")?;
+            write_str(out, b"")?;
+        }
+        write_str(out, b"contents")?;
+        out.write_all(&metadata.len().to_le_bytes())?;
+        let mut file = std::fs::File::open(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let copied = std::io::copy(&mut file, out)?;
+        if copied != metadata.len() {
+            anyhow::bail!("{} changed while it was hashed", path.display());
+        }
+        write_padding(out, copied)?;
+    } else {
+        anyhow::bail!("{} is a device, fifo or socket", path.display());
+    }
+    write_str(out, b")")?;
+    Ok(())
+}
+
+/// Read a tar archive into a tree of [`Node`]s, copying file contents into `spool`.
+fn read_tar(mut reader: impl Read, spool: &mut std::fs::File) -> anyhow::Result<Node> {
+    let mut root = Node::Directory(BTreeMap::new());
+    // Overrides for the next entry, from GNU long name entries or pax extended headers.
+    let mut long_path = None;
+    let mut long_link = None;
+    let mut pax_size = None;
+    let mut header = Vec::with_capacity(512);
+    loop {
+        header.clear();
+        let read = (&mut reader).take(512).read_to_end(&mut header)?;
+        // Some tarballs leave out the end of archive blocks.
+        if read == 0 {
+            break;
+        }
+        if read < 512 {
+            anyhow::bail!("not a tar archive, or a truncated one");
+        }
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        check_checksum(&header)?;
+        let size = match pax_size.take() {
+            Some(size) => size,
+            None => parse_number(&header[124..136])?,
+        };
+        let path = match long_path.take() {
+            Some(path) => path,
+            None => header_path(&header),
+        };
+        let link = long_link
+            .take()
+            .unwrap_or_else(|| field(&header[157..257]).to_owned());
+        let mode = parse_number(&header[100..108])?;
+        let padded = size.div_ceil(512) * 512;
+        match header[156] {
+            b'L' | b'K' | b'x' => {
+                let mut data = Vec::new();
+                (&mut reader).take(padded).read_to_end(&mut data)?;
+                data.truncate(size as usize);
+                match header[156] {
+                    b'L' => long_path = Some(field(&data).to_owned()),
+                    b'K' => long_link = Some(field(&data).to_owned()),
+                    _ => {
+                        for (key, value) in pax_records(&data)? {
+                            match key {
+                                b"path" => long_path = Some(value.to_owned()),
+                                b"linkpath" => long_link = Some(value.to_owned()),
+                                b"size" => {
+                                    pax_size = Some(std::str::from_utf8(value)?.parse()?);
+                                }
+                                _ => (),
+                            }
+                        }
+                    }
+                }
+                continue;
+            }
+            b'0' | b'\0' | b'7' => {
+                let offset = spool.stream_position()?;
+                let copied = std::io::copy(&mut (&mut reader).take(size), spool)?;
+                if copied != size {
+                    anyhow::bail!("tarball ends in the middle of a file");
+                }
+                std::io::copy(&mut (&mut reader).take(padded - size), &mut std::io::sink())?;
+                let node = Node::Regular {
+                    executable: mode & 0o100 != 0,
+                    offset,
+                    len: size,
+                };
+                insert(&mut root, &path, node)?;
+            }
+            b'1' => {
+                let target = lookup(&root, &link)
+                    .with_context(|| {
+                        format!(
+                            "hard link {} to missing file {}",
+                            String::from_utf8_lossy(&path),
+                            String::from_utf8_lossy(&link)
+                        )
+                    })?
+                    .clone();
+                insert(&mut root, &path, target)?;
+            }
+            b'2' => insert(&mut root, &path, Node::Symlink(link))?,
+            b'5' => {
+                // Don't throw away what was already put in the directory.
+                if !matches!(lookup(&root, &path), Some(Node::Directory(_))) {
+                    insert(&mut root, &path, Node::Directory(BTreeMap::new()))?;
+                }
+            }
+            b'3' | b'4' | b'6' => anyhow::bail!(
+                "tarball contains a device or fifo, {}",
+                String::from_utf8_lossy(&path)
+            ),
+            // Global pax headers, volume labels and the like don't describe files.
+            _ => {
+                std::io::copy(&mut (&mut reader).take(padded), &mut std::io::sink())?;
+            }
+        }
+    }
+    Ok(root)
+}
+
+/// The contents of a NUL-padded header field.
+fn field(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+fn header_path(header: &[u8]) -> Vec<u8> {
+    let name = field(&header[0..100]);
+    // The ustar prefix, the name is split in two if it doesn't fit. GNU tar, whose magic is
+    // `ustar  `, has other fields there.
+    let prefix = if &header[257..263] == b"ustar\0" {
+        field(&header[345..500])
+    } else {
+        &[]
+    };
+    if prefix.is_empty() {
+        name.to_owned()
+    } else {
+        [prefix, b"/", name].concat()
+    }
+}
+
+/// Parse a numeric header field, in octal, or in base-256 for numbers too large for it.
+fn parse_number(bytes: &[u8]) -> anyhow::Result<u64> {
+    if bytes[0] & 0x80 != 0 {
+        return Ok(bytes[1..]
+            .iter()
+            .fold(u64::from(bytes[0] & 0x7f), |n, &b| (n << 8) | u64::from(b)));
+    }
+    let digits = std::str::from_utf8(field(bytes))?.trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).with_context(|| format!("invalid tar header field {digits:?}"))
+}
+
+fn check_checksum(header: &[u8]) -> anyhow::Result<()> {
+    let expected = parse_number(&header[148..156]).context("not a tar archive")?;
+    // The checksum is computed with the checksum field itself filled with spaces.
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                32
+            } else {
+                u64::from(b)
+            }
+        })
+        .sum();
+    if sum != expected {
+        anyhow::bail!("not a tar archive, or a corrupted one");
+    }
+    Ok(())
+}
+
+/// Parse the `LENGTH KEY=VALUE\n` records of a pax extended header.
+fn pax_records(mut data: &[u8]) -> anyhow::Result<Vec<(&[u8], &[u8])>> {
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        let space = data
+            .iter()
+            .position(|&b| b == b' ')
+            .context("invalid pax header")?;
+        let len: usize = std::str::from_utf8(&data[..space])?.parse()?;
+        if len <= space + 1 || len > data.len() {
+            anyhow::bail!("invalid pax header");
+        }
+        let record = &data[space + 1..len - 1];
+        let eq = record
+            .iter()
+            .position(|&b| b == b'=')
+            .context("invalid pax header")?;
+        records.push((&record[..eq], &record[eq + 1..]));
+        data = &data[len..];
+    }
+    Ok(records)
+}
+
+/// Split `path` into its components, leaving out `.` and empty ones.
+fn components(path: &[u8]) -> anyhow::Result<Vec<&[u8]>> {
+    let components: Vec<_> = path
+        .split(|&b| b == b'/')
+        .filter(|c| !c.is_empty() && *c != b".")
+        .collect();
+    if components.contains(&&b".."[..]) {
+        anyhow::bail!(
+            "tarball contains a path outside of it, {}",
+            String::from_utf8_lossy(path)
+        );
+    }
+    Ok(components)
+}
+
+/// Put `node` at `path` under `root`, creating the directories leading to it. Like when
+/// unpacking, later entries replace earlier ones.
+fn insert(root: &mut Node, path: &[u8], node: Node) -> anyhow::Result<()> {
+    let components = components(path)?;
+    let Some((name, parents)) = components.split_last() else {
+        // The top-level directory itself, usually `./`.
+        return Ok(());
+    };
+    let mut dir = root;
+    for parent in parents {
+        let Node::Directory(entries) = dir else {
+            unreachable!()
+        };
+        dir = entries
+            .entry(parent.to_vec())
+            .and_modify(|entry| {
+                if !matches!(entry, Node::Directory(_)) {
+                    *entry = Node::Directory(BTreeMap::new());
+                }
+            })
+            .or_insert_with(|| Node::Directory(BTreeMap::new()));
+    }
+    let Node::Directory(entries) = dir else {
+        unreachable!()
+    };
+    entries.insert(name.to_vec(), node);
+    Ok(())
+}
+
+fn lookup<'a>(root: &'a Node, path: &[u8]) -> Option<&'a Node> {
+    components(path)
+        .ok()?
+        .into_iter()
+        .try_fold(root, |node, name| match node {
+            Node::Directory(entries) => entries.get(name),
+            _ => None,
+        })
+}
+
+/// Write `bytes` as a NAR string: its length, then itself padded to a multiple of 8 bytes.
+fn write_str(out: &mut impl Write, bytes: &[u8]) -> std::io::Result<()> {
+    out.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    out.write_all(bytes)?;
+    write_padding(out, bytes.len() as u64)
+}
+
+fn write_padding(out: &mut impl Write, len: u64) -> std::io::Result<()> {
+    out.write_all(&[0; 8][..(len.next_multiple_of(8) - len) as usize])
+}
+
+fn write_nar(out: &mut impl Write, node: &Node, spool: &mut std::fs::File) -> anyhow::Result<()> {
+    write_str(out, b"(")?;
+    write_str(out, b"type")?;
+    match node {
+        Node::Regular {
+            executable,
+            offset,
+            len,
+        } => {
+            write_str(out, b"regular")?;
+            if *executable {
+                write_str(out, b"executable")?;
+                write_str(out, b"")?;
+            }
+            write_str(out, b"contents")?;
+            out.write_all(&len.to_le_bytes())?;
+            spool.seek(std::io::SeekFrom::Start(*offset))?;
+            std::io::copy(&mut (&mut *spool).take(*len), out)?;
+            write_padding(out, *len)?;
+        }
+        Node::Symlink(target) => {
+            write_str(out, b"symlink")?;
+            write_str(out, b"target")?;
+            write_str(out, target)?;
+        }
+        Node::Directory(entries) => {
+            write_str(out, b"directory")?;
+            // Entries are ordered by name, bytewise, as nix wants them.
+            for (name, node) in entries {
+                write_str(out, b"entry")?;
+                write_str(out, b"(")?;
+                write_str(out, b"name")?;
+                write_str(out, name)?;
+                write_str(out, b"node")?;
+                write_nar(out, node, spool)?;
+                write_str(out, b")")?;
+            }
+        }
+    }
+    write_str(out, b")")?;
+    Ok(())
+}
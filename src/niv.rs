@@ -0,0 +1,88 @@
+//! Importing sources from a niv `nix/sources.json`.
+
+use crate::{Source, SourceType};
+use anyhow::Context;
+use std::collections::BTreeMap;
+
+/// A source as niv stores it. niv keeps arbitrary extra attributes (description, homepage,
+/// ...), only the ones needed to fetch and update the source are read.
+#[derive(serde::Deserialize)]
+struct NivSource {
+    #[serde(rename = "type")]
+    ty: Option<String>,
+    url: Option<String>,
+    repo: Option<String>,
+    branch: Option<String>,
+    rev: Option<String>,
+    sha256: Option<String>,
+    owner: Option<String>,
+    version: Option<String>,
+    url_template: Option<String>,
+}
+
+fn convert(niv: NivSource) -> anyhow::Result<Source> {
+    let (ty, url) = match niv.ty.as_deref() {
+        // github sources are tarballs too, with a url filled in from url_template.
+        Some("tarball" | "builtin-tarball") | None => (SourceType::Tarball, niv.url),
+        Some("file" | "builtin-url") => (SourceType::File, niv.url),
+        Some("git") => (SourceType::Git, niv.repo.clone()),
+        Some(ty) => anyhow::bail!("unsupported type {}", ty),
+    };
+    let url = url.context("no url")?;
+    let hash = niv
+        .sha256
+        .map(|hash| crate::to_sri(hash.as_ref(), crate::HashType::Sha256))
+        .transpose()?;
+    let mut source = Source {
+        hash,
+        ty: Some(ty),
+        ..Source::new(url.parse()?)
+    };
+    match (ty, niv.owner, niv.repo, niv.branch) {
+        (SourceType::Git, _, _, branch) => {
+            source.git_ref = branch.or_else(|| niv.rev.clone());
+            source.rev = niv.rev;
+        }
+        // niv's github sources follow a branch, through the tarball of its latest commit.
+        (SourceType::Tarball, Some(owner), Some(repo), Some(branch))
+            if source.url.host_str() == Some("github.com") =>
+        {
+            source.github_branch = Some(crate::github::GithubBranch {
+                owner,
+                repo,
+                branch,
+            });
+            source.rev = niv.rev;
+        }
+        _ => {
+            // Templates of other sources can be kept if their only placeholder is the version.
+            let template = niv
+                .url_template
+                .map(|t| t.replace("<version>", "{version}"))
+                .filter(|t| t.contains("{version}") && !t.contains('<'));
+            if let (Some(template), Some(version)) = (template, niv.version) {
+                source.url_template = Some(template);
+                source.version = Some(version);
+            }
+        }
+    }
+    Ok(source)
+}
+
+/// Read the niv sources file at `path`, converting each source that can be. Sources that can't
+/// are returned with the reason why.
+pub fn import(path: &std::path::Path) -> anyhow::Result<Vec<(String, anyhow::Result<Source>)>> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let sources: BTreeMap<String, serde_json::Value> =
+        serde_json::from_reader(std::io::BufReader::new(file))?;
+    Ok(sources
+        .into_iter()
+        .map(|(name, value)| {
+            let source = serde_json::from_value(value)
+                .map_err(anyhow::Error::from)
+                .and_then(convert);
+            (name, source)
+        })
+        .collect())
+}
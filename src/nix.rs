@@ -0,0 +1,336 @@
+//! Generating a nix expression that fetches every source.
+
+use crate::{Compression, Source, SourceType, Sources};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// Quote `s` as a nix string.
+pub fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Format `name` as an attribute name, quoting it if it isn't a valid identifier.
+pub fn attr_name(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with",
+    ];
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-'))
+        && !KEYWORDS.contains(&name);
+    if valid {
+        name.to_owned()
+    } else {
+        string(name)
+    }
+}
+
+/// The fetcher call for one source, and the arguments of the generated function it needs.
+/// IPFS sources are fetched through `gateways`.
+fn fetcher(
+    source: &Source,
+    hash: &ssri::Integrity,
+    gateways: &[url::Url],
+    args: &mut BTreeSet<&str>,
+) -> String {
+    if source.ty == Some(SourceType::Path) {
+        return path(source, hash);
+    }
+    if source.ty == Some(SourceType::Oci) {
+        args.insert("dockerTools");
+        return image(source, hash);
+    }
+    let url = source.resolved_url.as_ref().unwrap_or(&source.url);
+    if url.scheme() == "s3" {
+        if let Some(fetcher) = builtin(source, hash, url) {
+            return fetcher;
+        }
+        log::warn!(
+            "{} can only be fetched by the builtin fetchers of nix, which can't fetch it as it is hashed",
+            url
+        );
+    }
+    let url = crate::bucket::public_url(url).unwrap_or(url.clone());
+    let mut attrs = if url.scheme() == "ipfs" {
+        let urls: Vec<_> = gateways
+            .iter()
+            .filter_map(|gateway| crate::ipfs::gateway_url(gateway, &url).ok())
+            .map(|url| string(url.as_str()))
+            .collect();
+        vec![("urls", format!("[ {} ]", urls.join(" ")))]
+    } else {
+        vec![("url", string(url.as_str()))]
+    };
+    let fetcher = if source.ty == Some(SourceType::Git) {
+        if let Some(rev) = &source.rev {
+            attrs.push(("rev", string(rev)));
+        }
+        if source.submodules {
+            attrs.push(("fetchSubmodules", "true".to_owned()));
+        }
+        if source.deep_clone {
+            attrs.push(("deepClone", "true".to_owned()));
+        }
+        if let Some(subdir) = &source.subdir {
+            attrs.push(("sparseCheckout", format!("[ {} ]", string(subdir))));
+        }
+        "fetchgit"
+    } else if let Some(compression) = source.compression.filter(|_| source.decompress) {
+        let command = match compression {
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => {
+                args.insert("zstd");
+                attrs.push(("nativeBuildInputs", "[ zstd ]".to_owned()));
+                "zstd"
+            }
+        };
+        attrs.push(("downloadToTemp", "true".to_owned()));
+        attrs.push((
+            "postFetch",
+            string(&format!("{command} -dc \"$downloadedFile\" > \"$out\"")),
+        ));
+        "fetchurl"
+    } else if source
+        .unpack
+        .unwrap_or(source.ty.is_some_and(SourceType::unpacks))
+    {
+        if source.strip_root == Some(false) {
+            attrs.push(("stripRoot", "false".to_owned()));
+        }
+        if let Some(subdir) = &source.subdir {
+            // Only the subdirectory is kept, in place of the whole source.
+            let subdir = format!("'{}'", subdir.replace('\'', "'\\''"));
+            let script = format!(
+                "mv \"$out\"/{subdir} \"$TMPDIR/subdir\"\nrm -rf \"$out\"\nmv \"$TMPDIR/subdir\" \"$out\"\n"
+            );
+            attrs.push(("postFetch", string(&script)));
+        }
+        "fetchzip"
+    } else {
+        "fetchurl"
+    };
+    args.insert(fetcher);
+    if let Some(name) = &source.store_name {
+        attrs.push(("name", string(name)));
+    }
+    // `sha256` only takes sha256 hashes, `hash` takes SRI hashes of any type.
+    let key = match hash.pick_algorithm() {
+        ssri::Algorithm::Sha256 => "sha256",
+        _ => "hash",
+    };
+    attrs.push((key, string(&hash.to_string())));
+
+    let mut out = format!("{fetcher} {{\n");
+    for (key, value) in attrs {
+        writeln!(out, "    {key} = {value};").unwrap();
+    }
+    out.push_str("  }");
+    out
+}
+
+/// The call to the builtin fetcher of nix for an `s3://` source, which nix fetches with the
+/// credentials of the AWS tools, unlike the fetchers of nixpkgs. `None` if it can't fetch it the
+/// way it was hashed.
+fn builtin(source: &Source, hash: &ssri::Integrity, url: &url::Url) -> Option<String> {
+    let unpack = source
+        .unpack
+        .unwrap_or(source.ty.is_some_and(SourceType::unpacks));
+    if source.ty == Some(SourceType::Git)
+        || source.decompress
+        || source.subdir.is_some()
+        || (unpack && source.strip_root == Some(false))
+        || hash.pick_algorithm() != ssri::Algorithm::Sha256
+    {
+        return None;
+    }
+    let (fetcher, name) = if unpack {
+        let name = source.store_name.as_deref().unwrap_or("source");
+        ("fetchTarball", name.to_owned())
+    } else {
+        let filename = url.path_segments().and_then(|mut s| s.next_back());
+        ("fetchurl", crate::store_name(source, filename))
+    };
+    Some(format!(
+        "builtins.{fetcher} {{\n    url = {};\n    name = {};\n    sha256 = {};\n  }}",
+        string(url.as_str()),
+        string(&name),
+        string(&hash.to_string())
+    ))
+}
+
+/// The `dockerTools.pullImage` call pulling an image at the digest its tag was resolved to.
+fn image(source: &Source, hash: &ssri::Integrity) -> String {
+    let image = crate::oci::Image::from_url(&source.url);
+    let (name, tag) = match &image {
+        Ok(image) => (image.name(), image.tag.as_str()),
+        Err(_) => (source.url.to_string(), "latest"),
+    };
+    let mut out = format!(
+        "dockerTools.pullImage {{\n    imageName = {};\n",
+        string(&name)
+    );
+    if let Some(digest) = &source.rev {
+        writeln!(out, "    imageDigest = {};", string(digest)).unwrap();
+    }
+    writeln!(out, "    sha256 = {};", string(&hash.to_string())).unwrap();
+    writeln!(out, "    finalImageTag = {};", string(tag)).unwrap();
+    out.push_str("  }");
+    out
+}
+
+/// The `builtins.path` call adding a local path source to the store.
+fn path(source: &Source, hash: &ssri::Integrity) -> String {
+    let path = source
+        .url
+        .to_file_path()
+        .unwrap_or_else(|_| source.url.path().into());
+    let path = path.to_string_lossy();
+    let name = crate::store_name(source, path.rsplit('/').next());
+    format!(
+        "builtins.path {{\n    path = /. + {};\n    name = {};\n    sha256 = {};\n  }}",
+        string(&path),
+        string(&name),
+        string(&hash.to_string())
+    )
+}
+
+/// Generate a nix function taking the fetchers from nixpkgs, and returning an attribute set
+/// of all sources that have a hash. IPFS sources are fetched through any of `gateways`.
+pub fn generate(sources: &Sources, gateways: &[url::Url]) -> String {
+    let mut args = BTreeSet::new();
+    let mut body = String::new();
+    for (name, source) in &sources.sources {
+        let Some(hash) = &source.hash else {
+            log::warn!("{} has no hash, skipping", name);
+            continue;
+        };
+        let fetcher = fetcher(source, hash, gateways, &mut args);
+        writeln!(body, "  {} = {};", attr_name(name), fetcher).unwrap();
+    }
+    let args: Vec<_> = args.into_iter().collect();
+    format!(
+        "# Generated by nix-source, do not edit.\n{{ {} }}:\n{{\n{}}}\n",
+        args.join(", "),
+        body
+    )
+}
+
+/// A nix file reading the sources file at evaluation time, so it doesn't have to be generated
+/// again when sources change. Uses only builtin fetchers, so it doesn't need nixpkgs.
+const LOADER: &str = r#"# Generated by nix-source, do not edit. Run `nix-source generate` to update it.
+#
+# Reads @FILE@ when evaluated, and returns an attribute set of its sources, and those of the
+# files it includes.
+{ sourcesFile ? ./@FILE@ }:
+let
+  data = builtins.@PARSE@ (builtins.readFile sourcesFile);
+  # The sources of `data`, read from a file in `dir`, merged with those of the files it
+  # includes, which are read by their extension.
+  withIncluded = dir: data:
+    builtins.foldl' (sources: include: sources // readIncluded (dir + "/${include}"))
+      (data.sources or { }) (data.include or [ ]);
+  readIncluded = file:
+    let
+      text = builtins.readFile file;
+      toml = builtins.match ".*\\.toml" (toString file) != null;
+    in
+    withIncluded (dirOf file) (if toml then builtins.fromTOML text else builtins.fromJSON text);
+  # The builtin fetchers only take one url, so IPFS sources are fetched through the first
+  # gateway.
+  ipfsGateway = builtins.head ([ @IPFS_GATEWAYS@ ] ++ [ (throw "no IPFS gateways") ]);
+  # Store paths can only contain some characters.
+  sanitize = s:
+    builtins.concatStringsSep "" (map (x: if builtins.isList x then "_" else x)
+      (builtins.split "[^A-Za-z0-9+._?=-]+" s));
+  fetch = name: source:
+    let
+      type = source.type or "file";
+      fetchedUrl = source.resolved_url or source.url;
+      # Nix can't fetch from Google Cloud Storage, only the public url of an object.
+      url =
+        if builtins.substring 0 5 fetchedUrl == "gs://" then
+          "https://storage.googleapis.com/" + builtins.substring 5 (-1) fetchedUrl
+        else if builtins.substring 0 7 fetchedUrl == "ipfs://" then
+          "${ipfsGateway}/ipfs/" + builtins.substring 7 (-1) fetchedUrl
+        else
+          fetchedUrl;
+      unpack = source.unpack or (type == "tarball" || type == "channel");
+    in
+    if !(source ? hash) then
+      throw "${name} has no hash, run nix-source update first"
+    else if builtins.substring 0 7 source.hash != "sha256-" then
+      throw "${name} is not hashed with sha256, which the builtin fetchers need"
+    else if source.decompress or false then
+      throw "${name} is hashed decompressed, use nix-source emit-nix for it"
+    else if source ? subdir then
+      throw "${name} is only a subdirectory of what is fetched, use nix-source emit-nix for it"
+    else if type == "git" && source.deep_clone or false then
+      throw "${name} keeps the .git directory of its clone, use nix-source emit-nix for it"
+    else if type == "oci" then
+      throw "${name} is a container image, which needs dockerTools, use nix-source emit-nix for it"
+    else if type == "path" then
+      builtins.path {
+        path = /. + builtins.substring 7 (-1) source.url;
+        name = source.store_name or (sanitize (baseNameOf source.url));
+        sha256 = source.hash;
+      }
+    else if type == "git" then
+      builtins.fetchGit {
+        url = source.url;
+        rev = source.rev;
+        narHash = source.hash;
+        allRefs = true;
+        submodules = source.submodules or false;
+      }
+    else if unpack && !(source.strip_root or true) then
+      throw "${name} keeps the top-level directory of its tarball, use nix-source emit-nix for it"
+    else if unpack then
+      builtins.fetchTarball {
+        inherit url;
+        name = source.store_name or "source";
+        sha256 = source.hash;
+      }
+    else
+      builtins.fetchurl {
+        inherit url;
+        name = source.store_name or (sanitize (baseNameOf url));
+        sha256 = source.hash;
+      };
+in
+builtins.mapAttrs fetch (withIncluded (dirOf sourcesFile) data)
+"#;
+
+/// The loader for the sources file called `file_name` next to it, which is TOML if `toml` is
+/// set and JSON otherwise. IPFS sources are fetched through the first of `gateways`.
+pub fn loader(file_name: &str, toml: bool, gateways: &[url::Url]) -> String {
+    let gateways: Vec<_> = gateways
+        .iter()
+        .map(|gateway| string(gateway.as_str().trim_end_matches('/')))
+        .collect();
+    LOADER
+        .replace("@IPFS_GATEWAYS@", &gateways.join(" "))
+        .replace(
+            "./@FILE@",
+            &format!("./. + {}", string(&format!("/{file_name}"))),
+        )
+        .replace("@FILE@", file_name)
+        .replace("@PARSE@", if toml { "fromTOML" } else { "fromJSON" })
+}
@@ -0,0 +1,76 @@
+//! Looking up the versions of packages in nixpkgs, to compare sources with.
+//!
+//! There is no index of the versions of packages in a channel that is cheap to get, so the
+//! channel is evaluated with `nix-instantiate`, only as far as the version of each package.
+
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Url of the nixexprs tarball of the Nix channel `channel`.
+pub fn channel_url(channel: &str) -> String {
+    // Overridable to follow the channels of a mirror.
+    let channels =
+        std::env::var("NIX_SOURCE_CHANNELS_URL").unwrap_or("https://channels.nixos.org".to_owned());
+    format!(
+        "{}/{}/nixexprs.tar.xz",
+        channels.trim_end_matches('/'),
+        channel
+    )
+}
+
+/// The versions of the packages at the attribute paths `attrs`, like `python3Packages.foo`, in
+/// the nixpkgs of the channel `channel`, by name. Packages that aren't in it, fail to evaluate,
+/// or have no version, are left out.
+pub fn versions(
+    channel: &str,
+    attrs: &BTreeMap<String, String>,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut expr = String::new();
+    writeln!(expr, "let").unwrap();
+    writeln!(
+        expr,
+        "  pkgs = import (builtins.fetchTarball {}) {{ config = {{ }}; overlays = [ ]; }};",
+        crate::nix::string(&channel_url(channel))
+    )
+    .unwrap();
+    writeln!(expr, "  version = path:").unwrap();
+    writeln!(expr, "    let").unwrap();
+    writeln!(
+        expr,
+        "      result = builtins.tryEval ((pkgs.lib.attrByPath path {{ }} pkgs).version or null);"
+    )
+    .unwrap();
+    writeln!(expr, "    in").unwrap();
+    writeln!(expr, "    if result.success then result.value else null;").unwrap();
+    writeln!(expr, "in").unwrap();
+    writeln!(expr, "{{").unwrap();
+    for (name, attr) in attrs {
+        let path: Vec<_> = attr.split('.').map(crate::nix::string).collect();
+        writeln!(
+            expr,
+            "  {} = version [ {} ];",
+            crate::nix::attr_name(name),
+            path.join(" ")
+        )
+        .unwrap();
+    }
+    writeln!(expr, "}}").unwrap();
+    let output = std::process::Command::new("nix-instantiate")
+        .args(["--eval", "--strict", "--json", "--expr", &expr])
+        .output()
+        .context("failed to run nix-instantiate")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "failed to evaluate nixpkgs of {}: {}",
+            channel,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let versions: BTreeMap<String, Option<String>> = serde_json::from_slice(&output.stdout)
+        .context("nix-instantiate printed something that isn't the versions")?;
+    Ok(versions
+        .into_iter()
+        .filter_map(|(name, version)| Some((name, version?)))
+        .collect())
+}
@@ -0,0 +1,135 @@
+//! Importing sources from an npins `npins/sources.json`.
+
+use crate::{forge, github, gitlab, Source, SourceType};
+use anyhow::Context;
+use std::collections::BTreeMap;
+
+#[derive(serde::Deserialize)]
+struct Pins {
+    pins: BTreeMap<String, serde_json::Value>,
+}
+
+/// A pin as npins stores it, with the attributes of every kind of pin. Only the ones needed to
+/// fetch and update the source are read.
+#[derive(serde::Deserialize)]
+struct Pin {
+    #[serde(rename = "type")]
+    ty: String,
+    repository: Option<Repository>,
+    branch: Option<String>,
+    revision: Option<String>,
+    version: Option<String>,
+    /// Package of PyPi pins, channel of Channel pins.
+    name: Option<String>,
+    url: Option<String>,
+    hash: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "type")]
+enum Repository {
+    GitHub { owner: String, repo: String },
+    GitLab { repo_path: String, server: url::Url },
+    Git { url: String },
+}
+
+fn convert(pin: Pin) -> anyhow::Result<Source> {
+    let hash = pin
+        .hash
+        .map(|hash| {
+            // Older versions of npins store the base32 sha256 nix prints, newer ones SRI.
+            if hash.contains('-') {
+                Ok(hash.parse()?)
+            } else {
+                crate::to_sri(hash.as_ref(), crate::HashType::Sha256)
+            }
+        })
+        .transpose()?;
+    let url = pin.url.as_deref().map(str::parse).transpose()?;
+    let mut source = match (pin.ty.as_str(), pin.repository) {
+        ("Git" | "GitRelease", Some(Repository::Git { url })) => Source {
+            ty: Some(SourceType::Git),
+            git_ref: pin.branch.or(pin.version.clone()),
+            rev: pin.revision,
+            ..Source::new(url.parse()?)
+        },
+        ("Git", Some(repository)) => {
+            let mut source = Source {
+                ty: Some(SourceType::Tarball),
+                ..Source::new(url.context("no url")?)
+            };
+            // Only GitHub branches can be followed, GitLab ones are imported as they are.
+            if let (Repository::GitHub { owner, repo }, Some(branch)) = (repository, pin.branch) {
+                source.github_branch = Some(github::GithubBranch {
+                    owner,
+                    repo,
+                    branch,
+                });
+                source.rev = pin.revision;
+            }
+            source
+        }
+        ("GitRelease", Some(repository)) => {
+            let repository = match repository {
+                Repository::GitHub { owner, repo } => {
+                    forge::Repository::Github(github::GithubRepository { owner, repo })
+                }
+                Repository::GitLab { repo_path, server } => {
+                    forge::Repository::Gitlab(gitlab::GitlabProject {
+                        instance: server,
+                        project: repo_path,
+                    })
+                }
+                // Handled with the other plain git repositories above.
+                Repository::Git { .. } => unreachable!(),
+            };
+            Source {
+                ty: Some(SourceType::Tarball),
+                version: pin.version.clone(),
+                repository: Some(repository),
+                ..Source::new(url.context("no url")?)
+            }
+        }
+        ("PyPi", _) => Source {
+            ty: Some(SourceType::Pypi),
+            package: Some(pin.name.context("no package name")?),
+            version: pin.version.clone(),
+            ..Source::new(url.context("no url")?)
+        },
+        ("Channel", _) => {
+            let channel = pin.name.context("no channel name")?;
+            let url = format!("https://channels.nixos.org/{}/nixexprs.tar.xz", channel);
+            Source {
+                ty: Some(SourceType::Channel),
+                // The release the channel pointed to.
+                resolved_url: pin.url.as_deref().map(str::parse).transpose()?,
+                ..Source::new(url.parse()?)
+            }
+        }
+        ("Tarball", _) => Source {
+            ty: Some(SourceType::Tarball),
+            ..Source::new(url.context("no url")?)
+        },
+        (ty, _) => anyhow::bail!("unsupported type {}", ty),
+    };
+    source.hash = hash;
+    Ok(source)
+}
+
+/// Read the npins sources file at `path`, converting each pin that can be. Pins that can't are
+/// returned with the reason why.
+pub fn import(path: &std::path::Path) -> anyhow::Result<Vec<(String, anyhow::Result<Source>)>> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let pins: Pins = serde_json::from_reader(std::io::BufReader::new(file))?;
+    Ok(pins
+        .pins
+        .into_iter()
+        .map(|(name, value)| {
+            let source = serde_json::from_value(value)
+                .map_err(anyhow::Error::from)
+                .and_then(convert);
+            (name, source)
+        })
+        .collect())
+}
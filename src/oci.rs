@@ -0,0 +1,230 @@
+//! Container images in OCI or Docker registries, pinned by the digest their tag points to and
+//! prefetched with `nix-prefetch-docker`, for `dockerTools.pullImage`.
+//!
+//! Images are kept in the sources file as `docker://registry/repository:tag` urls, the way
+//! skopeo names them.
+
+use crate::{HashType, Source};
+use anyhow::Context;
+
+/// The manifest types asked for, image indexes first so multi-platform images resolve to the
+/// digest of their index, as they do for docker and skopeo.
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+/// An image, by tag.
+pub struct Image {
+    /// Host, and port, of the registry, `docker.io` for Docker Hub.
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+}
+
+impl Image {
+    /// Parse an image reference as docker takes it, like `nginx:1.25` or
+    /// `ghcr.io/owner/repo:tag`. The registry is Docker Hub if the first component doesn't look
+    /// like a host name, and the tag `latest` if there is none.
+    pub fn parse(reference: &str) -> anyhow::Result<Self> {
+        if reference.contains('@') {
+            anyhow::bail!("{} is pinned to a digest, give a tag to follow", reference);
+        }
+        let (registry, rest) = match reference.split_once('/') {
+            Some((first, rest)) if first.contains(['.', ':']) || first == "localhost" => {
+                (first.to_owned(), rest.to_owned())
+            }
+            _ => ("docker.io".to_owned(), reference.to_owned()),
+        };
+        // A colon after the last slash starts the tag.
+        let (repository, tag) = match rest.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+            _ => (rest.as_str(), "latest"),
+        };
+        let repository = if registry == "docker.io" && !repository.contains('/') {
+            format!("library/{repository}")
+        } else {
+            repository.to_owned()
+        };
+        if repository.is_empty() || tag.is_empty() {
+            anyhow::bail!("invalid image reference {}", reference);
+        }
+        Ok(Image {
+            registry,
+            repository,
+            tag: tag.to_owned(),
+        })
+    }
+
+    /// The image a `docker://` url is of.
+    pub fn from_url(url: &url::Url) -> anyhow::Result<Self> {
+        if url.scheme() != "docker" {
+            anyhow::bail!("{} is not a docker:// url", url);
+        }
+        Self::parse(&url[url::Position::BeforeHost..])
+    }
+
+    /// The `docker://` url the image is kept in the sources file as.
+    pub fn url(&self) -> anyhow::Result<url::Url> {
+        Ok(format!(
+            "docker://{}/{}:{}",
+            self.registry, self.repository, self.tag
+        )
+        .parse()?)
+    }
+
+    /// The name of the image, without its tag.
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.registry, self.repository)
+    }
+
+    /// The base url of the registry's API. Docker Hub serves it from another host, and
+    /// registries on the local machine are spoken to over plain http, like docker does.
+    fn api(&self) -> String {
+        let host = self.registry.split(':').next().unwrap_or_default();
+        match host {
+            "docker.io" => "https://registry-1.docker.io/v2".to_owned(),
+            "localhost" | "127.0.0.1" => format!("http://{}/v2", self.registry),
+            _ => format!("https://{}/v2", self.registry),
+        }
+    }
+}
+
+/// A token for pulling `image`, from the authorization server named by the `WWW-Authenticate`
+/// challenge of a registry that wants one. Docker Hub and most registries hand them out to
+/// anyone for public images.
+fn token(client: &crate::http::Client, image: &Image, challenge: &str) -> anyhow::Result<String> {
+    let Some(params) = challenge.strip_prefix("Bearer ") else {
+        anyhow::bail!(
+            "{} asks for unsupported authentication {}",
+            image.registry,
+            challenge
+        );
+    };
+    let params: std::collections::HashMap<_, _> = regex::Regex::new(r#"(\w+)="([^"]*)""#)
+        .unwrap()
+        .captures_iter(params)
+        .map(|c| (c[1].to_owned(), c[2].to_owned()))
+        .collect();
+    let realm = params
+        .get("realm")
+        .with_context(|| format!("{} asks for a token without a realm", image.registry))?;
+    let mut url: url::Url = realm.parse()?;
+    let scope = format!("repository:{}:pull", image.repository);
+    {
+        let mut query = url.query_pairs_mut();
+        if let Some(service) = params.get("service") {
+            query.append_pair("service", service);
+        }
+        query.append_pair("scope", params.get("scope").unwrap_or(&scope));
+    }
+    #[derive(serde::Deserialize)]
+    struct Token {
+        token: Option<String>,
+        access_token: Option<String>,
+    }
+    let token: Token = serde_json::from_reader(client.get(&url)?.into_reader())
+        .with_context(|| format!("invalid token from {}", realm))?;
+    token
+        .token
+        .or(token.access_token)
+        .with_context(|| format!("no token from {}", realm))
+}
+
+/// The digest of the manifest `image`'s tag points to.
+pub fn resolve(client: &crate::http::Client, image: &Image) -> anyhow::Result<String> {
+    let url: url::Url = format!(
+        "{}/{}/manifests/{}",
+        image.api(),
+        image.repository,
+        image.tag
+    )
+    .parse()?;
+    let request = |method: &str, token: Option<&str>| {
+        client.call(method, &url, |req| {
+            let req = req.set("Accept", MANIFEST_TYPES);
+            match token {
+                Some(token) => req.set("Authorization", &format!("Bearer {token}")),
+                None => req,
+            }
+        })
+    };
+    let (res, token) = match request("HEAD", None) {
+        Err(e) => match e.downcast_ref() {
+            Some(ureq::Error::Status(401, res)) => {
+                let challenge = res.header("WWW-Authenticate").unwrap_or_default();
+                let token = token(client, image, challenge)?;
+                (request("HEAD", Some(&token))?.0, Some(token))
+            }
+            _ => return Err(e),
+        },
+        Ok((res, _)) => (res, None),
+    };
+    if let Some(digest) = res.header("Docker-Content-Digest") {
+        return Ok(digest.to_owned());
+    }
+    // Not every registry says, the digest is then that of the manifest itself.
+    let (res, _) = request("GET", token.as_deref())?;
+    let mut manifest = Vec::new();
+    std::io::copy(&mut res.into_reader(), &mut manifest)?;
+    let digest: String = crate::store::sha256(&manifest)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    Ok(format!("sha256:{digest}"))
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PrefetchOutput {
+    image_digest: String,
+    /// SRI hash, only printed by newer versions of `nix-prefetch-docker`.
+    hash: Option<String>,
+    sha256: Option<String>,
+}
+
+/// Resolve the tag of the image `source` is of, and prefetch the image at the digest it points
+/// to, unless it is the one `source` is at already.
+pub fn refresh(
+    client: &crate::http::Client,
+    source: &Source,
+    force: bool,
+    hash_type: HashType,
+) -> anyhow::Result<Source> {
+    if hash_type != HashType::Sha256 {
+        anyhow::bail!("nix-prefetch-docker only hashes images with sha256");
+    }
+    let image = Image::from_url(&source.url)?;
+    let digest = resolve(client, &image)?;
+    log::debug!("\t{}:{} is at {}", image.name(), image.tag, digest);
+    if !force && source.hash.is_some() && source.rev.as_ref() == Some(&digest) {
+        log::debug!("\tnot modified");
+        return Ok(source.clone());
+    }
+    let mut command = std::process::Command::new("nix-prefetch-docker");
+    command.args(["--json", "--quiet"]);
+    command.args(["--image-name", &image.name()]);
+    command.args(["--image-tag", &image.tag]);
+    command.args(["--image-digest", &digest]);
+    let output = crate::prefetch::output_with_timeout(&mut command, client.prefetch_timeout())
+        .context("failed to run nix-prefetch-docker")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "nix-prefetch-docker failed for {}: {}",
+            source.url,
+            output.status
+        );
+    }
+    let output: PrefetchOutput = serde_json::from_slice(&output.stdout)
+        .context("failed to parse the output of nix-prefetch-docker")?;
+    let hash = match (output.hash, output.sha256) {
+        (Some(hash), _) => hash.parse()?,
+        (None, Some(sha256)) => crate::to_sri(sha256.as_ref(), HashType::Sha256)?,
+        (None, None) => anyhow::bail!("nix-prefetch-docker printed no hash"),
+    };
+    Ok(Source {
+        hash: Some(hash),
+        rev: Some(output.image_digest),
+        ..source.clone()
+    })
+}
@@ -0,0 +1,319 @@
+//! Hashing sources, either by ourselves or by adding them to the nix store with one of the
+//! tools nix provides.
+
+use crate::HashType;
+use anyhow::Context;
+use std::os::unix::ffi::OsStrExt;
+
+/// The tool used to prefetch sources.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum Prefetcher {
+    /// Download and hash sources without nix, which isn't needed then. Sources aren't added to
+    /// the store. Zip files and bzip2 tarballs, which it can't unpack, are left to the nix tools
+    /// if they are installed.
+    #[default]
+    Builtin,
+    /// `nix store prefetch-file`, available since nix 2.4. Tarballs are unpacked with its
+    /// `--unpack` rather than fetched with `nix flake prefetch tarball+<url>`, which hashes the
+    /// same NAR, but only with sha256, and always names the store path `source` instead of the
+    /// store name of the source.
+    Nix,
+    /// `nix-prefetch-url`, whose hash has to be converted to SRI with `nix hash to-sri`.
+    NixPrefetchUrl,
+}
+
+impl std::fmt::Display for Prefetcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Prefetcher::Builtin => "builtin",
+            Prefetcher::Nix => "nix",
+            Prefetcher::NixPrefetchUrl => "nix-prefetch-url",
+        })
+    }
+}
+
+impl std::str::FromStr for Prefetcher {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "builtin" => Ok(Prefetcher::Builtin),
+            "nix" => Ok(Prefetcher::Nix),
+            "nix-prefetch-url" => Ok(Prefetcher::NixPrefetchUrl),
+            "auto" => Ok(Prefetcher::detect()),
+            _ => Err(anyhow::anyhow!("invalid prefetcher")),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct PrefetchFileOutput {
+    hash: String,
+}
+
+impl Prefetcher {
+    /// `nix store prefetch-file` if the installed nix has it, `nix-prefetch-url` if it is older,
+    /// and the builtin prefetcher if nix isn't installed.
+    pub fn detect() -> Self {
+        let Ok(output) = std::process::Command::new("nix").arg("--version").output() else {
+            return Prefetcher::Builtin;
+        };
+        // e.g. "nix (Nix) 2.18.1"
+        let version = String::from_utf8(output.stdout).ok();
+        let version = version
+            .as_deref()
+            .and_then(|v| v.split_whitespace().last())
+            .and_then(|v| {
+                let mut parts = v.split('.').map(|p| p.parse::<u32>().ok());
+                Some((parts.next()??, parts.next()??))
+            });
+        match version {
+            Some(version) if version >= (2, 4) => Prefetcher::Nix,
+            _ => Prefetcher::NixPrefetchUrl,
+        }
+    }
+
+    /// Make sure the tools this prefetcher needs are available.
+    pub fn check(self) -> anyhow::Result<()> {
+        if self == Prefetcher::Builtin {
+            return Ok(());
+        }
+        which::which("nix").context("nix not found")?;
+        if self == Prefetcher::NixPrefetchUrl {
+            which::which("nix-prefetch-url").context("nix-prefetch-url not found")?;
+        }
+        Ok(())
+    }
+
+    /// Hash `url`, unpacking it first if `unpack` is set. The nix tools also add it to the store
+    /// as `name`. Downloads that are cut short are tried again.
+    pub fn prefetch(
+        self,
+        client: &crate::http::Client,
+        url: &url::Url,
+        name: &str,
+        unpack: bool,
+        hash_type: HashType,
+    ) -> anyhow::Result<ssri::Integrity> {
+        if client.is_offline() {
+            anyhow::bail!("can't prefetch {} offline", url);
+        }
+        client.retry(
+            || self.prefetch_once(client, url, name, unpack, hash_type),
+            is_transient,
+        )
+    }
+
+    fn prefetch_once(
+        self,
+        client: &crate::http::Client,
+        url: &url::Url,
+        name: &str,
+        unpack: bool,
+        hash_type: HashType,
+    ) -> anyhow::Result<ssri::Integrity> {
+        let (mut command, type_flag) = match self {
+            Prefetcher::Builtin => {
+                return match fetch_and_hash(client, url, unpack, hash_type) {
+                    // Zip files and bzip2 tarballs are left to the nix tools, if they are there.
+                    Err(e) if e.chain().any(|e| e.is::<crate::nar::Unsupported>()) => {
+                        let fallback = Prefetcher::detect();
+                        if fallback == Prefetcher::Builtin {
+                            return Err(e.context("nix is needed to unpack it"));
+                        }
+                        log::debug!("\tunpacking it with {} instead", fallback);
+                        fallback.check()?;
+                        fallback.prefetch_once(client, url, name, unpack, hash_type)
+                    }
+                    res => res,
+                };
+            }
+            Prefetcher::Nix => {
+                let mut command = std::process::Command::new("nix");
+                command.args([
+                    "--extra-experimental-features",
+                    "nix-command",
+                    "store",
+                    "prefetch-file",
+                    "--json",
+                ]);
+                (command, "--hash-type")
+            }
+            Prefetcher::NixPrefetchUrl => {
+                (std::process::Command::new("nix-prefetch-url"), "--type")
+            }
+        };
+        command.args(["--name", name]);
+        if unpack {
+            command.arg("--unpack");
+        }
+        command.args([type_flag, &hash_type.to_string()]);
+        command.arg(url.as_str());
+        let output = output_with_timeout(&mut command, client.prefetch_timeout())
+            .with_context(|| format!("failed to run {}", self))?;
+        log::debug!(
+            "{} printed {:?}",
+            self,
+            String::from_utf8_lossy(&output.stdout)
+        );
+        if !output.status.success() {
+            anyhow::bail!("{} failed for {}: {}", self, url, output.status);
+        }
+        match self {
+            Prefetcher::Builtin => unreachable!(),
+            Prefetcher::Nix => {
+                let output: PrefetchFileOutput = serde_json::from_slice(&output.stdout)
+                    .context("failed to parse the output of nix store prefetch-file")?;
+                Ok(output.hash.parse()?)
+            }
+            Prefetcher::NixPrefetchUrl => {
+                let hash = output.stdout.trim_ascii();
+                if !is_base32_hash(hash, hash_type) {
+                    anyhow::bail!(
+                        "{} printed {:?} instead of a {} hash for {} ({})",
+                        self,
+                        String::from_utf8_lossy(hash),
+                        hash_type,
+                        url,
+                        output.status
+                    );
+                }
+                crate::to_sri(std::ffi::OsStr::from_bytes(hash), hash_type)
+            }
+        }
+    }
+}
+
+/// Whether a prefetch failed because the connection broke while downloading. Errors talking to
+/// the server are retried by [`crate::http::Client`] already, and the nix tools don't tell why
+/// they failed. A tool killed for taking too long would likely take as long again.
+fn is_transient(err: &anyhow::Error) -> bool {
+    crate::http::is_interrupted(err)
+}
+
+/// A command killed by [`output_with_timeout`].
+#[derive(Debug)]
+pub struct TimedOut(std::time::Duration);
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "timed out after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Hash the NAR of what the tarball at `url` unpacks to, or of only `subdir` of it, keeping the
+/// top-level directory the nix tools always strip unless `strip_root` is set. This is done
+/// without them, so the source isn't added to the store.
+pub fn hash_unpacked(
+    client: &crate::http::Client,
+    url: &url::Url,
+    hash_type: HashType,
+    strip_root: bool,
+    subdir: Option<&str>,
+) -> anyhow::Result<ssri::Integrity> {
+    if client.is_offline() {
+        anyhow::bail!("can't prefetch {} offline", url);
+    }
+    client.retry(
+        || {
+            crate::nar::hash_tarball(open(client, url)?, hash_type, strip_root, subdir)
+                .with_context(|| format!("failed to unpack {}", url))
+        },
+        is_transient,
+    )
+}
+
+/// A download open for reading, removed when dropped.
+struct Downloaded {
+    file: std::fs::File,
+    _download: crate::http::Download,
+}
+
+impl std::io::Read for Downloaded {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+/// Open `url` for reading, from disk if it is a local file. Other urls are downloaded first,
+/// so the download can be resumed if it is interrupted.
+fn open(client: &crate::http::Client, url: &url::Url) -> anyhow::Result<Box<dyn std::io::Read>> {
+    Ok(if url.scheme() == "file" {
+        let path = url
+            .to_file_path()
+            .map_err(|_| anyhow::anyhow!("invalid file url {}", url))?;
+        Box::new(std::fs::File::open(&path).with_context(|| format!("failed to open {}", url))?)
+    } else {
+        let download = client.download(url, "download", |req| req)?;
+        Box::new(Downloaded {
+            file: std::fs::File::open(&download.path)?,
+            _download: download,
+        })
+    })
+}
+
+/// Hash `url` as it is downloaded, or the NAR of what it unpacks to if `unpack` is set.
+fn fetch_and_hash(
+    client: &crate::http::Client,
+    url: &url::Url,
+    unpack: bool,
+    hash_type: HashType,
+) -> anyhow::Result<ssri::Integrity> {
+    let mut reader = open(client, url)?;
+    if unpack {
+        return crate::nar::hash_tarball(reader, hash_type, true, None)
+            .with_context(|| format!("failed to unpack {}", url));
+    }
+    let mut hasher = ssri::IntegrityOpts::new().algorithm(hash_type.algorithm());
+    std::io::copy(&mut reader, &mut hasher).with_context(|| format!("failed to fetch {}", url))?;
+    Ok(hasher.result())
+}
+
+/// Whether `hash` looks like a `hash_type` hash in nix's base32, as `nix-prefetch-url` prints.
+fn is_base32_hash(hash: &[u8], hash_type: HashType) -> bool {
+    // nix's base32 leaves out e, o, u and t.
+    const ALPHABET: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+    let bits: usize = match hash_type {
+        HashType::Sha256 => 256,
+        HashType::Sha512 => 512,
+    };
+    hash.len() == bits.div_ceil(5) && hash.iter().all(|c| ALPHABET.contains(c))
+}
+
+/// Run `command` and collect its stdout, killing it if it runs for longer than `timeout`, if
+/// given. Its stderr is passed through.
+pub fn output_with_timeout(
+    command: &mut std::process::Command,
+    timeout: Option<std::time::Duration>,
+) -> anyhow::Result<std::process::Output> {
+    let mut child = command
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::inherit())
+        .spawn()?;
+    // Drain stdout concurrently, so a chatty child can't block on a full pipe.
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        std::io::Read::read_to_end(&mut stdout, &mut buf).map(|_| buf)
+    });
+    let start = std::time::Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if let Some(timeout) = timeout.filter(|&timeout| start.elapsed() > timeout) {
+            child.kill()?;
+            child.wait()?;
+            return Err(TimedOut(timeout).into());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    };
+    let stdout = reader.join().unwrap()?;
+    Ok(std::process::Output {
+        status,
+        stdout,
+        stderr: Vec::new(),
+    })
+}
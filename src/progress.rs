@@ -0,0 +1,139 @@
+//! A live display of what each source being refreshed is busy with, kept at the bottom of the
+//! terminal while log messages scroll by above it.
+
+use std::io::{IsTerminal, Write};
+
+/// Where a source is at.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum State {
+    Busy(crate::Stage),
+    Updated,
+    Unchanged,
+    Failed,
+}
+
+impl std::fmt::Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            State::Busy(crate::Stage::Checking) => "checking",
+            State::Busy(crate::Stage::Downloading) => "downloading",
+            State::Busy(crate::Stage::Hashing) => "hashing",
+            State::Updated => "updated",
+            State::Unchanged => "unchanged",
+            State::Failed => "failed",
+        })
+    }
+}
+
+struct Display {
+    /// Whether the display is shown. It never is if stderr isn't a terminal.
+    live: bool,
+    /// The sources being worked on, in the order they were started.
+    busy: Vec<(String, State)>,
+    finished: usize,
+    total: usize,
+    /// Number of lines drawn at the bottom of the terminal, to be cleared before redrawing.
+    drawn: usize,
+}
+
+static DISPLAY: std::sync::Mutex<Display> = std::sync::Mutex::new(Display {
+    live: false,
+    busy: Vec::new(),
+    finished: 0,
+    total: 0,
+    drawn: 0,
+});
+
+impl Display {
+    fn clear(&mut self, out: &mut impl Write) -> std::io::Result<()> {
+        if self.drawn > 0 {
+            write!(out, "\r\x1b[{}A\x1b[J", self.drawn)?;
+            self.drawn = 0;
+        }
+        Ok(())
+    }
+
+    fn draw(&mut self, out: &mut impl Write) -> std::io::Result<()> {
+        for (name, state) in &self.busy {
+            writeln!(out, "  {}: {}", name, state)?;
+        }
+        writeln!(out, "[{}/{}]", self.finished, self.total)?;
+        self.drawn = self.busy.len() + 1;
+        Ok(())
+    }
+}
+
+/// Start showing the progress of `total` sources, if stderr is a terminal. Returns whether it
+/// is, otherwise the log messages are all there is to show.
+pub fn start(total: usize) -> bool {
+    let mut display = DISPLAY.lock().unwrap();
+    display.live = std::io::stderr().is_terminal();
+    display.busy.clear();
+    display.finished = 0;
+    display.total = total;
+    display.live
+}
+
+/// Record that `name` is now at `state`. Finished sources are printed above the display, or
+/// logged without it.
+pub fn set(name: &str, state: State) {
+    let mut display = DISPLAY.lock().unwrap();
+    if !display.live {
+        // Logging goes through the display too.
+        drop(display);
+        if !matches!(state, State::Busy(_)) {
+            log::info!("{}: {}", name, state);
+        }
+        return;
+    }
+    let index = display.busy.iter().position(|(busy, _)| busy == name);
+    let mut out = std::io::stderr().lock();
+    // Failing to draw isn't worth failing the command over.
+    let _ = display.clear(&mut out);
+    match (state, index) {
+        (State::Busy(_), Some(index)) => display.busy[index].1 = state,
+        (State::Busy(_), None) => display.busy.push((name.to_owned(), state)),
+        (_, index) => {
+            if let Some(index) = index {
+                display.busy.remove(index);
+            }
+            display.finished += 1;
+            let _ = writeln!(out, "{}: {}", name, state);
+        }
+    }
+    let _ = display.draw(&mut out);
+}
+
+/// Stop showing the display, and clear it.
+pub fn finish() {
+    let mut display = DISPLAY.lock().unwrap();
+    if display.live {
+        let _ = display.clear(&mut std::io::stderr().lock());
+        display.live = false;
+    }
+}
+
+/// Stderr, with the display moved out of the way of what is written to it. Log messages go
+/// through this.
+pub struct Stderr;
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut display = DISPLAY.lock().unwrap();
+        let mut out = std::io::stderr().lock();
+        if !display.live {
+            return out.write(buf);
+        }
+        display.clear(&mut out)?;
+        out.write_all(buf)?;
+        // Only redraw once the line is complete, so it isn't split by the display.
+        if buf.ends_with(b"\n") {
+            display.draw(&mut out)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
@@ -0,0 +1,43 @@
+//! Following the latest release of a package on PyPI.
+
+use anyhow::Context;
+
+#[derive(serde::Deserialize)]
+struct Project {
+    info: Info,
+    /// Files of the latest release.
+    urls: Vec<File>,
+}
+
+#[derive(serde::Deserialize)]
+struct Info {
+    version: String,
+}
+
+#[derive(serde::Deserialize)]
+struct File {
+    packagetype: String,
+    url: url::Url,
+}
+
+/// Find the latest release of `package`, and return its version and the url of its sdist.
+pub fn latest(client: &crate::http::Client, package: &str) -> anyhow::Result<(String, url::Url)> {
+    // Overridable to use a mirror of the JSON API.
+    let api = std::env::var("NIX_SOURCE_PYPI_URL").unwrap_or("https://pypi.org/pypi".to_owned());
+    let url: url::Url = format!("{}/{}/json", api.trim_end_matches('/'), package).parse()?;
+    let res = match client.get(&url) {
+        Ok(res) => res,
+        Err(e) => match e.downcast_ref() {
+            Some(ureq::Error::Status(404, _)) => anyhow::bail!("no package {} on PyPI", package),
+            _ => return Err(e),
+        },
+    };
+    let project: Project =
+        serde_json::from_reader(res.into_reader()).context("failed to parse the PyPI project")?;
+    let sdist = project
+        .urls
+        .into_iter()
+        .find(|file| file.packagetype == "sdist")
+        .with_context(|| format!("{} {} has no sdist", package, project.info.version))?;
+    Ok((project.info.version, sdist.url))
+}
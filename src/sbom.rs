@@ -0,0 +1,168 @@
+//! Exporting sources as a software bill of materials, in the CycloneDX or SPDX JSON formats.
+//!
+//! Only the hashes of sources fetched as plain files are checksums of what was downloaded.
+//! Unpacked and git sources are hashed by nix as a NAR of their contents, which is recorded,
+//! but not as a checksum of the package.
+
+use crate::{Source, SourceType, Sources};
+
+/// When the bill of materials was made: `$SOURCE_DATE_EPOCH` if set, so the export is
+/// reproducible, or now.
+fn timestamp() -> String {
+    let time = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .and_then(|epoch| chrono::DateTime::from_timestamp(epoch, 0))
+        .unwrap_or_else(chrono::Utc::now);
+    time.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// The hash of the file the source was downloaded as, if that is what its hash is of, as the
+/// algorithm and the hash in hex.
+fn file_hash(source: &Source) -> Option<(ssri::Algorithm, String)> {
+    let ty = source.ty.unwrap_or(SourceType::File);
+    if matches!(ty, SourceType::Git | SourceType::Path | SourceType::Oci)
+        || source.decompress
+        || source.unpack.unwrap_or(ty.unpacks())
+    {
+        return None;
+    }
+    Some(source.hash.as_ref()?.to_hex())
+}
+
+/// The version of the source, or else the revision it is at.
+fn version(source: &Source) -> Option<&str> {
+    source.version.as_deref().or(source.rev.as_deref())
+}
+
+/// A CycloneDX 1.5 bill of materials, with a component for each source.
+pub fn cyclonedx(sources: &Sources) -> serde_json::Value {
+    let components: Vec<_> = sources
+        .sources
+        .iter()
+        .map(|(name, source)| {
+            let mut component = serde_json::json!({
+                "type": "library",
+                "bom-ref": name,
+                "name": name,
+                "externalReferences": [{ "type": "distribution", "url": source.url }],
+            });
+            if let Some(version) = version(source) {
+                component["version"] = version.into();
+            }
+            if let Some(description) = &source.description {
+                component["description"] = description.as_str().into();
+            }
+            if let Some(homepage) = &source.homepage {
+                component["externalReferences"]
+                    .as_array_mut()
+                    .unwrap()
+                    .push(serde_json::json!({ "type": "website", "url": homepage }));
+            }
+            if let Some(license) = &source.license {
+                component["licenses"] = serde_json::json!([{ "expression": license }]);
+            }
+            if let Some((algorithm, hex)) = file_hash(source) {
+                let algorithm = match algorithm {
+                    ssri::Algorithm::Sha512 => "SHA-512",
+                    ssri::Algorithm::Sha384 => "SHA-384",
+                    ssri::Algorithm::Sha256 => "SHA-256",
+                    ssri::Algorithm::Sha1 => "SHA-1",
+                    _ => return component,
+                };
+                component["hashes"] = serde_json::json!([{ "alg": algorithm, "content": hex }]);
+            } else if let Some(hash) = &source.hash {
+                component["properties"] =
+                    serde_json::json!([{ "name": "nix:narHash", "value": hash.to_string() }]);
+            }
+            component
+        })
+        .collect();
+    serde_json::json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "version": 1,
+        "metadata": {
+            "timestamp": timestamp(),
+            "tools": [{ "name": "nix-source", "version": env!("CARGO_PKG_VERSION") }],
+        },
+        "components": components,
+    })
+}
+
+/// An SPDX 2.3 document named `name`, with a package for each source.
+pub fn spdx(sources: &Sources, name: &str) -> serde_json::Value {
+    let id = |name: &str| {
+        format!(
+            "SPDXRef-{}",
+            name.replace(|c: char| !c.is_ascii_alphanumeric() && c != '.', "-")
+        )
+    };
+    let packages: Vec<_> = sources
+        .sources
+        .iter()
+        .map(|(name, source)| {
+            let mut package = serde_json::json!({
+                "name": name,
+                "SPDXID": id(name),
+                "downloadLocation": source.url,
+                "filesAnalyzed": false,
+                "licenseConcluded": "NOASSERTION",
+                "licenseDeclared": "NOASSERTION",
+                "copyrightText": "NOASSERTION",
+            });
+            if let Some(version) = version(source) {
+                package["versionInfo"] = version.into();
+            }
+            if let Some(description) = &source.description {
+                package["description"] = description.as_str().into();
+            }
+            if let Some(homepage) = &source.homepage {
+                package["homepage"] = homepage.as_str().into();
+            }
+            if let Some(license) = &source.license {
+                package["licenseDeclared"] = license.as_str().into();
+            }
+            if let Some((algorithm, hex)) = file_hash(source) {
+                package["checksums"] = serde_json::json!([{
+                    "algorithm": algorithm.to_string().to_uppercase(),
+                    "checksumValue": hex,
+                }]);
+            } else if let Some(hash) = &source.hash {
+                package["comment"] = format!("nix hash of the unpacked source: {hash}").into();
+            }
+            package
+        })
+        .collect();
+    let relationships: Vec<_> = sources
+        .sources
+        .keys()
+        .map(|name| {
+            serde_json::json!({
+                "spdxElementId": "SPDXRef-DOCUMENT",
+                "relationshipType": "DESCRIBES",
+                "relatedSpdxElement": id(name),
+            })
+        })
+        .collect();
+    // The namespace has to be unique to the document, made so by hashing what it describes.
+    let digest = ssri::IntegrityOpts::new()
+        .algorithm(ssri::Algorithm::Sha256)
+        .chain(serde_json::to_vec(&packages).unwrap_or_default())
+        .result()
+        .to_hex()
+        .1;
+    serde_json::json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": format!("https://spdx.org/spdxdocs/{name}-{digest}"),
+        "creationInfo": {
+            "created": timestamp(),
+            "creators": [format!("Tool: nix-source-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+        "relationships": relationships,
+    })
+}
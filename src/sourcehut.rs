@@ -0,0 +1,41 @@
+//! Tags of a repository on sourcehut.
+
+use anyhow::Context;
+
+/// A repository on git.sr.ht, or another instance of sourcehut's git service.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SourcehutRepository {
+    pub instance: url::Url,
+    /// User owning the repository, without the leading `~`.
+    pub owner: String,
+    pub repo: String,
+}
+
+impl crate::forge::Forge for SourcehutRepository {
+    fn url(&self) -> anyhow::Result<url::Url> {
+        Ok(format!(
+            "{}/~{}/{}",
+            self.instance.as_str().trim_end_matches('/'),
+            self.owner,
+            self.repo
+        )
+        .parse()?)
+    }
+
+    fn latest_release(&self, client: &crate::http::Client) -> anyhow::Result<String> {
+        // The API needs a token even for public repositories, but the feed of tags doesn't.
+        // It lists the newest tag first.
+        let url: url::Url = format!("{}/refs/rss.xml", self.url()?).parse()?;
+        let feed = client.get(&url)?.into_string()?;
+        let title = regex::Regex::new(r"<item>\s*<title>([^<]*)</title>").unwrap();
+        let tag = title
+            .captures(&feed)
+            .map(|c| c[1].trim().to_owned())
+            .with_context(|| format!("~{}/{} has no tags", self.owner, self.repo))?;
+        Ok(tag)
+    }
+
+    fn archive_url(&self, tag: &str) -> anyhow::Result<url::Url> {
+        Ok(format!("{}/archive/{}.tar.gz", self.url()?, tag).parse()?)
+    }
+}
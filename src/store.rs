@@ -0,0 +1,160 @@
+//! Paths of sources in the nix store, and garbage collector roots keeping them there.
+//!
+//! A fixed-output path only depends on the name and hash of what is in it, so it is computed the
+//! way nix computes it instead of asking the prefetcher, which the builtin one couldn't tell.
+
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+/// The directory of the nix store, which nix lets `$NIX_STORE_DIR` move.
+fn store_dir() -> String {
+    std::env::var("NIX_STORE_DIR").unwrap_or("/nix/store".to_owned())
+}
+
+/// The sha256 digest of `data`.
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    let hex = ssri::IntegrityOpts::new()
+        .algorithm(ssri::Algorithm::Sha256)
+        .chain(data)
+        .result()
+        .to_hex()
+        .1;
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// Encode `bytes` in nix's base32, which leaves out e, o, u and t, and starts from the end.
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+    let len = (bytes.len() * 8 - 1) / 5 + 1;
+    (0..len)
+        .rev()
+        .map(|n| {
+            let bit = n * 5;
+            let (i, j) = (bit / 8, bit % 8);
+            let mut c = bytes[i] >> j;
+            if i + 1 < bytes.len() && j > 3 {
+                c |= bytes[i + 1] << (8 - j);
+            }
+            ALPHABET[(c & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+/// The store path a fixed-output derivation called `name` with `hash` has. `recursive` is set
+/// if the hash is of the NAR of an unpacked source, rather than of a file.
+pub fn path(name: &str, hash: &ssri::Integrity, recursive: bool) -> PathBuf {
+    let (algorithm, hex) = hash.to_hex();
+    let store_dir = store_dir();
+    let fingerprint = if recursive && algorithm == ssri::Algorithm::Sha256 {
+        format!("source:sha256:{hex}:{store_dir}:{name}")
+    } else {
+        let method = if recursive { "r:" } else { "" };
+        let inner = sha256(format!("fixed:out:{method}{algorithm}:{hex}:").as_bytes());
+        let inner: String = inner.iter().map(|b| format!("{b:02x}")).collect();
+        format!("output:out:sha256:{inner}:{store_dir}:{name}")
+    };
+    // The hash of the fingerprint, folded to 160 bits.
+    let mut digest = [0u8; 20];
+    for (i, b) in sha256(fingerprint.as_bytes()).into_iter().enumerate() {
+        digest[i % 20] ^= b;
+    }
+    PathBuf::from(format!("{store_dir}/{}-{name}", base32(&digest)))
+}
+
+/// The first of the binary caches `caches` that has the store path `path`, asked for its
+/// narinfo. Caches that can't be reached are taken not to have it.
+pub fn find_substitute<'a>(
+    client: &crate::http::Client,
+    caches: &'a [url::Url],
+    path: &Path,
+) -> Option<&'a url::Url> {
+    let name = path.file_name()?.to_str()?;
+    let (hash, _) = name.split_once('-')?;
+    caches.iter().find(|cache| {
+        let narinfo = format!("{}/{hash}.narinfo", cache.as_str().trim_end_matches('/'));
+        let Ok(narinfo) = narinfo.parse() else {
+            return false;
+        };
+        match client.call("HEAD", &narinfo, |req| req) {
+            Ok((res, _)) => res.status() == 200,
+            Err(e) => {
+                log::debug!("\t{} doesn't have {}: {:#}", cache, name, e);
+                false
+            }
+        }
+    })
+}
+
+/// Whether `path` is valid in the local store, and its contents still have the hash nix
+/// recorded for it.
+pub fn is_valid(path: &Path) -> anyhow::Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let output = std::process::Command::new("nix-store")
+        .arg("--verify-path")
+        .arg(path)
+        .output()
+        .context("failed to run nix-store")?;
+    Ok(output.status.success())
+}
+
+/// The directory the garbage collector roots of the sources in `sources_file` are kept in.
+pub fn roots_dir(sources_file: &Path) -> PathBuf {
+    crate::state_dir(sources_file).join("gcroots")
+}
+
+/// Make `root` an indirect garbage collector root for the store path `path`.
+pub fn add_root(root: &Path, path: &Path) -> anyhow::Result<()> {
+    let output = std::process::Command::new("nix-store")
+        .arg("--add-root")
+        .arg(root)
+        .arg("--indirect")
+        .arg("--realise")
+        .arg(path)
+        .output()
+        .context("failed to run nix-store")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "failed to add a garbage collector root for {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Make the roots in `dir` match the store paths of `sources`: paths that are in the store get
+/// a root named after their source, and roots of other paths are removed.
+pub fn sync_roots(dir: &Path, sources: &crate::Sources) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let wanted = name
+            .to_str()
+            .and_then(|name| sources.sources.get(name))
+            .and_then(|source| source.store_path.as_ref());
+        if wanted.is_none() || std::fs::read_link(entry.path()).ok().as_ref() != wanted {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    for (name, source) in &sources.sources {
+        let Some(path) = &source.store_path else {
+            continue;
+        };
+        let root = dir.join(name);
+        if std::fs::read_link(&root).ok().as_ref() == Some(path) {
+            continue;
+        }
+        if !path.exists() {
+            log::warn!("{}: {} is not in the store", name, path.display());
+            continue;
+        }
+        add_root(&root, path)?;
+    }
+    Ok(())
+}
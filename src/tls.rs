@@ -0,0 +1,119 @@
+//! TLS configuration for servers signed by certificate authorities of their own, or that want
+//! a client certificate.
+
+use anyhow::Context;
+use base64::Engine;
+use std::path::Path;
+
+/// Build a TLS configuration trusting the certificates in `ca`, a PEM file or a directory of
+/// them, on top of the usual roots, and authenticating with the certificate in `cert` and the
+/// private key in `key`, if given. Returns None if there is nothing to change.
+pub fn config(
+    ca: Option<&Path>,
+    cert: Option<&Path>,
+    key: Option<&Path>,
+) -> anyhow::Result<Option<std::sync::Arc<rustls::ClientConfig>>> {
+    if ca.is_none() && cert.is_none() && key.is_none() {
+        return Ok(None);
+    }
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    if let Some(ca) = ca {
+        let files = if ca.is_dir() {
+            let mut files = Vec::new();
+            for entry in
+                std::fs::read_dir(ca).with_context(|| format!("failed to read {}", ca.display()))?
+            {
+                let path = entry?.path();
+                if path.is_file() {
+                    files.push(path);
+                }
+            }
+            files.sort();
+            files
+        } else {
+            vec![ca.to_owned()]
+        };
+        let mut added = 0;
+        for file in files {
+            for (_, der) in read_pem(&file)?
+                .into_iter()
+                .filter(|(label, _)| label == "CERTIFICATE")
+            {
+                roots
+                    .add(&rustls::Certificate(der))
+                    .with_context(|| format!("invalid certificate in {}", file.display()))?;
+                added += 1;
+            }
+        }
+        if added == 0 {
+            anyhow::bail!("no certificates found in {}", ca.display());
+        }
+    }
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let config = match (cert, key) {
+        (Some(cert), Some(key)) => {
+            let chain: Vec<_> = read_pem(cert)?
+                .into_iter()
+                .filter(|(label, _)| label == "CERTIFICATE")
+                .map(|(_, der)| rustls::Certificate(der))
+                .collect();
+            if chain.is_empty() {
+                anyhow::bail!("no certificates found in {}", cert.display());
+            }
+            let key = read_pem(key)?
+                .into_iter()
+                .find(|(label, _)| {
+                    matches!(
+                        label.as_str(),
+                        "PRIVATE KEY" | "RSA PRIVATE KEY" | "EC PRIVATE KEY"
+                    )
+                })
+                .with_context(|| format!("no private key found in {}", key.display()))?;
+            builder
+                .with_client_auth_cert(chain, rustls::PrivateKey(key.1))
+                .context("invalid client certificate or key")?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => anyhow::bail!("a client certificate needs both a certificate and a key"),
+    };
+    Ok(Some(std::sync::Arc::new(config)))
+}
+
+/// Read the blocks of a PEM file, as their labels and decoded contents.
+fn read_pem(path: &Path) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+    let pem = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let mut blocks = Vec::new();
+    let mut lines = pem.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        let Some(label) = line
+            .strip_prefix("-----BEGIN ")
+            .and_then(|rest| rest.strip_suffix("-----"))
+        else {
+            continue;
+        };
+        let end = format!("-----END {label}-----");
+        let mut data = String::new();
+        loop {
+            match lines.next() {
+                Some(line) if line == end => break,
+                Some(line) => data.push_str(line),
+                None => anyhow::bail!("unterminated {} in {}", label, path.display()),
+            }
+        }
+        let der = base64::engine::general_purpose::STANDARD
+            .decode(&data)
+            .with_context(|| format!("invalid {} in {}", label, path.display()))?;
+        blocks.push((label.to_owned(), der));
+    }
+    Ok(blocks)
+}
@@ -0,0 +1,54 @@
+//! Migrating sources files from older layouts to the current one, [`crate::VERSION`].
+
+use anyhow::Context;
+
+/// Upgrade the contents of a sources file in place, returning the version it was at. Files
+/// without a version are version 1.
+///
+/// Version 1 files may store a source's hash under `sha256`, or in a format other than SRI, as
+/// `nix-prefetch-url` prints it. These are converted to SRI, and moved to `hash`.
+pub fn upgrade(sources: &mut serde_json::Value) -> anyhow::Result<u64> {
+    let file = sources
+        .as_object_mut()
+        .context("the sources file is not an object")?;
+    let version = match file.get("version") {
+        Some(version) => version.as_u64().context("invalid version")?,
+        None => 1,
+    };
+    if version > crate::VERSION {
+        anyhow::bail!(
+            "the sources file is version {}, newer than the version {} this nix-source knows",
+            version,
+            crate::VERSION
+        );
+    }
+    if let Some(entries) = file.get_mut("sources").and_then(|s| s.as_object_mut()) {
+        for (name, source) in entries {
+            let Some(source) = source.as_object_mut() else {
+                continue;
+            };
+            if !source.contains_key("hash") {
+                if let Some(hash) = source.remove("sha256") {
+                    source.insert("hash".to_owned(), hash);
+                }
+            }
+            let Some(serde_json::Value::String(hash)) = source.get("hash") else {
+                continue;
+            };
+            if hash.contains('-') {
+                continue;
+            }
+            // Bare sha512 hashes are longer than any bare sha256 hash.
+            let hash_type = if hash.len() > 64 {
+                crate::HashType::Sha512
+            } else {
+                crate::HashType::Sha256
+            };
+            let sri = crate::to_sri(hash.as_ref(), hash_type)
+                .with_context(|| format!("failed to convert the hash of {} to SRI", name))?;
+            source.insert("hash".to_owned(), sri.to_string().into());
+        }
+    }
+    file.insert("version".to_owned(), crate::VERSION.into());
+    Ok(version)
+}
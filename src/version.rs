@@ -0,0 +1,69 @@
+//! Discovering the latest version of a source from a directory listing.
+
+use anyhow::Context;
+use std::cmp::Ordering;
+
+/// A directory listing, and a pattern matching the file names of each version in it.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct VersionIndex {
+    /// Url of the directory listing.
+    pub url: url::Url,
+    /// Regex matching file names in the listing. The version is captured by the group named
+    /// `version`, or the first group if there isn't one.
+    pub pattern: String,
+}
+
+/// Compare two version strings, treating runs of digits as numbers.
+pub fn compare(a: &str, b: &str) -> Ordering {
+    fn chunks(s: &str) -> impl Iterator<Item = &str> {
+        let mut rest = s;
+        std::iter::from_fn(move || {
+            let first = rest.chars().next()?;
+            let end = rest
+                .find(|c: char| c.is_ascii_digit() != first.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let (chunk, tail) = rest.split_at(end);
+            rest = tail;
+            Some(chunk)
+        })
+    }
+    let mut a = chunks(a);
+    let mut b = chunks(b);
+    loop {
+        let ord = match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.starts_with(|c: char| c.is_ascii_digit()) => {
+                let x = x.trim_start_matches('0');
+                let y = y.trim_start_matches('0');
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            (Some(x), Some(y)) => x.cmp(y),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+}
+
+impl VersionIndex {
+    /// Fetch the listing and return the highest version in it, along with its url.
+    pub fn latest(&self, client: &crate::http::Client) -> anyhow::Result<(String, url::Url)> {
+        let pattern = regex::Regex::new(&self.pattern)?;
+        let body = client.get(&self.url)?.into_string()?;
+        let (version, file) = pattern
+            .captures_iter(&body)
+            .filter_map(|c| {
+                let version = c.name("version").or_else(|| c.get(1))?;
+                Some((version.as_str(), c.get(0).unwrap().as_str()))
+            })
+            .max_by(|a, b| compare(a.0, b.0))
+            .with_context(|| format!("no file in {} matches {}", self.url, self.pattern))?;
+        let mut base = self.url.clone();
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Ok((version.to_owned(), base.join(file)?))
+    }
+}
@@ -0,0 +1,314 @@
+//! Shared test infrastructure: a minimal HTTP server, and fake nix tools so the binary can be run
+//! without network access or a nix installation.
+
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::Output;
+use std::sync::{Arc, Mutex};
+
+/// Hash printed by the fake `nix-prefetch-url` unless overridden.
+pub const HASH: &str = "0000000000000000000000000000000000000000000000000000";
+/// SRI form of [`HASH`] as produced by the fake `nix hash to-sri`.
+pub const SRI: &str = "sha256-0000000000000000000000000000000000000000000=";
+
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Close the connection after sending only this much of the body.
+    pub truncate: Option<usize>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            truncate: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn truncate(mut self, len: usize) -> Self {
+        self.truncate = Some(len);
+        self
+    }
+}
+
+/// An HTTP server answering every request with `handler`, recording the requests it gets.
+pub struct Server {
+    port: u16,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    pub fn start(handler: impl Fn(&Request) -> Response + Send + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                if reader.read_line(&mut line).is_err() {
+                    continue;
+                }
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap_or_default().to_owned();
+                let path = parts.next().unwrap_or_default().to_owned();
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                        break;
+                    }
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((k, v)) = line.split_once(':') {
+                        headers.push((k.trim().to_owned(), v.trim().to_owned()));
+                    }
+                }
+                let request = Request {
+                    method,
+                    path,
+                    headers,
+                };
+                let response = handler(&request);
+                let head = request.method == "HEAD";
+                log.lock().unwrap().push(request);
+                let mut out = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    response.status,
+                    response.body.len()
+                );
+                for (k, v) in &response.headers {
+                    out.push_str(&format!("{k}: {v}\r\n"));
+                }
+                out.push_str("\r\n");
+                let _ = stream.write_all(out.as_bytes());
+                if !head {
+                    let len = response.truncate.unwrap_or(response.body.len());
+                    let _ = stream.write_all(&response.body[..len]);
+                }
+            }
+        });
+        Self { port, requests }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{path}", self.port)
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// A temporary directory holding a sources file, and fake nix tools to run the binary with.
+pub struct Env {
+    pub dir: tempfile::TempDir,
+    sources_file: &'static str,
+}
+
+const FAKE_PREFETCH: &str = r#"#!/bin/sh
+echo "$@" >> "$FAKE_NIX_LOG"
+[ -n "$FAKE_PREFETCH_DELAY" ] && exec sleep "$FAKE_PREFETCH_DELAY"
+case "$*" in
+*"--type sha512"*) zeros=0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000 ;;
+*) zeros=0000000000000000000000000000000000000000000000000000 ;;
+esac
+echo "${FAKE_HASH:-$zeros}"
+"#;
+
+const FAKE_NIX: &str = r#"#!/bin/sh
+case "$1" in
+--version)
+    echo "nix (Nix) ${FAKE_NIX_VERSION:-2.3.16}"
+    ;;
+--extra-experimental-features)
+    echo "$@" >> "$FAKE_NIX_LOG"
+    case "$*" in
+    *" build --no-link "*)
+        # nix --extra-experimental-features 'nix-command flakes' build --no-link <installable>
+        case "$*" in *broken*) exit 1 ;; esac
+        exit 0
+        ;;
+    esac
+    # nix --extra-experimental-features nix-command store prefetch-file --json ...
+    type=$(echo "$@" | sed 's/.*--hash-type \([^ ]*\).*/\1/')
+    echo "{\"hash\": \"$type-0000000000000000000000000000000000000000000=\"}"
+    ;;
+hash)
+    if [ "$2" = path ]; then
+        # nix hash path --type <type> <path>
+        echo "$@" >> "$FAKE_NIX_LOG"
+        echo "$4-0000000000000000000000000000000000000000000000000000000000000000000000000000000000000w=="
+    else
+        # nix hash to-sri --type <type> <hash>
+        echo "$4-$(printf %s "$5" | cut -c1-42)0="
+    fi
+    ;;
+esac
+"#;
+
+/// Revision the fake `git ls-remote` and `nix-prefetch-git` report unless overridden.
+pub const REV: &str = "0123456789abcdef0123456789abcdef01234567";
+
+const FAKE_GIT: &str = r#"#!/bin/sh
+case "$1" in
+ls-remote)
+    # git ls-remote <url> <ref>
+    printf '%s\t%s\n' "${FAKE_REV:-0123456789abcdef0123456789abcdef01234567}" "$3"
+    ;;
+*)
+    echo "$@" >> "$FAKE_GIT_LOG"
+    ;;
+esac
+"#;
+
+const FAKE_PREFETCH_GIT: &str = r#"#!/bin/sh
+echo "$@" >> "$FAKE_NIX_LOG"
+echo "{\"rev\": \"${FAKE_REV:-0123456789abcdef0123456789abcdef01234567}\", \"sha256\": \"0000000000000000000000000000000000000000000000000000\", \"path\": \"/nix/store/00000000000000000000000000000000-checkout\"}"
+"#;
+
+impl Env {
+    pub fn new() -> Self {
+        Self::with_sources_file("sources.json")
+    }
+
+    /// An environment where the sources file is called `name`.
+    pub fn with_sources_file(name: &'static str) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = dir.path().join("bin");
+        std::fs::create_dir(&bin).unwrap();
+        for (name, script) in [
+            ("nix-prefetch-url", FAKE_PREFETCH),
+            ("nix", FAKE_NIX),
+            ("git", FAKE_GIT),
+            ("nix-prefetch-git", FAKE_PREFETCH_GIT),
+        ] {
+            let path = bin.join(name);
+            std::fs::write(&path, script).unwrap();
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        Self {
+            dir,
+            sources_file: name,
+        }
+    }
+
+    pub fn sources_path(&self) -> PathBuf {
+        self.dir.path().join(self.sources_file)
+    }
+
+    /// A command running the binary with the fake `nix-prefetch-url`.
+    pub fn command(&self) -> std::process::Command {
+        self.command_with_prefetcher(Some("nix-prefetch-url"))
+    }
+
+    /// A command running the binary with `prefetcher`, or the default one if `None`.
+    pub fn command_with_prefetcher(&self, prefetcher: Option<&str>) -> std::process::Command {
+        let path = format!(
+            "{}:{}",
+            self.dir.path().join("bin").display(),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_nix-source"));
+        command
+            .env("PATH", path)
+            .env("FAKE_NIX_LOG", self.dir.path().join("prefetch.log"))
+            .env("FAKE_GIT_LOG", self.dir.path().join("git.log"))
+            // Keep tokens and the config file of whoever runs the tests out.
+            .env("XDG_CONFIG_HOME", self.dir.path().join("config"))
+            .env("XDG_CACHE_HOME", self.dir.path().join("cache"))
+            .env("NETRC", self.dir.path().join("netrc"))
+            .env_remove("GITHUB_TOKEN")
+            .env_remove("GITLAB_TOKEN")
+            .env("AWS_CONFIG_FILE", self.dir.path().join("aws-config"))
+            .env(
+                "AWS_SHARED_CREDENTIALS_FILE",
+                self.dir.path().join("aws-credentials"),
+            )
+            .env_remove("AWS_ACCESS_KEY_ID")
+            .env_remove("AWS_SECRET_ACCESS_KEY")
+            .env_remove("AWS_SESSION_TOKEN")
+            .env_remove("AWS_PROFILE")
+            .env_remove("AWS_ENDPOINT_URL")
+            .env_remove("AWS_ENDPOINT_URL_S3")
+            .env_remove("GOOGLE_OAUTH_ACCESS_TOKEN")
+            .env_remove("http_proxy")
+            .env_remove("https_proxy")
+            .env_remove("HTTPS_PROXY")
+            .env_remove("all_proxy")
+            .env_remove("ALL_PROXY")
+            .arg("-s")
+            .arg(self.sources_path());
+        if let Some(prefetcher) = prefetcher {
+            command.args(["--prefetcher", prefetcher]);
+        }
+        command
+    }
+
+    /// Run the binary with `args`, panicking if it fails.
+    pub fn run(&self, args: &[&str]) -> Output {
+        let output = self.command().args(args).output().unwrap();
+        assert!(
+            output.status.success(),
+            "{args:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    }
+
+    pub fn sources(&self) -> serde_json::Value {
+        serde_json::from_slice(&std::fs::read(self.sources_path()).unwrap()).unwrap()
+    }
+
+    pub fn write_sources(&self, sources: serde_json::Value) {
+        std::fs::write(self.sources_path(), sources.to_string()).unwrap();
+    }
+
+    /// The arguments `nix-prefetch-url` or `nix store prefetch-file` was called with, one
+    /// invocation per line.
+    pub fn prefetch_log(&self) -> Vec<String> {
+        std::fs::read_to_string(self.dir.path().join("prefetch.log"))
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use common::{Env, SRI};
+
+#[test]
+fn sources_are_exported_as_flake_inputs() {
+    let env = Env::new();
+    let rev = "0123456789abcdef0123456789abcdef01234567";
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "lib": {
+                "url": format!("https://github.com/owner/lib/archive/{rev}.tar.gz"),
+                "type": "tarball",
+                "hash": SRI,
+                "github_branch": { "owner": "owner", "repo": "lib", "branch": "main" },
+                "rev": rev
+            },
+            "tools": { "url": "https://example.com/tools.tar.gz", "type": "tarball", "hash": SRI },
+            "doc": { "url": "https://example.com/doc.pdf", "type": "file", "hash": SRI },
+        }
+    }));
+    let lock = env.dir.path().join("flake.lock");
+    std::fs::write(
+        &lock,
+        serde_json::json!({
+            "nodes": {
+                "other": { "locked": { "type": "path", "path": "." } },
+                "root": { "inputs": { "other": "other" } }
+            },
+            "root": "root",
+            "version": 7
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let output = env.run(&[
+        "export",
+        "--format",
+        "flake",
+        "--lock",
+        lock.to_str().unwrap(),
+    ]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "inputs = {\n  \
+         lib = { url = \"github:owner/lib/main\"; flake = false; };\n  \
+         tools = { url = \"tarball+https://example.com/tools.tar.gz\"; flake = false; };\n\
+         };\n"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("skipping doc"));
+
+    let lock: serde_json::Value = serde_json::from_slice(&std::fs::read(&lock).unwrap()).unwrap();
+    let nodes = &lock["nodes"];
+    assert_eq!(nodes["lib"]["locked"]["rev"], rev);
+    assert_eq!(nodes["lib"]["locked"]["narHash"], SRI);
+    assert_eq!(nodes["lib"]["original"]["ref"], "main");
+    assert_eq!(nodes["tools"]["locked"]["type"], "tarball");
+    assert_eq!(
+        nodes["root"]["inputs"],
+        serde_json::json!({ "lib": "lib", "other": "other", "tools": "tools" })
+    );
+    assert!(nodes["other"].is_object());
+}
+
+#[test]
+fn generate_writes_a_loader_next_to_the_sources_file() {
+    let env = Env::with_sources_file("pins.toml");
+    env.run(&["generate"]);
+    let loader = std::fs::read_to_string(env.dir.path().join("sources.nix")).unwrap();
+    assert!(
+        loader.contains(r#"{ sourcesFile ? ./. + "/pins.toml" }:"#),
+        "{loader}"
+    );
+    assert!(loader.contains("builtins.fromTOML (builtins.readFile sourcesFile)"));
+}
+
+#[test]
+fn sources_are_exported_as_software_bills_of_materials() {
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "doc": {
+                "url": "https://example.com/doc-1.2.pdf",
+                "type": "file",
+                "hash": SRI,
+                "version": "1.2",
+            },
+            "tools": { "url": "https://example.com/tools.tar.gz", "type": "tarball", "hash": SRI },
+        }
+    }));
+    let export = |format: &str| {
+        let output = env
+            .command()
+            .env("SOURCE_DATE_EPOCH", "0")
+            .args(["export", "--format", format])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+
+    let bom = export("cyclonedx");
+    assert_eq!(bom["bomFormat"], "CycloneDX");
+    assert_eq!(bom["metadata"]["timestamp"], "1970-01-01T00:00:00Z");
+    let doc = &bom["components"][0];
+    assert_eq!(doc["name"], "doc");
+    assert_eq!(doc["version"], "1.2");
+    assert_eq!(
+        doc["externalReferences"][0]["url"],
+        "https://example.com/doc-1.2.pdf"
+    );
+    assert_eq!(doc["hashes"][0]["alg"], "SHA-256");
+    assert_eq!(doc["hashes"][0]["content"].as_str().unwrap().len(), 64);
+    // The hash of an unpacked source isn't the checksum of what was downloaded.
+    let tools = &bom["components"][1];
+    assert!(tools.get("hashes").is_none());
+    assert_eq!(tools["properties"][0]["value"], SRI);
+
+    let spdx = export("spdx");
+    assert_eq!(spdx["spdxVersion"], "SPDX-2.3");
+    let doc = &spdx["packages"][0];
+    assert_eq!(doc["SPDXID"], "SPDXRef-doc");
+    assert_eq!(doc["versionInfo"], "1.2");
+    assert_eq!(doc["downloadLocation"], "https://example.com/doc-1.2.pdf");
+    assert_eq!(doc["checksums"][0]["algorithm"], "SHA256");
+    assert!(spdx["packages"][1].get("checksums").is_none());
+    assert_eq!(spdx["relationships"].as_array().unwrap().len(), 2);
+    assert_eq!(export("spdx"), spdx);
+}
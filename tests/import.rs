@@ -0,0 +1,340 @@
+mod common;
+
+use common::{Env, SRI};
+
+#[test]
+fn niv_sources_are_imported() {
+    let env = Env::new();
+    let niv = env.dir.path().join("niv.json");
+    std::fs::write(
+        &niv,
+        serde_json::json!({
+            "nixpkgs": {
+                "branch": "nixos-unstable",
+                "owner": "NixOS",
+                "repo": "nixpkgs",
+                "rev": "abc",
+                "sha256": common::HASH,
+                "type": "tarball",
+                "url": "https://github.com/NixOS/nixpkgs/archive/abc.tar.gz",
+                "url_template": "https://github.com/<owner>/<repo>/archive/<rev>.tar.gz"
+            },
+            "repo": {
+                "branch": "main",
+                "repo": "https://example.com/repo.git",
+                "rev": "def",
+                "type": "git"
+            },
+            "tool": {
+                "sha256": common::HASH,
+                "type": "file",
+                "url": "https://example.com/tool-1.2.bin",
+                "url_template": "https://example.com/tool-<version>.bin",
+                "version": "1.2"
+            },
+            "local": { "type": "local", "path": "./foo" },
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let output = env.run(&["import", "--format", "niv", niv.to_str().unwrap()]);
+
+    let sources = env.sources();
+    let nixpkgs = &sources["sources"]["nixpkgs"];
+    assert_eq!(nixpkgs["hash"], SRI);
+    assert_eq!(nixpkgs["type"], "tarball");
+    assert_eq!(
+        nixpkgs["url"],
+        "https://github.com/NixOS/nixpkgs/archive/abc.tar.gz"
+    );
+    assert_eq!(
+        nixpkgs["github_branch"],
+        serde_json::json!({ "owner": "NixOS", "repo": "nixpkgs", "branch": "nixos-unstable" })
+    );
+    assert_eq!(nixpkgs["rev"], "abc");
+    let tool = &sources["sources"]["tool"];
+    assert_eq!(
+        tool["url_template"],
+        "https://example.com/tool-{version}.bin"
+    );
+    assert_eq!(tool["version"], "1.2");
+    let repo = &sources["sources"]["repo"];
+    assert_eq!(repo["type"], "git");
+    assert_eq!(repo["ref"], "main");
+    assert_eq!(repo["rev"], "def");
+    assert!(sources["sources"].get("local").is_none());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("skipping local"));
+}
+
+#[test]
+fn import_collisions_are_skipped_errors_or_suffixed() {
+    let env = Env::new();
+    let niv = env.dir.path().join("niv.json");
+    std::fs::write(
+        &niv,
+        serde_json::json!({
+            "tool": {
+                "sha256": common::HASH,
+                "type": "file",
+                "url": "https://example.com/tool-1.2.bin",
+            },
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let niv = niv.to_str().unwrap();
+    env.run(&["import", "--format", "niv", niv]);
+
+    let output = env.run(&["import", "--format", "niv", niv]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("skipping tool: source already exists"),
+        "{stderr}"
+    );
+    assert!(env.sources()["sources"].get("tool-2").is_none());
+
+    let output = env
+        .command()
+        .args(["import", "--format", "niv", niv, "--on-collision", "error"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("source tool already exists"));
+
+    env.run(&["import", "--format", "niv", niv, "--on-collision", "suffix"]);
+    let sources = env.sources();
+    assert_eq!(
+        sources["sources"]["tool-2"]["url"],
+        "https://example.com/tool-1.2.bin"
+    );
+    assert_eq!(sources["sources"]["tool"], sources["sources"]["tool-2"]);
+}
+
+#[test]
+fn manifest_entries_are_added_independently() {
+    let server = common::Server::start(|req| match req.path.as_str() {
+        "/missing.txt" => common::Response::new(404),
+        _ => common::Response::new(200),
+    });
+    let env = Env::new();
+    env.run(&["add", "existing", &server.url("/existing.txt")]);
+    let manifest = env.dir.path().join("manifest.json");
+    std::fs::write(
+        &manifest,
+        serde_json::json!([
+            { "name": "foo", "url": server.url("/foo"), "type": "tarball" },
+            { "name": "missing", "url": server.url("/missing.txt") },
+            { "name": "existing", "url": server.url("/other.txt") },
+            { "name": "bar", "url": server.url("/bar.txt") },
+        ])
+        .to_string(),
+    )
+    .unwrap();
+    let output = env
+        .command()
+        .args(["batch-add", manifest.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("2 sources could not be added"), "{stderr}");
+
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["foo"]["type"], "tarball");
+    assert_eq!(sources["sources"]["bar"]["hash"], SRI);
+    assert!(sources["sources"].get("missing").is_none());
+    assert_eq!(
+        sources["sources"]["existing"]["url"],
+        server.url("/existing.txt")
+    );
+
+    env.command()
+        .args([
+            "batch-add",
+            manifest.to_str().unwrap(),
+            "--update-if-exists",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(
+        env.sources()["sources"]["existing"]["url"],
+        server.url("/other.txt")
+    );
+}
+
+#[test]
+fn manifest_entries_with_taken_names_can_be_suffixed() {
+    let server = common::Server::start(|_| common::Response::new(200));
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    let manifest = env.dir.path().join("manifest.json");
+    std::fs::write(
+        &manifest,
+        serde_json::json!([
+            { "name": "foo", "url": server.url("/foo-1.txt") },
+            { "name": "foo", "url": server.url("/foo-2.txt") },
+        ])
+        .to_string(),
+    )
+    .unwrap();
+    env.run(&[
+        "batch-add",
+        manifest.to_str().unwrap(),
+        "--on-collision",
+        "suffix",
+    ]);
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["foo"]["url"], server.url("/foo.txt"));
+    assert_eq!(sources["sources"]["foo-2"]["url"], server.url("/foo-1.txt"));
+    assert_eq!(sources["sources"]["foo-3"]["url"], server.url("/foo-2.txt"));
+
+    let output = env.run(&[
+        "batch-add",
+        manifest.to_str().unwrap(),
+        "--on-collision",
+        "skip",
+    ]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("skipping foo"));
+    assert_eq!(env.sources()["sources"].as_object().unwrap().len(), 3);
+}
+
+#[test]
+fn npins_pins_are_imported() {
+    let env = Env::new();
+    let npins = env.dir.path().join("npins.json");
+    let rev = "0123456789abcdef0123456789abcdef01234567";
+    std::fs::write(
+        &npins,
+        serde_json::json!({
+            "pins": {
+                "nixpkgs": {
+                    "type": "Channel",
+                    "name": "nixos-unstable",
+                    "url": "https://releases.nixos.org/nixos/unstable/nixos-24.11pre1.abc/nixexprs.tar.xz",
+                    "hash": common::HASH
+                },
+                "tool": {
+                    "type": "GitRelease",
+                    "repository": { "type": "GitHub", "owner": "owner", "repo": "tool" },
+                    "pre_releases": false,
+                    "version": "v1.0",
+                    "revision": rev,
+                    "url": "https://api.github.com/repos/owner/tool/tarball/v1.0",
+                    "hash": SRI
+                },
+                "lib": {
+                    "type": "Git",
+                    "repository": { "type": "GitHub", "owner": "owner", "repo": "lib" },
+                    "branch": "main",
+                    "revision": rev,
+                    "url": format!("https://github.com/owner/lib/archive/{rev}.tar.gz"),
+                    "hash": common::HASH
+                },
+                "repo": {
+                    "type": "Git",
+                    "repository": { "type": "Git", "url": "https://example.com/repo.git" },
+                    "branch": "main",
+                    "revision": rev,
+                    "url": null,
+                    "hash": common::HASH
+                },
+                "black": {
+                    "type": "PyPi",
+                    "name": "black",
+                    "version": "24.1.0",
+                    "url": "https://files.pythonhosted.org/black-24.1.0.tar.gz",
+                    "hash": common::HASH
+                },
+                "other": { "type": "Container", "image_name": "foo" },
+            },
+            "version": 5
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let output = env.run(&["import", "--format", "npins", npins.to_str().unwrap()]);
+
+    let sources = &env.sources()["sources"];
+    assert_eq!(sources["nixpkgs"]["type"], "channel");
+    assert_eq!(
+        sources["nixpkgs"]["url"],
+        "https://channels.nixos.org/nixos-unstable/nixexprs.tar.xz"
+    );
+    assert_eq!(sources["nixpkgs"]["hash"], SRI);
+    assert_eq!(sources["tool"]["repository"]["forge"], "github");
+    assert_eq!(sources["tool"]["version"], "v1.0");
+    assert_eq!(sources["tool"]["hash"], SRI);
+    assert_eq!(sources["lib"]["github_branch"]["branch"], "main");
+    assert_eq!(sources["lib"]["rev"], rev);
+    assert_eq!(sources["repo"]["type"], "git");
+    assert_eq!(sources["repo"]["url"], "https://example.com/repo.git");
+    assert_eq!(sources["repo"]["ref"], "main");
+    assert_eq!(sources["black"]["type"], "pypi");
+    assert_eq!(sources["black"]["package"], "black");
+    assert!(sources.get("other").is_none());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unsupported type Container"));
+}
+
+#[test]
+fn flake_lock_inputs_are_imported() {
+    let env = Env::new();
+    let lock = env.dir.path().join("flake.lock");
+    let rev = "0123456789abcdef0123456789abcdef01234567";
+    std::fs::write(
+        &lock,
+        serde_json::json!({
+            "nodes": {
+                "nixpkgs": {
+                    "locked": {
+                        "lastModified": 1700000000,
+                        "narHash": SRI,
+                        "owner": "NixOS",
+                        "repo": "nixpkgs",
+                        "rev": rev,
+                        "type": "github"
+                    },
+                    "original": {
+                        "owner": "NixOS",
+                        "ref": "nixos-unstable",
+                        "repo": "nixpkgs",
+                        "type": "github"
+                    }
+                },
+                "tools": {
+                    "locked": {
+                        "narHash": SRI,
+                        "type": "tarball",
+                        "url": "https://example.com/tools.tar.gz"
+                    },
+                    "original": { "type": "tarball", "url": "https://example.com/tools.tar.gz" }
+                },
+                "local": {
+                    "locked": { "lastModified": 1, "narHash": SRI, "path": "./local", "type": "path" },
+                    "original": { "path": "./local", "type": "path" }
+                },
+                "root": { "inputs": { "local": "local", "nixpkgs": "nixpkgs", "tools": "tools" } }
+            },
+            "root": "root",
+            "version": 7
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let output = env.run(&["import", "--format", "flake-lock", lock.to_str().unwrap()]);
+
+    let sources = &env.sources()["sources"];
+    assert_eq!(
+        sources["nixpkgs"]["url"],
+        format!("https://github.com/NixOS/nixpkgs/archive/{rev}.tar.gz")
+    );
+    assert_eq!(sources["nixpkgs"]["hash"], SRI);
+    assert_eq!(
+        sources["nixpkgs"]["github_branch"]["branch"],
+        "nixos-unstable"
+    );
+    assert_eq!(sources["nixpkgs"]["rev"], rev);
+    assert_eq!(sources["tools"]["type"], "tarball");
+    assert!(sources.get("root").is_none());
+    assert!(sources.get("local").is_none());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unsupported type path"));
+}
@@ -0,0 +1,418 @@
+mod common;
+
+use common::{Env, Response, Server};
+
+#[test]
+fn ls_filters_by_name_and_type() {
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "foo-src": { "url": "https://example.com/foo.tar.gz", "type": "tarball" },
+            "foo-doc": { "url": "https://example.com/foo.pdf", "type": "file" },
+            "bar-src": { "url": "https://example.com/bar.tar.gz", "type": "tarball" },
+        }
+    }));
+    let list = |args: &[&str]| {
+        let output = env
+            .command()
+            .arg("ls")
+            .args(args)
+            .arg("--json")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let entries: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).unwrap();
+        entries
+            .iter()
+            .map(|e| e["name"].as_str().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(list(&["foo-*"]), ["foo-doc", "foo-src"]);
+    assert_eq!(list(&["--type", "tarball"]), ["bar-src", "foo-src"]);
+    assert_eq!(list(&["foo-*", "--type", "tarball"]), ["foo-src"]);
+}
+
+#[test]
+fn ls_prints_a_table() {
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "foo": {
+                "url": "https://example.com/foo.tar.gz",
+                "type": "tarball",
+                "version": "1.0",
+                "hash": common::SRI,
+                "description": "a library for parsing, printing and rewriting foo files",
+                "homepage": "https://foo.example.com/",
+                "license": "MIT",
+            },
+            "bar": { "url": "https://example.com/bar" },
+        }
+    }));
+    let output = env.run(&["ls"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "NAME  TYPE     VERSION  HASH  URL\n\
+         bar   -        -        no    https://example.com/bar\n\
+         foo   tarball  1.0      yes   https://example.com/foo.tar.gz\n"
+    );
+    let output = env.run(&["ls", "--long"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "NAME  TYPE     VERSION  HASH  DESCRIPTION                               HOMEPAGE                  LICENSE  URL\n\
+         bar   -        -        no    -                                         -                         -        https://example.com/bar\n\
+         foo   tarball  1.0      yes   a library for parsing, printing and r...  https://foo.example.com/  MIT      https://example.com/foo.tar.gz\n"
+    );
+}
+
+#[test]
+fn get_prints_a_single_field() {
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "foo": {
+                "url": "https://example.com/foo.tar.gz",
+                "type": "tarball",
+                "store_name": "foo-src",
+                "pinned": true,
+            },
+        }
+    }));
+    let get = |field: &str| env.command().args(["get", "foo", field]).output().unwrap();
+    assert_eq!(get("url").stdout, b"https://example.com/foo.tar.gz\n");
+    assert_eq!(get("type").stdout, b"tarball\n");
+    assert_eq!(get("store-name").stdout, b"foo-src\n");
+    assert_eq!(get("pinned").stdout, b"true\n");
+
+    let output = get("hash");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("foo has no hash"));
+}
+
+#[test]
+fn get_prints_json_values() {
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "foo": {
+                "url": "https://example.com/foo.tar.gz",
+                "headers": { "Accept": "application/octet-stream" },
+            },
+        }
+    }));
+    let get = |field: &str| env.run(&["--output", "json", "get", "foo", field]).stdout;
+    let url: serde_json::Value = serde_json::from_slice(&get("url")).unwrap();
+    assert_eq!(url, "https://example.com/foo.tar.gz");
+    let headers: serde_json::Value = serde_json::from_slice(&get("headers")).unwrap();
+    assert_eq!(
+        headers,
+        serde_json::json!({ "Accept": "application/octet-stream" })
+    );
+}
+
+#[test]
+fn completions_complete_subcommands_flags_and_source_names() {
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "foo": { "url": "https://example.com/foo.tar.gz", "type": "tarball" },
+            "bar": { "url": "https://example.com/bar.tar.gz", "type": "tarball" },
+        }
+    }));
+    let output = env.command().args(["ls", "--names"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"bar\nfoo\n");
+
+    for shell in ["bash", "zsh", "fish"] {
+        let output = env.command().args(["completions", shell]).output().unwrap();
+        assert!(output.status.success());
+        let script = String::from_utf8(output.stdout).unwrap();
+        assert!(script.contains("update"), "{shell}: {script}");
+        assert!(script.contains("include-pinned"), "{shell}: {script}");
+        assert!(script.contains("ls --names"), "{shell}: {script}");
+    }
+    let output = env
+        .command()
+        .args(["completions", "tcsh"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn man_pages_cover_every_subcommand_and_are_not_advertised() {
+    let env = Env::new();
+    let dir = env.dir.path().join("man");
+    // Packagers run it on its own, without the flags every other command gets here.
+    let generate = || {
+        let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_nix-source"));
+        command.arg("generate-manpages");
+        command
+    };
+    let output = generate().arg(&dir).output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let page = std::fs::read_to_string(dir.join("nix-source.1")).unwrap();
+    assert!(page.starts_with(".TH \"NIX-SOURCE\" 1"), "{page}");
+    assert!(page.contains("\\fBupdate\\fR"), "{page}");
+    let add = std::fs::read_to_string(dir.join("nix-source-add.1")).unwrap();
+    assert!(add.contains("\\fB\\-\\-github\\fR"), "{add}");
+    assert!(add.contains(".SH SEE ALSO"), "{add}");
+    let update = std::fs::read_to_string(dir.join("nix-source-update.1")).unwrap();
+    assert!(update.contains("include\\-pinned"), "{update}");
+    let reference = std::fs::read_to_string(dir.join("nix-source.txt")).unwrap();
+    assert!(reference.contains("nix-source add [options] <name> [<url>]"));
+    assert!(reference.contains("--github <github>"));
+
+    let help = env.command().arg("--help").output().unwrap();
+    assert!(!String::from_utf8_lossy(&help.stdout).contains("generate-manpages"));
+    let script = env
+        .command()
+        .args(["completions", "bash"])
+        .output()
+        .unwrap();
+    assert!(!String::from_utf8_lossy(&script.stdout).contains("generate-manpages"));
+    let output = generate().output().unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn defaults_come_from_the_config_files() {
+    let env = Env::new();
+    let project = env.dir.path().join("project");
+    std::fs::create_dir_all(project.join("nix")).unwrap();
+    std::fs::create_dir_all(project.join("sub")).unwrap();
+    std::fs::write(
+        project.join("nix").join("sources.json"),
+        serde_json::json!({
+            "sources": { "foo": { "url": "https://example.com/foo.tar.gz" } }
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let config = env.dir.path().join("config").join("nix-source");
+    std::fs::create_dir_all(&config).unwrap();
+    std::fs::write(config.join("config.toml"), "sources = \"elsewhere.json\"\n").unwrap();
+    // The project's sources file is relative to the project, and wins over the user's.
+    std::fs::write(
+        project.join(".nix-source.toml"),
+        "sources = \"nix/sources.json\"\n",
+    )
+    .unwrap();
+    let ls = || {
+        std::process::Command::new(env!("CARGO_BIN_EXE_nix-source"))
+            .current_dir(project.join("sub"))
+            .env("XDG_CONFIG_HOME", env.dir.path().join("config"))
+            .env_remove("NIX_SOURCE_FILE")
+            .args(["ls", "--names"])
+            .output()
+            .unwrap()
+    };
+    let output = ls();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(output.stdout, b"foo\n");
+
+    std::fs::write(config.join("config.toml"), "hash-type = \"md5\"\n").unwrap();
+    let output = ls();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid defaults in the config file"));
+}
+
+#[test]
+fn the_sources_file_is_found_in_parent_directories() {
+    let env = Env::new();
+    let project = env.dir.path().join("project");
+    let sub = project.join("a").join("b");
+    std::fs::create_dir_all(project.join("nix")).unwrap();
+    std::fs::create_dir_all(&sub).unwrap();
+    std::fs::write(
+        project.join("nix").join("sources.json"),
+        serde_json::json!({
+            "sources": { "foo": { "url": "https://example.com/foo.tar.gz" } }
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_nix-source"))
+        .current_dir(&sub)
+        .env("XDG_CONFIG_HOME", env.dir.path().join("config"))
+        .env_remove("NIX_SOURCE_FILE")
+        .args(["ls", "--names"])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(output.stdout, b"foo\n");
+    assert!(!sub.join("sources.json").exists());
+}
+
+#[test]
+fn metadata_is_set_shown_and_kept_across_updates() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&[
+        "add",
+        "foo",
+        &server.url("/foo.tar.gz"),
+        "--description",
+        "the foo library",
+        "--homepage",
+        "https://foo.example.com/",
+        "--license",
+        "MIT",
+    ]);
+    env.run(&["update", "foo", "--force"]);
+    let output = env.run(&["show", "foo"]);
+    let show = String::from_utf8_lossy(&output.stdout);
+    assert!(show.contains("description: the foo library"), "{show}");
+    assert!(
+        show.contains("homepage: https://foo.example.com/"),
+        "{show}"
+    );
+    assert!(show.contains("license: MIT"), "{show}");
+
+    // Changing only metadata doesn't fetch the source again.
+    let requests = server.requests().len();
+    env.run(&[
+        "--offline",
+        "set",
+        "foo",
+        "--license",
+        "",
+        "--description",
+        "foo",
+    ]);
+    assert_eq!(server.requests().len(), requests);
+    let output = env.run(&["ls", "--json"]);
+    let entries: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(entries[0]["description"], "foo");
+    assert_eq!(entries[0]["homepage"], "https://foo.example.com/");
+    assert!(entries[0]["license"].is_null());
+}
+
+#[test]
+fn show_prints_a_source_readably() {
+    let env = Env::new();
+    let foo = serde_json::json!({
+        "url": "https://example.com/foo-1.0.tar.gz",
+        "type": "tarball",
+        "hash": common::SRI,
+        "etag": "\"v1\"",
+        "last_modified": "2024-01-01T00:00:00Z",
+        "version": "1.0",
+        "store_name": "foo-src",
+        "mirrors": ["https://a.example.com/foo.tar.gz", "https://b.example.com/foo.tar.gz"],
+    });
+    env.write_sources(serde_json::json!({ "sources": { "foo": foo } }));
+    let output = env.run(&["show", "foo"]);
+    let show = String::from_utf8_lossy(&output.stdout);
+    for line in [
+        "name: foo",
+        "type: tarball",
+        &format!("hash: {}", common::SRI),
+        "etag: \"v1\"",
+        "last modified: 2024-01-01T00:00:00Z",
+        "version: 1.0",
+        "mirrors: https://a.example.com/foo.tar.gz, https://b.example.com/foo.tar.gz",
+    ] {
+        assert!(show.lines().any(|l| l == line), "{line}: {show}");
+    }
+    assert_eq!(show.matches("store name").count(), 1, "{show}");
+    assert!(show.ends_with("store name: foo-src\n"), "{show}");
+
+    let output = env.run(&["show", "foo", "--json"]);
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json, foo);
+}
+
+#[test]
+fn sources_are_listed_and_updated_by_group() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&[
+        "add",
+        "cmake",
+        &server.url("/cmake.txt"),
+        "--group",
+        "build-tools",
+    ]);
+    env.run(&["add", "ninja", &server.url("/ninja.txt")]);
+    env.run(&["add", "zlib", &server.url("/zlib.txt")]);
+    env.run(&["--offline", "set", "ninja", "--group", "build-tools"]);
+
+    let output = env.run(&["ls", "--names", "--group", "build-tools"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "cmake\nninja\n");
+
+    let before = env.prefetch_log().len();
+    env.run(&["update", "--group", "build-tools"]);
+    let log = env.prefetch_log();
+    assert_eq!(log.len(), before + 2);
+    assert!(!log[before..].iter().any(|line| line.contains("zlib")));
+
+    let output = env
+        .command()
+        .args(["update", "--group", "docs"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no source is in group docs"));
+}
+
+#[test]
+fn versions_are_compared_with_nixpkgs() {
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "hello": { "url": "https://example.com/hello-2.10.tar.gz", "version": "2.10" },
+            "curl": { "url": "https://example.com/curl.tar.gz", "version": "v9.0" },
+            "foo": {
+                "url": "https://example.com/foo.tar.gz",
+                "version": "1.0",
+                "nixpkgs": "python3Packages.foo",
+            },
+            "gone": { "url": "https://example.com/gone.tar.gz", "version": "1.0" },
+            "unversioned": { "url": "https://example.com/unversioned.tar.gz" },
+        }
+    }));
+    let nix_instantiate = env.dir.path().join("bin/nix-instantiate");
+    std::fs::write(
+        &nix_instantiate,
+        format!(
+            "#!/bin/sh\nprintf %s \"$5\" > {}\n\
+             echo '{{\"curl\": \"8.0\", \"foo\": \"1.0\", \"gone\": null, \"hello\": \"2.12.1\"}}'\n",
+            env.dir.path().join("expr.nix").display()
+        ),
+    )
+    .unwrap();
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(&nix_instantiate, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = env.run(&[
+        "--output",
+        "json",
+        "compare-nixpkgs",
+        "--channel",
+        "nixos-24.05",
+    ]);
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["hello"]["status"], "older");
+    assert_eq!(report["hello"]["nixpkgs"], "2.12.1");
+    assert_eq!(report["curl"]["status"], "newer");
+    assert_eq!(report["foo"]["status"], "same");
+    assert_eq!(report["gone"]["status"], "missing");
+    assert!(report.get("unversioned").is_none());
+    let expr = std::fs::read_to_string(env.dir.path().join("expr.nix")).unwrap();
+    assert!(expr.contains("https://channels.nixos.org/nixos-24.05/nixexprs.tar.xz"));
+    assert!(
+        expr.contains(r#"foo = version [ "python3Packages" "foo" ];"#),
+        "{expr}"
+    );
+    assert!(expr.contains(r#"hello = version [ "hello" ];"#), "{expr}");
+}
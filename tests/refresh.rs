@@ -0,0 +1,2696 @@
+mod common;
+
+use common::{Env, Response, Server, REV, SRI};
+
+#[test]
+fn not_modified_skips_prefetch() {
+    let server = Server::start(|req| {
+        if req.header("If-None-Match") == Some("\"v1\"") {
+            Response::new(304)
+        } else {
+            Response::new(200).header("ETag", "\"v1\"")
+        }
+    });
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    env.run(&["update"]);
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].header("If-None-Match"), None);
+    assert_eq!(requests[1].header("If-None-Match"), Some("\"v1\""));
+    assert_eq!(env.prefetch_log().len(), 1);
+    assert_eq!(env.sources()["sources"]["foo"]["hash"], SRI);
+}
+
+#[test]
+fn hashes_are_printed_unless_quiet() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    let output = env.run(&["add", "foo", &server.url("/foo.txt")]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&format!("foo: {SRI}")));
+    let output = env.run(&["set", "foo", "--url", &server.url("/bar.txt")]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&format!("foo: {SRI}")));
+    let output = env.run(&["--quiet", "add", "bar", &server.url("/bar.txt")]);
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn added_sources_are_printed_as_json() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    let output = env.run(&["--output", "json", "add", "foo", &server.url("/foo.txt")]);
+    let added: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        added,
+        serde_json::json!({ "foo": env.sources()["sources"]["foo"] })
+    );
+    assert_eq!(added["foo"]["hash"], SRI);
+}
+
+#[test]
+fn changed_sources_are_printed_as_json() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    let output = env.run(&[
+        "--output",
+        "json",
+        "set",
+        "foo",
+        "--url",
+        &server.url("/bar.txt"),
+    ]);
+    let changed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(changed["foo"]["url"], server.url("/bar.txt"));
+    assert_eq!(changed["foo"]["hash"], SRI);
+}
+
+#[test]
+fn bumped_sources_are_printed_as_json() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&[
+        "add",
+        "foo",
+        &server.url("/foo-{version}.txt"),
+        "--version",
+        "1.0",
+    ]);
+    let output = env.run(&["--output", "json", "bump", "foo", "1.1"]);
+    let bumped: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        bumped,
+        serde_json::json!({ "foo": env.sources()["sources"]["foo"] })
+    );
+    assert_eq!(bumped["foo"]["version"], "1.1");
+}
+
+#[test]
+fn strong_and_weak_etags_round_trip() {
+    let server = Server::start(|req| {
+        let etag = match req.path.as_str() {
+            "/strong.txt" => "\"abc\"",
+            _ => "W/\"abc\"",
+        };
+        if req.header("If-None-Match") == Some(etag) {
+            Response::new(304)
+        } else {
+            Response::new(200).header("ETag", etag)
+        }
+    });
+    let env = Env::new();
+    env.run(&["add", "strong", &server.url("/strong.txt")]);
+    env.run(&["add", "weak", &server.url("/weak.txt")]);
+
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["strong"]["etag"], "\"abc\"");
+    assert_eq!(sources["sources"]["weak"]["etag"], "W/\"abc\"");
+
+    env.run(&["update"]);
+    assert_eq!(env.prefetch_log().len(), 2);
+    let mut sent: Vec<_> = server.requests()[2..]
+        .iter()
+        .map(|r| r.header("If-None-Match").unwrap().to_owned())
+        .collect();
+    sent.sort();
+    assert_eq!(sent, ["\"abc\"", "W/\"abc\""]);
+}
+
+#[test]
+fn content_disposition_names_the_store_path() {
+    let server = Server::start(|_| {
+        Response::new(200).header(
+            "Content-Disposition",
+            "attachment; filename=\"foo-1.0.tar.gz\"",
+        )
+    });
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/download")]);
+
+    let log = env.prefetch_log();
+    assert!(
+        log[0].starts_with("--name foo-1.0.tar.gz --unpack "),
+        "{log:?}"
+    );
+    assert_eq!(env.sources()["sources"]["foo"]["type"], "tarball");
+}
+
+#[test]
+fn redirects_are_followed_and_recorded() {
+    let server = Server::start(|req| match req.path.as_str() {
+        "/latest" => Response::new(302).header("Location", "/v1/foo.zip"),
+        _ => Response::new(200),
+    });
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/latest"), "--record-redirects"]);
+
+    let paths: Vec<_> = server.requests().into_iter().map(|r| r.path).collect();
+    assert_eq!(paths, ["/latest", "/v1/foo.zip"]);
+    let sources = env.sources();
+    assert_eq!(
+        sources["sources"]["foo"]["redirects"],
+        serde_json::json!([server.url("/v1/foo.zip")])
+    );
+    assert_eq!(
+        sources["sources"]["foo"]["resolved_url"],
+        server.url("/v1/foo.zip")
+    );
+    assert!(env.prefetch_log()[0].ends_with(&server.url("/v1/foo.zip")));
+
+    // Fetched again without recording them, the old chain is dropped.
+    env.run(&["update", "--force"]);
+    assert!(env.sources()["sources"]["foo"].get("redirects").is_none());
+}
+
+#[test]
+fn follow_pins_where_redirects_lead() {
+    let server = Server::start(|req| match req.path.as_str() {
+        "/latest" => Response::new(302).header("Location", "/v1/foo.zip"),
+        _ => Response::new(200),
+    });
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/latest")]);
+    assert_eq!(
+        env.sources()["sources"]["foo"]["url"],
+        server.url("/latest")
+    );
+
+    env.run(&["update", "--follow"]);
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["foo"]["url"], server.url("/v1/foo.zip"));
+    assert!(sources["sources"]["foo"].get("resolved_url").is_none());
+    let before = server.requests().len();
+    env.run(&["update"]);
+    let paths: Vec<_> = server.requests()[before..]
+        .iter()
+        .map(|r| r.path.clone())
+        .collect();
+    assert_eq!(paths, ["/v1/foo.zip"]);
+}
+
+#[test]
+fn redirect_loops_are_detected() {
+    let server = Server::start(|req| match req.path.as_str() {
+        "/a" => Response::new(302).header("Location", "/b"),
+        _ => Response::new(302).header("Location", "/a"),
+    });
+    let env = Env::new();
+    let output = env
+        .command()
+        .args(["add", "foo", &server.url("/a")])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("redirect loop"));
+}
+
+#[test]
+fn type_is_detected_from_extension() {
+    let server = Server::start(|_| Response::new(200).header("Content-Type", "application/gzip"));
+    let env = Env::new();
+    env.run(&["add", "archive", &server.url("/foo.tar.xz")]);
+    env.run(&["add", "plain", &server.url("/foo.txt")]);
+
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["archive"]["type"], "tarball");
+    assert_eq!(sources["sources"]["plain"]["type"], "file");
+}
+
+#[test]
+fn type_is_detected_from_content_type() {
+    let server = Server::start(|req| match req.path.as_str() {
+        "/archive" => Response::new(200).header("Content-Type", "application/gzip"),
+        _ => Response::new(200).header("Content-Type", "text/plain"),
+    });
+    let env = Env::new();
+    env.run(&["add", "archive", &server.url("/archive")]);
+    env.run(&["add", "plain", &server.url("/plain")]);
+
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["archive"]["type"], "tarball");
+    assert_eq!(sources["sources"]["plain"]["type"], "file");
+    let log = env.prefetch_log();
+    assert!(log[0].contains("--unpack"));
+    assert!(!log[1].contains("--unpack"));
+}
+
+#[test]
+fn if_modified_since_is_sent_in_gmt() {
+    let server = Server::start(|_| Response::new(304));
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "foo": {
+                "url": server.url("/foo.txt"),
+                "hash": SRI,
+                "last_modified": "2024-01-01T12:00:00+02:00",
+            }
+        }
+    }));
+    env.run(&["update"]);
+
+    assert_eq!(
+        server.requests()[0].header("If-Modified-Since"),
+        Some("Mon, 01 Jan 2024 10:00:00 GMT")
+    );
+}
+
+#[test]
+fn get_is_used_when_head_is_rejected() {
+    let server = Server::start(|req| match req.method.as_str() {
+        "HEAD" => Response::new(405),
+        _ => Response::new(200).header("ETag", "\"v1\"").body("content"),
+    });
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+
+    let methods: Vec<_> = server.requests().into_iter().map(|r| r.method).collect();
+    assert_eq!(methods, ["HEAD", "GET"]);
+    assert_eq!(env.sources()["sources"]["foo"]["etag"], "\"v1\"");
+}
+
+#[test]
+fn update_records_the_detected_type() {
+    let server = Server::start(|_| {
+        Response::new(200).header("Content-Disposition", "attachment; filename=\"foo.tar.gz\"")
+    });
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": { "foo": { "url": server.url("/download") } }
+    }));
+    env.run(&["update"]);
+
+    assert_eq!(env.sources()["sources"]["foo"]["type"], "tarball");
+}
+
+#[test]
+fn server_errors_are_retried() {
+    let attempts = std::sync::atomic::AtomicUsize::new(0);
+    let server = Server::start(move |_| {
+        if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < 2 {
+            Response::new(503)
+        } else {
+            Response::new(200)
+        }
+    });
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+
+    assert_eq!(server.requests().len(), 3);
+    assert_eq!(env.sources()["sources"]["foo"]["hash"], SRI);
+}
+
+#[test]
+fn client_errors_are_not_retried() {
+    let server = Server::start(|_| Response::new(404));
+    let env = Env::new();
+    let output = env
+        .command()
+        .args(["add", "foo", &server.url("/foo.txt")])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    // A missing file is missing for GET too, so there is no falling back to it.
+    let methods: Vec<_> = server.requests().into_iter().map(|r| r.method).collect();
+    assert_eq!(methods, ["HEAD"]);
+}
+
+#[test]
+fn bucket_objects_are_fetched_with_signed_requests() {
+    let server = Server::start(|req| {
+        let signed = req.header("Authorization").is_some_and(|auth| {
+            auth.starts_with("AWS4-HMAC-SHA256 Credential=AKID/")
+                && auth.contains("/eu-west-1/s3/aws4_request, ")
+                && auth.contains("SignedHeaders=host;x-amz-content-sha256;x-amz-date, ")
+        }) || req.header("Authorization") == Some("Bearer token");
+        if signed && req.header("x-amz-date").is_some() != req.path.starts_with("/gcs") {
+            Response::new(200).header("ETag", "\"1\"").body("content")
+        } else {
+            Response::new(403)
+        }
+    });
+    let env = Env::new();
+    std::fs::write(
+        env.dir.path().join("aws-credentials"),
+        "[default]\naws_access_key_id = AKID\naws_secret_access_key = secret\n",
+    )
+    .unwrap();
+    let status = env
+        .command()
+        .env("AWS_ENDPOINT_URL", server.url(""))
+        .env("AWS_REGION", "eu-west-1")
+        .args(["add", "foo", "s3://bucket/dir/foo%20bar.txt"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let status = env
+        .command()
+        .env("STORAGE_EMULATOR_HOST", server.url(""))
+        .env("GOOGLE_OAUTH_ACCESS_TOKEN", "token")
+        .args(["add", "bar", "gs://gcs/bar.txt"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // Both the probe and the download are signed, and nothing else is fetched.
+    let requests = server.requests();
+    assert_eq!(
+        requests.iter().map(|r| r.path.as_str()).collect::<Vec<_>>(),
+        [
+            "/bucket/dir/foo%20bar.txt",
+            "/bucket/dir/foo%20bar.txt",
+            "/gcs/bar.txt",
+            "/gcs/bar.txt"
+        ]
+    );
+    let sources = env.sources();
+    assert_eq!(
+        sources["sources"]["foo"]["url"],
+        "s3://bucket/dir/foo%20bar.txt"
+    );
+    assert_eq!(sources["sources"]["foo"]["etag"], "\"1\"");
+    assert!(env.prefetch_log()[0].contains("file://"));
+}
+
+#[test]
+fn custom_headers_are_sent_with_variables_substituted() {
+    let server = Server::start(|req| match req.header("Authorization") {
+        Some("Bearer secret") => Response::new(200).body("content"),
+        _ => Response::new(401),
+    });
+    let env = Env::new();
+    let status = env
+        .command()
+        .env("TOKEN", "secret")
+        .args(["add", "foo", &server.url("/foo.txt")])
+        .args(["--header", "Authorization: Bearer ${TOKEN}"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(requests
+        .iter()
+        .all(|r| r.header("Authorization") == Some("Bearer secret")));
+    // The secret itself is not stored.
+    assert_eq!(
+        env.sources()["sources"]["foo"]["headers"]["Authorization"],
+        "Bearer ${TOKEN}"
+    );
+    // The download is prefetched from a local file, as nix-prefetch-url can't send the header.
+    assert!(env.prefetch_log()[0].contains("file://"));
+
+    let output = env
+        .command()
+        .env_remove("TOKEN")
+        .args(["update"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("TOKEN"));
+}
+
+#[test]
+fn custom_headers_are_not_sent_where_sources_redirect() {
+    let mirror = Server::start(|_| Response::new(200).header("ETag", "\"v1\"").body("content"));
+    let target = mirror.url("/foo.txt");
+    let server = Server::start(move |_| Response::new(302).header("Location", &target));
+    let env = Env::new();
+    env.run(&[
+        "add",
+        "foo",
+        &server.url("/foo.txt"),
+        "--header",
+        "X-Api-Key: secret",
+    ]);
+
+    assert!(server
+        .requests()
+        .iter()
+        .all(|r| r.header("X-Api-Key") == Some("secret")));
+    let requests = mirror.requests();
+    assert!(!requests.is_empty());
+    assert!(requests.iter().all(|r| r.header("X-Api-Key").is_none()));
+
+    // Nor when only checking whether it changed.
+    let before = mirror.requests().len();
+    env.run(&["outdated"]);
+    let requests = mirror.requests();
+    assert!(requests.len() > before);
+    assert!(requests.iter().all(|r| r.header("X-Api-Key").is_none()));
+    assert!(server
+        .requests()
+        .iter()
+        .all(|r| r.header("X-Api-Key") == Some("secret")));
+}
+
+#[test]
+fn force_skips_conditional_requests() {
+    let server = Server::start(|req| {
+        if req.header("If-None-Match").is_some() {
+            Response::new(304)
+        } else {
+            Response::new(200).header("ETag", "\"v1\"")
+        }
+    });
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    env.run(&["update", "--force", "foo"]);
+    env.run(&["update", "--force"]);
+
+    let requests = server.requests();
+    assert!(requests.iter().all(|r| r.header("If-None-Match").is_none()));
+    assert_eq!(env.prefetch_log().len(), 3);
+}
+
+#[test]
+fn pinned_sources_are_only_updated_by_name() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    env.run(&["add", "bar", &server.url("/bar.txt")]);
+    env.run(&["pin", "foo"]);
+    assert_eq!(env.sources()["sources"]["foo"]["pinned"], true);
+
+    env.run(&["update"]);
+    assert_eq!(env.prefetch_log().len(), 3);
+    let output = env.run(&["update", "foo"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("foo is pinned"));
+    assert_eq!(env.prefetch_log().len(), 4);
+    env.run(&["update", "--include-pinned"]);
+    assert_eq!(env.prefetch_log().len(), 6);
+
+    env.run(&["unpin", "foo"]);
+    assert!(env.sources()["sources"]["foo"].get("pinned").is_none());
+}
+
+#[test]
+fn frozen_sources_are_only_updated_with_force() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    env.run(&["add", "bar", &server.url("/bar.txt")]);
+    env.run(&["freeze", "foo"]);
+    assert_eq!(env.sources()["sources"]["foo"]["frozen"], true);
+    assert!(env.sources()["sources"]["foo"].get("pinned").is_none());
+
+    env.run(&["update", "--include-pinned"]);
+    assert_eq!(env.prefetch_log().len(), 3);
+    let output = env.run(&["update", "foo"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("foo is frozen"));
+    assert_eq!(env.prefetch_log().len(), 3);
+    env.run(&["update", "--force"]);
+    assert_eq!(env.prefetch_log().len(), 5);
+
+    env.run(&["unfreeze", "foo"]);
+    assert!(env.sources()["sources"]["foo"].get("frozen").is_none());
+    env.run(&["update", "foo"]);
+    assert_eq!(env.prefetch_log().len(), 6);
+}
+
+#[test]
+fn update_selects_sources_by_name_glob_and_except() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    for name in ["rust-a", "rust-b", "rust-c", "other", "left-alone"] {
+        env.run(&["add", name, &server.url(&format!("/{name}.txt"))]);
+    }
+    let before = env.prefetch_log().len();
+    env.run(&["update", "rust-*", "other", "--except", "rust-b"]);
+    let mut updated: Vec<_> = env.prefetch_log()[before..]
+        .iter()
+        .filter_map(|line| {
+            ["rust-a", "rust-b", "rust-c", "other", "left-alone"]
+                .into_iter()
+                .find(|name| line.contains(&format!("/{name}.txt")))
+        })
+        .collect();
+    updated.sort();
+    assert_eq!(updated, ["other", "rust-a", "rust-c"]);
+
+    let output = env.command().args(["update", "python-*"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no source matches python-*"));
+}
+
+#[test]
+fn max_age_skips_recently_checked_sources() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    for name in ["fresh", "stale"] {
+        env.run(&["add", name, &server.url(&format!("/{name}.txt"))]);
+    }
+    // Sources never checked by update are checked.
+    env.run(&["update", "--max-age", "1d"]);
+    let checked = env
+        .sources_path()
+        .with_file_name(".nix-source/last-checked.json");
+    let mut times: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&checked).unwrap()).unwrap();
+    assert!(times["fresh"].is_string());
+    times["stale"] = "2020-01-01T00:00:00+00:00".into();
+    std::fs::write(&checked, times.to_string()).unwrap();
+
+    let before = server.requests().len();
+    env.run(&["update", "--max-age", "1d"]);
+    let paths: Vec<_> = server.requests()[before..]
+        .iter()
+        .map(|r| r.path.clone())
+        .collect();
+    assert!(paths.iter().all(|path| path == "/stale.txt"), "{paths:?}");
+    assert!(!paths.is_empty());
+
+    let output = env
+        .command()
+        .args(["update", "--max-age", "7x"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn interactive_update_asks_for_each_change() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    for name in ["bar", "baz", "foo"] {
+        env.run(&["add", name, &server.url(&format!("/{name}.txt"))]);
+    }
+    let new_hash = "1".repeat(52);
+    let new_sri = format!("sha256-{}0=", &new_hash[..42]);
+    let mut child = env
+        .command()
+        .env("FAKE_HASH", &new_hash)
+        .args(["update", "--force", "--interactive"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Asked again after an answer that isn't one, and the last change is skipped at the end
+    // of the input.
+    use std::io::Write;
+    write!(child.stdin.take().unwrap(), "y\nmaybe\nf\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.matches("Accept the change to baz?").count(), 2);
+
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["bar"]["hash"], new_sri);
+    assert_eq!(sources["sources"]["baz"]["hash"], SRI);
+    assert_eq!(sources["sources"]["baz"]["frozen"], true);
+    assert_eq!(sources["sources"]["foo"]["hash"], SRI);
+    assert!(sources["sources"]["foo"].get("frozen").is_none());
+
+    // Frozen from the prompt, the source isn't updated even when named.
+    let before = env.prefetch_log().len();
+    let output = env.run(&["update", "baz"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("baz is frozen"));
+    assert_eq!(env.prefetch_log().len(), before);
+}
+
+#[test]
+fn hash_type_is_kept_on_update() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&[
+        "--hash-type",
+        "sha512",
+        "add",
+        "foo",
+        &server.url("/foo.txt"),
+    ]);
+    env.run(&["update", "--force"]);
+
+    let log = env.prefetch_log();
+    assert!(log.iter().all(|l| l.contains("--type sha512")), "{log:?}");
+    let hash = env.sources()["sources"]["foo"]["hash"].clone();
+    assert!(hash.as_str().unwrap().starts_with("sha512-"), "{hash}");
+}
+
+#[test]
+fn bump_substitutes_the_version_into_the_url_template() {
+    let server = Server::start(|_| Response::new(200).header("ETag", "\"v1\""));
+    let env = Env::new();
+    let template = server.url("/foo-{version}.txt");
+    env.run(&["add", "foo", &template, "--version", "1.0"]);
+    env.run(&["bump", "foo", "1.1"]);
+
+    let paths: Vec<_> = server.requests().into_iter().map(|r| r.path).collect();
+    assert_eq!(paths, ["/foo-1.0.txt", "/foo-1.1.txt"]);
+    // The etag of the old version is not sent for the new one.
+    assert!(server.requests()[1].header("If-None-Match").is_none());
+    let sources = env.sources();
+    let foo = &sources["sources"]["foo"];
+    assert_eq!(foo["url"], server.url("/foo-1.1.txt"));
+    assert_eq!(foo["url_template"], template);
+    assert_eq!(foo["version"], "1.1");
+    assert_eq!(foo["hash"], SRI);
+
+    // A template is never fetched as it is.
+    let before = server.requests().len();
+    let output = env
+        .command()
+        .args(["add", "bar", &server.url("/bar-{version}.txt")])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is a url template"));
+    assert_eq!(server.requests().len(), before);
+}
+
+#[test]
+fn mirrors_are_tried_when_the_url_fails() {
+    let server = Server::start(|req| match req.path.as_str() {
+        "/mirror/foo.txt" => Response::new(200),
+        _ => Response::new(404),
+    });
+    let env = Env::new();
+    let mirror = server.url("/mirror/foo.txt");
+    env.run(&["add", "foo", &server.url("/foo.txt"), "--mirror", &mirror]);
+
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["foo"]["url"], server.url("/foo.txt"));
+    assert_eq!(sources["sources"]["foo"]["hash"], SRI);
+    assert_eq!(sources["sources"]["foo"]["fetched_from"], mirror);
+    assert!(env.prefetch_log()[0].ends_with(&mirror));
+
+    // A mirror serving something else is rejected.
+    let output = env
+        .command()
+        .env(
+            "FAKE_HASH",
+            "1111111111111111111111111111111111111111111111111111",
+        )
+        .args(["update", "--force"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("does not serve the same content"));
+    // Failures are listed by source at the end.
+    assert!(stderr.contains("1 sources failed to update: foo"));
+}
+
+#[test]
+fn failing_sources_dont_stop_the_others_from_updating() {
+    let broken = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let fail = broken.clone();
+    let server = Server::start(move |req| {
+        if req.path == "/bad.txt" && fail.load(std::sync::atomic::Ordering::Relaxed) {
+            Response::new(404)
+        } else {
+            Response::new(200)
+        }
+    });
+    let env = Env::new();
+    for name in ["a", "bad", "c"] {
+        env.run(&["add", name, &server.url(&format!("/{name}.txt"))]);
+    }
+    broken.store(true, std::sync::atomic::Ordering::Relaxed);
+    let new_hash = "1".repeat(52);
+    let new_sri = format!("sha256-{}0=", &new_hash[..42]);
+    let output = env
+        .command()
+        .env("FAKE_HASH", &new_hash)
+        .args(["update", "--force", "-j", "2"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("1 sources failed to update: bad"),
+        "{stderr}"
+    );
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["a"]["hash"], new_sri);
+    assert_eq!(sources["sources"]["bad"]["hash"], SRI);
+    assert_eq!(sources["sources"]["c"]["hash"], new_sri);
+}
+
+#[test]
+fn add_updates_an_existing_source_if_asked() {
+    let server = Server::start(|_| Response::new(200).header("ETag", "\"v1\""));
+    let env = Env::new();
+    let url = server.url("/foo.txt");
+    env.run(&["add", "foo", &url, "--store-name", "custom"]);
+    env.run(&["add", "foo", &url, "--update-if-exists"]);
+    assert_eq!(server.requests()[1].header("If-None-Match"), Some("\"v1\""));
+    assert_eq!(env.sources()["sources"]["foo"]["store_name"], "custom");
+
+    let other = server.url("/bar.txt");
+    env.run(&["add", "foo", &other, "--update-if-exists"]);
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["foo"]["url"], other);
+    assert!(sources["sources"]["foo"].get("store_name").is_none());
+    assert_eq!(sources["sources"].as_object().unwrap().len(), 1);
+}
+
+#[test]
+fn invalid_store_names_are_refused() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    let url = server.url("/foo.txt");
+    let output = env
+        .command()
+        .args(["add", "foo", &url, "--store-name", "a/b"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("a/b is not a valid store name"));
+
+    env.run(&["add", "foo", &url]);
+    let before = env.sources();
+    let output = env
+        .command()
+        .args(["set", "foo", "--store-name", "a/b"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("a/b is not a valid store name"));
+    assert_eq!(env.sources(), before);
+}
+
+#[test]
+fn nix_store_prefetch_file_is_used_if_requested() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    let status = env
+        .command_with_prefetcher(Some("nix"))
+        .args(["add", "foo", &server.url("/foo.tar.gz")])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let log = env.prefetch_log();
+    assert!(
+        log[0]
+            .contains("store prefetch-file --json --name foo.tar.gz --unpack --hash-type sha256 "),
+        "{log:?}"
+    );
+    assert_eq!(env.sources()["sources"]["foo"]["hash"], SRI);
+}
+
+#[test]
+fn auto_prefetcher_picks_by_nix_version() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    for (version, name) in [("2.18.1", "new"), ("2.3.16", "old")] {
+        let status = env
+            .command_with_prefetcher(Some("auto"))
+            .env("FAKE_NIX_VERSION", version)
+            .args(["add", name, &server.url("/foo.tar.gz")])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    let log = env.prefetch_log();
+    assert!(log[0].contains("store prefetch-file --json"), "{log:?}");
+    assert!(log[1].starts_with("--name foo.tar.gz --unpack"), "{log:?}");
+    assert_eq!(env.sources()["sources"]["new"]["hash"], SRI);
+    assert_eq!(env.sources()["sources"]["old"]["hash"], SRI);
+}
+
+#[test]
+fn file_names_are_sanitized_into_store_names() {
+    let cases = [
+        ("", "source"),
+        (".", "source"),
+        ("..", "source"),
+        ("@@@", "source"),
+        (".hidden", "hidden"),
+        ("a  b@@c", "a_b_c"),
+        ("normal-1.2.3.tar.gz", "normal-1.2.3.tar.gz"),
+    ];
+    let server = Server::start(move |req| {
+        let i: usize = req.path[1..].parse().unwrap();
+        let disposition = format!("attachment; filename=\"{}\"", cases[i].0);
+        Response::new(200).header("Content-Disposition", &disposition)
+    });
+    let env = Env::new();
+    for (i, _) in cases.iter().enumerate() {
+        env.run(&[
+            "add",
+            &i.to_string(),
+            &server.url(&format!("/{i}")),
+            "-t",
+            "file",
+        ]);
+    }
+
+    let log = env.prefetch_log();
+    for ((name, expected), line) in cases.iter().zip(&log) {
+        assert!(
+            line.starts_with(&format!("--name {expected} ")),
+            "{name:?}: {line}"
+        );
+    }
+}
+
+#[test]
+fn local_files_are_hashed_without_network_access() {
+    let env = Env::new();
+    let path = env.dir.path().join("vendored.tar.gz");
+    std::fs::write(&path, "content").unwrap();
+    env.run(&["add", "local", path.to_str().unwrap()]);
+
+    let sources = env.sources();
+    let local = &sources["sources"]["local"];
+    let url = format!("file://{}", path.canonicalize().unwrap().display());
+    assert_eq!(local["url"], url);
+    assert_eq!(local["type"], "tarball");
+    assert_eq!(local["size"], 7);
+    assert!(local.get("last_modified").is_some());
+    assert_eq!(
+        env.prefetch_log()[0],
+        format!("--name vendored.tar.gz --unpack --type sha256 {url}")
+    );
+
+    std::fs::remove_file(&path).unwrap();
+    let output = env.command().args(["update"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("does not exist"));
+}
+
+#[test]
+fn local_directories_are_hashed_as_paths() {
+    use std::os::unix::fs::PermissionsExt;
+    let env = Env::new();
+    let dir = env.dir.path().join("vendored");
+    std::fs::create_dir_all(dir.join("bin")).unwrap();
+    std::fs::write(dir.join("a"), "hello\n").unwrap();
+    std::fs::write(dir.join("bin/run"), "#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(dir.join("bin/run"), std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::os::unix::fs::symlink("a", dir.join("link")).unwrap();
+    let url = format!("file://{}", dir.canonicalize().unwrap().display());
+    env.run(&["add", "vendored", &url]);
+
+    // Computed from the directory with a separate implementation of the NAR format.
+    let sources = env.sources();
+    let vendored = &sources["sources"]["vendored"];
+    assert_eq!(vendored["type"], "path");
+    assert_eq!(
+        vendored["hash"],
+        "sha256-nhGTdYBG33l2oXadTQw9BjMqn2xrSPC3cAh99TgATt0="
+    );
+    assert!(env.prefetch_log().is_empty());
+    let output = env.run(&["emit-nix"]);
+    let nix = String::from_utf8_lossy(&output.stdout);
+    assert!(nix.contains("builtins.path {"), "{nix}");
+    assert!(nix.contains(r#"name = "vendored";"#), "{nix}");
+
+    std::fs::write(dir.join("a"), "changed\n").unwrap();
+    env.run(&["update"]);
+    assert_ne!(
+        env.sources()["sources"]["vendored"]["hash"],
+        "sha256-nhGTdYBG33l2oXadTQw9BjMqn2xrSPC3cAh99TgATt0="
+    );
+}
+
+#[test]
+fn github_releases_are_followed() {
+    let tag = std::sync::Arc::new(std::sync::Mutex::new("v1.0"));
+    let current = tag.clone();
+    let server = Server::start(move |req| match req.path.as_str() {
+        "/repos/owner/repo/releases/latest" => {
+            let tag = *current.lock().unwrap();
+            let base = req.header("Host").unwrap();
+            Response::new(200).body(
+                serde_json::json!({
+                    "tag_name": tag,
+                    "assets": [
+                        { "name": "repo.zip", "browser_download_url": format!("http://{base}/{tag}/repo.zip") },
+                        { "name": "repo.tar.gz", "browser_download_url": format!("http://{base}/{tag}/repo.tar.gz") },
+                    ],
+                })
+                .to_string(),
+            )
+        }
+        _ => Response::new(200).header("ETag", "\"v1\""),
+    });
+    let env = Env::new();
+    let run = |args: &[&str]| {
+        let status = env
+            .command()
+            .env("GITHUB_API_URL", server.url(""))
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+    run(&[
+        "add",
+        "foo",
+        "--github",
+        "owner/repo",
+        "--asset",
+        r"\.tar\.gz$",
+    ]);
+    let sources = env.sources();
+    assert_eq!(
+        sources["sources"]["foo"]["url"],
+        server.url("/v1.0/repo.tar.gz")
+    );
+    assert_eq!(sources["sources"]["foo"]["version"], "v1.0");
+
+    *tag.lock().unwrap() = "v1.1";
+    run(&["update"]);
+    let sources = env.sources();
+    assert_eq!(
+        sources["sources"]["foo"]["url"],
+        server.url("/v1.1/repo.tar.gz")
+    );
+    assert_eq!(sources["sources"]["foo"]["version"], "v1.1");
+    // The etag of the old release is not sent for the new one.
+    let requests = server.requests();
+    assert!(requests.last().unwrap().header("If-None-Match").is_none());
+}
+
+#[test]
+fn github_release_sources_fetch_the_asset_as_a_file() {
+    let tag = std::sync::Arc::new(std::sync::Mutex::new("v1.0"));
+    let current = tag.clone();
+    let server = Server::start(move |req| match req.path.as_str() {
+        "/repos/owner/repo/releases/latest" => {
+            let tag = *current.lock().unwrap();
+            let base = req.header("Host").unwrap();
+            Response::new(200).body(
+                serde_json::json!({
+                    "tag_name": tag,
+                    "assets": [
+                        { "name": "repo.tar.gz", "browser_download_url": format!("http://{base}/{tag}/repo.tar.gz") },
+                    ],
+                })
+                .to_string(),
+            )
+        }
+        _ => Response::new(200),
+    });
+    let env = Env::new();
+    let run = |args: &[&str]| {
+        let output = env
+            .command()
+            .env("GITHUB_API_URL", server.url(""))
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    };
+    run(&[
+        "add",
+        "foo",
+        "owner/repo",
+        "--type",
+        "github-release",
+        "--asset",
+        r"\.tar\.gz$",
+    ]);
+    let foo = &env.sources()["sources"]["foo"];
+    assert_eq!(foo["type"], "github-release");
+    assert_eq!(foo["url"], server.url("/v1.0/repo.tar.gz"));
+    assert_eq!(foo["version"], "v1.0");
+    assert!(!env.prefetch_log()[0].contains("--unpack"));
+
+    *tag.lock().unwrap() = "v1.1";
+    run(&["update"]);
+    let foo = &env.sources()["sources"]["foo"];
+    assert_eq!(foo["url"], server.url("/v1.1/repo.tar.gz"));
+    assert_eq!(foo["version"], "v1.1");
+    // The asset that was hashed is verified, not that of whatever release is the latest.
+    *tag.lock().unwrap() = "v1.2";
+    let before = env.prefetch_log().len();
+    run(&["verify"]);
+    assert!(env.prefetch_log()[before].ends_with(&server.url("/v1.1/repo.tar.gz")));
+
+    let output = env
+        .command()
+        .args(["add", "bar", "owner/repo", "--type", "github-release"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("need --asset"));
+}
+
+#[test]
+fn release_notes_are_shown_on_update() {
+    let tag = std::sync::Arc::new(std::sync::Mutex::new("v1.0"));
+    let current = tag.clone();
+    let server = Server::start(move |req| match req.path.as_str() {
+        "/repos/owner/repo/releases/latest" => {
+            let tag = *current.lock().unwrap();
+            let base = req.header("Host").unwrap();
+            Response::new(200).body(
+                serde_json::json!({
+                    "tag_name": tag,
+                    "assets": [
+                        { "name": "repo.tar.gz", "browser_download_url": format!("http://{base}/{tag}/repo.tar.gz") },
+                    ],
+                })
+                .to_string(),
+            )
+        }
+        "/repos/owner/repo/releases/tags/v1.1" => Response::new(200)
+            .body(serde_json::json!({ "body": "Fixed a bug.\nAdded a feature." }).to_string()),
+        _ => Response::new(200),
+    });
+    let env = Env::new();
+    let run = |args: &[&str]| {
+        env.command()
+            .env("GITHUB_API_URL", server.url(""))
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let output = run(&[
+        "add",
+        "foo",
+        "--github",
+        "owner/repo",
+        "--asset",
+        r"\.tar\.gz$",
+    ]);
+    assert!(output.status.success());
+
+    *tag.lock().unwrap() = "v1.1";
+    // Out of date, so the dry run fails.
+    let output = run(&["update", "--notes", "--dry-run", "--json"]);
+    assert_eq!(output.status.code(), Some(2));
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["foo"]["notes"], "Fixed a bug.\nAdded a feature.");
+    let output = run(&["update", "--notes"]);
+    assert!(output.status.success());
+    let output = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        output.contains("\t| Fixed a bug.\n\t| Added a feature.\n"),
+        "{output}"
+    );
+}
+
+#[test]
+fn github_branches_are_followed() {
+    let head = std::sync::Arc::new(std::sync::Mutex::new(REV));
+    let current = head.clone();
+    let server = Server::start(move |req| match req.path.as_str() {
+        "/repos/owner/repo/commits/main" => {
+            assert_eq!(req.header("Accept"), Some("application/vnd.github.sha"));
+            Response::new(200).body(*current.lock().unwrap())
+        }
+        _ => Response::new(200),
+    });
+    let env = Env::new();
+    let run = |args: &[&str]| {
+        let status = env
+            .command()
+            .env("GITHUB_API_URL", server.url(""))
+            .env("GITHUB_SERVER_URL", server.url(""))
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+    run(&["add", "foo", "--github", "owner/repo", "--branch", "main"]);
+    let sources = env.sources();
+    assert_eq!(
+        sources["sources"]["foo"]["url"],
+        server.url(&format!("/owner/repo/archive/{REV}.tar.gz"))
+    );
+    assert_eq!(sources["sources"]["foo"]["rev"], REV);
+    assert_eq!(env.prefetch_log().len(), 1);
+
+    // The branch hasn't moved, so there is nothing to fetch.
+    let requests = server.requests().len();
+    run(&["update"]);
+    assert_eq!(server.requests().len(), requests + 1);
+    assert_eq!(env.prefetch_log().len(), 1);
+
+    let rev = "89abcdef0123456789abcdef0123456789abcdef";
+    *head.lock().unwrap() = rev;
+    run(&["update"]);
+    let sources = env.sources();
+    assert_eq!(
+        sources["sources"]["foo"]["url"],
+        server.url(&format!("/owner/repo/archive/{rev}.tar.gz"))
+    );
+    assert_eq!(sources["sources"]["foo"]["rev"], rev);
+    assert_eq!(env.prefetch_log().len(), 2);
+}
+
+#[test]
+fn gitlab_releases_are_followed() {
+    let tag = std::sync::Arc::new(std::sync::Mutex::new("v1.0"));
+    let current = tag.clone();
+    let server = Server::start(move |req| {
+        if req
+            .path
+            .starts_with("/api/v4/projects/group%2Fsub%2Fproject/releases")
+        {
+            let tag = *current.lock().unwrap();
+            Response::new(200).body(serde_json::json!([{ "tag_name": tag }]).to_string())
+        } else {
+            Response::new(200)
+        }
+    });
+    let env = Env::new();
+    let instance = server.url("");
+    env.run(&[
+        "add",
+        "foo",
+        "group/sub/project",
+        "--forge",
+        "gitlab",
+        "--instance",
+        &instance,
+    ]);
+    let sources = env.sources();
+    assert_eq!(
+        sources["sources"]["foo"]["url"],
+        server.url("/group/sub/project/-/archive/v1.0/project-v1.0.tar.gz")
+    );
+    assert_eq!(sources["sources"]["foo"]["version"], "v1.0");
+    assert_eq!(sources["sources"]["foo"]["repository"]["forge"], "gitlab");
+
+    *tag.lock().unwrap() = "v1.1";
+    env.run(&["update"]);
+    let sources = env.sources();
+    assert_eq!(
+        sources["sources"]["foo"]["url"],
+        server.url("/group/sub/project/-/archive/v1.1/project-v1.1.tar.gz")
+    );
+    assert_eq!(sources["sources"]["foo"]["version"], "v1.1");
+}
+
+#[test]
+fn gitlab_branches_are_followed() {
+    let rev = std::sync::Arc::new(std::sync::Mutex::new(REV));
+    let current = rev.clone();
+    let server = Server::start(move |req| {
+        if req.path == "/api/v4/projects/group%2Fproject/repository/branches/main" {
+            let rev = *current.lock().unwrap();
+            Response::new(200)
+                .body(serde_json::json!({ "name": "main", "commit": { "id": rev } }).to_string())
+        } else {
+            Response::new(200)
+        }
+    });
+    let env = Env::new();
+    let instance = server.url("");
+    env.run(&[
+        "add",
+        "foo",
+        "group/project",
+        "--forge",
+        "gitlab",
+        "--instance",
+        &instance,
+        "--branch",
+        "main",
+    ]);
+    let foo = &env.sources()["sources"]["foo"];
+    assert_eq!(
+        foo["url"],
+        server.url(&format!(
+            "/group/project/-/archive/{REV}/project-{REV}.tar.gz"
+        ))
+    );
+    assert_eq!(foo["rev"], REV);
+    assert_eq!(foo["branch"], "main");
+
+    // The branch didn't move, so nothing is fetched.
+    env.run(&["update"]);
+    assert_eq!(env.prefetch_log().len(), 1);
+
+    let new = "89abcdef0123456789abcdef0123456789abcdef";
+    *rev.lock().unwrap() = new;
+    env.run(&["update"]);
+    let foo = &env.sources()["sources"]["foo"];
+    assert_eq!(
+        foo["url"],
+        server.url(&format!(
+            "/group/project/-/archive/{new}/project-{new}.tar.gz"
+        ))
+    );
+    assert_eq!(foo["rev"], new);
+    assert_eq!(env.prefetch_log().len(), 2);
+}
+
+#[test]
+fn pypi_packages_follow_the_latest_sdist() {
+    let server = Server::start(|req| match req.path.as_str() {
+        "/pypi/requests/json" => {
+            let base = req.header("Host").unwrap();
+            Response::new(200).body(
+                serde_json::json!({
+                    "info": { "version": "2.32.3" },
+                    "urls": [
+                        { "packagetype": "bdist_wheel", "url": format!("http://{base}/requests-2.32.3-py3-none-any.whl") },
+                        { "packagetype": "sdist", "url": format!("http://{base}/requests-2.32.3.tar.gz") },
+                    ],
+                })
+                .to_string(),
+            )
+        }
+        _ => Response::new(200),
+    });
+    let env = Env::new();
+    let status = env
+        .command()
+        .env("NIX_SOURCE_PYPI_URL", server.url("/pypi"))
+        .args(["add", "requests", "requests", "--type", "pypi"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let source = &env.sources()["sources"]["requests"];
+    assert_eq!(source["url"], server.url("/requests-2.32.3.tar.gz"));
+    assert_eq!(source["version"], "2.32.3");
+    assert_eq!(source["package"], "requests");
+    assert_eq!(source["type"], "pypi");
+    // sdists are hashed as files, like fetchPypi does.
+    assert!(!env.prefetch_log()[0].contains("--unpack"));
+}
+
+#[test]
+fn crates_follow_the_latest_stable_version() {
+    let server = Server::start(|req| match req.path.as_str() {
+        "/api/v1/crates/serde" => {
+            assert!(req.header("User-Agent").unwrap().starts_with("nix-source/"));
+            Response::new(200).body(
+                serde_json::json!({
+                    "crate": { "max_stable_version": "1.0.210", "max_version": "2.0.0-rc.1" },
+                })
+                .to_string(),
+            )
+        }
+        _ => Response::new(200),
+    });
+    let env = Env::new();
+    let status = env
+        .command()
+        .env("NIX_SOURCE_CRATES_API_URL", server.url("/api/v1"))
+        .env("NIX_SOURCE_CRATES_DOWNLOAD_URL", server.url("/crates"))
+        .args(["add", "serde", "serde", "--type", "crate"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let source = &env.sources()["sources"]["serde"];
+    assert_eq!(
+        source["url"],
+        server.url("/crates/serde/serde-1.0.210.crate")
+    );
+    assert_eq!(source["version"], "1.0.210");
+    assert_eq!(source["type"], "crate");
+}
+
+#[test]
+fn channels_follow_their_current_release() {
+    let server = Server::start(|req| match req.path.as_str() {
+        "/nixos-unstable/nixexprs.tar.xz" => Response::new(302).header(
+            "Location",
+            "/nixos/unstable/nixos-24.11pre1234.abcdef/nixexprs.tar.xz",
+        ),
+        _ => Response::new(200),
+    });
+    let env = Env::new();
+    let status = env
+        .command()
+        .env("NIX_SOURCE_CHANNELS_URL", server.url(""))
+        .args(["add", "nixpkgs", "nixos-unstable", "--type", "channel"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let source = &env.sources()["sources"]["nixpkgs"];
+    assert_eq!(source["url"], server.url("/nixos-unstable/nixexprs.tar.xz"));
+    let release = server.url("/nixos/unstable/nixos-24.11pre1234.abcdef/nixexprs.tar.xz");
+    assert_eq!(source["resolved_url"], release);
+    assert_eq!(source["version"], "nixos-24.11pre1234.abcdef");
+    let log = env.prefetch_log();
+    assert!(
+        log[0].contains("--unpack") && log[0].ends_with(&release),
+        "{log:?}"
+    );
+}
+
+#[test]
+fn gitea_and_sourcehut_tags_are_followed() {
+    let server = Server::start(|req| match req.path.as_str() {
+        "/api/v1/repos/owner/repo/tags?limit=1" => {
+            Response::new(200).body(r#"[{ "name": "v2.0" }]"#)
+        }
+        "/~owner/repo/refs/rss.xml" => Response::new(200).body(
+            "<rss><channel><title>refs</title>\
+             <item><title>v1.2</title></item><item><title>v1.1</title></item>\
+             </channel></rss>",
+        ),
+        _ => Response::new(200),
+    });
+    let env = Env::new();
+    let instance = server.url("");
+    for (name, forge) in [("gitea", "codeberg"), ("srht", "sourcehut")] {
+        let path = if forge == "sourcehut" {
+            "~owner/repo"
+        } else {
+            "owner/repo"
+        };
+        env.run(&["add", name, path, "--forge", forge, "--instance", &instance]);
+    }
+    let sources = env.sources();
+    assert_eq!(
+        sources["sources"]["gitea"]["url"],
+        server.url("/owner/repo/archive/v2.0.tar.gz")
+    );
+    assert_eq!(sources["sources"]["gitea"]["version"], "v2.0");
+    assert_eq!(
+        sources["sources"]["srht"]["url"],
+        server.url("/~owner/repo/archive/v1.2.tar.gz")
+    );
+    assert_eq!(sources["sources"]["srht"]["version"], "v1.2");
+}
+
+#[test]
+fn slow_prefetches_time_out() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    let add = |args: &[&str]| {
+        env.command()
+            .env("FAKE_PREFETCH_DELAY", "2")
+            .args(args)
+            .args(["add", "foo", &server.url("/foo.txt")])
+            .output()
+            .unwrap()
+    };
+    // Waiting for a server and prefetching are limited separately.
+    let output = add(&["--timeout", "1"]);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("timed out"));
+
+    let start = std::time::Instant::now();
+    let output = add(&["--prefetch-timeout", "1"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("timed out"));
+    assert!(start.elapsed() < std::time::Duration::from_secs(2));
+    // A prefetch that took too long isn't tried again.
+    assert_eq!(env.prefetch_log().len(), 2);
+}
+
+#[test]
+fn revalidate_catches_content_changed_behind_a_304() {
+    let server = Server::start(|req| {
+        if req.header("If-None-Match").is_some() {
+            Response::new(304)
+        } else {
+            Response::new(200).header("ETag", "\"v1\"")
+        }
+    });
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    let output = env
+        .command()
+        .env(
+            "FAKE_HASH",
+            "1111111111111111111111111111111111111111111111111111",
+        )
+        .args(["update", "--revalidate", "--dry-run"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("but its content changed"), "{stderr}");
+    // The conditional request is still made.
+    assert_eq!(server.requests()[1].header("If-None-Match"), Some("\"v1\""));
+    assert_eq!(env.prefetch_log().len(), 2);
+}
+
+#[test]
+fn update_prints_a_json_summary() {
+    let server = Server::start(|req| match req.path.as_str() {
+        "/gone.txt" => Response::new(404),
+        "/same.txt" => Response::new(304),
+        _ => Response::new(200),
+    });
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "new": { "url": server.url("/new.txt") },
+            "same": { "url": server.url("/same.txt"), "hash": SRI, "etag": "\"v1\"" },
+            "gone": { "url": server.url("/gone.txt") },
+        }
+    }));
+    let output = env.command().args(["update", "--json"]).output().unwrap();
+    assert!(!output.status.success());
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        summary["new"],
+        serde_json::json!({ "status": "updated", "old": null, "new": SRI, "changes": {} })
+    );
+    assert_eq!(
+        summary["same"],
+        serde_json::json!({ "status": "unchanged" })
+    );
+    assert_eq!(summary["gone"]["status"], "error");
+    assert!(summary["gone"]["error"].as_str().unwrap().contains("404"));
+}
+
+#[test]
+fn malformed_prefetch_output_is_an_error() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    for hash in [" ", "not a hash"] {
+        let output = env
+            .command()
+            .env("FAKE_HASH", hash)
+            .args(["add", "foo", &server.url("/foo.txt")])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("instead of a sha256 hash"), "{stderr}");
+        assert!(stderr.contains(&server.url("/foo.txt")), "{stderr}");
+    }
+    assert!(!env.sources_path().exists());
+
+    // A failing conversion to SRI is reported as such, not as a hash that can't be parsed.
+    let nix = env.dir.path().join("bin/nix");
+    std::fs::write(
+        &nix,
+        "#!/bin/sh
+[ \"$1\" = --version ] && echo 'nix (Nix) 2.3.16' && exit 0
+exit 1
+",
+    )
+    .unwrap();
+    let output = env
+        .command()
+        .args(["add", "foo", &server.url("/foo.txt")])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("nix hash to-sri failed"), "{stderr}");
+}
+
+#[test]
+fn git_sources_are_only_hashed_when_the_revision_changes() {
+    let env = Env::new();
+    env.run(&[
+        "add",
+        "repo",
+        "https://example.com/repo.git",
+        "--type",
+        "git",
+        "--rev",
+        "main",
+    ]);
+    assert_eq!(env.sources()["sources"]["repo"]["rev"], REV);
+    assert_eq!(env.prefetch_log().len(), 1);
+
+    env.run(&["update"]);
+    assert_eq!(env.prefetch_log().len(), 1);
+
+    let rev = "89abcdef0123456789abcdef0123456789abcdef";
+    let output = env
+        .command()
+        .env("FAKE_REV", rev)
+        .arg("update")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(env.prefetch_log().len(), 2);
+    assert!(env.prefetch_log()[1].contains("--rev main"));
+    assert_eq!(env.sources()["sources"]["repo"]["rev"], rev);
+}
+
+#[test]
+fn git_submodules_and_deep_clones_are_fetched_like_fetchgit() {
+    let env = Env::new();
+    env.run(&[
+        "add",
+        "repo",
+        "https://example.com/repo.git",
+        "--type",
+        "git",
+        "--submodules",
+        "--deep-clone",
+    ]);
+    let log = env.prefetch_log();
+    assert!(log[0].contains("--fetch-submodules"), "{log:?}");
+    assert!(log[0].contains("--deepClone --leave-dotGit"), "{log:?}");
+    assert_eq!(env.sources()["sources"]["repo"]["submodules"], true);
+
+    let output = env.run(&["emit-nix"]);
+    let nix = String::from_utf8_lossy(&output.stdout);
+    assert!(nix.contains("fetchSubmodules = true;"), "{nix}");
+    assert!(nix.contains("deepClone = true;"), "{nix}");
+
+    let server = Server::start(|_| Response::new(200));
+    let output = env
+        .command()
+        .args(["add", "foo", &server.url("/foo.txt"), "--submodules"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn set_changes_a_source_and_fetches_it_again() {
+    let server = Server::start(|_| Response::new(200).header("ETag", "\"v1\""));
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    assert_eq!(env.sources()["sources"]["foo"]["type"], "file");
+
+    let url = server.url("/foo.tar.gz");
+    env.run(&["set", "foo", "--url", &url, "--type", "tarball"]);
+    let source = &env.sources()["sources"]["foo"];
+    assert_eq!(source["url"], url);
+    assert_eq!(source["type"], "tarball");
+    assert_eq!(source["hash"], SRI);
+    // The etag of the old url isn't sent for the new one.
+    let requests = server.requests();
+    assert!(requests.last().unwrap().header("If-None-Match").is_none());
+    let log = env.prefetch_log();
+    assert!(
+        log[1].contains("--unpack") && log[1].ends_with(&url),
+        "{log:?}"
+    );
+}
+
+#[test]
+fn outdated_checks_without_fetching() {
+    let etag = std::sync::Arc::new(std::sync::Mutex::new("\"v1\""));
+    let current = etag.clone();
+    let server = Server::start(move |req| {
+        let etag = *current.lock().unwrap();
+        if req.header("If-None-Match") == Some(etag) {
+            Response::new(304)
+        } else {
+            Response::new(200).header("ETag", etag)
+        }
+    });
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    let before = std::fs::read(env.sources_path()).unwrap();
+
+    let output = env.command().arg("outdated").output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "foo: up to date\n");
+
+    *etag.lock().unwrap() = "\"v2\"";
+    let output = env.command().arg("outdated").output().unwrap();
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "foo: outdated, changed upstream\n"
+    );
+    assert_eq!(env.prefetch_log().len(), 1);
+    assert_eq!(std::fs::read(env.sources_path()).unwrap(), before);
+}
+
+#[test]
+fn interrupted_downloads_are_resumed() {
+    let server = Server::start(|req| match req.header("Range") {
+        None => Response::new(200)
+            .header("ETag", "\"1\"")
+            .body("0123456789")
+            .truncate(4),
+        Some("bytes=4-") if req.header("If-Range") == Some("\"1\"") => Response::new(206)
+            .header("ETag", "\"1\"")
+            .header("Content-Range", "bytes 4-9/10")
+            .body("456789"),
+        Some(_) => Response::new(416),
+    });
+    let env = Env::new();
+    let output = env
+        .command_with_prefetcher(None)
+        .args(["add", "foo", &server.url("/foo.bin")])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let ranges: Vec<_> = server
+        .requests()
+        .iter()
+        .filter(|req| req.method == "GET")
+        .map(|req| req.header("Range").map(str::to_owned))
+        .collect();
+    assert_eq!(ranges, [None, Some("bytes=4-".to_owned())]);
+    assert_eq!(
+        env.sources()["sources"]["foo"]["hash"],
+        "sha256-hNiYd/DUBB77a/kaFvAkjy/Vc+avBcGflr7bn4gveII="
+    );
+    // Nothing is left in the cache once the download is done.
+    let cache = env.dir.path().join("cache/nix-source/downloads");
+    assert_eq!(std::fs::read_dir(cache).unwrap().count(), 0);
+}
+
+#[test]
+fn builtin_prefetcher_hashes_without_nix() {
+    let server =
+        Server::start(|_| Response::new(200).body(&include_bytes!("data/source.tar.gz")[..]));
+    let env = Env::new();
+    for args in [
+        ["add", "unpacked", &server.url("/source.tar.gz")],
+        ["add", "flat", &server.url("/source.tar.gz?flat")],
+    ] {
+        let mut command = env.command_with_prefetcher(None);
+        command.args(args);
+        if args[1] == "flat" {
+            command.args(["--type", "file"]);
+        }
+        let output = command.output().unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    // Computed from the unpacked tarball with a separate implementation of the NAR format.
+    let sources = env.sources();
+    assert_eq!(
+        sources["sources"]["unpacked"]["hash"],
+        "sha256-oA61u2VktagvnOR4U86WAZUQFR7gxPTjsbywy6oGebE="
+    );
+    assert_eq!(
+        sources["sources"]["flat"]["hash"],
+        "sha256-ZFMSgDMYWyQWQRvBwxOCfcbqRB//ql6gYk6qwGzdyCs="
+    );
+    assert!(env.prefetch_log().is_empty());
+}
+
+#[test]
+fn strip_root_can_be_turned_off() {
+    let server =
+        Server::start(|_| Response::new(200).body(&include_bytes!("data/source.tar.gz")[..]));
+    let env = Env::new();
+    env.run(&[
+        "add",
+        "foo",
+        &server.url("/source.tar.gz"),
+        "--no-strip-root",
+    ]);
+
+    // The hash of the directory holding the top-level one, from the same separate
+    // implementation of the NAR format.
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["foo"]["strip_root"], false);
+    assert_eq!(
+        sources["sources"]["foo"]["hash"],
+        "sha256-zUELTpgbAPtgFhMuTDdOUptYLX/d3YM5rGReLsgROA8="
+    );
+    assert!(env.prefetch_log().is_empty());
+
+    let output = env.run(&["emit-nix"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("stripRoot = false;"));
+}
+
+#[test]
+fn only_a_subdirectory_of_a_source_can_be_hashed() {
+    let server =
+        Server::start(|_| Response::new(200).body(&include_bytes!("data/source.tar.gz")[..]));
+    let env = Env::new();
+    env.run(&[
+        "add",
+        "bin",
+        &server.url("/source.tar.gz"),
+        "--subdir",
+        "bin/",
+    ]);
+
+    // The hash of only bin of the unpacked tarball, from the same separate implementation of
+    // the NAR format.
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["bin"]["subdir"], "bin");
+    assert_eq!(
+        sources["sources"]["bin"]["hash"],
+        "sha256-X3a4jyIXVdr2HiveooPJKUr5r4F8zlnRp+wYVZpmQ0k="
+    );
+    let output = env.run(&["emit-nix"]);
+    let nix = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        nix.contains(r#"mv \"$out\"/'bin' \"$TMPDIR/subdir\""#),
+        "{nix}"
+    );
+
+    env.run(&[
+        "add",
+        "repo",
+        "https://example.com/repo.git",
+        "--type",
+        "git",
+        "--subdir",
+        "packages/foo",
+    ]);
+    assert!(env.prefetch_log()[0].contains("--sparse-checkout packages/foo"));
+    let output = env.run(&["emit-nix"]);
+    let nix = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        nix.contains(r#"sparseCheckout = [ "packages/foo" ];"#),
+        "{nix}"
+    );
+
+    for subdir in ["../etc", "a//b", "/"] {
+        let output = env
+            .command()
+            .args([
+                "add",
+                "bad",
+                &server.url("/source.tar.gz"),
+                "--subdir",
+                subdir,
+            ])
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{subdir}");
+    }
+}
+
+#[test]
+fn store_paths_are_recorded_and_kept_by_gc_roots() {
+    let server =
+        Server::start(|_| Response::new(200).body(&include_bytes!("data/source.tar.gz")[..]));
+    let env = Env::new();
+    let output = env
+        .command_with_prefetcher(None)
+        .args(["add", "unpacked", &server.url("/source.tar.gz")])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    // As nix computes it for the hash from builtin_prefetcher_hashes_without_nix.
+    assert_eq!(
+        env.sources()["sources"]["unpacked"]["store_path"],
+        "/nix/store/16iig34vhj8c998v2xhf3k4hjsk8019j-source.tar.gz"
+    );
+
+    // nix-store --add-root <root> --indirect --realise <path>
+    let nix_store = env.dir.path().join("bin").join("nix-store");
+    std::fs::write(
+        &nix_store,
+        "#!/bin/sh
+ln -s \"$5\" \"$2\"\n",
+    )
+    .unwrap();
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(&nix_store, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let store = env.dir.path().join("store");
+    let output = env
+        .command()
+        .env("NIX_STORE_DIR", &store)
+        .args(["add", "stored", &server.url("/foo.txt")])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let path = env.sources()["sources"]["stored"]["store_path"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+    assert!(path.starts_with(store.to_str().unwrap()));
+    std::fs::create_dir_all(&path).unwrap();
+
+    let roots = env.dir.path().join(".nix-source").join("gcroots");
+    let output = env
+        .command()
+        .env("NIX_STORE_DIR", &store)
+        .arg("gc-roots")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        std::fs::read_link(roots.join("stored")).unwrap(),
+        std::path::Path::new(&path)
+    );
+    // Not in the store, as the builtin prefetcher doesn't add it.
+    assert!(!roots.join("unpacked").exists());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unpacked"));
+
+    env.run(&["gc-roots", "--remove"]);
+    assert!(!roots.exists());
+}
+
+#[test]
+fn verify_local_checks_the_store_offline() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    // nix-store --verify-path <path>
+    let nix_store = env.dir.path().join("bin").join("nix-store");
+    std::fs::write(
+        &nix_store,
+        "#!/bin/sh\n[ \"$1\" = --verify-path ] && [ -d \"$2\" ]\n",
+    )
+    .unwrap();
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(&nix_store, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let store = env.dir.path().join("store");
+    let run = |args: &[&str]| {
+        env.command()
+            .env("NIX_STORE_DIR", &store)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    assert!(run(&["add", "foo", &server.url("/foo.tar.gz")])
+        .status
+        .success());
+    let path = env.sources()["sources"]["foo"]["store_path"]
+        .as_str()
+        .unwrap()
+        .to_owned();
+
+    let output = run(&["verify", "--local"]);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "foo: not in the store\n"
+    );
+
+    std::fs::create_dir_all(&path).unwrap();
+    let requests = server.requests().len();
+    let output = run(&["--offline", "verify", "--local"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "foo: ok\n");
+    assert_eq!(server.requests().len(), requests);
+}
+
+#[test]
+fn substitutable_sources_are_not_downloaded() {
+    let server = Server::start(|req| match req.path.as_str() {
+        // The store path of the fake hash, unpacked and named as fetchzip names it.
+        "/hit/412zfqvy5bn2y1i0hdzdj5gr7dgfsjpv.narinfo" => Response::new(200),
+        "/foo.tar.gz" => Response::new(200),
+        _ => Response::new(404),
+    });
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.tar.gz")]);
+    let config = env.dir.path().join("config").join("nix-source");
+    std::fs::create_dir_all(&config).unwrap();
+    let write_config = |caches: &[&str]| {
+        let caches: Vec<_> = caches
+            .iter()
+            .map(|cache| format!("\"{}\"", server.url(cache)))
+            .collect();
+        std::fs::write(
+            config.join("config.toml"),
+            format!("substituters = [{}]\n", caches.join(", ")),
+        )
+        .unwrap();
+    };
+
+    write_config(&["/miss", "/hit"]);
+    let prefetched = env.prefetch_log().len();
+    env.run(&["verify", "--substitute"]);
+    env.run(&["update", "--substitute"]);
+    assert_eq!(env.prefetch_log().len(), prefetched);
+    assert_eq!(env.sources()["sources"]["foo"]["hash"], SRI);
+
+    // Without a cache having it, it is downloaded again.
+    write_config(&["/miss"]);
+    env.run(&["verify", "--substitute"]);
+    assert!(env.prefetch_log().len() > prefetched);
+}
+
+#[test]
+fn builtin_prefetcher_leaves_zip_files_to_nix() {
+    let server = Server::start(|_| Response::new(200).body("PK\x03\x04 not really a zip file"));
+    let env = Env::new();
+    let output = env
+        .command_with_prefetcher(None)
+        .args(["add", "foo", &server.url("/foo.zip")])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(env.sources()["sources"]["foo"]["hash"], SRI);
+    let log = env.prefetch_log();
+    assert_eq!(log.len(), 1);
+    assert!(log[0].contains("--unpack"), "{log:?}");
+}
+
+#[test]
+fn dry_run_prints_what_would_change() {
+    let modified = std::sync::Arc::new(std::sync::Mutex::new("Mon, 01 Jan 2024 00:00:00 GMT"));
+    let current = modified.clone();
+    let server = Server::start(move |req| match req.path.as_str() {
+        "/index/" => Response::new(200)
+            .body(r#"<a href="tool-1.0.tar.gz">1.0</a> <a href="tool-1.1.tar.gz">1.1</a>"#),
+        "/same.txt" => Response::new(304),
+        _ => Response::new(200).header("Last-Modified", *current.lock().unwrap()),
+    });
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "foo": {
+                "url": server.url("/foo.txt"),
+                "hash": SRI,
+                "last_modified": "2024-01-01T00:00:00+00:00",
+            },
+            "same": { "url": server.url("/same.txt"), "hash": SRI, "etag": "\"v1\"" },
+            "tool": {
+                "url": server.url("/tool-1.0.tar.gz"),
+                "hash": SRI,
+                "version": "1.0",
+                "version_index": {
+                    "url": server.url("/index/"),
+                    "pattern": r"tool-(\d+\.\d+)\.tar\.gz",
+                },
+            },
+        }
+    }));
+    let before = std::fs::read(env.sources_path()).unwrap();
+    // Dry runs only make the checks outdated makes, so the nix tools are never run.
+    let bin = env.dir.path().join("bin");
+    for tool in ["nix", "nix-prefetch-url"] {
+        std::fs::write(
+            bin.join(tool),
+            "#!/bin/sh\necho \"$0 $@\" >> \"$FAKE_NIX_LOG\"\nexit 1\n",
+        )
+        .unwrap();
+    }
+
+    *modified.lock().unwrap() = "Tue, 02 Jan 2024 00:00:00 GMT";
+    let output = env
+        .command()
+        .args(["update", "--dry-run"])
+        .output()
+        .unwrap();
+    // Out of date, which CI can tell from a failure.
+    assert_eq!(
+        output.status.code(),
+        Some(2),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "foo: would change, changed upstream\n\
+         same: unchanged\n\
+         tool: would change, new version 1.1\n"
+    );
+    assert_eq!(std::fs::read(env.sources_path()).unwrap(), before);
+
+    let output = env
+        .command()
+        .args(["--output", "json", "update", "--dry-run", "same", "tool"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        summary,
+        serde_json::json!({
+            "same": { "status": "unchanged", "reason": null },
+            "tool": { "status": "updated", "reason": "new version 1.1" },
+        })
+    );
+    assert_eq!(env.prefetch_log(), Vec::<String>::new());
+}
+
+#[test]
+fn output_json_prints_results_of_checks() {
+    let server = Server::start(|req| {
+        if req.header("If-None-Match") == Some("\"v1\"") {
+            Response::new(304)
+        } else {
+            Response::new(200).header("ETag", "\"v1\"")
+        }
+    });
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    env.run(&["add", "bar", &server.url("/bar.txt")]);
+    let mut sources = env.sources();
+    sources["sources"]["bar"]["hash"] = SRI.replace('0', "1").into();
+    env.write_sources(sources);
+
+    let output = env
+        .command()
+        .args(["--output", "json", "verify"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(summary["foo"], serde_json::json!({ "status": "ok" }));
+    assert_eq!(summary["bar"]["status"], "mismatch");
+    assert_eq!(summary["bar"]["upstream"], SRI);
+
+    let output = env.run(&["--output", "json", "outdated", "foo"]);
+    let summary: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        summary,
+        serde_json::json!({ "foo": { "status": "up-to-date", "reason": null } })
+    );
+}
+
+#[test]
+fn versions_from_a_listing_fill_the_url_template() {
+    let latest = std::sync::Arc::new(std::sync::Mutex::new("1.10"));
+    let current = latest.clone();
+    let server = Server::start(move |req| {
+        if req.path == "/releases/" {
+            let body = format!(
+                "<a href=\"foo-1.2.tar.gz\">foo-1.2.tar.gz</a>\n\
+                 <a href=\"foo-{0}.tar.gz\">foo-{0}.tar.gz</a>\n\
+                 <a href=\"foo-1.9.tar.gz\">foo-1.9.tar.gz</a>\n",
+                current.lock().unwrap()
+            );
+            Response::new(200).body(body)
+        } else {
+            Response::new(200)
+        }
+    });
+    let env = Env::new();
+    env.run(&[
+        "add",
+        "foo",
+        &server.url("/download/{version}/foo.tar.gz"),
+        "--listing",
+        &server.url("/releases/"),
+        "--version-regex",
+        r"foo-([0-9.]+)\.tar\.gz",
+    ]);
+    let source = &env.sources()["sources"]["foo"];
+    assert_eq!(source["version"], "1.10");
+    assert_eq!(source["url"], server.url("/download/1.10/foo.tar.gz"));
+
+    *latest.lock().unwrap() = "1.11";
+    env.run(&["update", "--check-latest"]);
+    let source = &env.sources()["sources"]["foo"];
+    assert_eq!(source["version"], "1.11");
+    assert_eq!(source["url"], server.url("/download/1.11/foo.tar.gz"));
+}
+
+#[test]
+fn forge_tokens_from_the_config_file_are_sent() {
+    let server = Server::start(|req| match req.path.as_str() {
+        "/repos/owner/repo/commits/main" => Response::new(200).body(REV),
+        _ => Response::new(200),
+    });
+    let env = Env::new();
+    let config = env.dir.path().join("config").join("nix-source");
+    std::fs::create_dir_all(&config).unwrap();
+    std::fs::write(config.join("config.toml"), "github-token = \"secret\"\n").unwrap();
+    let status = env
+        .command()
+        .env("GITHUB_API_URL", server.url(""))
+        .env("GITHUB_SERVER_URL", server.url(""))
+        .args(["add", "foo", "--github", "owner/repo", "--branch", "main"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let requests = server.requests();
+    assert!(requests
+        .iter()
+        .all(|req| req.header("Authorization") == Some("Bearer secret")));
+    // nix-prefetch-url can't send the token, so the tarball is downloaded for it.
+    assert!(requests
+        .iter()
+        .any(|req| req.path.starts_with("/owner/repo/archive/") && req.method == "GET"));
+    assert!(env.prefetch_log()[0].contains("file://"));
+    assert_eq!(env.sources()["sources"]["foo"]["hash"], SRI);
+}
+
+#[test]
+fn credentials_are_sent_to_their_hosts() {
+    let server = Server::start(|req| match req.header("Authorization") {
+        Some(_) => Response::new(200),
+        None => Response::new(401),
+    });
+    let env = Env::new();
+    std::fs::write(
+        env.dir.path().join("netrc"),
+        "machine 127.0.0.1\n  login user\n  password secret\n",
+    )
+    .unwrap();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    for req in server.requests() {
+        // base64 of user:secret
+        assert_eq!(req.header("Authorization"), Some("Basic dXNlcjpzZWNyZXQ="));
+    }
+    // nix-prefetch-url can't authenticate, so the file is downloaded for it.
+    assert!(env.prefetch_log()[0].contains("file://"));
+
+    // The config file takes precedence over .netrc.
+    let config = env.dir.path().join("config").join("nix-source");
+    std::fs::create_dir_all(&config).unwrap();
+    std::fs::write(
+        config.join("config.toml"),
+        "[hosts.\"127.0.0.1\"]\ntoken = \"abc\"\n",
+    )
+    .unwrap();
+    let requests = server.requests().len();
+    env.run(&["update", "--force"]);
+    let last = server.requests().pop().unwrap();
+    assert!(server.requests().len() > requests);
+    assert_eq!(last.header("Authorization"), Some("Bearer abc"));
+    let sources = std::fs::read_to_string(env.sources_path()).unwrap();
+    assert!(!sources.contains("secret") && !sources.contains("abc"));
+}
+
+#[test]
+fn requests_go_through_the_proxy() {
+    let proxy = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&[
+        "--proxy",
+        &proxy.url(""),
+        "add",
+        "foo",
+        "http://example.invalid/foo.txt",
+    ]);
+    let requests = proxy.requests();
+    assert!(!requests.is_empty());
+    assert!(requests
+        .iter()
+        .all(|req| req.path == "http://example.invalid/foo.txt"));
+
+    // Nothing listens on port 1, so this only works if the proxy is bypassed.
+    let server = Server::start(|_| Response::new(200));
+    let output = env
+        .command()
+        .env("http_proxy", "http://127.0.0.1:1")
+        .env("no_proxy", "example.com,127.0.0.1")
+        .args(["add", "bar", &server.url("/bar.txt")])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn certificate_authorities_are_loaded_from_the_ca_file() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    let ca = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/ca.pem");
+    env.run(&["--ca-file", ca, "add", "foo", &server.url("/foo.txt")]);
+    // nix-prefetch-url doesn't trust the same certificates, so the file is downloaded for it.
+    assert!(env.prefetch_log()[0].contains("file://"));
+
+    let empty = env.dir.path().join("empty.pem");
+    std::fs::write(&empty, "").unwrap();
+    let output = env
+        .command()
+        .args(["--ca-file", empty.to_str().unwrap(), "ls"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no certificates found"));
+}
+
+#[test]
+fn rate_limits_are_waited_out_within_the_budget() {
+    let attempts = std::sync::atomic::AtomicUsize::new(0);
+    let server = Server::start(move |_| {
+        if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+            Response::new(429).header("Retry-After", "1")
+        } else {
+            Response::new(200)
+        }
+    });
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    assert_eq!(env.sources()["sources"]["foo"]["hash"], SRI);
+
+    let limited = Server::start(|_| {
+        Response::new(403)
+            .header("X-RateLimit-Remaining", "0")
+            .header("X-RateLimit-Reset", "99999999999")
+    });
+    let start = std::time::Instant::now();
+    let output = env
+        .command()
+        .args(["add", "bar", &limited.url("/bar.txt")])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("rate limited"));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn offline_forbids_network_access() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    let requests = server.requests().len();
+
+    let output = env.run(&["--offline", "get", "foo", "hash"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), SRI);
+    env.run(&["--offline", "ls"]);
+
+    let output = env
+        .command()
+        .args(["--offline", "update"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--offline"));
+    assert_eq!(server.requests().len(), requests);
+    assert_eq!(env.prefetch_log().len(), 1);
+}
+
+#[test]
+fn update_prints_a_line_per_source() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    env.run(&["add", "bar", &server.url("/bar.txt")]);
+    let output = env.run(&["update", "--force"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut lines: Vec<_> = stderr.lines().collect();
+    // Sources are updated concurrently, and reported as they finish.
+    lines.sort();
+    assert_eq!(lines, ["bar: unchanged", "foo: unchanged"]);
+
+    let output = env.run(&["--verbose", "update", "--force"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains(SRI));
+}
+
+#[test]
+fn commit_commits_the_sources_file_if_anything_changed() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    env.run(&["add", "bar", &server.url("/bar.txt")]);
+    let git_log = || std::fs::read_to_string(env.dir.path().join("git.log")).unwrap_or_default();
+    env.run(&["update", "--force", "--commit"]);
+    assert_eq!(git_log(), "");
+
+    let status = env
+        .command()
+        .env(
+            "FAKE_HASH",
+            "1111111111111111111111111111111111111111111111111111",
+        )
+        .args(["update", "--force", "--commit", "foo"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(
+        git_log(),
+        format!(
+            "add -- sources.json\n\
+             commit --message Update foo\n\n\
+             foo: {SRI} -> sha256-1111111111111111111111111111111111111111110=\n \
+             -- sources.json\n"
+        )
+    );
+}
+
+#[test]
+fn commit_commits_included_files_too() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    let team = env.dir.path().join("team");
+    std::fs::create_dir(&team).unwrap();
+    std::fs::write(
+        team.join("sources.toml"),
+        format!(
+            "[sources.foo]\nurl = \"{}\"\nhash = \"{SRI}\"\n",
+            server.url("/foo.txt")
+        ),
+    )
+    .unwrap();
+    env.write_sources(serde_json::json!({ "include": ["team/sources.toml"], "sources": {} }));
+    let status = env
+        .command()
+        .env(
+            "FAKE_HASH",
+            "1111111111111111111111111111111111111111111111111111",
+        )
+        .args(["update", "--force", "--commit"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let log = std::fs::read_to_string(env.dir.path().join("git.log")).unwrap();
+    assert!(
+        log.starts_with("add -- team/sources.toml sources.json\n"),
+        "{log}"
+    );
+    assert!(
+        log.ends_with(" -- team/sources.toml sources.json\n"),
+        "{log}"
+    );
+}
+
+#[test]
+fn failing_post_update_hooks_keep_the_old_source() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&["add", "good", &server.url("/good.txt")]);
+    env.run(&[
+        "add",
+        "bad",
+        &server.url("/bad.txt"),
+        "--post-update",
+        "exit 1",
+    ]);
+    let log = env.dir.path().join("hooks.log");
+    // Hooks see the sources file with the change in it.
+    let hook = format!(
+        "echo $NIX_SOURCE_NAME $NIX_SOURCE_OLD_HASH $NIX_SOURCE_NEW_HASH >> {0} && \
+         grep -c sha256-1111 {1} >> {0}",
+        log.display(),
+        env.sources_path().display()
+    );
+    let output = env
+        .command()
+        .env(
+            "FAKE_HASH",
+            "1111111111111111111111111111111111111111111111111111",
+        )
+        .args(["update", "--force", "-j", "1", "--post-update", &hook])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("post-update hook failed"));
+    let new = "sha256-1111111111111111111111111111111111111111110=";
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        format!("bad {SRI} {new}\n1\ngood {SRI} {new}\n1\n")
+    );
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["bad"]["hash"], SRI);
+    assert_eq!(sources["sources"]["good"]["hash"], new);
+}
+
+#[test]
+fn failing_test_builds_keep_the_old_source() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&["add", "good", &server.url("/good.txt")]);
+    env.run(&[
+        "add",
+        "bad",
+        &server.url("/bad.txt"),
+        "--test-build",
+        ".#broken",
+    ]);
+    let output = env
+        .command()
+        .env(
+            "FAKE_HASH",
+            "1111111111111111111111111111111111111111111111111111",
+        )
+        .args(["update", "--force", "-j", "1", "--test-build", ".#package"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("test build failed"));
+    let builds: Vec<_> = env
+        .prefetch_log()
+        .into_iter()
+        .filter(|line| line.contains(" build "))
+        .collect();
+    assert_eq!(
+        builds,
+        [
+            "--extra-experimental-features nix-command flakes build --no-link .#package",
+            "--extra-experimental-features nix-command flakes build --no-link .#broken",
+            "--extra-experimental-features nix-command flakes build --no-link .#package",
+        ]
+    );
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["bad"]["hash"], SRI);
+    assert_eq!(
+        sources["sources"]["good"]["hash"],
+        "sha256-1111111111111111111111111111111111111111110="
+    );
+}
+
+#[test]
+fn signify_signatures_are_verified() {
+    let server = Server::start(|req| match req.path.as_str() {
+        "/good.sig" => Response::new(200).body("good"),
+        "/bad.sig" => Response::new(200).body("bad"),
+        _ => Response::new(200).body("contents"),
+    });
+    let env = Env::new();
+    // signify -V -q -p <key> -x <signature> -m <file>
+    let signify = env.dir.path().join("bin").join("signify");
+    std::fs::write(
+        &signify,
+        "#!/bin/sh\n[ \"$(tail -n 1 \"$4\")\" = RWKEY ] && [ \"$(cat \"$6\")\" = good ]\n",
+    )
+    .unwrap();
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(&signify, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let add = |name: &str, sig: &str| {
+        env.command()
+            .args(["add", name, &server.url("/foo.tar.gz")])
+            .args([
+                "--verify-sig",
+                &server.url(sig),
+                "--key",
+                "RWKEY",
+                "--signify",
+            ])
+            .output()
+            .unwrap()
+    };
+    let output = add("good", "/good.sig");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let signature = &env.sources()["sources"]["good"]["signature"];
+    assert_eq!(signature["key"], "RWKEY");
+    assert_eq!(signature["signify"], true);
+
+    let output = add("bad", "/bad.sig");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not a valid signature by RWKEY"));
+    assert!(env.sources()["sources"].get("bad").is_none());
+}
+
+#[test]
+fn checksum_files_are_checked() {
+    // sha256 of "contents".
+    const SUM: &str = "d1b2a59fbea7e20077af9f91b27e95e865061b270be03ff539ab3b73587882e8";
+    let sums = std::sync::Arc::new(std::sync::Mutex::new(format!(
+        "{SUM}  foo.tar.gz\nSHA256 (bar.tar.gz) = {SUM}\n"
+    )));
+    let server = Server::start({
+        let sums = sums.clone();
+        move |req| match req.path.as_str() {
+            "/SHA256SUMS" => Response::new(200).body(sums.lock().unwrap().clone()),
+            _ => Response::new(200).body("contents"),
+        }
+    });
+    let env = Env::new();
+    let checksums = server.url("/SHA256SUMS");
+    for name in ["foo", "bar"] {
+        env.run(&[
+            "add",
+            name,
+            &server.url(&format!("/{name}.tar.gz")),
+            "--checksums",
+            &checksums,
+        ]);
+    }
+    assert_eq!(env.sources()["sources"]["foo"]["checksums"], checksums);
+
+    let output = env
+        .command()
+        .args([
+            "add",
+            "baz",
+            &server.url("/baz.tar.gz"),
+            "--checksums",
+            &checksums,
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("has no entry for baz.tar.gz"));
+
+    *sums.lock().unwrap() = format!("{}  foo.tar.gz\n", "0".repeat(64));
+    let output = env
+        .command()
+        .args(["update", "foo", "--force"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains(&format!("foo.tar.gz has sha256 hash {SUM}")));
+}
+
+#[test]
+fn hash_type_can_be_chosen_per_source() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&[
+        "add",
+        "foo",
+        &server.url("/foo.txt"),
+        "--hash-type",
+        "sha512",
+    ]);
+    env.run(&["add", "bar", &server.url("/bar.txt")]);
+    env.run(&[
+        "add",
+        "repo",
+        "https://example.com/repo.git",
+        "--type",
+        "git",
+        "--hash-type",
+        "sha512",
+    ]);
+    env.run(&["update", "--force"]);
+
+    let sources = env.sources();
+    for name in ["foo", "repo"] {
+        let hash = sources["sources"][name]["hash"].as_str().unwrap();
+        assert!(hash.starts_with("sha512-"), "{name}: {hash}");
+    }
+    assert_eq!(sources["sources"]["bar"]["hash"], SRI);
+    // The checkout nix-prefetch-git made is hashed again.
+    assert!(env
+        .prefetch_log()
+        .iter()
+        .any(|l| l.starts_with("hash path --type sha512 /nix/store/")));
+}
+
+#[test]
+fn images_are_pulled_again_only_when_their_tag_moves() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::{Arc, Mutex};
+    let digest = Arc::new(Mutex::new("sha256:aaaa"));
+    let realm = Arc::new(Mutex::new(String::new()));
+    let server = Server::start({
+        let (digest, realm) = (digest.clone(), realm.clone());
+        move |req| match (req.path.as_str(), req.header("Authorization")) {
+            ("/token?service=registry&scope=repository%3Ateam%2Fapp%3Apull", None) => {
+                Response::new(200).body(r#"{"token": "secret"}"#)
+            }
+            ("/v2/team/app/manifests/1.0", Some("Bearer secret")) => {
+                Response::new(200).header("Docker-Content-Digest", &digest.lock().unwrap())
+            }
+            _ => Response::new(401).header(
+                "WWW-Authenticate",
+                &format!(
+                    "Bearer realm=\"{}\",service=\"registry\"",
+                    realm.lock().unwrap()
+                ),
+            ),
+        }
+    });
+    *realm.lock().unwrap() = server.url("/token");
+    let env = Env::new();
+    let log = env.dir.path().join("docker.log");
+    let prefetch = env.dir.path().join("bin").join("nix-prefetch-docker");
+    std::fs::write(
+        &prefetch,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> {}\n\
+             echo '{{\"imageName\": \"'$4'\", \"imageDigest\": \"'$8'\", \"hash\": \"{SRI}\"}}'\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&prefetch, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let image = server.url("/team/app:1.0").replace("http://", "");
+    env.run(&["add", "app", "--type", "oci", &image]);
+
+    let registry = image.split('/').next().unwrap();
+    let source = &env.sources()["sources"]["app"];
+    assert_eq!(source["url"], format!("docker://{registry}/team/app:1.0"));
+    assert_eq!(source["rev"], "sha256:aaaa");
+    assert_eq!(source["hash"], SRI);
+    let calls = || std::fs::read_to_string(&log).unwrap().lines().count();
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap().trim(),
+        format!(
+            "--json --quiet --image-name {registry}/team/app --image-tag 1.0 --image-digest sha256:aaaa"
+        )
+    );
+
+    env.run(&["update"]);
+    assert_eq!(calls(), 1);
+    *digest.lock().unwrap() = "sha256:bbbb";
+    env.run(&["update"]);
+    assert_eq!(calls(), 2);
+    assert_eq!(env.sources()["sources"]["app"]["rev"], "sha256:bbbb");
+
+    let nix = String::from_utf8(env.run(&["emit-nix"]).stdout).unwrap();
+    assert!(nix.starts_with("# Generated by nix-source, do not edit.\n{ dockerTools }:"));
+    assert!(nix.contains(&format!(
+        "dockerTools.pullImage {{\n    imageName = \"{registry}/team/app\";\n    \
+         imageDigest = \"sha256:bbbb\";\n    sha256 = \"{SRI}\";\n    finalImageTag = \"1.0\";\n  }}"
+    )));
+}
+
+#[test]
+fn ipfs_sources_fall_back_to_gateways_serving_their_cid() {
+    const CID: &str = "bafkreihnoabliopjvscf6irvpwbcxlauirzq7pnwafwt5skdekl3t3e7om";
+    let server = Server::start(|req| {
+        if req.path.starts_with("/lying/") {
+            Response::new(200).body("something else")
+        } else if req.path.starts_with("/good/") {
+            Response::new(200).body("content")
+        } else {
+            Response::new(404)
+        }
+    });
+    let env = Env::new();
+    let config = env.dir.path().join("config/nix-source");
+    std::fs::create_dir_all(&config).unwrap();
+    let gateways = ["/lying", "/missing", "/good"].map(|path| format!("\"{}\"", server.url(path)));
+    std::fs::write(
+        config.join("config.toml"),
+        format!("ipfs-gateways = [{}]\n", gateways.join(", ")),
+    )
+    .unwrap();
+    let url = format!("ipfs://{CID}");
+    let output = env
+        .command_with_prefetcher(None)
+        .args(["add", "foo", &url])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let source = &env.sources()["sources"]["foo"];
+    assert_eq!(source["url"], url);
+    assert_eq!(
+        source["hash"],
+        "sha256-7XACtDnprIRfIjV9giusFERzD722AW0+yUMil7nsn3M="
+    );
+    assert!(source.get("resolved_url").is_none());
+
+    // What is on IPFS doesn't change, there is nothing to check.
+    let requests = server.requests().len();
+    env.run(&["update"]);
+    assert_eq!(server.requests().len(), requests);
+
+    let nix = String::from_utf8(env.run(&["emit-nix"]).stdout).unwrap();
+    let urls: Vec<_> = ["/lying", "/missing", "/good"]
+        .map(|path| format!("\"{}/ipfs/{CID}\"", server.url(path)))
+        .into();
+    assert!(
+        nix.contains(&format!("    urls = [ {} ];\n", urls.join(" "))),
+        "{nix}"
+    );
+}
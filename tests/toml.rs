@@ -0,0 +1,40 @@
+mod common;
+
+use common::{Env, Response, Server, SRI};
+
+#[test]
+fn toml_sources_round_trip() {
+    let server = Server::start(|_| Response::new(200).header("ETag", "\"v1\""));
+    let env = Env::with_sources_file("sources.toml");
+    let path = env.sources_path();
+    std::fs::write(&path, "version = 2\n\n[other]\nkey = \"value\"\n").unwrap();
+    env.run(&["add", "foo", &server.url("/foo.tar.gz")]);
+    env.run(&["add", "bar", &server.url("/bar.txt")]);
+    env.run(&["update"]);
+    env.run(&["rm", "bar"]);
+
+    let sources: toml::Value = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(sources["version"].as_integer(), Some(2));
+    assert_eq!(sources["other"]["key"].as_str(), Some("value"));
+    let foo = &sources["sources"]["foo"];
+    assert_eq!(foo["hash"].as_str(), Some(SRI));
+    assert_eq!(foo["etag"].as_str(), Some("\"v1\""));
+    assert_eq!(foo["type"].as_str(), Some("tarball"));
+    assert!(sources["sources"].get("bar").is_none());
+}
+
+#[test]
+fn format_overrides_the_extension() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::with_sources_file("sources.lock");
+    env.run(&["--format", "toml", "add", "foo", &server.url("/foo.tar.gz")]);
+
+    let text = std::fs::read_to_string(env.sources_path()).unwrap();
+    let sources: toml::Value = toml::from_str(&text).unwrap();
+    assert_eq!(sources["sources"]["foo"]["hash"].as_str(), Some(SRI));
+    let output = env.run(&["--format", "toml", "get", "foo", "hash"]);
+    assert_eq!(output.stdout, format!("{SRI}\n").as_bytes());
+    // Read as JSON, the file makes no sense.
+    let output = env.command().args(["get", "foo", "hash"]).output().unwrap();
+    assert!(!output.status.success());
+}
@@ -0,0 +1,421 @@
+mod common;
+
+use common::{Env, Response, Server, HASH, SRI};
+
+#[test]
+fn sources_file_is_replaced_atomically() {
+    let env = Env::new();
+    let sources: serde_json::Map<_, _> = (0..2000)
+        .map(|i| {
+            let source = serde_json::json!({ "url": format!("https://example.com/{i}.tar.gz") });
+            (format!("source-{i}"), source)
+        })
+        .collect();
+    env.write_sources(serde_json::json!({ "sources": sources }));
+
+    let done = std::sync::atomic::AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        let reader = scope.spawn(|| {
+            let mut reads = 0;
+            while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                let contents = std::fs::read(env.sources_path()).unwrap();
+                serde_json::from_slice::<serde_json::Value>(&contents)
+                    .expect("sources file is always complete");
+                reads += 1;
+            }
+            reads
+        });
+        for i in 0..20 {
+            env.run(&["rm", &format!("source-{i}")]);
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert!(reader.join().unwrap() > 0);
+    });
+    assert_eq!(env.sources()["sources"].as_object().unwrap().len(), 1980);
+}
+
+#[test]
+fn concurrent_changes_are_not_lost() {
+    let server = Server::start(|_| {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        Response::new(200)
+    });
+    let env = Env::new();
+    std::thread::scope(|scope| {
+        for i in 0..4 {
+            let (env, server) = (&env, &server);
+            scope.spawn(move || {
+                env.run(&[
+                    "add",
+                    &format!("source-{i}"),
+                    &server.url(&format!("/{i}.txt")),
+                ]);
+            });
+        }
+    });
+    assert_eq!(env.sources()["sources"].as_object().unwrap().len(), 4);
+}
+
+#[test]
+fn no_wait_fails_if_the_sources_file_is_locked() {
+    let env = Env::new();
+    env.write_sources(
+        serde_json::json!({ "sources": { "foo": { "url": "https://example.com/foo" } } }),
+    );
+    let lock = std::fs::File::create(env.dir.path().join("sources.json.lock")).unwrap();
+    lock.lock().unwrap();
+
+    let output = env
+        .command()
+        .args(["--no-wait", "rm", "foo"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("is locked by another process"),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    lock.unlock().unwrap();
+    env.run(&["--no-wait", "rm", "foo"]);
+    assert!(env.sources()["sources"].as_object().unwrap().is_empty());
+}
+
+#[test]
+fn readers_dont_create_the_lock_file() {
+    let env = Env::new();
+    env.write_sources(
+        serde_json::json!({ "sources": { "foo": { "url": "https://example.com/foo" } } }),
+    );
+    let lock_path = env.dir.path().join("sources.json.lock");
+    env.run(&["--read-only", "ls"]);
+    env.run(&["show", "foo"]);
+    assert!(!lock_path.exists());
+
+    // Once there is one, readers still wait for writers.
+    let lock = std::fs::File::create(&lock_path).unwrap();
+    lock.lock().unwrap();
+    let output = env
+        .command()
+        .args(["--no-wait", "--read-only", "ls"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is locked by another process"));
+}
+
+#[test]
+fn sources_are_written_in_name_order() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    for name in ["zlib", "curl", "openssl", "bzip2"] {
+        env.run(&["add", name, &server.url(&format!("/{name}.tar.gz"))]);
+    }
+    let contents = std::fs::read_to_string(env.sources_path()).unwrap();
+    let positions: Vec<_> = ["bzip2", "curl", "openssl", "zlib"]
+        .iter()
+        .map(|name| contents.find(&format!("\"{name}\": {{")).unwrap())
+        .collect();
+    assert!(positions.is_sorted(), "{contents}");
+}
+
+#[test]
+fn frozen_fails_instead_of_modifying_the_sources_file() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    let before = std::fs::read(env.sources_path()).unwrap();
+
+    // Nothing changes, so there is nothing to forbid.
+    env.run(&["--frozen", "update", "--force"]);
+    let output = env
+        .command()
+        .env(
+            "FAKE_HASH",
+            "1111111111111111111111111111111111111111111111111111",
+        )
+        .args(["--frozen", "update", "--force"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--frozen"));
+    assert_eq!(std::fs::read(env.sources_path()).unwrap(), before);
+}
+
+#[test]
+fn unparseable_sources_files_are_only_replaced_with_force() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    std::fs::write(env.sources_path(), "{ \"sources\": ").unwrap();
+    let output = env
+        .command()
+        .args(["add", "foo", &server.url("/foo.txt")])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("pass --force"));
+    assert_eq!(
+        std::fs::read_to_string(env.sources_path()).unwrap(),
+        "{ \"sources\": "
+    );
+
+    env.run(&["--force", "add", "foo", &server.url("/foo.txt")]);
+    let backup = env.dir.path().join("sources.json.bak");
+    assert_eq!(std::fs::read_to_string(backup).unwrap(), "{ \"sources\": ");
+    assert_eq!(env.sources()["sources"]["foo"]["hash"], SRI);
+}
+
+#[test]
+fn upgrade_converts_old_sources_files() {
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "foo": { "url": "https://example.com/foo.tar.gz", "sha256": HASH },
+            "bar": { "url": "https://example.com/bar.tar.gz", "hash": HASH },
+            "baz": { "url": "https://example.com/baz.tar.gz", "hash": SRI },
+        }
+    }));
+    env.run(&["upgrade"]);
+    let sources = env.sources();
+    assert_eq!(sources["version"], 2);
+    for name in ["foo", "bar", "baz"] {
+        assert_eq!(sources["sources"][name]["hash"], SRI, "{name}");
+    }
+    assert!(sources["sources"]["foo"].get("sha256").is_none());
+
+    // Files from a newer version are left alone.
+    env.write_sources(serde_json::json!({ "version": 3, "sources": {} }));
+    for args in [&["ls"][..], &["upgrade"]] {
+        let output = env.command().args(args).output().unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("version 3"));
+    }
+}
+
+#[test]
+fn fmt_rewrites_the_sources_file() {
+    let env = Env::new();
+    std::fs::write(
+        env.sources_path(),
+        r#"{"sources": {"zlib": {"url": "https://example.com/zlib.tar.gz"},
+            "curl": {"url": "https://example.com/curl.tar.gz"}}}"#,
+    )
+    .unwrap();
+    let check = || env.command().args(["fmt", "--check"]).output().unwrap();
+    let output = check();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not formatted"));
+
+    env.run(&["fmt"]);
+    assert!(check().status.success());
+    let contents = std::fs::read_to_string(env.sources_path()).unwrap();
+    assert!(contents.find("\"curl\"").unwrap() < contents.find("\"zlib\"").unwrap());
+    env.run(&["fmt"]);
+    assert_eq!(
+        std::fs::read_to_string(env.sources_path()).unwrap(),
+        contents
+    );
+
+    // Included files are checked with only their own sources.
+    let team = env.dir.path().join("team");
+    std::fs::create_dir(&team).unwrap();
+    std::fs::write(
+        team.join("sources.toml"),
+        "[sources.zstd]\nurl = \"https://example.com/zstd.tar.gz\"\n",
+    )
+    .unwrap();
+    env.write_sources(serde_json::json!({ "include": ["team/sources.toml"], "sources": {} }));
+    assert!(!check().status.success());
+    env.run(&["fmt"]);
+    let output = check();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let root = std::fs::read_to_string(env.sources_path()).unwrap();
+    assert!(!root.contains("zstd"), "{root}");
+    let team = std::fs::read_to_string(team.join("sources.toml")).unwrap();
+    assert!(team.contains("zstd"), "{team}");
+}
+
+#[test]
+fn rename_keeps_the_source_in_its_file() {
+    let env = Env::new();
+    let team = env.dir.path().join("team");
+    std::fs::create_dir(&team).unwrap();
+    std::fs::write(
+        team.join("sources.toml"),
+        "[sources.zstd]\nurl = \"https://example.com/zstd.tar.gz\"\n",
+    )
+    .unwrap();
+    env.write_sources(serde_json::json!({
+        "include": ["team/sources.toml"],
+        "sources": { "foo": { "url": "https://example.com/foo.tar.gz" } },
+    }));
+    let checked = env.dir.path().join(".nix-source/last-checked.json");
+    std::fs::create_dir_all(checked.parent().unwrap()).unwrap();
+    std::fs::write(&checked, r#"{"zstd": "2026-01-01T00:00:00+00:00"}"#).unwrap();
+
+    env.run(&["rename", "zstd", "libzstd"]);
+    let team = std::fs::read_to_string(team.join("sources.toml")).unwrap();
+    assert!(team.contains("[sources.libzstd]"), "{team}");
+    assert!(env.sources()["sources"].get("libzstd").is_none());
+    // It isn't taken for a source that was never checked.
+    let times: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&checked).unwrap()).unwrap();
+    assert_eq!(
+        times,
+        serde_json::json!({ "libzstd": "2026-01-01T00:00:00Z" })
+    );
+
+    for (old, new, error) in [
+        ("foo", "libzstd", "source libzstd already exists"),
+        ("zstd", "bar", "source zstd does not exist"),
+    ] {
+        let output = env.command().args(["rename", old, new]).output().unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains(error));
+    }
+}
+
+#[test]
+fn rollback_restores_the_previous_pin() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    env.run(&["add", "foo", &server.url("/foo.txt")]);
+    let new_hash = "1".repeat(52);
+    let new_sri = format!("sha256-{}0=", &new_hash[..42]);
+    let output = env
+        .command()
+        .env("FAKE_HASH", &new_hash)
+        .args(["update", "foo", "--force"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["foo"]["hash"], new_sri);
+    let history = sources["sources"]["foo"]["history"].as_array().unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0]["hash"], SRI);
+
+    let requests = server.requests().len();
+    env.run(&["--offline", "rollback", "foo"]);
+    assert_eq!(server.requests().len(), requests);
+    let sources = env.sources();
+    assert_eq!(sources["sources"]["foo"]["hash"], SRI);
+    // Rolling back again undoes it.
+    assert_eq!(sources["sources"]["foo"]["history"][0]["hash"], new_sri);
+    let output = env.run(&["rollback", "foo", "--list"]);
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with(&format!("1: - {new_sri} ")));
+
+    let output = env
+        .command()
+        .args(["rollback", "foo", "2"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("there are 1 previous pins, not 2"));
+}
+
+#[test]
+fn included_sources_files_are_merged_and_written_back() {
+    let server = Server::start(|_| Response::new(200));
+    let env = Env::new();
+    let team = env.dir.path().join("team");
+    std::fs::create_dir(&team).unwrap();
+    std::fs::write(
+        team.join("sources.toml"),
+        format!(
+            "[sources.theirs]\nurl = \"{}\"\n",
+            server.url("/theirs.txt")
+        ),
+    )
+    .unwrap();
+    env.write_sources(serde_json::json!({ "include": ["team/sources.toml"], "sources": {} }));
+
+    env.run(&["add", "ours", &server.url("/ours.txt")]);
+    env.run(&["update", "theirs"]);
+    let output = env.run(&["ls", "--names"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ours\ntheirs\n");
+
+    // Each source stays in the file it came from, new ones go to the including file.
+    let sources = env.sources();
+    assert_eq!(sources["include"], serde_json::json!(["team/sources.toml"]));
+    assert!(sources["sources"].get("theirs").is_none());
+    assert_eq!(sources["sources"]["ours"]["hash"], SRI);
+    let theirs: toml::Value =
+        toml::from_str(&std::fs::read_to_string(team.join("sources.toml")).unwrap()).unwrap();
+    assert_eq!(theirs["sources"]["theirs"]["hash"].as_str(), Some(SRI));
+    assert!(theirs["sources"].get("ours").is_none());
+
+    // A name can only be defined once.
+    std::fs::write(
+        team.join("sources.toml"),
+        format!("[sources.ours]\nurl = \"{}\"\n", server.url("/ours.txt")),
+    )
+    .unwrap();
+    let output = env.command().args(["ls"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("ours is defined again"));
+}
+
+#[test]
+fn removals_are_listed_as_json_on_dry_runs() {
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": { "foo": { "url": "https://example.com/foo.tar.gz" } }
+    }));
+    let before = std::fs::read(env.sources_path()).unwrap();
+    let output = env.run(&["--output", "json", "rm", "foo", "--dry-run"]);
+    let removed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(removed, serde_json::json!(["foo"]));
+    assert_eq!(std::fs::read(env.sources_path()).unwrap(), before);
+}
+
+#[test]
+fn duplicates_are_listed_as_json() {
+    let env = Env::new();
+    let source = serde_json::json!({ "url": "https://example.com/foo.tar.gz", "hash": SRI });
+    env.write_sources(serde_json::json!({
+        "sources": { "a": source, "b": source, "c": source, "d": { "url": "https://example.com/d" } }
+    }));
+    let output = env.run(&["--output", "json", "dedupe", "--apply"]);
+    let duplicates: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(duplicates, serde_json::json!({ "b": "a", "c": "a" }));
+    let sources = env.sources();
+    let names: Vec<_> = sources["sources"].as_object().unwrap().keys().collect();
+    assert_eq!(names, ["a", "d"]);
+
+    // There is no one to confirm the removals.
+    let output = env
+        .command()
+        .args(["--output", "json", "dedupe"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn unreferenced_sources_are_listed_as_json() {
+    let env = Env::new();
+    env.write_sources(serde_json::json!({
+        "sources": {
+            "foo": { "url": "https://example.com/foo.tar.gz" },
+            "bar": { "url": "https://example.com/bar.tar.gz" },
+            "baz": { "url": "https://example.com/baz.tar.gz" },
+        }
+    }));
+    let nix = env.dir.path().join("default.nix");
+    std::fs::write(&nix, "sources.foo").unwrap();
+    let nix = nix.to_str().unwrap();
+    let prune = |args: &[&str]| {
+        let output = env.run(&[&["--output", "json", "prune", nix], args].concat());
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+    assert_eq!(prune(&[]), serde_json::json!(["bar", "baz"]));
+    assert_eq!(env.sources()["sources"].as_object().unwrap().len(), 3);
+    assert_eq!(prune(&["--yes"]), serde_json::json!(["bar", "baz"]));
+    assert_eq!(env.sources()["sources"].as_object().unwrap().len(), 1);
+}